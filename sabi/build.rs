@@ -0,0 +1,66 @@
+//! Bakes the names of replicated types into the binary.
+//!
+//! Set `SABI_TYPES_PATH` to an absolute path to a `types.toml` while building and the names
+//! will be embedded as a const map so `ReplicateId::try_name` works without the file. The
+//! ids are recomputed from the names, so two types hashing to the same id fail the build.
+//! With the `toml-ids` feature the ids in the file are embedded as they are.
+
+use std::{env, fs, path::PathBuf};
+
+/// Same as `ReplicateId::from_name`.
+fn replicate_id(name: &str) -> u16 {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in name.as_bytes() {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+
+    hash as u16
+}
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=SABI_TYPES_PATH");
+    let toml_ids = env::var_os("CARGO_FEATURE_TOML_IDS").is_some();
+
+    let out_path = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR")).join("types.rs");
+
+    let mut types: Vec<(String, u16)> = Vec::new();
+    if let Some(path) = env::var_os("SABI_TYPES_PATH").filter(|path| !path.is_empty()) {
+        let path = PathBuf::from(path);
+        println!("cargo:rerun-if-changed={}", path.display());
+
+        let contents = fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("could not read {}: {}", path.display(), err));
+        let value: toml::Value = toml::from_str(&contents)
+            .unwrap_or_else(|err| panic!("could not parse {}: {}", path.display(), err));
+
+        if let Some(replicate) = value.get("replicate").and_then(|r| r.as_table()) {
+            for (name, id) in replicate {
+                let id = match id.as_integer() {
+                    Some(id) if toml_ids => id as u16,
+                    _ => replicate_id(name),
+                };
+                types.push((name.clone(), id));
+            }
+        }
+    }
+
+    types.sort_by_key(|(_, id)| *id);
+    for pair in types.windows(2) {
+        if pair[0].1 == pair[1].1 {
+            panic!(
+                "replicate id {} is used by multiple types: {}, {}, rename one of them",
+                pair[0].1, pair[0].0, pair[1].0
+            );
+        }
+    }
+
+    let mut generated = String::from("pub const EMBEDDED_TYPES: &[(&str, u16)] = &[\n");
+    for (name, id) in types {
+        generated.push_str(&format!("    ({:?}, {}),\n", name, id));
+    }
+    generated.push_str("];\n");
+
+    fs::write(&out_path, generated)
+        .unwrap_or_else(|err| panic!("could not write {}: {}", out_path.display(), err));
+}
@@ -0,0 +1,96 @@
+//! Smooth rendering between fixed network ticks.
+//!
+//! Run with `cargo run --example interpolation`.
+//!
+//! The simulation only moves things on the network tick, which is usually slower than the
+//! frame rate. Keeping the position from before the last tick around lets rendering lerp
+//! between the two using `NetworkSimulationInfo::interpolation_alpha`.
+
+use std::time::Duration;
+
+use bevy::{app::ScheduleRunnerSettings, prelude::*};
+
+use sabi::{
+    stage::{
+        NetworkCoreStage, NetworkSimulationAppExt, NetworkSimulationInfo, NetworkSimulationStage,
+        NetworkStage,
+    },
+    tick::tick_hz,
+};
+
+/// Simulated position, only changes on network ticks.
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct Position(pub Vec3);
+
+/// `Position` before the last tick.
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct PreviousPosition(pub Vec3);
+
+pub const SPEED: f32 = 2.0;
+
+/// Runs on the network tick.
+pub fn simulate(
+    sim_info: Res<NetworkSimulationInfo>,
+    mut query: Query<(&mut Position, &mut PreviousPosition)>,
+) {
+    let delta = sim_info.step.as_secs_f32();
+    for (mut position, mut previous) in &mut query {
+        previous.0 = position.0;
+        position.0.x += SPEED * delta;
+    }
+}
+
+/// Runs every frame after the network stage.
+pub fn interpolate(
+    sim_info: Res<NetworkSimulationInfo>,
+    mut query: Query<(&Position, &PreviousPosition, &mut Transform)>,
+) {
+    let alpha = sim_info.interpolation_alpha();
+    for (position, previous, mut transform) in &mut query {
+        transform.translation = previous.0.lerp(position.0, alpha);
+    }
+}
+
+pub fn app() -> App {
+    let mut app = App::new();
+    app.insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
+        1.0 / 144.0,
+    )));
+    app.add_plugins(MinimalPlugins);
+    app.add_plugin(bevy::log::LogPlugin::default());
+
+    app.insert_resource(sabi::Local);
+    app.add_stage_before(
+        CoreStage::Update,
+        NetworkStage,
+        NetworkSimulationStage::new(tick_hz(20)),
+    );
+    app.add_network_stage(NetworkCoreStage::Update, SystemStage::parallel());
+
+    app.add_network_system(simulate);
+    app.add_system(interpolate);
+    app.world.spawn((
+        Position::default(),
+        PreviousPosition::default(),
+        Transform::default(),
+    ));
+    app
+}
+
+fn main() {
+    app().run();
+}
+
+#[test]
+pub fn smoke() {
+    let mut app = app();
+    for _ in 0..10 {
+        app.update();
+    }
+
+    let alpha = app
+        .world
+        .resource::<NetworkSimulationInfo>()
+        .interpolation_alpha();
+    assert!((0.0..=1.0).contains(&alpha));
+}
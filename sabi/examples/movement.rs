@@ -0,0 +1,136 @@
+//! Minimal authoritative movement.
+//!
+//! Run a server with `cargo run --example movement -- --server` and then any number of
+//! clients with `cargo run --example movement -- --client`.
+//!
+//! The client samples its input every frame, predicts its own movement and gets corrected
+//! by the server's replicated `Transform` when they disagree.
+
+use std::time::Duration;
+
+use bevy::{app::ScheduleRunnerSettings, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use sabi::{
+    prelude::*,
+    protocol::{localhost_ip, new_renet_client, new_renet_server, PlayerSpawner, PORT},
+    stage::NetworkSimulationAppExt,
+};
+
+/// Input the client sends to the server every tick.
+#[derive(Resource, Component, Debug, Default, Clone, Serialize, Deserialize)]
+pub struct PlayerInput {
+    pub direction: Vec2,
+}
+
+/// Marker for player characters.
+#[derive(Component, Debug, Default, Clone)]
+pub struct Player;
+
+pub const SPEED: f32 = 5.0;
+
+fn apply_movement(input: &PlayerInput, transform: &mut Transform, delta: f32) {
+    let direction = input.direction.clamp_length_max(1.0);
+    transform.translation += Vec3::new(direction.x, 0.0, direction.y) * SPEED * delta;
+}
+
+/// Authoritative movement, the server has the input of each player on their entity.
+pub fn server_movement(
+    sim_info: Res<sabi::stage::NetworkSimulationInfo>,
+    mut players: Query<(&PlayerInput, &mut Transform), With<Player>>,
+) {
+    let delta = sim_info.step.as_secs_f32();
+    for (input, mut transform) in &mut players {
+        apply_movement(input, &mut *transform, delta);
+    }
+}
+
+/// Predicted movement, the client only knows its own input.
+pub fn client_movement(
+    sim_info: Res<sabi::stage::NetworkSimulationInfo>,
+    input: Res<PlayerInput>,
+    mut players: Query<&mut Transform, With<Owned>>,
+) {
+    let delta = sim_info.step.as_secs_f32();
+    for mut transform in &mut players {
+        apply_movement(&*input, &mut *transform, delta);
+    }
+}
+
+pub fn spawn_player(commands: &mut Commands, client_id: ClientId) -> Entity {
+    commands
+        .spawn((
+            Player,
+            Owned,
+            PlayerInput::default(),
+            Transform::default(),
+            GlobalTransform::default(),
+            Name::new(format!("Player {}", client_id)),
+        ))
+        .id()
+}
+
+/// Walk in a circle, a real game would read the keyboard/gamepad here.
+pub fn sample_input(time: Res<Time>, mut input: ResMut<PlayerInput>) {
+    let angle = time.elapsed_seconds();
+    input.direction = Vec2::new(angle.cos(), angle.sin());
+}
+
+pub fn base_app() -> App {
+    let mut app = App::new();
+    app.insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
+        1.0 / 60.0,
+    )));
+    app.add_plugins(MinimalPlugins);
+    app.add_plugin(bevy::log::LogPlugin::default());
+    app
+}
+
+pub fn server_app(port: u16) -> App {
+    let mut app = base_app();
+    app.insert_resource(sabi::Server);
+    app.insert_resource(
+        new_renet_server(localhost_ip(), None, port).expect("could not create server"),
+    );
+    app.insert_resource(PlayerSpawner::new(spawn_player));
+    app.add_plugin(SabiPlugin::<PlayerInput>::default());
+
+    app.add_network_system(server_movement);
+    app
+}
+
+pub fn client_app(port: u16) -> App {
+    let mut app = base_app();
+    app.insert_resource(sabi::Client);
+    app.insert_resource(new_renet_client(localhost_ip(), port).expect("could not create client"));
+    app.insert_resource(PlayerInput::default());
+    app.add_plugin(SabiPlugin::<PlayerInput>::default());
+
+    app.add_system(sample_input);
+    app.add_network_system(client_movement);
+    app
+}
+
+fn main() {
+    let mode = std::env::args().nth(1);
+    let mut app = match mode.as_deref() {
+        Some("--server") => server_app(PORT),
+        Some("--client") => client_app(PORT),
+        _ => {
+            println!("usage: movement [--server|--client]");
+            return;
+        }
+    };
+
+    app.run();
+}
+
+#[test]
+pub fn smoke() {
+    let mut server = server_app(0);
+    for _ in 0..10 {
+        server.update();
+    }
+
+    assert!(server.world.contains_resource::<NetworkTick>());
+}
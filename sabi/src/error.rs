@@ -1,6 +1,13 @@
 #[derive(Debug, Clone)]
 pub enum SabiError {
     NoSocketAddr,
+    MessageTooLarge { size: usize, max: usize },
+    Compress(String),
+    Decompress(String),
+    Serialize(String),
+    Deserialize(String),
+    DuplicateReplicateId { id: u16, names: Vec<String> },
+    RegistryVersionMismatch { expected: u32, found: u32 },
 }
 
 impl std::error::Error for SabiError {}
@@ -9,6 +16,24 @@ impl std::fmt::Display for SabiError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             &Self::NoSocketAddr => write!(f, "no socket addr found"),
+            &Self::MessageTooLarge { size, max } => {
+                write!(f, "message too large: {} bytes, max {} bytes", size, max)
+            }
+            &Self::Compress(ref err) => write!(f, "could not compress message: {}", err),
+            &Self::Decompress(ref err) => write!(f, "could not decompress message: {}", err),
+            &Self::Serialize(ref err) => write!(f, "could not serialize: {}", err),
+            &Self::Deserialize(ref err) => write!(f, "could not deserialize message: {}", err),
+            &Self::DuplicateReplicateId { id, ref names } => write!(
+                f,
+                "replicate id {} is used by multiple types: {}",
+                id,
+                names.join(", ")
+            ),
+            &Self::RegistryVersionMismatch { expected, found } => write!(
+                f,
+                "types registry is version {} but version {} is expected, delete it to regenerate",
+                found, expected
+            ),
         }
     }
 }
@@ -30,17 +30,34 @@ pub struct Local;
 pub mod prelude {
     #[cfg(feature = "public")]
     pub use crate::protocol::{
-        ClientChannel, Owned, ServerChannel, ServerEntities, ServerEntity, ServerMessage,
+        ClientChannel, Owned, OwnedBy, ServerChannel, ServerEntities, ServerEntity,
+        ServerEntityDespawned, ServerMessage,
     };
 
     pub use crate::error::SabiError;
-    pub use crate::lobby::{ClientId, Lobby};
-    pub use crate::tick::{tick_hz, NetworkTick};
+    pub use crate::lobby::{
+        ClientId, LocalPlayer, Lobby, PlayerConnected, PlayerDisconnected, PlayerInfo,
+    };
+    pub use crate::tick::{tick_hz, NetworkTick, NetworkTimer};
 
     #[cfg(feature = "public")]
-    pub use crate::plugin::{ReplicatePlugin, SabiPlugin};
+    pub use crate::plugin::{
+        CorrectionPlugin, DisconnectGrace, InterpolationPlugin, PredictionPlugin, ReplicateAppExt,
+        ReplicateConfig, ReplicatePlugin, ReplicateResourcePlugin, SabiPlugin, ServerDisconnected,
+        ServerLost,
+    };
+    #[cfg(feature = "public")]
+    pub use crate::protocol::codec::{MalformedMessage, NetworkErrorStats};
+    #[cfg(feature = "public")]
+    pub use crate::protocol::stats::{ReceivedStats, ReplicationStats};
     #[cfg(feature = "public")]
-    pub use crate::replicate::{replicate_id, ReplicateId};
+    pub use crate::protocol::correction::{CorrectionError, CorrectionMode};
+    #[cfg(feature = "public")]
+    pub use crate::protocol::interpolate::{Interpolate, InterpolationDelay};
+    #[cfg(feature = "public")]
+    pub use crate::replicate::{
+        replicate_id, NoReplicate, ReplicateId, ReplicateRegistry, WorldReplicateExt,
+    };
 }
 
 #[cfg(feature = "public")]
@@ -6,5 +6,132 @@ pub type ClientId = u64;
 /// Renet Client ID -> Player Character Entity mapping
 #[derive(Resource, Debug, Default)]
 pub struct Lobby {
-    pub players: HashMap<ClientId, Entity>,
+    players: HashMap<ClientId, Entity>,
+    /// Reverse of `players`.
+    clients: HashMap<Entity, ClientId>,
+}
+
+impl Lobby {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set a client's player entity, returns the entity they had before.
+    pub fn insert(&mut self, client_id: ClientId, entity: Entity) -> Option<Entity> {
+        if let Some(other) = self.clients.insert(entity, client_id) {
+            if other != client_id {
+                self.players.remove(&other);
+            }
+        }
+
+        let previous = self.players.insert(client_id, entity);
+        if let Some(previous) = previous {
+            if previous != entity {
+                self.clients.remove(&previous);
+            }
+        }
+
+        previous
+    }
+
+    pub fn remove(&mut self, client_id: &ClientId) -> Option<Entity> {
+        let entity = self.players.remove(client_id)?;
+        self.clients.remove(&entity);
+        Some(entity)
+    }
+
+    /// Player entity of a client.
+    pub fn entity(&self, client_id: &ClientId) -> Option<Entity> {
+        self.players.get(client_id).cloned()
+    }
+
+    /// Client that a player entity belongs to.
+    pub fn client_of(&self, entity: Entity) -> Option<ClientId> {
+        self.clients.get(&entity).cloned()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ClientId, &Entity)> {
+        self.players.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.players.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.players.is_empty()
+    }
+}
+
+/// Server side, a client connected and was given a player entity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlayerConnected {
+    pub client_id: ClientId,
+    pub entity: Entity,
+}
+
+/// Server side, a client disconnected and their player entity was despawned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlayerDisconnected {
+    pub client_id: ClientId,
+}
+
+/// Server side info about the client a player entity belongs to.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlayerInfo {
+    pub client_id: ClientId,
+    /// Round trip time to the client, updated as their inputs come in.
+    pub ping_ms: u32,
+}
+
+impl PlayerInfo {
+    pub fn new(client_id: ClientId) -> Self {
+        Self {
+            client_id,
+            ping_ms: 0,
+        }
+    }
+}
+
+/// Which player we are on the client, set by the server.
+#[derive(Resource, Debug, Default, Clone, Copy)]
+pub struct LocalPlayer {
+    pub id: Option<ClientId>,
+}
+
+impl LocalPlayer {
+    /// Our player's entity if the server has told us about it yet.
+    pub fn entity(&self, lobby: &Lobby) -> Option<Entity> {
+        self.id.and_then(|id| lobby.entity(&id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn reverse_lookup_in_sync() {
+        let mut lobby = Lobby::new();
+        let first = Entity::from_raw(1);
+        let second = Entity::from_raw(2);
+
+        assert_eq!(lobby.insert(1, first), None);
+        assert_eq!(lobby.client_of(first), Some(1));
+
+        // Respawned player, the old entity isn't theirs anymore.
+        assert_eq!(lobby.insert(1, second), Some(first));
+        assert_eq!(lobby.client_of(first), None);
+        assert_eq!(lobby.client_of(second), Some(1));
+
+        // Entity handed to another client.
+        lobby.insert(2, second);
+        assert_eq!(lobby.entity(&1), None);
+        assert_eq!(lobby.client_of(second), Some(2));
+        assert_eq!(lobby.len(), 1);
+
+        assert_eq!(lobby.remove(&2), Some(second));
+        assert_eq!(lobby.client_of(second), None);
+        assert!(lobby.is_empty());
+    }
 }
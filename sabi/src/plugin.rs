@@ -1,10 +1,14 @@
-use std::{marker::PhantomData, time::Duration};
+use std::{marker::PhantomData, path::PathBuf, time::Duration};
 
 use bevy::prelude::*;
+#[cfg(feature = "public")]
+use bevy::ecs::query::ReadOnlyWorldQuery;
+#[cfg(feature = "public")]
+use bevy::transform::TransformSystem;
 use bevy::reflect::GetTypeRegistration;
 #[cfg(feature = "public")]
 use bevy_renet::{
-    renet::{RenetClient, RenetError, RenetServer},
+    renet::{DisconnectionReason, NetcodeDisconnectReason, RenetClient, RenetError, RenetServer},
     RenetClientPlugin,
 };
 use iyes_loopless::prelude::{ConditionHelpers, IntoConditionalSystem};
@@ -12,11 +16,13 @@ use serde::{Deserialize, Serialize};
 
 use crate::stage::{
     NetworkCoreStage, NetworkSimulationAppExt, NetworkSimulationInfo, NetworkSimulationStage,
-    NetworkStage,
+    NetworkStage, DEFAULT_MAX_TICKS_PER_FRAME,
 };
 #[cfg(feature = "public")]
 use crate::{
     protocol::{
+        correction::{CorrectionMode, CorrectionTime, PreCorrection, Smooth},
+        interpolate::{Interpolate, InterpolationDelay},
         resim::SnapshotBuffer,
         update::{server_send_interest, EntityUpdate},
     },
@@ -28,10 +34,222 @@ use crate::prelude::*;
 #[cfg(feature = "public")]
 use crate::protocol::*;
 
+/// How a component should be replicated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplicateConfig {
+    /// Only look for changes every `frequency` ticks, changes in between are sent together.
+    pub frequency: u64,
+    /// Rewind and resimulate `Owned` entities on the client, see `PredictionPlugin`.
+    pub predict_owned: bool,
+    /// Send changes against the previous value instead of the whole component, see
+    /// `protocol::delta`.
+    pub delta: bool,
+    /// Send changes on the reliable `ServerChannel::BaselineUpdate` instead of the unreliable
+    /// `ServerChannel::EntityUpdate`, for components that rarely change.
+    pub reliable: bool,
+}
+
+impl Default for ReplicateConfig {
+    fn default() -> Self {
+        Self {
+            frequency: 1,
+            predict_owned: true,
+            delta: false,
+            reliable: false,
+        }
+    }
+}
+
+impl ReplicateConfig {
+    pub fn with_frequency(mut self, frequency: u64) -> Self {
+        self.frequency = frequency;
+        self
+    }
+
+    /// Don't predict `Owned` entities, for adding a `PredictionPlugin` with a different filter.
+    pub fn without_prediction(mut self) -> Self {
+        self.predict_owned = false;
+        self
+    }
+
+    pub fn with_delta(mut self) -> Self {
+        self.delta = true;
+        self
+    }
+
+    pub fn with_reliable(mut self) -> Self {
+        self.reliable = true;
+        self
+    }
+}
+
+/// Client side prediction for `C` on entities matching the filter `F`.
+///
+/// Only these entities are snapshotted, rewound and resimulated. Everything else keeps the
+/// latest state received from the server, since we have no inputs to resimulate it with.
+#[cfg(feature = "public")]
+pub struct PredictionPlugin<C, F = With<Owned>>(PhantomData<(C, F)>);
+
+#[cfg(feature = "public")]
+impl<C, F> Default for PredictionPlugin<C, F> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+#[cfg(feature = "public")]
+impl<C, F> Plugin for PredictionPlugin<C, F>
+where
+    C: 'static + Component + Clone,
+    F: 'static + ReadOnlyWorldQuery + Send + Sync,
+{
+    fn build(&self, app: &mut App) {
+        app.insert_resource(SnapshotBuffer::<C>::new());
+        app.init_resource::<NetworkBufferConfig>();
+        app.add_event::<ServerDisconnected>();
+        app.add_system(reset_on_disconnect::<SnapshotBuffer<C>>);
+        app.add_meta_network_system(
+            crate::protocol::resim::store_snapshot::<C, F>
+                .run_if_resource_exists::<RenetClient>()
+                .run_if_resource_exists::<NetworkTick>()
+                .run_if(client_connected),
+        );
+        app.add_rewind_network_system(crate::protocol::resim::rewind::<C, F>);
+        app.add_rewind_network_system(crate::protocol::resim::rewind_remove::<C, F>);
+    }
+}
+
+/// Client side prediction for the resource `R`, e.g. a match timer or RNG seed.
+///
+/// `R` is snapshotted each tick and rewound along with predicted components so resimulating
+/// starts from the same state. This doesn't send `R` over the network.
+#[cfg(feature = "public")]
+pub struct ReplicateResourcePlugin<R>(PhantomData<R>);
+
+#[cfg(feature = "public")]
+impl<R> Default for ReplicateResourcePlugin<R> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+#[cfg(feature = "public")]
+impl<R> Plugin for ReplicateResourcePlugin<R>
+where
+    R: Resource + Clone,
+{
+    fn build(&self, app: &mut App) {
+        app.insert_resource(crate::protocol::resim::ResourceSnapshotBuffer::<R>::new());
+        app.init_resource::<NetworkBufferConfig>();
+        app.add_event::<ServerDisconnected>();
+        app.add_system(reset_on_disconnect::<crate::protocol::resim::ResourceSnapshotBuffer<R>>);
+        app.add_meta_network_system(
+            crate::protocol::resim::store_resource_snapshot::<R>
+                .run_if_resource_exists::<RenetClient>()
+                .run_if_resource_exists::<NetworkTick>()
+                .run_if_resource_exists::<R>()
+                .run_if(client_connected),
+        );
+        // Resimulated ticks replace the snapshots of the mispredicted ones.
+        app.add_update_history_network_system(
+            crate::protocol::resim::store_resource_snapshot::<R>
+                .run_if_resource_exists::<R>()
+                .after("client_update"),
+        );
+        app.add_rewind_network_system(
+            crate::protocol::resim::rewind_resource::<R>.run_if_resource_exists::<R>(),
+        );
+    }
+}
+
+/// Smooth out mispredictions of `C` on entities matching `F` instead of snapping to them.
+///
+/// Only the rendered value (`Smooth::Render`) is offset, and only for root entities since the
+/// offset is applied after transform propagation.
+#[cfg(feature = "public")]
+pub struct CorrectionPlugin<C, F = With<Owned>> {
+    pub time: Duration,
+    phantom: PhantomData<(C, F)>,
+}
+
+#[cfg(feature = "public")]
+impl<C, F> CorrectionPlugin<C, F> {
+    pub fn new(time: Duration) -> Self {
+        Self {
+            time,
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "public")]
+impl<C, F> Plugin for CorrectionPlugin<C, F>
+where
+    C: Smooth,
+    F: 'static + ReadOnlyWorldQuery + Send + Sync,
+{
+    fn build(&self, app: &mut App) {
+        app.insert_resource(CorrectionTime::<C>::new(self.time));
+        app.init_resource::<PreCorrection<C>>();
+        app.add_rewind_network_system(crate::protocol::correction::store_pre_correction::<C, F>);
+        app.add_system(crate::protocol::correction::measure_correction::<C>);
+        app.add_system_to_stage(
+            CoreStage::PostUpdate,
+            crate::protocol::correction::apply_correction::<C>
+                .after(TransformSystem::TransformPropagate),
+        );
+    }
+}
+
+/// Interpolate `C` between received updates on entities matching `F`, see
+/// `protocol::interpolate`.
+#[cfg(feature = "public")]
+pub struct InterpolationPlugin<C, F = Without<Owned>>(PhantomData<(C, F)>);
+
+#[cfg(feature = "public")]
+impl<C, F> Default for InterpolationPlugin<C, F> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+#[cfg(feature = "public")]
+impl<C, F> Plugin for InterpolationPlugin<C, F>
+where
+    C: Interpolate,
+    F: 'static + ReadOnlyWorldQuery + Send + Sync,
+{
+    fn build(&self, app: &mut App) {
+        app.init_resource::<InterpolationDelay>();
+        app.add_update_history_network_system(
+            crate::protocol::interpolate::buffer_interpolated::<C, F>
+                .run_if_resource_exists::<NetworkTick>()
+                .after("client_update"),
+        );
+        app.add_system_to_stage(
+            CoreStage::PostUpdate,
+            crate::protocol::interpolate::interpolate::<C, F>
+                .after(TransformSystem::TransformPropagate),
+        );
+        app.add_system_to_stage(
+            CoreStage::PostUpdate,
+            crate::protocol::interpolate::remove_owned_interpolation::<C>,
+        );
+    }
+}
+
+/// Replicate `C` on entities matching the query filter `F`.
 #[cfg(feature = "public")]
-pub struct ReplicatePlugin<C>(PhantomData<C>)
+pub struct ReplicatePlugin<C, F = Without<crate::replicate::NoReplicate>>
 where
-    C: 'static + Component + Reflect + FromReflect + GetTypeRegistration + Clone;
+    C: 'static + Component + Reflect + FromReflect + GetTypeRegistration + Clone,
+    F: 'static + ReadOnlyWorldQuery + Send + Sync,
+{
+    pub config: ReplicateConfig,
+    /// Extra plugins only added on the client, e.g. from `ReplicatePlugin::with_correction`.
+    client_plugins: Vec<Box<dyn Fn(&mut App) + Send + Sync>>,
+    phantom: PhantomData<(C, F)>,
+}
 
 #[cfg(feature = "public")]
 impl<C> Default for ReplicatePlugin<C>
@@ -39,7 +257,100 @@ where
     C: 'static + Component + Reflect + FromReflect + GetTypeRegistration + Clone,
 {
     fn default() -> Self {
-        Self(PhantomData)
+        Self::with_config(ReplicateConfig::default())
+    }
+}
+
+#[cfg(feature = "public")]
+impl<C> ReplicatePlugin<C>
+where
+    C: 'static + Component + Reflect + FromReflect + GetTypeRegistration + Clone,
+{
+    pub fn with_config(config: ReplicateConfig) -> Self {
+        Self {
+            config,
+            client_plugins: Vec::new(),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Replicate with delta encoding, small components are still sent in full.
+    pub fn delta() -> Self {
+        Self::with_config(ReplicateConfig::default().with_delta())
+    }
+
+    /// Only replicate entities matching `F` instead of everything without `NoReplicate`.
+    pub fn filtered<F>(self) -> ReplicatePlugin<C, F>
+    where
+        F: 'static + ReadOnlyWorldQuery + Send + Sync,
+    {
+        ReplicatePlugin {
+            config: self.config,
+            client_plugins: self.client_plugins,
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "public")]
+impl<C> ReplicatePlugin<C>
+where
+    C: 'static + Smooth + Reflect + FromReflect + GetTypeRegistration,
+{
+    /// Replicate with how predicted entities should be corrected after a rewind.
+    pub fn with_correction(mode: CorrectionMode) -> Self {
+        let mut plugin = Self::default();
+        if let CorrectionMode::Smooth { time } = mode {
+            plugin.client_plugins.push(Box::new(move |app: &mut App| {
+                app.add_plugin(CorrectionPlugin::<C, With<Owned>>::new(time));
+            }));
+        }
+        plugin
+    }
+}
+
+#[cfg(feature = "public")]
+impl<C, F> ReplicatePlugin<C, F>
+where
+    C: 'static + Interpolate + Reflect + FromReflect + GetTypeRegistration,
+    F: 'static + ReadOnlyWorldQuery + Send + Sync,
+{
+    /// Interpolate entities we aren't predicting instead of applying updates as they arrive.
+    pub fn interpolated(mut self) -> Self {
+        self.client_plugins.push(Box::new(|app: &mut App| {
+            app.add_plugin(InterpolationPlugin::<C, Without<Owned>>::default());
+        }));
+        self
+    }
+}
+
+/// Register a component for reflection and replication in one go.
+#[cfg(feature = "public")]
+pub trait ReplicateAppExt {
+    fn replicate<C>(&mut self) -> &mut Self
+    where
+        C: 'static + Component + Reflect + FromReflect + GetTypeRegistration + Clone;
+
+    fn replicate_with<C>(&mut self, config: ReplicateConfig) -> &mut Self
+    where
+        C: 'static + Component + Reflect + FromReflect + GetTypeRegistration + Clone;
+}
+
+#[cfg(feature = "public")]
+impl ReplicateAppExt for App {
+    fn replicate<C>(&mut self) -> &mut Self
+    where
+        C: 'static + Component + Reflect + FromReflect + GetTypeRegistration + Clone,
+    {
+        self.replicate_with::<C>(ReplicateConfig::default())
+    }
+
+    fn replicate_with<C>(&mut self, config: ReplicateConfig) -> &mut Self
+    where
+        C: 'static + Component + Reflect + FromReflect + GetTypeRegistration + Clone,
+    {
+        self.register_type::<C>();
+        self.add_plugin(ReplicatePlugin::<C>::with_config(config))
     }
 }
 
@@ -47,48 +358,122 @@ where
 pub struct ServerQueueInterest;
 
 #[cfg(feature = "public")]
-impl<C> Plugin for ReplicatePlugin<C>
+impl<C, F> Plugin for ReplicatePlugin<C, F>
 where
     C: 'static + Component + Reflect + FromReflect + GetTypeRegistration + Clone,
+    F: 'static + ReadOnlyWorldQuery + Send + Sync,
 {
     fn build(&self, app: &mut App) {
         app.register_type::<C>();
+        let replicate_id = app.world.replicate_id::<C>();
+        app.world
+            .get_resource_or_insert_with(crate::protocol::update::ComponentSerializers::new)
+            .register::<C>();
+        if self.config.delta {
+            app.world
+                .get_resource_or_insert_with(crate::protocol::delta::DeltaComponents::new)
+                .insert(replicate_id);
+        }
+        if self.config.reliable {
+            app.world
+                .get_resource_or_insert_with(crate::protocol::interest::ReliableComponents::new)
+                .insert(replicate_id);
+        }
 
         if app.world.contains_resource::<crate::Server>() {
             app.add_meta_network_system(
-                crate::protocol::update::server_queue_interest::<C>
+                crate::protocol::update::server_queue_interest::<C, F>
                     .before("server_send_interest")
                     .after("queue_interests"),
             );
 
-            app.add_meta_network_system(crate::protocol::interest::component_changes::<C>);
+            let frequency = self.config.frequency.max(1);
+            app.add_meta_network_system(
+                crate::protocol::interest::component_changes::<C, F>
+                    .run_if(move |tick: Res<NetworkTick>| tick.tick() % frequency == 0)
+                    .after("setup_baseload")
+                    .before("clear_baseload"),
+            );
+
+            app.add_meta_network_system(
+                crate::protocol::interest::baseload_components::<C, F>
+                    .after("setup_baseload")
+                    .before("clear_baseload"),
+            );
 
             app.add_meta_network_system(
-                crate::protocol::interest::baseload_components::<C>.before("clear_baseload"),
+                crate::protocol::relevancy::component_relevancy::<C>
+                    .after("update_relevancy")
+                    .before("clear_baseload"),
+            );
+
+            app.add_system_to_stage(
+                CoreStage::Last,
+                crate::protocol::despawn::component_removals::<C>,
             );
         }
 
         if app.world.contains_resource::<crate::Client>() {
-            app.insert_resource(SnapshotBuffer::<C>::new());
             app.add_update_history_network_system(
-                crate::protocol::update::client_update::<C>.after("client_apply_server_update"),
+                crate::protocol::update::client_update::<C>
+                    .label("client_update")
+                    .after("client_apply_server_update"),
             );
+            app.world
+                .get_resource_or_insert_with(crate::protocol::update::ComponentRemovers::new)
+                .register::<C>();
 
-            app.add_meta_network_system(
-                crate::protocol::resim::store_snapshot::<C>
-                    .run_if_resource_exists::<RenetClient>()
-                    .run_if_resource_exists::<NetworkTick>()
-                    .run_if(client_connected),
-            );
-            app.add_rewind_network_system(crate::protocol::resim::rewind::<C>);
+            if self.config.predict_owned {
+                app.add_plugin(PredictionPlugin::<C, With<Owned>>::default());
+            }
+
+            for client_plugin in self.client_plugins.iter() {
+                client_plugin(app);
+            }
         }
     }
 }
 
+pub const DEFAULT_BASELOAD_CHUNK_SIZE: usize = 64;
+
+/// Most interests queued for a single client, see `MaxQueueDepth`.
+pub const DEFAULT_MAX_QUEUE_DEPTH: usize = 1 << 16;
+
+/// Largest a message can decompress to, see `MaxMessageBytes`.
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+/// How long a client keeps predicting after losing the server before giving up.
+pub const DEFAULT_DISCONNECT_GRACE: Duration = Duration::from_secs(2);
+
 #[derive(Debug, Clone)]
 pub struct SabiPlugin<I> {
     pub phantom: PhantomData<I>,
     pub tick_rate: Duration,
+    /// Replicate `Transform` by default.
+    pub replicate_transform: bool,
+    /// Replicate `GlobalTransform` by default.
+    pub replicate_global_transform: bool,
+    /// Replicate `Name` by default.
+    pub replicate_name: bool,
+    /// Replicate `Visibility` and `ComputedVisibility` by default, see
+    /// `replicate::visibility`.
+    pub replicate_visibility: bool,
+    /// Replicate `NetworkTimer`, off by default.
+    pub replicate_network_timer: bool,
+    /// How many baseloaded interests to queue for a new client per tick.
+    pub baseload_chunk_size: usize,
+    /// Where to write the names of replicated types, see `replicate::default_types_path`.
+    ///
+    /// Only used with the `dev-registry` feature.
+    pub types_path: Option<PathBuf>,
+    /// How long the client keeps simulating after losing the server, see `ServerLost`.
+    pub disconnect_grace: Duration,
+    /// Most interests the server will queue for a single client.
+    pub max_queue_depth: usize,
+    /// Largest size a received message can decompress to.
+    pub max_message_bytes: usize,
+    /// Most ticks simulated in one frame, see `NetworkSimulationInfo::max_ticks_per_frame`.
+    pub max_ticks_per_frame: u32,
 }
 
 impl<I> Default for SabiPlugin<I> {
@@ -96,10 +481,99 @@ impl<I> Default for SabiPlugin<I> {
         Self {
             phantom: PhantomData,
             tick_rate: tick_hz(32),
+            replicate_transform: true,
+            replicate_global_transform: true,
+            replicate_name: true,
+            replicate_visibility: true,
+            replicate_network_timer: false,
+            baseload_chunk_size: DEFAULT_BASELOAD_CHUNK_SIZE,
+            types_path: None,
+            disconnect_grace: DEFAULT_DISCONNECT_GRACE,
+            max_queue_depth: DEFAULT_MAX_QUEUE_DEPTH,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+            max_ticks_per_frame: DEFAULT_MAX_TICKS_PER_FRAME,
         }
     }
 }
 
+impl<I> SabiPlugin<I> {
+    pub fn with_tick_rate(mut self, tick_rate: Duration) -> Self {
+        self.tick_rate = tick_rate;
+        self
+    }
+
+    /// Don't replicate `Transform` unless it is added with a `ReplicatePlugin`.
+    pub fn without_transform(mut self) -> Self {
+        self.replicate_transform = false;
+        self
+    }
+
+    /// Don't replicate `GlobalTransform` unless it is added with a `ReplicatePlugin`.
+    pub fn without_global_transform(mut self) -> Self {
+        self.replicate_global_transform = false;
+        self
+    }
+
+    /// Don't replicate `Name` unless it is added with a `ReplicatePlugin`.
+    pub fn without_name(mut self) -> Self {
+        self.replicate_name = false;
+        self
+    }
+
+    /// Don't replicate `Visibility` or `ComputedVisibility` unless it is added with a
+    /// `ReplicateVisibilityPlugin`.
+    pub fn without_visibility(mut self) -> Self {
+        self.replicate_visibility = false;
+        self
+    }
+
+    /// Replicate `NetworkTimer`, or add it yourself with a `ReplicatePlugin`.
+    pub fn with_network_timer(mut self) -> Self {
+        self.replicate_network_timer = true;
+        self
+    }
+
+    pub fn with_baseload_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.baseload_chunk_size = chunk_size;
+        self
+    }
+
+    /// Write the names of replicated types to `path` instead of `types.toml`.
+    pub fn with_types_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.types_path = Some(path.into());
+        self
+    }
+
+    /// Cap how many interests can be queued for a client, changes past this are dropped.
+    pub fn with_max_queue_depth(mut self, max_queue_depth: usize) -> Self {
+        self.max_queue_depth = max_queue_depth;
+        self
+    }
+
+    /// Drop received messages that would decompress to more than `max_message_bytes`.
+    pub fn with_max_message_bytes(mut self, max_message_bytes: usize) -> Self {
+        self.max_message_bytes = max_message_bytes;
+        self
+    }
+
+    /// Simulate at most `max_ticks` per frame, dropping the rest of the time we are behind.
+    pub fn with_max_ticks_per_frame(mut self, max_ticks: u32) -> Self {
+        self.max_ticks_per_frame = max_ticks;
+        self
+    }
+
+    /// Keep predicting for `grace` after losing the server before removing the `NetworkTick`.
+    ///
+    /// Only useful if game code reconnects with a new `RenetClient` in that time, see
+    /// `ServerLost`.
+    ///
+    /// `Duration::ZERO` gives up as soon as the connection drops.
+    pub fn with_disconnect_grace(mut self, grace: Duration) -> Self {
+        self.disconnect_grace = grace;
+        self
+    }
+}
+
 impl<I> Plugin for SabiPlugin<I>
 where
     I: 'static
@@ -115,6 +589,13 @@ where
         + Resource,
 {
     fn build(&self, app: &mut App) {
+        #[cfg(feature = "public")]
+        app.world.init_resource::<ReplicateRegistry>();
+        #[cfg(feature = "public")]
+        if let Some(path) = &self.types_path {
+            app.world.resource_mut::<ReplicateRegistry>().set_path(path.clone());
+        }
+
         app.world
             .init_resource::<crate::protocol::demands::ReplicateDemands>();
 
@@ -139,6 +620,7 @@ where
 
         #[cfg(feature = "public")]
         app.add_event::<(ServerEntity, ComponentsUpdate)>();
+        app.add_event::<crate::stage::RewindEvent>();
         app.add_stage_before(
             CoreStage::Update,
             NetworkStage,
@@ -167,14 +649,56 @@ where
             SystemStage::parallel(),
         );
 
+        #[cfg(feature = "public")]
+        {
+            use crate::protocol::codec::MessageCodec;
+
+            let compression = app
+                .world
+                .get_resource_or_insert_with(crate::protocol::codec::CompressionConfig::default)
+                .clone();
+            if !app
+                .world
+                .contains_resource::<crate::protocol::codec::NetworkCodec>()
+            {
+                let codec = compression.codec();
+                if let Some(dictionary_id) = codec.dictionary_id() {
+                    info!("compressing with dictionary {:x}", dictionary_id);
+                }
+                app.insert_resource(crate::protocol::codec::NetworkCodec::new(codec));
+            }
+        }
+        #[cfg(feature = "public")]
+        app.init_resource::<crate::protocol::codec::ComponentCodec>();
+        #[cfg(feature = "public")]
+        app.init_resource::<crate::protocol::delta::DeltaComponents>();
+        #[cfg(feature = "public")]
+        app.init_resource::<crate::protocol::interest::ReliableComponents>();
+        #[cfg(feature = "public")]
+        app.init_resource::<crate::protocol::input::InputConfig>();
+        #[cfg(feature = "public")]
+        app.init_resource::<crate::protocol::NetworkBufferConfig>();
+        #[cfg(feature = "public")]
+        app.add_event::<crate::protocol::codec::MalformedMessage>();
+        #[cfg(feature = "public")]
+        app.init_resource::<crate::protocol::codec::NetworkErrorStats>();
+        #[cfg(feature = "public")]
+        app.add_system(crate::protocol::codec::count_malformed_messages);
+        #[cfg(feature = "public")]
+        app.insert_resource(crate::protocol::codec::MaxMessageBytes(
+            self.max_message_bytes,
+        ));
         #[cfg(feature = "public")]
         app.insert_resource(ServerEntities::default());
         #[cfg(feature = "public")]
         app.insert_resource(EntityUpdate::new());
         app.init_resource::<NetworkTick>();
         if !app.world.contains_resource::<NetworkSimulationInfo>() {
-            app.insert_resource(NetworkSimulationInfo::new(self.tick_rate));
+            let mut info = NetworkSimulationInfo::new(self.tick_rate);
+            info.set_max_ticks_per_frame(self.max_ticks_per_frame);
+            app.insert_resource(info);
         }
+        app.init_resource::<crate::stage::SimulationControl>();
 
         app.insert_resource(Lobby::default());
 
@@ -182,6 +706,19 @@ where
         app.add_plugin(ReplicatePhysics3dPlugin);
         //app.add_plugin(ReplicatePhysics2dPlugin);
         if app.world.contains_resource::<crate::Server>() {
+            #[cfg(feature = "public")]
+            app.insert_resource(crate::protocol::interest::BaseloadChunker::new(
+                self.baseload_chunk_size,
+            ));
+            #[cfg(feature = "public")]
+            app.insert_resource(crate::protocol::interest::MaxQueueDepth(
+                self.max_queue_depth,
+            ));
+            #[cfg(feature = "public")]
+            app.insert_resource(crate::protocol::input::MaxInputSize {
+                decompressed: self.max_message_bytes,
+                ..Default::default()
+            });
             #[cfg(feature = "public")]
             app.add_plugin(SabiServerPlugin::<I>::default());
         }
@@ -196,17 +733,53 @@ where
         //app.add_apply_update_network_system(bevy::transform::transform_propagate_system);
 
         #[cfg(feature = "public")]
-        app.add_plugin(ReplicatePlugin::<Transform>::default());
+        if self.replicate_transform {
+            app.add_plugin(ReplicatePlugin::<Transform>::default());
+        }
+        #[cfg(feature = "public")]
+        if self.replicate_global_transform {
+            app.add_plugin(ReplicatePlugin::<GlobalTransform>::default());
+        }
+        #[cfg(feature = "public")]
+        if self.replicate_transform && self.replicate_global_transform {
+            // Clients can propagate the `GlobalTransform` themselves.
+            app.add_plugin(
+                crate::protocol::demands::DeduplicateWith::<Transform, GlobalTransform>::default(),
+            );
+        }
+        #[cfg(feature = "public")]
+        if self.replicate_name {
+            app.add_plugin(ReplicatePlugin::<Name>::default());
+        }
         #[cfg(feature = "public")]
-        app.add_plugin(ReplicatePlugin::<GlobalTransform>::default());
+        if self.replicate_visibility {
+            app.add_plugin(crate::replicate::visibility::ReplicateVisibilityPlugin);
+        }
         #[cfg(feature = "public")]
-        app.add_plugin(ReplicatePlugin::<Name>::default());
+        if self.replicate_network_timer {
+            app.add_plugin(ReplicatePlugin::<NetworkTimer>::default());
+        }
 
         app.insert_resource(PreviousRenetError(None));
+        app.add_event::<ProtocolMismatch>();
+        app.add_event::<ServerDisconnected>();
+        app.insert_resource(DisconnectGrace(self.disconnect_grace));
         #[cfg(feature = "public")]
         app.add_system(handle_renet_error);
         #[cfg(feature = "public")]
-        app.add_system(handle_client_disconnect);
+        app.add_system(handle_client_disconnect::<RenetClient>);
+        #[cfg(all(feature = "public", feature = "dev-registry"))]
+        app.add_system_to_stage(CoreStage::Last, crate::replicate::flush_types_on_exit);
+        #[cfg(feature = "public")]
+        app.add_startup_system_to_stage(
+            StartupStage::PostStartup,
+            crate::replicate::check_replicate_ids,
+        );
+        #[cfg(all(feature = "public", feature = "dev-registry"))]
+        app.add_startup_system_to_stage(
+            StartupStage::PostStartup,
+            crate::replicate::compact_registry_on_startup,
+        );
     }
 }
 
@@ -237,10 +810,19 @@ where
         app.insert_resource(crate::protocol::interest::InterestsToSend::new());
         app.insert_resource(crate::protocol::interest::ClientInterestQueues::new());
         app.insert_resource(crate::protocol::interest::Baseload::new());
+        app.insert_resource(crate::protocol::interest::ClientKnownEntities::new());
+        app.insert_resource(crate::protocol::interest::BaselineInterests::new());
         app.insert_resource(crate::protocol::interest::ClientUnackedInterests::new());
+        app.insert_resource(crate::protocol::despawn::ClientEntityDespawns::new());
+        app.insert_resource(crate::protocol::despawn::ClientComponentRemovals::new());
+        app.insert_resource(crate::protocol::relevancy::ClientRelevancy::new());
+        app.init_resource::<crate::protocol::relevancy::RelevancyPolicy>();
         //app.insert_resource(crate::protocol::interest::SentInterests::new());
 
         app.insert_resource(crate::protocol::update::ClientEntityUpdates::new());
+        app.insert_resource(crate::protocol::version::ClientSentVersions::new());
+        app.insert_resource(crate::protocol::delta::ClientSentBaselines::new());
+        app.init_resource::<crate::protocol::stats::ReplicationStats>();
 
         app.insert_resource(crate::protocol::ack::ClientAcks::new());
 
@@ -248,17 +830,95 @@ where
         app.insert_resource(crate::protocol::demands::ReplicateMaxSize::default());
         app.insert_resource(crate::protocol::input::ClientQueuedInputs::<I>::new());
         app.insert_resource(crate::protocol::input::ClientReceivedHistory::new());
+        app.insert_resource(crate::protocol::input::ClientInputAcks::new());
+        app.init_resource::<crate::protocol::input::InputConfig>();
+        app.init_resource::<crate::protocol::input::InputFallbackConfig>();
+        app.init_resource::<crate::protocol::input::MaxInputSize>();
+        app.insert_resource(crate::protocol::input::DroppedInputs::new());
 
         app.add_plugin(bevy_renet::RenetServerPlugin {
             clear_events: false,
         });
 
-        app.add_network_system_set(bevy_renet::RenetServerPlugin::get_clear_event_systems());
+        // Cleared once per tick in the meta stage, anything reading `ServerEvent` needs to run
+        // in the meta stage before this or it can miss events on frames with multiple ticks.
+        app.add_meta_network_system_set(
+            bevy_renet::RenetServerPlugin::get_clear_event_systems().label("clear_server_events"),
+        );
 
-        app.add_system(crate::protocol::interest::setup_baseload.label("setup_baseload"));
+        app.add_event::<crate::lobby::PlayerConnected>();
+        app.add_event::<crate::lobby::PlayerDisconnected>();
+
+        app.add_meta_network_system(
+            crate::protocol::interest::setup_baseload
+                .label("setup_baseload")
+                .before("clear_baseload")
+                .before("clear_server_events"),
+        );
+        app.add_meta_network_system(
+            crate::protocol::server::server_handle_connections
+                .run_if_resource_exists::<RenetServer>()
+                .label("server_handle_connections")
+                .before("clear_server_events"),
+        );
+
+        // Per client state that would leak if it isn't dropped on disconnect.
+        let mut client_states = crate::protocol::server::ClientStates::new();
+        client_states.register::<crate::protocol::interest::InterestsToSend>();
+        client_states.register::<crate::protocol::interest::ClientInterestQueues>();
+        client_states.register::<crate::protocol::interest::Baseload>();
+        client_states.register::<crate::protocol::interest::BaseloadChunker>();
+        client_states.register::<crate::protocol::interest::ClientKnownEntities>();
+        client_states.register::<crate::protocol::interest::BaselineInterests>();
+        client_states.register::<crate::protocol::interest::ClientUnackedInterests>();
+        client_states.register::<crate::protocol::despawn::ClientEntityDespawns>();
+        client_states.register::<crate::protocol::despawn::ClientComponentRemovals>();
+        client_states.register::<crate::protocol::relevancy::ClientRelevancy>();
+        client_states.register::<crate::protocol::update::ClientEntityUpdates>();
+        client_states.register::<crate::protocol::version::ClientSentVersions>();
+        client_states.register::<crate::protocol::delta::ClientSentBaselines>();
+        client_states.register::<crate::protocol::ack::ClientAcks>();
+        client_states.register::<crate::protocol::input::ClientQueuedInputs<I>>();
+        client_states.register::<crate::protocol::input::ClientReceivedHistory>();
+        client_states.register::<crate::protocol::input::ClientInputAcks>();
+        client_states.register::<crate::protocol::input::DroppedInputs>();
+        client_states.register::<crate::protocol::codec::NetworkErrorStats>();
+        client_states.register::<crate::protocol::stats::ReplicationStats>();
+        app.insert_resource(client_states);
+        app.add_meta_network_system(
+            crate::protocol::server::forget_disconnected
+                .label("forget_disconnected")
+                .before("clear_server_events"),
+        );
+
+        app.insert_resource(crate::protocol::server::ServerMessages::new());
+        app.add_system(
+            crate::protocol::server::server_send_messages
+                .run_if_resource_exists::<RenetServer>()
+                .label("server_send_messages"),
+        );
         app.add_meta_network_system(
             crate::protocol::interest::clear_baseloads.label("clear_baseload"),
         );
+        app.init_resource::<crate::protocol::interest::BaseloadChunker>();
+        app.init_resource::<crate::protocol::interest::MaxQueueDepth>();
+        app.add_meta_network_system(
+            crate::protocol::interest::feed_baseloads
+                .label("feed_baseloads")
+                .after("clear_baseload")
+                .before("queue_interests"),
+        );
+        app.add_meta_network_system(
+            crate::protocol::relevancy::update_relevancy
+                .label("update_relevancy")
+                .before("clear_baseload"),
+        );
+        app.add_meta_network_system(
+            crate::protocol::relevancy::filter_relevancy
+                .after("feed_baseloads")
+                .after("resend_unacked")
+                .before("queue_interests"),
+        );
 
         app.add_meta_network_system(
             crate::protocol::input::server_recv_input::<I>
@@ -266,6 +926,10 @@ where
                 .label("recv_input"),
         );
 
+        app.add_meta_network_system(
+            crate::protocol::server::update_player_info.after("recv_input"),
+        );
+
         app.add_meta_network_system(
             crate::protocol::input::server_apply_input::<I>
                 .run_if_resource_exists::<RenetServer>()
@@ -273,10 +937,28 @@ where
                 .after("recv_input"),
         );
 
+        app.add_meta_network_system(
+            crate::protocol::interest::ack_interests
+                .label("ack_interests")
+                .after("recv_input"),
+        );
+        app.add_meta_network_system(
+            crate::protocol::interest::resend_unacked
+                .label("resend_unacked")
+                .after("ack_interests")
+                .before("queue_interests"),
+        );
         app.add_meta_network_system(
             crate::protocol::interest::queue_interests.label("queue_interests"),
         );
 
+        app.add_meta_network_system(
+            crate::protocol::despawn::server_detect_despawns
+                .label("detect_despawns")
+                .after("clear_baseload")
+                .before("server_send_interest"),
+        );
+
         app.add_meta_network_system(
             server_send_interest
                 .run_if_resource_exists::<RenetServer>()
@@ -320,6 +1002,31 @@ where
         app.add_network_system_set(RenetClientPlugin::get_clear_event_systems());
 
         app.insert_resource(crate::protocol::update::UpdateMessages::new());
+        app.insert_resource(crate::protocol::version::ReceivedVersions::new());
+        app.insert_resource(crate::protocol::delta::ReceivedBaselines::new());
+        app.insert_resource(crate::protocol::ack::ReceivedTicks::default());
+        app.init_resource::<crate::protocol::stats::ReceivedStats>();
+        app.init_resource::<crate::protocol::codec::MaxMessageBytes>();
+        app.insert_resource(crate::protocol::input::AckedInputs::default());
+        let input_config = app
+            .world
+            .get_resource_or_insert_with(crate::protocol::input::InputConfig::default)
+            .clone();
+        app.insert_resource(crate::protocol::input::InputLossStats::new(&input_config));
+        app.insert_resource(crate::protocol::input::QueuedInputs::<I>::new());
+        app.add_event::<ServerMessage>();
+
+        app.add_system(
+            crate::protocol::client::client_recv_server_message
+                .run_if_resource_exists::<RenetClient>()
+                .run_if(client_connected)
+                .label("client_recv_server_message"),
+        );
+        app.init_resource::<LocalPlayer>();
+        app.add_system(
+            crate::protocol::client::client_handle_server_message
+                .after("client_recv_server_message"),
+        );
 
         app.add_meta_network_system(
             crate::protocol::update::client_recv_interest
@@ -333,6 +1040,13 @@ where
                 .run_if_resource_exists::<NetworkTick>()
                 .label("client_apply_server_update"),
         );
+        app.init_resource::<crate::protocol::update::ComponentRemovers>();
+        app.add_update_history_network_system(
+            crate::protocol::update::client_apply_despawns
+                .run_if_resource_exists::<NetworkTick>()
+                .after("client_apply_server_update")
+                .after("client_update"),
+        );
 
         app.add_meta_network_system(
             crate::protocol::input::client_update_input_buffer::<I>
@@ -348,6 +1062,11 @@ where
                 .before("client_recv_interest")
                 .after("client_update_input_buffer"),
         );
+        app.add_meta_network_system(
+            crate::protocol::input::client_track_input_loss::<I>
+                .run_if_resource_exists::<RenetClient>()
+                .after("client_recv_interest"),
+        );
 
         app.add_input_history_network_system(
             crate::protocol::input::client_apply_input_buffer::<I>
@@ -355,6 +1074,29 @@ where
                 //.run_if(client_connected)
                 .label("client_apply_input_buffer"),
         );
+
+        app.add_event::<ServerDisconnected>();
+        app.add_event::<crate::protocol::client::ServerEntityDespawned>();
+        app.add_system(crate::protocol::client::client_disconnect_entities);
+        // Server despawns are applied in the network stage, so catch them on the same tick
+        // and anything despawned locally at the end of the frame.
+        app.add_meta_network_system(
+            crate::protocol::client::client_clean_server_entities
+                .after("client_recv_interest"),
+        );
+        app.add_system_to_stage(
+            CoreStage::Last,
+            crate::protocol::client::client_clean_server_entities,
+        );
+        app.add_system(reset_on_disconnect::<crate::protocol::update::UpdateMessages>);
+        app.add_system(reset_on_disconnect::<crate::protocol::version::ReceivedVersions>);
+        app.add_system(reset_on_disconnect::<crate::protocol::delta::ReceivedBaselines>);
+        app.add_system(reset_on_disconnect::<crate::protocol::ack::ReceivedTicks>);
+        app.add_system(reset_on_disconnect::<crate::protocol::stats::ReceivedStats>);
+        app.add_system(reset_on_disconnect::<crate::protocol::input::AckedInputs>);
+        app.add_system(reset_on_disconnect::<crate::protocol::input::QueuedInputs<I>>);
+        app.add_system(reset_on_disconnect::<Lobby>);
+        app.add_system(reset_on_disconnect::<LocalPlayer>);
     }
 }
 
@@ -379,31 +1121,417 @@ pub fn handle_renet_error(
     }
 }
 
+/// The client was rejected before it ever connected to the server.
+///
+/// Only sent when the server denied us or our connect token, see `is_protocol_mismatch`.
+/// Timeouts, an unreachable server and so on are just logged.
+#[derive(Debug, Clone)]
+pub struct ProtocolMismatch {
+    /// Our `protocol_id()`.
+    pub local: u64,
+    /// Disconnect reason that renet gave us.
+    pub reason: String,
+}
+
+impl std::fmt::Display for ProtocolMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "could not connect to the server, the client/server versions are likely mismatched \
+            (local protocol id: {}, reason: {}), make sure both are built from the same version",
+            self.local, self.reason
+        )
+    }
+}
+
+/// Whether a disconnect before we ever connected was the server refusing us, which is what
+/// a different `protocol_id()` on either side ends up as.
+pub fn is_protocol_mismatch(reason: &DisconnectionReason) -> bool {
+    matches!(
+        reason,
+        DisconnectionReason::Netcode(
+            NetcodeDisconnectReason::ConnectionDenied
+                | NetcodeDisconnectReason::ConnectTokenExpired
+        )
+    )
+}
+
+/// How long the client keeps predicting after losing the server.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct DisconnectGrace(pub Duration);
+
+impl Default for DisconnectGrace {
+    fn default() -> Self {
+        Self(DEFAULT_DISCONNECT_GRACE)
+    }
+}
+
+/// The client lost the server and is predicting on its own until `DisconnectGrace` runs out.
+///
+/// Renet can't reconnect a client once it is disconnected, so the `RenetClient` is removed.
+/// To resume, game code has to insert a new one (e.g. from `new_renet_client`) while this
+/// is present. This is removed once that client connects, otherwise the `NetworkTick` is
+/// removed along with it when the grace runs out.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct ServerLost {
+    /// `Time::elapsed` when the connection dropped.
+    pub since: Duration,
+}
+
+impl ServerLost {
+    pub fn expired(&self, now: Duration, grace: Duration) -> bool {
+        now.saturating_sub(self.since) >= grace
+    }
+}
+
+/// The client gave up on the server, anything we kept for it should be thrown away.
+#[derive(Debug, Clone, Copy)]
+pub struct ServerDisconnected;
+
+/// What `handle_client_disconnect` needs to know about the client's connection.
+#[cfg(feature = "public")]
+pub trait ClientConnection: Resource {
+    fn is_connected(&self) -> bool;
+    fn disconnected(&self) -> Option<DisconnectionReason>;
+}
+
+#[cfg(feature = "public")]
+impl ClientConnection for RenetClient {
+    fn is_connected(&self) -> bool {
+        RenetClient::is_connected(self)
+    }
+
+    fn disconnected(&self) -> Option<DisconnectionReason> {
+        RenetClient::disconnected(self)
+    }
+}
+
 /// Reset the networking state if the client was disconnected from the server so we can
 /// try and reconnect in the future without weirdness like duplicate entities.
+///
+/// The tick is kept around for `DisconnectGrace` so a short outage doesn't stop the
+/// simulation, see `ServerLost`.
 #[cfg(feature = "public")]
-pub fn handle_client_disconnect(
+pub fn handle_client_disconnect<C: ClientConnection>(
     mut commands: Commands,
+    mut was_connected: Local<bool>,
+    mut protocol_mismatch: EventWriter<ProtocolMismatch>,
+    mut server_disconnected: EventWriter<ServerDisconnected>,
+    time: Res<Time>,
+    grace: Option<Res<DisconnectGrace>>,
+    lost: Option<Res<ServerLost>>,
     local: Option<Res<crate::Local>>,
     tick: Option<Res<NetworkTick>>,
-    client: Option<Res<RenetClient>>,
+    sim_info: Option<ResMut<NetworkSimulationInfo>>,
+    client: Option<Res<C>>,
     server: Option<Res<RenetServer>>,
 ) {
     if local.is_some() {
         return;
     }
 
+    let now = time.elapsed();
+    let grace = grace.map(|grace| grace.0).unwrap_or(DEFAULT_DISCONNECT_GRACE);
+    let mut connected = false;
+    let mut give_up = false;
+
     if let Some(client) = client {
+        if client.is_connected() {
+            *was_connected = true;
+            connected = true;
+            if lost.is_some() {
+                info!("reconnected to the server, resuming");
+                commands.remove_resource::<ServerLost>();
+            }
+        }
+
         let disconnected = client.disconnected();
         if let Some(reason) = disconnected {
             error!("client disconnected: {}", reason);
-            commands.remove_resource::<RenetClient>();
-            commands.remove_resource::<NetworkTick>();
+
+            if !*was_connected && is_protocol_mismatch(&reason) {
+                let mismatch = ProtocolMismatch {
+                    local: crate::protocol::protocol_id(),
+                    reason: reason.to_string(),
+                };
+                error!("{}", mismatch);
+                protocol_mismatch.send(mismatch);
+            }
+
+            *was_connected = false;
+            connected = false;
+            commands.remove_resource::<C>();
+            if grace.is_zero() || tick.is_none() {
+                give_up = true;
+            } else if lost.is_none() {
+                warn!("lost the server, predicting for up to {:?}", grace);
+                commands.insert_resource(ServerLost { since: now });
+            }
         }
-    } else {
-        if server.is_none() && tick.is_some() {
-            error!("server disconnected, removing tick");
-            commands.remove_resource::<NetworkTick>();
+    } else if server.is_none() && tick.is_some() && lost.is_none() {
+        error!("server disconnected, removing tick");
+        give_up = true;
+    }
+
+    if let Some(lost) = lost {
+        if !connected && lost.expired(now, grace) {
+            error!("server did not come back within {:?}, removing tick", grace);
+            give_up = true;
+        }
+    }
+
+    if give_up {
+        commands.remove_resource::<NetworkTick>();
+        commands.remove_resource::<ServerInfo>();
+        commands.remove_resource::<ServerLost>();
+        if let Some(mut sim_info) = sim_info {
+            sim_info.reset_step();
         }
+        server_disconnected.send(ServerDisconnected);
+    }
+}
+
+#[cfg(all(test, feature = "public"))]
+mod test {
+    use super::*;
+
+    #[derive(Component, Reflect, FromReflect, Default, Clone)]
+    #[reflect(Component)]
+    struct Health(u32);
+
+    #[test]
+    pub fn replicate_registers() {
+        let mut app = App::new();
+        app.init_resource::<AppTypeRegistry>();
+        app.insert_resource(crate::Server);
+        app.add_stage_before(
+            CoreStage::Update,
+            NetworkStage,
+            NetworkSimulationStage::new(crate::tick::tick_hz(32)),
+        );
+
+        app.replicate::<Health>();
+
+        assert!(app
+            .world
+            .resource::<AppTypeRegistry>()
+            .read()
+            .get(std::any::TypeId::of::<Health>())
+            .is_some());
+        assert!(app.is_plugin_added::<ReplicatePlugin<Health>>());
+        assert_eq!(
+            app.world
+                .resource::<ReplicateRegistry>()
+                .name(replicate_id::<Health>())
+                .as_deref(),
+            Some(std::any::type_name::<Health>())
+        );
+
+        let meta = &app.get_network_stage().meta;
+        assert!(meta
+            .parallel_systems()
+            .iter()
+            .any(|system| system.name().contains("component_changes")));
+        assert!(meta
+            .parallel_systems()
+            .iter()
+            .any(|system| system.name().contains("baseload_components")));
+    }
+
+    #[test]
+    pub fn without_name() {
+        #[derive(Resource, Component, Debug, Default, Clone, Serialize, Deserialize)]
+        struct TestInput;
+
+        let mut app = App::new();
+        app.init_resource::<AppTypeRegistry>();
+        app.insert_resource(crate::Server);
+        app.add_plugin(SabiPlugin::<TestInput>::default().without_name());
+
+        assert!(!app.is_plugin_added::<ReplicatePlugin<Name>>());
+        assert!(app.is_plugin_added::<ReplicatePlugin<Transform>>());
+
+        let replicates = |app: &mut App, type_name: &str| {
+            app.get_network_stage()
+                .meta
+                .parallel_systems()
+                .iter()
+                .any(|system| {
+                    let name = system.name();
+                    (name.contains("component_changes") || name.contains("baseload_components"))
+                        && name.contains(type_name)
+                })
+        };
+        assert!(!replicates(&mut app, std::any::type_name::<Name>()));
+        assert!(replicates(&mut app, std::any::type_name::<Transform>()));
+    }
+
+    fn outage_world(grace: Duration) -> (World, SystemStage) {
+        let mut world = World::new();
+        world.init_resource::<Time>();
+        world.init_resource::<Events<ProtocolMismatch>>();
+        world.init_resource::<Events<ServerDisconnected>>();
+        world.insert_resource(DisconnectGrace(grace));
+        world.insert_resource(NetworkSimulationInfo::new(crate::tick::tick_hz(32)));
+        world.insert_resource(NetworkTick::new(10));
+
+        let mut stage = SystemStage::single_threaded();
+        stage.add_system(handle_client_disconnect::<TestClient>);
+        (world, stage)
+    }
+
+    /// Stands in for a `RenetClient` so we don't need a socket.
+    #[derive(Resource, Default)]
+    struct TestClient {
+        connected: bool,
+        disconnected: Option<DisconnectionReason>,
+    }
+
+    impl TestClient {
+        fn connecting() -> Self {
+            Self::default()
+        }
+
+        fn connected() -> Self {
+            Self {
+                connected: true,
+                disconnected: None,
+            }
+        }
+
+        fn disconnected(reason: NetcodeDisconnectReason) -> Self {
+            Self {
+                connected: false,
+                disconnected: Some(DisconnectionReason::Netcode(reason)),
+            }
+        }
+    }
+
+    impl ClientConnection for TestClient {
+        fn is_connected(&self) -> bool {
+            self.connected
+        }
+
+        fn disconnected(&self) -> Option<DisconnectionReason> {
+            self.disconnected
+        }
+    }
+
+    fn advance(world: &mut World, elapsed: Duration) {
+        let mut time = world.resource_mut::<Time>();
+        let startup = time.startup();
+        time.update_with_instant(startup + elapsed);
+    }
+
+    fn mismatches(world: &World) -> usize {
+        let events = world.resource::<Events<ProtocolMismatch>>();
+        events.get_reader().iter(events).count()
+    }
+
+    #[test]
+    pub fn protocol_mismatch() {
+        // What a server with a different `protocol_id()` ends up as.
+        let (mut world, mut stage) = outage_world(Duration::from_secs(2));
+        world.insert_resource(TestClient::connecting());
+        stage.run(&mut world);
+        assert_eq!(mismatches(&world), 0);
+
+        world.insert_resource(TestClient::disconnected(
+            NetcodeDisconnectReason::ConnectionDenied,
+        ));
+        stage.run(&mut world);
+        assert_eq!(mismatches(&world), 1);
+        assert!(!world.contains_resource::<TestClient>());
+    }
+
+    #[test]
+    pub fn timeout_is_not_a_mismatch() {
+        // Nothing is listening, so the connection request times out.
+        let (mut world, mut stage) = outage_world(Duration::from_secs(2));
+        world.insert_resource(TestClient::disconnected(
+            NetcodeDisconnectReason::ConnectionRequestTimedOut,
+        ));
+        stage.run(&mut world);
+        assert_eq!(mismatches(&world), 0);
+        assert!(!world.contains_resource::<TestClient>());
+
+        // Only counts before we ever connected.
+        world.insert_resource(TestClient::connected());
+        stage.run(&mut world);
+        world.insert_resource(TestClient::disconnected(
+            NetcodeDisconnectReason::ConnectionDenied,
+        ));
+        stage.run(&mut world);
+        assert_eq!(mismatches(&world), 0);
+
+        assert!(!is_protocol_mismatch(&DisconnectionReason::Netcode(
+            NetcodeDisconnectReason::ConnectionRequestTimedOut
+        )));
+        assert!(!is_protocol_mismatch(&DisconnectionReason::Netcode(
+            NetcodeDisconnectReason::ConnectionTimedOut
+        )));
+        assert!(is_protocol_mismatch(&DisconnectionReason::Netcode(
+            NetcodeDisconnectReason::ConnectionDenied
+        )));
+    }
+
+    /// Drop the connection of the client in the world, like the server going away.
+    fn drop_connection(world: &mut World) {
+        world.insert_resource(TestClient::disconnected(
+            NetcodeDisconnectReason::ConnectionTimedOut,
+        ));
+    }
+
+    #[test]
+    pub fn short_outage_keeps_predicting() {
+        let (mut world, mut stage) = outage_world(Duration::from_secs(2));
+
+        world.insert_resource(TestClient::connected());
+        stage.run(&mut world);
+        assert!(!world.contains_resource::<ServerLost>());
+
+        advance(&mut world, Duration::from_secs(1));
+        drop_connection(&mut world);
+        stage.run(&mut world);
+        assert!(!world.contains_resource::<TestClient>());
+        assert!(world.contains_resource::<ServerLost>());
+        assert!(world.contains_resource::<NetworkTick>());
+
+        // Game code reconnects before the grace runs out.
+        advance(&mut world, Duration::from_millis(2500));
+        world.insert_resource(TestClient::connected());
+        stage.run(&mut world);
+        assert!(!world.contains_resource::<ServerLost>());
+        assert!(world.contains_resource::<NetworkTick>());
+        assert_eq!(mismatches(&world), 0);
+
+        advance(&mut world, Duration::from_secs(10));
+        stage.run(&mut world);
+        assert!(world.contains_resource::<NetworkTick>());
+    }
+
+    #[test]
+    pub fn long_outage_removes_tick() {
+        let (mut world, mut stage) = outage_world(Duration::from_secs(2));
+
+        world.insert_resource(TestClient::connected());
+        stage.run(&mut world);
+
+        drop_connection(&mut world);
+        stage.run(&mut world);
+        assert!(world.contains_resource::<ServerLost>());
+
+        advance(&mut world, Duration::from_secs(1));
+        stage.run(&mut world);
+        assert!(world.contains_resource::<NetworkTick>());
+
+        // Nobody reconnected in time.
+        advance(&mut world, Duration::from_secs(3));
+        stage.run(&mut world);
+        assert!(!world.contains_resource::<NetworkTick>());
+        assert!(!world.contains_resource::<ServerLost>());
+
+        let events = world.resource::<Events<ServerDisconnected>>();
+        assert_eq!(events.get_reader().iter(events).count(), 1);
     }
 }
@@ -3,7 +3,7 @@ use std::collections::{btree_map::Entry, BTreeMap};
 
 use serde::{Deserialize, Serialize};
 
-use super::{ClientId, NetworkTick};
+use super::{ClientId, ClientState, NetworkTick};
 
 /// Per client ack bits.
 ///
@@ -32,30 +32,80 @@ impl ClientAcks {
             }
         }
     }
+
+    pub fn get(&self, client_id: &ClientId) -> Option<&NetworkAck> {
+        self.acks.get(client_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ClientId, &NetworkAck)> {
+        self.acks.iter()
+    }
 }
 
+impl ClientState for ClientAcks {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.acks.remove(client_id);
+    }
+}
+
+/// How many ticks before the base a `NetworkAck` can track.
+///
+/// Anything older is treated as lost, so this bounds how much round trip time we can
+/// handle before data stops being resent, ~1 second at 64hz.
+pub const ACK_BITS: i64 = 64;
+
 /// Bitset of previous ticks that were successfully retrieved.
 #[derive(Debug, Clone, Serialize, Deserialize, Reflect, FromReflect)]
 pub struct NetworkAck {
     base: NetworkTick,
-    ack: u32,
+    ack: u64,
+}
+
+impl Default for NetworkAck {
+    fn default() -> Self {
+        Self::new(NetworkTick::default())
+    }
 }
 
 impl NetworkAck {
     pub fn new(base: NetworkTick) -> Self {
-        Self { base: base, ack: 0 }
+        Self { base, ack: 0 }
+    }
+
+    pub fn base(&self) -> NetworkTick {
+        self.base
+    }
+
+    /// Mark a tick as received, moving the base forward if it is newer than anything so far.
+    pub fn receive(&mut self, tick: &NetworkTick) {
+        let mut next = *tick;
+        next.increment_tick();
+        if next.signed_diff(self.base) > 0 {
+            self.set_base(next);
+        }
+
+        self.ack(tick);
+    }
+
+    pub fn is_acked(&self, tick: &NetworkTick) -> bool {
+        let diff = self.base.signed_diff(*tick) - 1;
+        diff >= 0 && diff < ACK_BITS && self.ack & (1 << diff) != 0
     }
 
     pub fn ack(&mut self, tick: &NetworkTick) {
-        let diff = self.base.tick() as i64 - tick.tick() as i64 - 1;
-        if diff >= 0 && diff <= 32 {
+        let diff = self.base.signed_diff(*tick) - 1;
+        if diff >= 0 && diff < ACK_BITS {
             self.ack |= 1 << diff;
         }
     }
 
     pub fn apply_ack(&mut self, ack: &NetworkAck) {
-        let base_diff = self.base.tick() as i64 - ack.base.tick() as i64;
-        if base_diff > 0 {
+        if ack.base.signed_diff(self.base) > 0 {
+            self.set_base(ack.base);
+        }
+
+        let base_diff = self.base.signed_diff(ack.base);
+        if base_diff >= 0 && base_diff < ACK_BITS {
             self.ack |= ack.ack << base_diff;
         }
     }
@@ -64,21 +114,22 @@ impl NetworkAck {
     pub fn set_base(&mut self, new_base: NetworkTick) -> Vec<NetworkTick> {
         let mut unacked = Vec::new();
 
-        let base_diff = new_base.tick() as i64 - self.base.tick() as i64;
-        for index in ((32 - base_diff).max(0)..32).rev() {
-            let tick_num = self.base.tick() as i64 - index as i64;
-            if tick_num > 0 {
-                let tick = NetworkTick::new(tick_num as u64 - 1);
+        let base_diff = new_base.signed_diff(self.base);
+        for index in ((ACK_BITS - base_diff).max(0)..ACK_BITS).rev() {
+            if let Some(tick) = self.base.checked_sub(index as u64 + 1) {
                 if self.ack & (1 << index) == 0 {
                     unacked.push(tick);
                 }
             }
         }
 
-        if base_diff >= 32 {
+        if base_diff >= ACK_BITS {
+            // Ticks between the bases that fall outside the new window were never acked.
             self.ack = 0;
-            unacked
-                .extend((self.base.tick() + 32..new_base.tick()).map(|num| NetworkTick::new(num)));
+            unacked.extend(NetworkTick::range(
+                self.base,
+                new_base.saturating_sub(ACK_BITS as u64),
+            ));
         } else if base_diff > 0 {
             self.ack = self.ack << base_diff;
         }
@@ -89,6 +140,10 @@ impl NetworkAck {
     }
 }
 
+/// Client side acks of the server ticks we received updates for, sent back with our inputs.
+#[derive(Resource, Default, Debug, Clone, Deref, DerefMut)]
+pub struct ReceivedTicks(pub NetworkAck);
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -140,13 +195,62 @@ mod test {
             ack.ack(&tick);
         }
 
-        let unacked = ack.set_base(NetworkTick::new(35));
+        // Still inside the window.
+        assert!(ack.set_base(NetworkTick::new(35)).is_empty());
+
+        let unacked = ack.set_base(NetworkTick::new(66));
         assert_eq!(unacked.as_slice(), &ticks[..2]);
-        let unacked = ack.set_base(NetworkTick::new(65));
+        let unacked = ack.set_base(NetworkTick::new(96));
 
-        let extended_unacked = (21..=32)
+        let extended_unacked = (21..=31)
             .map(|num| NetworkTick::new(num))
             .collect::<Vec<_>>();
         assert_eq!(unacked.as_slice(), extended_unacked.as_slice());
     }
+
+    #[test]
+    pub fn high_latency_ack() {
+        let mut ack = NetworkAck::new(NetworkTick::new(100));
+        // 40 ticks behind, would have been dropped with 32 bits.
+        ack.ack(&NetworkTick::new(60));
+
+        let unacked = ack.set_base(NetworkTick::new(164));
+        assert_eq!(unacked.len(), 63);
+        assert!(!unacked.contains(&NetworkTick::new(60)));
+        assert!(unacked.contains(&NetworkTick::new(59)));
+        assert!(unacked.contains(&NetworkTick::new(61)));
+
+        // Jumping past the window reports everything in between as lost: the old window
+        // 100..=163 and then 164..236 which falls outside the new one.
+        let unacked = ack.set_base(NetworkTick::new(300));
+        assert_eq!(unacked.first(), Some(&NetworkTick::new(100)));
+        assert_eq!(unacked.last(), Some(&NetworkTick::new(235)));
+        assert_eq!(unacked.len(), 64 + (300 - 164 - 64) as usize);
+        assert_eq!(unacked.len(), 136);
+    }
+
+    #[test]
+    pub fn received_ticks() {
+        let mut client = NetworkAck::default();
+        client.receive(&NetworkTick::new(10));
+        client.receive(&NetworkTick::new(12));
+        // Arrived late.
+        client.receive(&NetworkTick::new(11));
+        assert_eq!(client.base(), NetworkTick::new(13));
+
+        let mut server = NetworkAck::default();
+        server.apply_ack(&client);
+        assert!(server.is_acked(&NetworkTick::new(10)));
+        assert!(server.is_acked(&NetworkTick::new(11)));
+        assert!(server.is_acked(&NetworkTick::new(12)));
+        assert!(!server.is_acked(&NetworkTick::new(13)));
+
+        // Newer acks keep what was acked before.
+        let mut newer = NetworkAck::new(NetworkTick::new(20));
+        newer.ack(&NetworkTick::new(19));
+        server.apply_ack(&newer);
+        assert!(server.is_acked(&NetworkTick::new(10)));
+        assert!(server.is_acked(&NetworkTick::new(19)));
+        assert!(!server.is_acked(&NetworkTick::new(15)));
+    }
 }
@@ -1,22 +1,30 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, utils::HashMap};
 use bevy_renet::renet::{ClientAuthentication, ConnectToken, RenetClient};
 use bevy::ecs::entity::{EntityMap, MapEntitiesError};
 
 use std::error::Error;
 use std::net::{ToSocketAddrs, UdpSocket};
 
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
-use crate::protocol::*;
+use crate::protocol::{codec::MalformedMessage, *};
+use crate::stage::NetworkSimulationInfo;
 
 pub fn new_renet_client<S: AsRef<str>>(ip: S, port: u16) -> Result<RenetClient, Box<dyn Error>> {
+    new_renet_client_with_protocol(ip, port, protocol_id())
+}
+
+pub fn new_renet_client_with_protocol<S: AsRef<str>>(
+    ip: S,
+    port: u16,
+    protocol_id: u64,
+) -> Result<RenetClient, Box<dyn Error>> {
     let server_addr = format!("{}:{}", ip.as_ref(), port)
         .to_socket_addrs()?
         .next()
         .ok_or(SabiError::NoSocketAddr)?;
 
     info!("server addr: {:?}", server_addr);
-    let protocol_id = protocol_id();
     info!("protocol id: {:?}", protocol_id);
 
     let connection_config = client_renet_config();
@@ -48,6 +56,90 @@ pub fn new_renet_client<S: AsRef<str>>(ip: S, port: u16) -> Result<RenetClient,
     )?)
 }
 
+/// Receive reliable messages from the server and send them out as `ServerMessage` events.
+pub fn client_recv_server_message(
+    mut client: ResMut<RenetClient>,
+    mut server_messages: EventWriter<ServerMessage>,
+    mut malformed: EventWriter<MalformedMessage>,
+) {
+    while let Some(message) = client.receive_message(ServerChannel::Message.id()) {
+        match ServerMessage::decode(message.as_slice()) {
+            Ok(message) => server_messages.send(message),
+            Err(err) => {
+                error!("dropping message from server: {}", err);
+                malformed.send(MalformedMessage {
+                    client_id: None,
+                    channel: ServerChannel::Message.id(),
+                    error: err,
+                });
+            }
+        }
+    }
+}
+
+/// Settings the server we are connected to told us about.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub tick_rate: Duration,
+    pub max_clients: u8,
+}
+
+/// Act on the messages the server sent us.
+///
+/// Entities the server refers to that we haven't received yet are spawned early through
+/// `ServerEntities` so updates for them will be applied to the same entity later.
+pub fn client_handle_server_message(
+    mut commands: Commands,
+    mut sim_info: ResMut<NetworkSimulationInfo>,
+    mut server_entities: ResMut<ServerEntities>,
+    mut lobby: ResMut<Lobby>,
+    mut local_player: ResMut<LocalPlayer>,
+    mut server_messages: EventReader<ServerMessage>,
+) {
+    for message in server_messages.iter() {
+        match message {
+            ServerMessage::ServerInfo {
+                tick_rate_ns,
+                max_clients,
+            } => {
+                if *tick_rate_ns == 0 {
+                    warn!("server sent a tick rate of 0, ignoring");
+                    continue;
+                }
+
+                let tick_rate = Duration::from_nanos(*tick_rate_ns);
+                if tick_rate != sim_info.step {
+                    info!(
+                        "using server tick rate of {:?} instead of {:?}",
+                        tick_rate, sim_info.step
+                    );
+                    sim_info.step = tick_rate;
+                }
+
+                commands.insert_resource(ServerInfo {
+                    tick_rate: tick_rate,
+                    max_clients: *max_clients,
+                });
+            }
+            ServerMessage::SetPlayer { id } => {
+                info!("we are player {}", id);
+                local_player.id = Some(*id);
+            }
+            ServerMessage::AssignOwnership { entity, client_id } => {
+                let entity = server_entities.spawn_or_get(&mut commands, *entity);
+                commands.entity(entity).insert((Owned, OwnedBy(*client_id)));
+            }
+            ServerMessage::PlayerConnected { id, entity } => {
+                let entity = server_entities.spawn_or_get(&mut commands, *entity);
+                lobby.insert(*id, entity);
+            }
+            ServerMessage::PlayerDisconnected { id } => {
+                lobby.remove(id);
+            }
+        }
+    }
+}
+
 pub fn client_connected(client: Option<Res<RenetClient>>) -> bool {
     match client {
         Some(client) => client.is_connected(),
@@ -59,57 +151,321 @@ pub fn client_connected(client: Option<Res<RenetClient>>) -> bool {
 ///
 /// This is so clients can figure out which entity the server is talking about.
 #[derive(Resource, Default, Debug)]
-pub struct ServerEntities(EntityMap);
+pub struct ServerEntities {
+    map: EntityMap,
+    /// Reverse of `map`, for referring to our entities when talking to the server.
+    local: HashMap<Entity, ServerEntity>,
+}
 
 impl ServerEntities {
     pub fn new() -> Self {
-        Self(Default::default())
+        Self::default()
     }
 
     pub fn spawn_or_get(&mut self, commands: &mut Commands, server_entity: Entity) -> Entity {
-        match self.0.entry(server_entity) {
+        match self.map.entry(server_entity) {
             Entry::Occupied(entity) => *entity.get(),
             Entry::Vacant(vacant) => {
                 let new_entity = commands.spawn(ServerEntity(server_entity)).id();
                 vacant.insert(new_entity);
+                self.local.insert(new_entity, ServerEntity(server_entity));
                 new_entity
             }
         }
     }
 
     pub fn get(&self, entities: &Entities, server_entity: ServerEntity) -> Option<Entity> {
-        self.0.get(server_entity.0).ok()
+        self.map.get(server_entity.0).ok()
+    }
+
+    /// Server entity that a local entity was spawned for.
+    pub fn local_to_server(&self, entity: Entity) -> Option<ServerEntity> {
+        self.local.get(&entity).cloned()
     }
 
+    /// Forget about a server entity, returns the entity it was mapped to.
+    pub fn remove(&mut self, server_entity: ServerEntity) -> Option<Entity> {
+        let entity = self.map.remove(server_entity.0)?;
+        self.local.remove(&entity);
+        Some(entity)
+    }
+
+    /// Forget any server entities whose local entity was despawned, returns true if
+    /// there were any.
     pub fn clean(&mut self, entities: &Entities) -> bool {
-        /*
-        let mut dead = Vec::new();
-        for (server_entity, entity) in self.0.iter() {
-            if !entities.contains(*entity) {
-                dead.push(*server_entity);
-            }
+        !self.clean_dead(entities).is_empty()
+    }
+
+    /// Same as `clean`, but sends a `ServerEntityDespawned` for each mapping removed.
+    pub fn clean_with_events(
+        &mut self,
+        entities: &Entities,
+        events: &mut EventWriter<ServerEntityDespawned>,
+    ) -> bool {
+        let dead = self.clean_dead(entities);
+        for (local_entity, server_entity) in dead.iter() {
+            events.send(ServerEntityDespawned {
+                server_entity: *server_entity,
+                local_entity: *local_entity,
+            });
         }
 
-        for server_entity in dead.iter() {
-            self.0.remove(server_entity);
+        !dead.is_empty()
+    }
+
+    fn clean_dead(&mut self, entities: &Entities) -> Vec<(Entity, ServerEntity)> {
+        let dead = self
+            .local
+            .keys()
+            .filter(|entity| !entities.contains(**entity))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut removed = Vec::with_capacity(dead.len());
+        for entity in dead {
+            if let Some(server_entity) = self.local.remove(&entity) {
+                self.map.remove(server_entity.0);
+                removed.push((entity, server_entity));
+            }
         }
-        dead.len() > 0
- */
-false
+
+        removed
     }
 
     /// Despawn any server entities
     pub fn disconnect(&mut self, entities: &Entities, commands: &mut Commands) {
-        /*
-        for (_server_entity, entity) in self.0.drain() {
+        for (entity, server_entity) in self.local.drain() {
+            self.map.remove(server_entity.0);
             if entities.contains(entity) {
                 commands.entity(entity).despawn_recursive();
             }
         }
- */
     }
 
     pub fn map(&self) -> &EntityMap {
-        &self.0
+        &self.map
+    }
+}
+
+/// A server entity's local entity was despawned, either by the server or locally.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerEntityDespawned {
+    pub server_entity: ServerEntity,
+    pub local_entity: Entity,
+}
+
+/// Forget server entities that were despawned, so they get a fresh entity if the
+/// server mentions them again.
+pub fn client_clean_server_entities(
+    entities: &Entities,
+    removed: RemovedComponents<ServerEntity>,
+    mut server_entities: ResMut<ServerEntities>,
+    mut despawned: EventWriter<ServerEntityDespawned>,
+) {
+    if removed.iter().next().is_some() {
+        server_entities.clean_with_events(entities, &mut despawned);
+    }
+}
+
+/// Despawn everything the server gave us once we give up on it, a reconnect will baseload
+/// them all again.
+pub fn client_disconnect_entities(
+    mut commands: Commands,
+    entities: &Entities,
+    mut disconnected: EventReader<ServerDisconnected>,
+    mut server_entities: ResMut<ServerEntities>,
+) {
+    if disconnected.iter().count() > 0 {
+        server_entities.disconnect(entities, &mut commands);
+    }
+}
+
+/// Client side, reset `R` once we give up on the server so a reconnect starts clean.
+pub fn reset_on_disconnect<R>(
+    mut disconnected: EventReader<ServerDisconnected>,
+    mut state: ResMut<R>,
+) where
+    R: Resource + Default,
+{
+    if disconnected.iter().count() > 0 {
+        *state = R::default();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn server_message_round_trip() {
+        let message = ServerMessage::PlayerConnected {
+            id: 5,
+            entity: Entity::from_raw(3),
+        };
+
+        let decoded = ServerMessage::decode(&message.encode()).unwrap();
+        assert_eq!(decoded, message);
+        assert!(ServerMessage::decode(&[255, 255, 255, 255, 255]).is_err());
+    }
+
+    #[test]
+    pub fn assign_ownership() {
+        let mut world = World::new();
+        world.init_resource::<ServerEntities>();
+        world.init_resource::<Lobby>();
+        world.init_resource::<LocalPlayer>();
+        world.init_resource::<Events<ServerMessage>>();
+        world.insert_resource(NetworkSimulationInfo::new(crate::tick::tick_hz(32)));
+
+        let server_player = Entity::from_raw(42);
+        let mut events = world.resource_mut::<Events<ServerMessage>>();
+        events.send(ServerMessage::SetPlayer { id: 7 });
+        events.send(ServerMessage::PlayerConnected {
+            id: 7,
+            entity: server_player,
+        });
+        events.send(ServerMessage::AssignOwnership {
+            entity: server_player,
+            client_id: 7,
+        });
+
+        let mut stage = SystemStage::single_threaded().with_system(client_handle_server_message);
+        stage.run(&mut world);
+
+        let player = world
+            .resource::<ServerEntities>()
+            .get(world.entities(), ServerEntity::from_entity(server_player))
+            .expect("server entity was mapped");
+        assert!(world.get::<Owned>(player).is_some());
+        assert_eq!(world.get::<OwnedBy>(player), Some(&OwnedBy(7)));
+        assert_eq!(
+            world.get::<ServerEntity>(player),
+            Some(&ServerEntity::from_entity(server_player))
+        );
+        assert_eq!(
+            world.resource::<ServerEntities>().local_to_server(player),
+            Some(ServerEntity::from_entity(server_player))
+        );
+
+        let local_player = world.resource::<LocalPlayer>();
+        assert_eq!(local_player.entity(world.resource::<Lobby>()), Some(player));
+
+        // Despawned locally, both directions are forgotten.
+        world.despawn(player);
+        world.resource_scope(|world, mut server_entities: Mut<ServerEntities>| {
+            assert!(server_entities.clean(world.entities()));
+            assert!(!server_entities.clean(world.entities()));
+            assert_eq!(server_entities.local_to_server(player), None);
+            assert_eq!(
+                server_entities.get(world.entities(), ServerEntity::from_entity(server_player)),
+                None
+            );
+        });
+    }
+
+    #[test]
+    pub fn reconnect_without_duplicates() {
+        let mut world = World::new();
+        world.init_resource::<ServerEntities>();
+        world.init_resource::<Lobby>();
+        world.init_resource::<Events<ServerDisconnected>>();
+
+        let server_entity = Entity::from_raw(42);
+        let map = |world: &mut World| {
+            world.resource_scope(|world, mut server_entities: Mut<ServerEntities>| {
+                let mut queue = bevy::ecs::system::CommandQueue::default();
+                let mut commands = Commands::new(&mut queue, world);
+                let entity = server_entities.spawn_or_get(&mut commands, server_entity);
+                queue.apply(world);
+                entity
+            })
+        };
+
+        let first = map(&mut world);
+        world.resource_mut::<Lobby>().insert(7, first);
+        assert_eq!(map(&mut world), first);
+
+        world
+            .resource_mut::<Events<ServerDisconnected>>()
+            .send(ServerDisconnected);
+        let mut stage = SystemStage::single_threaded()
+            .with_system(client_disconnect_entities)
+            .with_system(reset_on_disconnect::<Lobby>);
+        stage.run(&mut world);
+
+        assert!(world.get_entity(first).is_none());
+        assert!(world.resource::<Lobby>().is_empty());
+        assert_eq!(
+            world
+                .resource::<ServerEntities>()
+                .get(world.entities(), ServerEntity::from_entity(server_entity)),
+            None
+        );
+
+        // Baseloaded again after reconnecting.
+        let second = map(&mut world);
+        assert_ne!(second, first);
+        let mut query = world.query::<&ServerEntity>();
+        assert_eq!(query.iter(&world).count(), 1);
+    }
+
+    #[test]
+    pub fn server_tick_rate() {
+        let mut world = World::new();
+        world.init_resource::<ServerEntities>();
+        world.init_resource::<Lobby>();
+        world.init_resource::<LocalPlayer>();
+        world.init_resource::<Events<ServerMessage>>();
+        world.insert_resource(NetworkSimulationInfo::new(crate::tick::tick_hz(32)));
+
+        world
+            .resource_mut::<Events<ServerMessage>>()
+            .send(ServerMessage::ServerInfo {
+                tick_rate_ns: crate::tick::tick_hz(60).as_nanos() as u64,
+                max_clients: 10,
+            });
+
+        let mut stage = SystemStage::single_threaded().with_system(client_handle_server_message);
+        stage.run(&mut world);
+
+        let mut sim_info = world.resource_mut::<NetworkSimulationInfo>();
+        assert_eq!(sim_info.step, crate::tick::tick_hz(60));
+        sim_info.reset_step();
+        assert_eq!(sim_info.step, crate::tick::tick_hz(32));
+        assert_eq!(world.resource::<ServerInfo>().max_clients, 10);
+    }
+
+    #[test]
+    pub fn despawn_events() {
+        let mut world = World::new();
+        world.init_resource::<ServerEntities>();
+        world.init_resource::<Events<ServerEntityDespawned>>();
+
+        let server_entity = Entity::from_raw(42);
+        let entity = world.resource_scope(|world, mut server_entities: Mut<ServerEntities>| {
+            let mut queue = bevy::ecs::system::CommandQueue::default();
+            let mut commands = Commands::new(&mut queue, world);
+            let entity = server_entities.spawn_or_get(&mut commands, server_entity);
+            queue.apply(world);
+            entity
+        });
+
+        let mut stage = SystemStage::single_threaded().with_system(client_clean_server_entities);
+        stage.run(&mut world);
+        assert!(world.resource::<Events<ServerEntityDespawned>>().is_empty());
+
+        world.despawn(entity);
+        stage.run(&mut world);
+
+        let events = world.resource::<Events<ServerEntityDespawned>>();
+        let mut reader = events.get_reader();
+        assert_eq!(
+            reader.iter(events).cloned().collect::<Vec<_>>(),
+            vec![ServerEntityDespawned {
+                server_entity: ServerEntity::from_entity(server_entity),
+                local_entity: entity,
+            }]
+        );
+        assert_eq!(world.resource::<ServerEntities>().local_to_server(entity), None);
     }
 }
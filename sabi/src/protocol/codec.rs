@@ -0,0 +1,564 @@
+use bevy::{
+    prelude::*,
+    reflect::{
+        serde::{ReflectSerializer, UntypedReflectDeserializer},
+        TypeRegistry,
+    },
+};
+use bincode::Options;
+use serde::de::DeserializeSeed;
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+};
+
+use zstd::bulk::{Compressor, Decompressor};
+
+use super::{ClientId, ClientState};
+use crate::prelude::*;
+
+/// Compression applied to whole messages before they are sent over the wire.
+pub trait MessageCodec: 'static + Send + Sync {
+    /// Unique name of this codec, this is part of the `protocol_id()` so both
+    /// sides have to agree on it.
+    fn id(&self) -> &'static str;
+
+    fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, SabiError>;
+
+    /// Decompress a message, erroring if it would be larger than `max_size`.
+    fn decompress(&mut self, data: &[u8], max_size: usize) -> Result<Vec<u8>, SabiError>;
+
+    /// Hash of any dictionary the codec compresses with, this is part of the
+    /// `protocol_id()` as well.
+    fn dictionary_id(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Zstd compression, this is the default.
+///
+/// The compression contexts are made on first use and reused for every message after.
+pub struct ZstdCodec {
+    pub level: i32,
+    dictionary: Option<Arc<[u8]>>,
+    // Contexts aren't `Sync`, but we only ever use them through `&mut self`.
+    compressor: Mutex<Option<Compressor<'static>>>,
+    decompressor: Mutex<Option<Decompressor<'static>>>,
+}
+
+impl Default for ZstdCodec {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl Clone for ZstdCodec {
+    fn clone(&self) -> Self {
+        Self {
+            level: self.level,
+            dictionary: self.dictionary.clone(),
+            compressor: Mutex::new(None),
+            decompressor: Mutex::new(None),
+        }
+    }
+}
+
+impl std::fmt::Debug for ZstdCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ZstdCodec")
+            .field("level", &self.level)
+            .field("dictionary_id", &self.dictionary_id())
+            .finish()
+    }
+}
+
+impl ZstdCodec {
+    pub fn new(level: i32) -> Self {
+        Self {
+            level,
+            dictionary: None,
+            compressor: Mutex::new(None),
+            decompressor: Mutex::new(None),
+        }
+    }
+
+    /// Compress with a dictionary, see `message_sample` for building one.
+    pub fn with_dictionary(mut self, dictionary: Vec<u8>) -> Self {
+        self.dictionary = Some(dictionary.into());
+        self
+    }
+
+    fn compressor(&mut self) -> Result<&mut Compressor<'static>, SabiError> {
+        let compressor = self
+            .compressor
+            .get_mut()
+            .map_err(|err| SabiError::Compress(err.to_string()))?;
+
+        if compressor.is_none() {
+            let new = match &self.dictionary {
+                Some(dictionary) => Compressor::with_dictionary(self.level, dictionary),
+                None => Compressor::new(self.level),
+            };
+            *compressor = Some(new.map_err(|err| SabiError::Compress(err.to_string()))?);
+        }
+
+        Ok(compressor.as_mut().expect("compressor was just made"))
+    }
+
+    fn decompressor(&mut self) -> Result<&mut Decompressor<'static>, SabiError> {
+        let decompressor = self
+            .decompressor
+            .get_mut()
+            .map_err(|err| SabiError::Decompress(err.to_string()))?;
+
+        if decompressor.is_none() {
+            let new = match &self.dictionary {
+                Some(dictionary) => Decompressor::with_dictionary(dictionary),
+                None => Decompressor::new(),
+            };
+            *decompressor = Some(new.map_err(|err| SabiError::Decompress(err.to_string()))?);
+        }
+
+        Ok(decompressor.as_mut().expect("decompressor was just made"))
+    }
+}
+
+impl MessageCodec for ZstdCodec {
+    fn id(&self) -> &'static str {
+        "zstd"
+    }
+
+    fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, SabiError> {
+        self.compressor()?
+            .compress(data)
+            .map_err(|err| SabiError::Compress(err.to_string()))
+    }
+
+    fn decompress(&mut self, data: &[u8], max_size: usize) -> Result<Vec<u8>, SabiError> {
+        // Check the size the frame claims before allocating anything for it, the
+        // decompression itself still won't go past `capacity` if it lied.
+        let capacity = match zstd::zstd_safe::get_frame_content_size(data) {
+            Ok(Some(size)) if size > max_size as u64 => {
+                return Err(SabiError::MessageTooLarge {
+                    size: size as usize,
+                    max: max_size,
+                });
+            }
+            Ok(Some(size)) => size as usize,
+            Ok(None) => max_size,
+            Err(_) => {
+                return Err(SabiError::Decompress(
+                    "not a zstd frame or unknown content size".to_owned(),
+                ))
+            }
+        };
+
+        self.decompressor()?
+            .decompress(data, capacity)
+            .map_err(|err| SabiError::Decompress(err.to_string()))
+    }
+
+    fn dictionary_id(&self) -> Option<u64> {
+        let dictionary = self.dictionary.as_ref()?;
+        let mut hasher = DefaultHasher::new();
+        dictionary.hash(&mut hasher);
+        Some(hasher.finish())
+    }
+}
+
+/// No compression at all, useful for LAN games where bandwidth doesn't matter much.
+#[derive(Debug, Default, Clone)]
+pub struct PassthroughCodec;
+
+impl MessageCodec for PassthroughCodec {
+    fn id(&self) -> &'static str {
+        "passthrough"
+    }
+
+    fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, SabiError> {
+        Ok(data.to_vec())
+    }
+
+    fn decompress(&mut self, data: &[u8], max_size: usize) -> Result<Vec<u8>, SabiError> {
+        if data.len() > max_size {
+            return Err(SabiError::MessageTooLarge {
+                size: data.len(),
+                max: max_size,
+            });
+        }
+
+        Ok(data.to_vec())
+    }
+}
+
+/// Codec used for every message we send or receive.
+///
+/// Insert this before adding the `SabiPlugin` to change it from the default zstd codec, make
+/// sure to use `protocol_id_with_codec` when creating the renet client/server as well.
+#[derive(Resource)]
+pub struct NetworkCodec(pub Box<dyn MessageCodec>);
+
+impl NetworkCodec {
+    pub fn new<C: MessageCodec>(codec: C) -> Self {
+        Self(Box::new(codec))
+    }
+}
+
+impl Default for NetworkCodec {
+    fn default() -> Self {
+        Self::new(ZstdCodec::default())
+    }
+}
+
+impl std::ops::Deref for NetworkCodec {
+    type Target = dyn MessageCodec;
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl std::ops::DerefMut for NetworkCodec {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut *self.0
+    }
+}
+
+/// Settings for the default zstd `NetworkCodec`, both sides have to agree on these.
+///
+/// Insert this before adding the `SabiPlugin` to change it, and use
+/// `protocol_id_with_compression` when creating the renet client/server.
+#[derive(Resource, Debug, Clone, PartialEq)]
+pub struct CompressionConfig {
+    pub level: i32,
+    /// Compress with `./dictionary/update.dict` if it exists.
+    pub dictionary: bool,
+    /// Compress entity updates from the server.
+    pub updates: bool,
+    /// Compress inputs from clients.
+    pub inputs: bool,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            level: 0,
+            dictionary: true,
+            updates: true,
+            inputs: true,
+        }
+    }
+}
+
+impl CompressionConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build the codec these settings describe, loading the dictionary if there is one.
+    pub fn codec(&self) -> ZstdCodec {
+        let codec = ZstdCodec::new(self.level);
+        if !self.dictionary {
+            return codec;
+        }
+
+        match crate::message_sample::read_dictionary("update") {
+            Ok(dictionary) => codec.with_dictionary(dictionary),
+            Err(_) => codec,
+        }
+    }
+}
+
+/// Largest size a message from the server is allowed to decompress to.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct MaxMessageBytes(pub usize);
+
+impl Default for MaxMessageBytes {
+    fn default() -> Self {
+        Self(crate::plugin::DEFAULT_MAX_MESSAGE_BYTES)
+    }
+}
+
+/// A message that arrived over the network but couldn't be decoded, and was dropped.
+#[derive(Debug, Clone)]
+pub struct MalformedMessage {
+    /// Client that sent it, `None` if it came from the server.
+    pub client_id: Option<ClientId>,
+    pub channel: u8,
+    pub error: SabiError,
+}
+
+/// Count of malformed messages from the server and from each client.
+///
+/// The server can use this to kick clients that keep sending garbage.
+#[derive(Resource, Default, Debug, Clone)]
+pub struct NetworkErrorStats {
+    server: u64,
+    clients: BTreeMap<ClientId, u64>,
+}
+
+impl NetworkErrorStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, malformed: &MalformedMessage) {
+        match malformed.client_id {
+            Some(client_id) => *self.clients.entry(client_id).or_default() += 1,
+            None => self.server += 1,
+        }
+    }
+
+    /// Malformed messages we received from the server.
+    pub fn server(&self) -> u64 {
+        self.server
+    }
+
+    /// Malformed messages we received from a client.
+    pub fn client(&self, client_id: &ClientId) -> u64 {
+        self.clients.get(client_id).cloned().unwrap_or(0)
+    }
+}
+
+impl ClientState for NetworkErrorStats {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+    }
+}
+
+pub fn count_malformed_messages(
+    mut malformed: EventReader<MalformedMessage>,
+    mut stats: ResMut<NetworkErrorStats>,
+) {
+    for malformed in malformed.iter() {
+        stats.record(malformed);
+    }
+}
+
+/// Serialization of individual replicated components.
+///
+/// Both sides have to use the same codec.
+pub trait ReplicationCodec: 'static + Send + Sync {
+    fn encode(&self, value: &dyn Reflect, registry: &TypeRegistry) -> Result<Vec<u8>, SabiError>;
+    fn decode(&self, data: &[u8], registry: &TypeRegistry) -> Result<Box<dyn Reflect>, SabiError>;
+}
+
+/// Ron, this is the default.
+///
+/// Self describing so it is easy to read when debugging, but not very compact.
+#[derive(Debug, Default, Clone)]
+pub struct RonReplicationCodec;
+
+impl ReplicationCodec for RonReplicationCodec {
+    fn encode(&self, value: &dyn Reflect, registry: &TypeRegistry) -> Result<Vec<u8>, SabiError> {
+        let serializer = ReflectSerializer::new(value, registry);
+        ron::ser::to_string(&serializer)
+            .map(|data| data.into_bytes())
+            .map_err(|err| SabiError::Serialize(err.to_string()))
+    }
+
+    fn decode(&self, data: &[u8], registry: &TypeRegistry) -> Result<Box<dyn Reflect>, SabiError> {
+        let mut deserializer = ron::de::Deserializer::from_bytes(data)
+            .map_err(|err| SabiError::Deserialize(err.to_string()))?;
+        UntypedReflectDeserializer::new(registry)
+            .deserialize(&mut deserializer)
+            .map_err(|err| SabiError::Deserialize(err.to_string()))
+    }
+}
+
+/// Bincode, much smaller than ron.
+#[derive(Debug, Default, Clone)]
+pub struct BincodeReplicationCodec;
+
+impl ReplicationCodec for BincodeReplicationCodec {
+    fn encode(&self, value: &dyn Reflect, registry: &TypeRegistry) -> Result<Vec<u8>, SabiError> {
+        let serializer = ReflectSerializer::new(value, registry);
+        bincode::DefaultOptions::new()
+            .serialize(&serializer)
+            .map_err(|err| SabiError::Serialize(err.to_string()))
+    }
+
+    fn decode(&self, data: &[u8], registry: &TypeRegistry) -> Result<Box<dyn Reflect>, SabiError> {
+        bincode::DefaultOptions::new()
+            .deserialize_seed(UntypedReflectDeserializer::new(registry), data)
+            .map_err(|err| SabiError::Deserialize(err.to_string()))
+    }
+}
+
+/// Codec used for replicated components.
+///
+/// Insert this before adding the `SabiPlugin` to change it from ron.
+#[derive(Resource)]
+pub struct ComponentCodec(pub Box<dyn ReplicationCodec>);
+
+impl ComponentCodec {
+    pub fn new<C: ReplicationCodec>(codec: C) -> Self {
+        Self(Box::new(codec))
+    }
+}
+
+impl Default for ComponentCodec {
+    fn default() -> Self {
+        Self::new(RonReplicationCodec)
+    }
+}
+
+impl std::ops::Deref for ComponentCodec {
+    type Target = dyn ReplicationCodec;
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::update::{EntityUpdate, UpdateMessage};
+
+    #[test]
+    pub fn passthrough_round_trip() {
+        let mut codec = NetworkCodec::new(PassthroughCodec);
+
+        let message = UpdateMessage {
+            tick: NetworkTick::new(5),
+            input_deviation: Default::default(),
+            input_ack: Default::default(),
+            entity_update: EntityUpdate::new(),
+            component_despawn: Vec::new(),
+            entity_despawn: Vec::new(),
+        };
+
+        let serialized = bincode::serialize(&message).unwrap();
+        let compressed = codec.compress(&serialized).unwrap();
+        assert_eq!(compressed, serialized);
+
+        let decompressed = codec.decompress(&compressed, 10 * 1024).unwrap();
+        let decoded: UpdateMessage = bincode::deserialize(&decompressed).unwrap();
+        assert_eq!(decoded.tick, message.tick);
+
+        assert!(codec.decompress(&compressed, 1).is_err());
+    }
+
+    #[test]
+    pub fn dictionary_round_trip() {
+        // Raw content works as a dictionary as well as trained ones.
+        let dictionary = b"entity 12 moved to 36, 84;".repeat(8);
+
+        let mut codec = ZstdCodec::new(3).with_dictionary(dictionary.clone());
+        let message = b"entity 12 moved to 36, 84";
+        let compressed = codec.compress(message).unwrap();
+        assert_eq!(codec.decompress(&compressed, 1024).unwrap(), message);
+        // Contexts are reused between messages.
+        let compressed = codec.compress(message).unwrap();
+        assert_eq!(codec.decompress(&compressed, 1024).unwrap(), message);
+
+        // Can't be read without the same dictionary.
+        assert_ne!(
+            ZstdCodec::default().decompress(&compressed, 1024).ok(),
+            Some(message.to_vec())
+        );
+
+        let clone = codec.clone();
+        assert!(clone.dictionary_id().is_some());
+        assert_eq!(clone.dictionary_id(), codec.dictionary_id());
+        assert_ne!(
+            crate::protocol::protocol_id_with_codec(&codec),
+            crate::protocol::protocol_id_with_codec(&ZstdCodec::default()),
+        );
+    }
+
+    #[test]
+    pub fn codec_protocol_id() {
+        assert_ne!(
+            crate::protocol::protocol_id_with_codec(&ZstdCodec::default()),
+            crate::protocol::protocol_id_with_codec(&PassthroughCodec),
+        );
+        assert_eq!(
+            crate::protocol::protocol_id(),
+            crate::protocol::protocol_id_with_compression(&CompressionConfig::default()),
+        );
+
+        let uncompressed_inputs = CompressionConfig {
+            inputs: false,
+            ..Default::default()
+        };
+        assert_ne!(
+            crate::protocol::protocol_id_with_compression(&uncompressed_inputs),
+            crate::protocol::protocol_id(),
+        );
+    }
+
+    #[derive(Reflect, FromReflect, Debug, PartialEq, Default)]
+    struct Health {
+        current: u32,
+        max: u32,
+    }
+
+    #[test]
+    pub fn component_codecs() {
+        let mut registry = TypeRegistry::default();
+        registry.register::<Health>();
+
+        let health = Health {
+            current: 5,
+            max: 10,
+        };
+        let codecs: [ComponentCodec; 2] = [
+            ComponentCodec::new(RonReplicationCodec),
+            ComponentCodec::new(BincodeReplicationCodec),
+        ];
+        for codec in codecs.iter() {
+            let data = codec.encode(&health, &registry).unwrap();
+            let decoded = codec.decode(&data, &registry).unwrap();
+            assert_eq!(
+                Health::from_reflect(&*decoded),
+                Some(Health {
+                    current: 5,
+                    max: 10
+                })
+            );
+
+            assert!(codec.decode(&data[..data.len() / 2], &registry).is_err());
+        }
+    }
+
+    #[test]
+    pub fn malformed_stats() {
+        let mut world = World::new();
+        world.init_resource::<Events<MalformedMessage>>();
+        world.init_resource::<NetworkErrorStats>();
+
+        // Claims to be far bigger than we allow, rejected before decompressing.
+        let mut codec = ZstdCodec::default();
+        let compressed = codec.compress(&[0u8; 64 * 1024]).unwrap();
+        let error = codec.decompress(&compressed, 1024).unwrap_err();
+        assert!(matches!(error, SabiError::MessageTooLarge { size, .. } if size == 64 * 1024));
+
+        let mut events = world.resource_mut::<Events<MalformedMessage>>();
+        for client_id in [1, 1, 2] {
+            events.send(MalformedMessage {
+                client_id: Some(client_id),
+                channel: ClientChannel::Input.id(),
+                error: error.clone(),
+            });
+        }
+        events.send(MalformedMessage {
+            client_id: None,
+            channel: ServerChannel::EntityUpdate.id(),
+            error: SabiError::Deserialize("garbage".to_owned()),
+        });
+
+        let mut stage = SystemStage::single_threaded().with_system(count_malformed_messages);
+        stage.run(&mut world);
+
+        let mut stats = world.resource_mut::<NetworkErrorStats>();
+        assert_eq!(stats.client(&1), 2);
+        assert_eq!(stats.client(&2), 1);
+        assert_eq!(stats.client(&3), 0);
+        assert_eq!(stats.server(), 1);
+
+        stats.forget_client(&1);
+        assert_eq!(stats.client(&1), 0);
+    }
+}
@@ -0,0 +1,248 @@
+//! Visual smoothing of mispredictions.
+//!
+//! When a rewind resimulates to a different value than what we were showing, the
+//! difference is kept as a `CorrectionError` and blended away over a few frames. Only
+//! the rendered value (e.g. `GlobalTransform`) is offset, the simulated one is left alone.
+
+use std::{collections::BTreeMap, fmt::Debug, marker::PhantomData, time::Duration};
+
+use bevy::{ecs::query::ReadOnlyWorldQuery, prelude::*};
+
+/// How a mispredicted component is corrected on the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorrectionMode {
+    /// Jump straight to the corrected value.
+    Snap,
+    /// Blend from the mispredicted value to the corrected one over `time`.
+    Smooth { time: Duration },
+}
+
+impl Default for CorrectionMode {
+    fn default() -> Self {
+        CorrectionMode::Snap
+    }
+}
+
+/// Components that can have their corrections smoothed out.
+pub trait Smooth: 'static + Component + Clone {
+    /// Offset from the corrected value back to the mispredicted one.
+    type Error: 'static + Send + Sync + Clone + Debug;
+    /// Component the offset is shown on.
+    type Render: Component;
+
+    fn error(before: &Self, after: &Self) -> Self::Error;
+    /// Too small to bother smoothing.
+    fn negligible(error: &Self::Error) -> bool;
+    /// `error` scaled by `weight` between 0 and 1.
+    fn scale(error: &Self::Error, weight: f32) -> Self::Error;
+    fn combine(a: &Self::Error, b: &Self::Error) -> Self::Error;
+    fn apply(render: &mut Self::Render, error: &Self::Error);
+}
+
+impl Smooth for Transform {
+    type Error = (Vec3, Quat);
+    type Render = GlobalTransform;
+
+    fn error(before: &Self, after: &Self) -> Self::Error {
+        (
+            before.translation - after.translation,
+            before.rotation * after.rotation.inverse(),
+        )
+    }
+
+    fn negligible(error: &Self::Error) -> bool {
+        error.0.length_squared() < 1e-8 && error.1.abs_diff_eq(Quat::IDENTITY, 1e-6)
+    }
+
+    fn scale(error: &Self::Error, weight: f32) -> Self::Error {
+        (error.0 * weight, Quat::IDENTITY.slerp(error.1, weight))
+    }
+
+    fn combine(a: &Self::Error, b: &Self::Error) -> Self::Error {
+        (a.0 + b.0, a.1 * b.1)
+    }
+
+    fn apply(render: &mut Self::Render, error: &Self::Error) {
+        let mut transform = render.compute_transform();
+        transform.translation += error.0;
+        transform.rotation = (error.1 * transform.rotation).normalize();
+        *render = GlobalTransform::from(transform);
+    }
+}
+
+/// What is left of a correction that is still being blended away.
+#[derive(Component, Debug, Clone)]
+pub struct CorrectionError<C: Smooth> {
+    pub error: C::Error,
+    pub remaining: Duration,
+}
+
+impl<C: Smooth> CorrectionError<C> {
+    /// How much of the error is still shown.
+    pub fn weight(&self, time: Duration) -> f32 {
+        if time.is_zero() {
+            return 0.0;
+        }
+
+        (self.remaining.as_secs_f32() / time.as_secs_f32()).clamp(0.0, 1.0)
+    }
+}
+
+/// Values from before the last rewind, compared against after the resimulation.
+#[derive(Resource, Debug)]
+pub struct PreCorrection<C>(BTreeMap<Entity, C>);
+
+impl<C> Default for PreCorrection<C> {
+    fn default() -> Self {
+        Self(Default::default())
+    }
+}
+
+/// Blend time for corrections of `C`.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct CorrectionTime<C> {
+    pub time: Duration,
+    phantom: PhantomData<C>,
+}
+
+impl<C> CorrectionTime<C> {
+    pub fn new(time: Duration) -> Self {
+        Self {
+            time,
+            phantom: PhantomData,
+        }
+    }
+}
+
+/// Remember what we were showing before rewinding.
+///
+/// Runs in the rewind stage, the rewind itself is applied through commands so this still
+/// sees the old values. If we rewind more than once before measuring, the first values
+/// are the ones that were actually shown.
+pub fn store_pre_correction<C: Smooth, F: 'static + ReadOnlyWorldQuery>(
+    mut pre: ResMut<PreCorrection<C>>,
+    query: Query<(Entity, &C), F>,
+) {
+    for (entity, component) in query.iter() {
+        pre.0.entry(entity).or_insert_with(|| component.clone());
+    }
+}
+
+/// Compare against the resimulated values and keep the difference to blend away.
+pub fn measure_correction<C: Smooth>(
+    mut commands: Commands,
+    time: Res<CorrectionTime<C>>,
+    mut pre: ResMut<PreCorrection<C>>,
+    mut query: Query<(&C, Option<&mut CorrectionError<C>>)>,
+) {
+    for (entity, before) in std::mem::take(&mut pre.0) {
+        if let Ok((after, existing)) = query.get_mut(entity) {
+            let error = C::error(&before, after);
+            if C::negligible(&error) {
+                continue;
+            }
+
+            match existing {
+                Some(mut existing) => {
+                    let remaining = C::scale(&existing.error, existing.weight(time.time));
+                    existing.error = C::combine(&remaining, &error);
+                    existing.remaining = time.time;
+                }
+                None => {
+                    commands.entity(entity).insert(CorrectionError::<C> {
+                        error,
+                        remaining: time.time,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Offset the rendered value by what is left of the error.
+///
+/// `C` is marked as changed so the render value is recomputed from the simulated one
+/// next frame instead of the offset piling up.
+pub fn apply_correction<C: Smooth>(
+    mut commands: Commands,
+    time: Res<Time>,
+    correction_time: Res<CorrectionTime<C>>,
+    mut query: Query<(Entity, &mut CorrectionError<C>, &mut C, &mut C::Render)>,
+) {
+    for (entity, mut correction, mut component, mut render) in query.iter_mut() {
+        component.set_changed();
+
+        let weight = correction.weight(correction_time.time);
+        if weight <= 0.0 {
+            commands.entity(entity).remove::<CorrectionError<C>>();
+            continue;
+        }
+
+        C::apply(&mut *render, &C::scale(&correction.error, weight));
+        correction.remaining = correction.remaining.saturating_sub(time.delta());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn transform_error_blends_to_zero() {
+        let before = Transform::from_xyz(1.0, 0.0, 0.0);
+        let after = Transform::from_xyz(0.0, 0.0, 0.0);
+        let error = Transform::error(&before, &after);
+
+        let mut render = GlobalTransform::from(after);
+        Transform::apply(&mut render, &Transform::scale(&error, 1.0));
+        assert!(render.translation().abs_diff_eq(before.translation, 1e-5));
+
+        let mut render = GlobalTransform::from(after);
+        Transform::apply(&mut render, &Transform::scale(&error, 0.5));
+        assert!(render
+            .translation()
+            .abs_diff_eq(Vec3::new(0.5, 0.0, 0.0), 1e-5));
+
+        let correction = CorrectionError::<Transform> {
+            error,
+            remaining: Duration::from_millis(50),
+        };
+        assert_eq!(correction.weight(Duration::from_millis(100)), 0.5);
+        assert_eq!(correction.weight(Duration::ZERO), 0.0);
+    }
+
+    #[test]
+    pub fn measure_after_resim() {
+        let mut world = World::new();
+        let entity = world.spawn(Transform::from_xyz(2.0, 0.0, 0.0)).id();
+        world.insert_resource(CorrectionTime::<Transform>::new(Duration::from_millis(100)));
+        world.init_resource::<PreCorrection<Transform>>();
+
+        let mut rewind =
+            SystemStage::single_threaded().with_system(store_pre_correction::<Transform, ()>);
+        rewind.run(&mut world);
+
+        // Resimulation ended up somewhere else.
+        world
+            .entity_mut(entity)
+            .insert(Transform::from_xyz(0.0, 0.0, 0.0));
+
+        let mut measure =
+            SystemStage::single_threaded().with_system(measure_correction::<Transform>);
+        measure.run(&mut world);
+
+        let correction = world.get::<CorrectionError<Transform>>(entity).unwrap();
+        assert!(correction
+            .error
+            .0
+            .abs_diff_eq(Vec3::new(2.0, 0.0, 0.0), 1e-5));
+        assert_eq!(correction.remaining, Duration::from_millis(100));
+        assert!(world.resource::<PreCorrection<Transform>>().0.is_empty());
+
+        // Nothing changed, nothing to smooth.
+        let other = world.spawn(Transform::from_xyz(1.0, 0.0, 0.0)).id();
+        rewind.run(&mut world);
+        measure.run(&mut world);
+        assert!(world.get::<CorrectionError<Transform>>(other).is_none());
+    }
+}
@@ -0,0 +1,447 @@
+//! Delta encoding of component updates.
+//!
+//! Components replicated with `ReplicateConfig::with_delta` are sent as the bytes that
+//! changed since the newest version the client acked. Until something is acked, or if
+//! the acked version is too old for the client to still have, the full value is sent.
+//! If the client can't rebuild a delta anyway it asks for a full resend the same way it
+//! does for any other gap in versions.
+//!
+//! Delta format, all lengths in bytes:
+//! `[new length: u16 le] ([skip: u8] [count: u8] [count changed bytes])*`
+
+use std::collections::BTreeMap;
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use super::{
+    ack::{NetworkAck, ACK_BITS},
+    demands::ReplicateSizeEstimates,
+    interest::Interest,
+    version::ReplicateVersion,
+    ClientId, ClientState, NetworkTick, ReplicateId,
+};
+use crate::error::SabiError;
+
+/// Components smaller than this are always sent in full, the delta overhead isn't worth it.
+pub const MIN_DELTA_SIZE: usize = 16;
+
+/// How many versions of a component the client keeps to rebuild deltas against.
+pub const DELTA_HISTORY: u32 = 32;
+
+/// Components that are delta encoded, registered on both the server and client.
+#[derive(Resource, Default, Debug, Clone)]
+pub struct DeltaComponents(HashSet<ReplicateId>);
+
+impl DeltaComponents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, replicate_id: ReplicateId) {
+        self.0.insert(replicate_id);
+    }
+
+    pub fn contains(&self, replicate_id: &ReplicateId) -> bool {
+        self.0.contains(replicate_id)
+    }
+
+    /// Should we try delta encoding this component at all.
+    pub fn worth_it(&self, replicate_id: &ReplicateId, estimates: &ReplicateSizeEstimates) -> bool {
+        self.contains(replicate_id) && estimates.get(replicate_id) >= MIN_DELTA_SIZE
+    }
+}
+
+/// Values that can be sent as the changes against a baseline the other side already has.
+pub trait Diffable: Sized {
+    type Delta;
+
+    /// Changes from `base` to `self`, `None` if sending `self` in full is cheaper.
+    fn diff(&self, base: &Self) -> Option<Self::Delta>;
+    fn apply(base: &Self, delta: &Self::Delta) -> Result<Self, SabiError>;
+}
+
+/// Serialized components, see `encode_delta`.
+impl Diffable for Vec<u8> {
+    type Delta = Vec<u8>;
+
+    fn diff(&self, base: &Self) -> Option<Self::Delta> {
+        encode_delta(base, self)
+    }
+
+    fn apply(base: &Self, delta: &Self::Delta) -> Result<Self, SabiError> {
+        apply_delta(base, delta)
+    }
+}
+
+/// Full value of a component along with its version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeltaBaseline {
+    pub version: ReplicateVersion,
+    pub data: Vec<u8>,
+}
+
+/// Values of an interest sent to a client, waiting for the client to ack them.
+#[derive(Default, Debug, Clone)]
+struct SentBaselines {
+    acked: Option<DeltaBaseline>,
+    pending: BTreeMap<NetworkTick, DeltaBaseline>,
+}
+
+impl SentBaselines {
+    /// Promote the newest acked value and drop anything that can't be acked anymore.
+    fn apply_ack(&mut self, ack: &NetworkAck) {
+        let newest_acked = self
+            .pending
+            .keys()
+            .rev()
+            .find(|tick| ack.is_acked(tick))
+            .cloned();
+
+        if let Some(tick) = newest_acked {
+            let baseline = self.pending.remove(&tick).expect("pending baseline");
+            self.pending.retain(|pending, _| *pending > tick);
+            if self
+                .acked
+                .as_ref()
+                .map_or(true, |acked| acked.version < baseline.version)
+            {
+                self.acked = Some(baseline);
+            }
+        }
+
+        // Too old to show up in an ack, these were lost.
+        let base = ack.base();
+        self.pending
+            .retain(|pending, _| base.signed_diff(*pending) <= ACK_BITS);
+    }
+}
+
+/// Server side baselines sent to each client and which of them the client acked.
+#[derive(Resource, Default, Debug, Clone)]
+pub struct ClientSentBaselines {
+    clients: BTreeMap<ClientId, HashMap<Interest, SentBaselines>>,
+    /// Values for this tick that haven't been handed to renet yet, see `confirm_sent`.
+    queued: BTreeMap<ClientId, HashMap<Interest, (NetworkTick, DeltaBaseline)>>,
+}
+
+impl ClientSentBaselines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a value sent on `tick`, it can be used as a baseline once that tick is acked.
+    pub fn sent(
+        &mut self,
+        client_id: ClientId,
+        interest: Interest,
+        tick: NetworkTick,
+        baseline: DeltaBaseline,
+    ) {
+        self.clients
+            .entry(client_id)
+            .or_default()
+            .entry(interest)
+            .or_default()
+            .pending
+            .insert(tick, baseline);
+    }
+
+    /// Hold on to a value we are about to send, it only counts as sent once
+    /// `confirm_sent` is called for it.
+    pub fn queue(
+        &mut self,
+        client_id: ClientId,
+        interest: Interest,
+        tick: NetworkTick,
+        baseline: DeltaBaseline,
+    ) {
+        self.queued
+            .entry(client_id)
+            .or_default()
+            .insert(interest, (tick, baseline));
+    }
+
+    /// Record the queued values of interests that went out, anything else queued for
+    /// the client never reached it and is discarded.
+    pub fn confirm_sent(&mut self, client_id: ClientId, sent: &[Interest]) {
+        let mut queued = match self.queued.remove(&client_id) {
+            Some(queued) => queued,
+            None => return,
+        };
+
+        for interest in sent {
+            if let Some((tick, baseline)) = queued.remove(interest) {
+                self.sent(client_id, *interest, tick, baseline);
+            }
+        }
+    }
+
+    /// Newest value of an interest we know the client received.
+    pub fn acked(
+        &mut self,
+        client_id: &ClientId,
+        interest: &Interest,
+        ack: &NetworkAck,
+    ) -> Option<&DeltaBaseline> {
+        let sent = self.clients.get_mut(client_id)?.get_mut(interest)?;
+        sent.apply_ack(ack);
+        sent.acked.as_ref()
+    }
+
+    /// The client lost track of this interest, the next one needs to be sent in full.
+    pub fn forget(&mut self, client_id: &ClientId, interest: &Interest) {
+        if let Some(baselines) = self.clients.get_mut(client_id) {
+            baselines.remove(interest);
+        }
+    }
+}
+
+impl ClientState for ClientSentBaselines {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+        self.queued.remove(client_id);
+    }
+}
+
+/// Client side history of full values received from the server, the server can diff
+/// against any of them that it knows we received.
+#[derive(Resource, Default, Debug, Clone)]
+pub struct ReceivedBaselines {
+    baselines: HashMap<Interest, BTreeMap<ReplicateVersion, Vec<u8>>>,
+}
+
+impl ReceivedBaselines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, interest: &Interest, version: &ReplicateVersion) -> Option<&Vec<u8>> {
+        self.baselines
+            .get(interest)
+            .and_then(|versions| versions.get(version))
+    }
+
+    /// Keep a received value, only the last `DELTA_HISTORY` versions are kept.
+    pub fn insert(&mut self, interest: Interest, baseline: DeltaBaseline) {
+        let versions = self.baselines.entry(interest).or_default();
+        versions.insert(baseline.version, baseline.data);
+        while versions.len() > DELTA_HISTORY as usize {
+            let oldest = *versions.keys().next().expect("versions is not empty");
+            versions.remove(&oldest);
+        }
+    }
+
+    /// Rebuild the full value of a delta against `from`, `None` if we don't have it.
+    pub fn rebuild(
+        &self,
+        interest: &Interest,
+        from: ReplicateVersion,
+        delta: &Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        let baseline = self.get(interest, &from)?;
+        match Diffable::apply(baseline, delta) {
+            Ok(data) => Some(data),
+            Err(err) => {
+                error!("could not rebuild {:?}: {}", interest, err);
+                None
+            }
+        }
+    }
+
+    /// Stop tracking an entity the server despawned.
+    pub fn forget_entity(&mut self, entity: Entity) {
+        self.baselines.retain(|interest, _| interest.0 != entity);
+    }
+
+    pub fn clear(&mut self) {
+        self.baselines.clear();
+    }
+}
+
+/// Encode the changes from `baseline` to `data`, `None` if the delta wouldn't be smaller.
+pub fn encode_delta(baseline: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() > u16::MAX as usize {
+        return None;
+    }
+
+    let mut delta = Vec::new();
+    delta.extend_from_slice(&(data.len() as u16).to_le_bytes());
+
+    let changed = |index: usize| baseline.get(index) != data.get(index);
+
+    let mut index = 0;
+    while index < data.len() {
+        let mut skip = 0;
+        while index < data.len() && skip < u8::MAX as usize && !changed(index) {
+            skip += 1;
+            index += 1;
+        }
+
+        let start = index;
+        while index < data.len() && index - start < u8::MAX as usize && changed(index) {
+            index += 1;
+        }
+
+        // Anything unchanged at the end is already in the baseline.
+        if index == start && index == data.len() {
+            break;
+        }
+
+        delta.push(skip as u8);
+        delta.push((index - start) as u8);
+        delta.extend_from_slice(&data[start..index]);
+
+        if delta.len() >= data.len() {
+            return None;
+        }
+    }
+
+    Some(delta)
+}
+
+/// Apply a delta from `encode_delta` to the baseline it was made against.
+pub fn apply_delta(baseline: &[u8], delta: &[u8]) -> Result<Vec<u8>, SabiError> {
+    let invalid = || SabiError::Deserialize("invalid component delta".to_owned());
+
+    let (len, mut delta) = match delta {
+        [low, high, rest @ ..] => (u16::from_le_bytes([*low, *high]) as usize, rest),
+        _ => return Err(invalid()),
+    };
+
+    let mut data = baseline.to_vec();
+    data.resize(len, 0);
+
+    let mut index = 0;
+    while let [skip, count, rest @ ..] = delta {
+        index += *skip as usize;
+        let count = *count as usize;
+        if rest.len() < count || index + count > len {
+            return Err(invalid());
+        }
+
+        data[index..index + count].copy_from_slice(&rest[..count]);
+        index += count;
+        delta = &rest[count..];
+    }
+
+    if !delta.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn delta_round_trip() {
+        let baseline = b"(translation:(x:1.0,y:2.0,z:3.0),rotation:(0.0,0.0,0.0,1.0))".to_vec();
+        let moved = b"(translation:(x:1.5,y:2.0,z:3.0),rotation:(0.0,0.0,0.0,1.0))".to_vec();
+
+        let delta = encode_delta(&baseline, &moved).expect("small change should be smaller");
+        assert!(delta.len() < moved.len());
+        assert_eq!(apply_delta(&baseline, &delta).unwrap(), moved);
+
+        // Lengths changing either way.
+        let mut longer = baseline.clone();
+        longer.extend_from_slice(b",scale:(1.0)");
+        let delta = encode_delta(&baseline, &longer).unwrap();
+        assert_eq!(apply_delta(&baseline, &delta).unwrap(), longer);
+        let delta = encode_delta(&longer, &baseline).unwrap();
+        assert_eq!(apply_delta(&longer, &delta).unwrap(), baseline);
+
+        // Completely different data isn't worth a delta.
+        assert_eq!(encode_delta(&[0; 32], &[1; 32]), None);
+        assert!(apply_delta(&baseline, &[0]).is_err());
+    }
+
+    #[test]
+    pub fn rebuild_needs_matching_version() {
+        let interest = (Entity::from_raw(0), ReplicateId(1));
+        let baseline = vec![0u8; 32];
+        let mut changed = baseline.clone();
+        changed[4] = 1;
+        let delta = encode_delta(&baseline, &changed).unwrap();
+
+        let mut received = ReceivedBaselines::new();
+        assert_eq!(
+            received.rebuild(&interest, ReplicateVersion(1), &delta),
+            None
+        );
+
+        received.insert(
+            interest,
+            DeltaBaseline {
+                version: ReplicateVersion(1),
+                data: baseline,
+            },
+        );
+        assert_eq!(
+            received.rebuild(&interest, ReplicateVersion(2), &delta),
+            None
+        );
+        assert_eq!(
+            received.rebuild(&interest, ReplicateVersion(1), &delta),
+            Some(changed)
+        );
+    }
+
+    #[test]
+    pub fn acked_baselines() {
+        let interest = (Entity::from_raw(0), ReplicateId(1));
+        let baseline = |version| DeltaBaseline {
+            version: ReplicateVersion(version),
+            data: vec![version as u8; 32],
+        };
+
+        let mut sent = ClientSentBaselines::new();
+        sent.sent(1, interest, NetworkTick::new(10), baseline(1));
+        sent.sent(1, interest, NetworkTick::new(11), baseline(2));
+
+        // Nothing acked yet, send in full.
+        let ack = NetworkAck::new(NetworkTick::new(12));
+        assert_eq!(sent.acked(&1, &interest, &ack), None);
+
+        let mut ack = NetworkAck::new(NetworkTick::new(12));
+        ack.ack(&NetworkTick::new(10));
+        assert_eq!(sent.acked(&1, &interest, &ack), Some(&baseline(1)));
+
+        // Tick 11 was lost, we keep building on the last acked version.
+        let ack = NetworkAck::new(NetworkTick::new(200));
+        assert_eq!(sent.acked(&1, &interest, &ack), Some(&baseline(1)));
+
+        sent.forget(&1, &interest);
+        assert_eq!(sent.acked(&1, &interest, &ack), None);
+        assert_eq!(sent.acked(&2, &interest, &ack), None);
+    }
+
+    #[test]
+    pub fn unsent_baselines_ignored() {
+        let sent_interest = (Entity::from_raw(0), ReplicateId(1));
+        let unsent_interest = (Entity::from_raw(1), ReplicateId(1));
+        let baseline = DeltaBaseline {
+            version: ReplicateVersion(1),
+            data: vec![1; 32],
+        };
+
+        let mut sent = ClientSentBaselines::new();
+        sent.queue(1, sent_interest, NetworkTick::new(10), baseline.clone());
+        sent.queue(1, unsent_interest, NetworkTick::new(10), baseline.clone());
+        sent.confirm_sent(1, &[sent_interest]);
+
+        // Other chunks of tick 10 were acked, but the unsent one never reached the client.
+        let mut ack = NetworkAck::new(NetworkTick::new(11));
+        ack.ack(&NetworkTick::new(10));
+        assert_eq!(sent.acked(&1, &sent_interest, &ack), Some(&baseline));
+        assert_eq!(sent.acked(&1, &unsent_interest, &ack), None);
+
+        // Nothing queued is kept around for the next tick.
+        sent.confirm_sent(1, &[unsent_interest]);
+        assert_eq!(sent.acked(&1, &unsent_interest, &ack), None);
+    }
+}
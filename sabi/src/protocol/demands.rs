@@ -3,16 +3,41 @@ use bevy::prelude::*;
 use std::marker::PhantomData;
 
 use crate::protocol::*;
+use crate::replicate::WorldReplicateExt;
 
 pub const DEFAULT_ESTIMATE: usize = 128;
 
+/// Weight of the newest size in the moving average of `SizeEstimate`.
+pub const ESTIMATE_ALPHA: f32 = 0.2;
+
+/// Serialized size of a component, averaged so variable sized components don't jump around.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SizeEstimate {
+    pub last: usize,
+    pub ema: f32,
+}
+
+impl SizeEstimate {
+    pub fn new(size: usize) -> Self {
+        Self {
+            last: size,
+            ema: size as f32,
+        }
+    }
+
+    pub fn add(&mut self, size: usize) {
+        self.last = size;
+        self.ema += ESTIMATE_ALPHA * (size as f32 - self.ema);
+    }
+}
+
 /// Try to guess what size the components that we are replicating will be.
 ///
 /// We might want to do this the other way around where we serialize each component before
 /// and then we combine each message so we know the definitive size before we queue
 /// them up.
 #[derive(Resource, Debug, Clone)]
-pub struct ReplicateSizeEstimates(HashMap<ReplicateId, usize>);
+pub struct ReplicateSizeEstimates(HashMap<ReplicateId, SizeEstimate>);
 
 impl ReplicateSizeEstimates {
     pub fn new() -> Self {
@@ -20,11 +45,22 @@ impl ReplicateSizeEstimates {
     }
 
     pub fn add(&mut self, id: ReplicateId, estimate: usize) {
-        self.0.insert(id, estimate);
+        self.0
+            .entry(id)
+            .and_modify(|existing| existing.add(estimate))
+            .or_insert_with(|| SizeEstimate::new(estimate));
     }
 
     pub fn get(&self, id: &ReplicateId) -> usize {
-        self.0.get(id).cloned().unwrap_or(DEFAULT_ESTIMATE)
+        self.0
+            .get(id)
+            .map(|estimate| estimate.ema as usize)
+            .unwrap_or(DEFAULT_ESTIMATE)
+    }
+
+    /// Most recent size we saw, unlike `get` this isn't averaged.
+    pub fn last(&self, id: &ReplicateId) -> Option<usize> {
+        self.0.get(id).map(|estimate| estimate.last)
     }
 }
 
@@ -60,6 +96,8 @@ where
 {
     fn build(&self, app: &mut App) {
         app.world.init_resource::<ReplicateDemands>();
+        let root = app.world.replicate_id::<ROOT>();
+        let dependency = app.world.replicate_id::<DEPENDENCY>();
 
         let mut demands = app
             .world
@@ -68,9 +106,9 @@ where
 
         demands
             .require
-            .entry(crate::replicate_id::<ROOT>())
+            .entry(root)
             .or_insert(Vec::new())
-            .push(crate::replicate_id::<DEPENDENCY>())
+            .push(dependency)
     }
 }
 
@@ -89,6 +127,45 @@ impl<A, B> Default for RequireTogether<A, B> {
     }
 }
 
+/// Don't send `REDUNDANT` for an entity if `PRIMARY` is being sent for it.
+#[derive(Debug, Clone, Copy)]
+pub struct DeduplicateWith<PRIMARY, REDUNDANT>(PhantomData<(PRIMARY, REDUNDANT)>);
+
+impl<PRIMARY, REDUNDANT> DeduplicateWith<PRIMARY, REDUNDANT> {
+    pub fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<PRIMARY, REDUNDANT> Default for DeduplicateWith<PRIMARY, REDUNDANT> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<PRIMARY, REDUNDANT> Plugin for DeduplicateWith<PRIMARY, REDUNDANT>
+where
+    PRIMARY: 'static + Reflect + FromReflect,
+    REDUNDANT: 'static + Reflect + FromReflect,
+{
+    fn build(&self, app: &mut App) {
+        app.world.init_resource::<ReplicateDemands>();
+        let primary = app.world.replicate_id::<PRIMARY>();
+        let redundant = app.world.replicate_id::<REDUNDANT>();
+
+        let mut demands = app
+            .world
+            .get_resource_mut::<ReplicateDemands>()
+            .expect("replicate demands");
+
+        demands
+            .dedup
+            .entry(primary)
+            .or_insert(Vec::new())
+            .push(redundant)
+    }
+}
+
 /// What components must be sent together and what can be left out if multiple are being sent.
 ///
 /// This is mainly for saving bandwidth on stuff like sending both `Transform` and `GlobalTransform`
@@ -96,5 +173,31 @@ impl<A, B> Default for RequireTogether<A, B> {
 #[derive(Resource, Debug, Default, Clone)]
 pub struct ReplicateDemands {
     pub require: HashMap<ReplicateId, Vec<ReplicateId>>,
+    /// Components that can be left out of an entity's update when the key is sent.
     pub dedup: HashMap<ReplicateId, Vec<ReplicateId>>,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn estimate_moving_average() {
+        let id = ReplicateId(1);
+        let mut estimates = ReplicateSizeEstimates::new();
+        assert_eq!(estimates.get(&id), DEFAULT_ESTIMATE);
+
+        estimates.add(id, 100);
+        assert_eq!(estimates.get(&id), 100);
+
+        // A single outlier only moves the estimate part of the way.
+        estimates.add(id, 600);
+        assert_eq!(estimates.get(&id), 200);
+        assert_eq!(estimates.last(&id), Some(600));
+
+        for _ in 0..50 {
+            estimates.add(id, 600);
+        }
+        assert!(estimates.get(&id) >= 599);
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::BTreeMap;
+
+use bevy::{ecs::entity::Entities, prelude::*};
+
+use crate::replicate::replicate_id;
+
+use super::{
+    ack::{ClientAcks, NetworkAck, ACK_BITS},
+    interest::{ClientInterestQueues, ClientKnownEntities, Interest},
+    version::{ClientSentVersions, ReplicateVersion},
+    ClientId, ClientState, NetworkTick, ReplicateId,
+};
+
+/// A despawn or removal along with the ticks of the updates it went out in.
+///
+/// Updates are sent unreliably so these are sent with every update until the client acks
+/// one of them, applying a despawn more than once on the client is harmless.
+#[derive(Debug, Clone)]
+pub struct PendingAck {
+    pub detected: NetworkTick,
+    pub sent: Vec<NetworkTick>,
+}
+
+impl PendingAck {
+    pub fn new(detected: NetworkTick) -> Self {
+        Self {
+            detected: detected,
+            sent: Vec::new(),
+        }
+    }
+
+    /// The client acked an update this was in, or it is too old for the acks to tell us.
+    pub fn is_done(&self, current_tick: NetworkTick, ack: Option<&NetworkAck>) -> bool {
+        if current_tick.signed_diff(self.detected) >= ACK_BITS {
+            return true;
+        }
+
+        ack.map(|ack| self.sent.iter().any(|tick| ack.is_acked(tick)))
+            .unwrap_or(false)
+    }
+}
+
+/// Despawns that still need to be sent to each client.
+#[derive(Resource, Default, Debug, Clone)]
+pub struct ClientEntityDespawns {
+    clients: BTreeMap<ClientId, BTreeMap<Entity, PendingAck>>,
+}
+
+impl ClientEntityDespawns {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, client_id: ClientId, entity: Entity, tick: NetworkTick) {
+        self.clients
+            .entry(client_id)
+            .or_default()
+            .entry(entity)
+            .or_insert_with(|| PendingAck::new(tick));
+    }
+
+    /// Entities this client should be told were despawned.
+    pub fn get(&self, client_id: &ClientId) -> Vec<Entity> {
+        self.clients
+            .get(client_id)
+            .map(|despawns| despawns.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Everything queued for this client went out in the update for `tick`.
+    pub fn sent(&mut self, client_id: &ClientId, tick: NetworkTick) {
+        if let Some(despawns) = self.clients.get_mut(client_id) {
+            for pending in despawns.values_mut() {
+                pending.sent.push(tick);
+            }
+        }
+    }
+
+    /// Stop sending despawns the client acked.
+    pub fn retain(&mut self, current_tick: NetworkTick, acks: &ClientAcks) {
+        for (client_id, despawns) in self.clients.iter_mut() {
+            let ack = acks.get(client_id);
+            despawns.retain(|_, pending| !pending.is_done(current_tick, ack));
+        }
+
+        self.clients.retain(|_, despawns| despawns.len() > 0);
+    }
+}
+
+impl ClientState for ClientEntityDespawns {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+    }
+}
+
+/// Diff the entities each client knows about against the ones still alive.
+///
+/// `Entity` includes the generation, so an entity that was despawned and had its slot
+/// reused in the same tick still shows up as a despawn of the old entity.
+pub fn detect_despawns(
+    tick: NetworkTick,
+    entities: &Entities,
+    known: &mut ClientKnownEntities,
+    despawns: &mut ClientEntityDespawns,
+) {
+    for (client_id, known_entities) in known.iter_mut() {
+        known_entities.retain(|entity| {
+            let alive = entities.contains(*entity);
+            if !alive {
+                despawns.push(*client_id, *entity, tick);
+            }
+            alive
+        });
+    }
+}
+
+/// Component removals that still need to be sent to each client.
+///
+/// The removal bumps the component's version so anything older for it that arrives later
+/// is dropped by the client instead of adding the component back.
+#[derive(Resource, Default, Debug, Clone)]
+pub struct ClientComponentRemovals {
+    clients: BTreeMap<ClientId, BTreeMap<Interest, (ReplicateVersion, PendingAck)>>,
+}
+
+impl ClientComponentRemovals {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(
+        &mut self,
+        client_id: ClientId,
+        interest: Interest,
+        version: ReplicateVersion,
+        tick: NetworkTick,
+    ) {
+        self.clients
+            .entry(client_id)
+            .or_default()
+            .insert(interest, (version, PendingAck::new(tick)));
+    }
+
+    /// Removals this client should be told about.
+    pub fn get(&self, client_id: &ClientId) -> Vec<(Entity, ReplicateId, ReplicateVersion)> {
+        self.clients
+            .get(client_id)
+            .map(|removals| {
+                removals
+                    .iter()
+                    .map(|((entity, replicate_id), (version, _))| {
+                        (*entity, *replicate_id, *version)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Everything queued for this client went out in the update for `tick`.
+    pub fn sent(&mut self, client_id: &ClientId, tick: NetworkTick) {
+        if let Some(removals) = self.clients.get_mut(client_id) {
+            for (_, pending) in removals.values_mut() {
+                pending.sent.push(tick);
+            }
+        }
+    }
+
+    /// Stop sending removals the client acked.
+    pub fn retain(&mut self, current_tick: NetworkTick, acks: &ClientAcks) {
+        for (client_id, removals) in self.clients.iter_mut() {
+            let ack = acks.get(client_id);
+            removals.retain(|_, (_, pending)| !pending.is_done(current_tick, ack));
+        }
+
+        self.clients.retain(|_, removals| removals.len() > 0);
+    }
+}
+
+impl ClientState for ClientComponentRemovals {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+    }
+}
+
+/// Queue removed components for clients that know about the entity.
+///
+/// Any update still queued for the component is dropped so it can't be sent after
+/// the removal. Entities that were despawned entirely are left to `detect_despawns`.
+pub fn queue_removals(
+    tick: NetworkTick,
+    entities: &Entities,
+    removed: &[Interest],
+    known: &ClientKnownEntities,
+    queues: &mut ClientInterestQueues,
+    versions: &mut ClientSentVersions,
+    removals: &mut ClientComponentRemovals,
+) {
+    for interest in removed {
+        if !entities.contains(interest.0) {
+            continue;
+        }
+
+        for (client_id, queue) in queues.iter_mut() {
+            if !known.knows(client_id, &interest.0) {
+                continue;
+            }
+
+            queue.remove(interest);
+            let version = versions.bump(*client_id, *interest);
+            removals.push(*client_id, *interest, version, tick);
+        }
+    }
+}
+
+/// Collect removed components.
+///
+/// This runs once a frame at the end of it rather than on the network tick, removals are
+/// cleared at the end of the frame so we would miss or duplicate them otherwise.
+///
+/// Components that were added back in the same frame aren't removals, the add shows up
+/// as a change instead.
+pub fn component_removals<C>(
+    tick: Res<NetworkTick>,
+    entities: &Entities,
+    removed: RemovedComponents<C>,
+    present: Query<(), With<C>>,
+    known: Res<ClientKnownEntities>,
+    acks: Res<ClientAcks>,
+    mut queues: ResMut<ClientInterestQueues>,
+    mut versions: ResMut<ClientSentVersions>,
+    mut removals: ResMut<ClientComponentRemovals>,
+) where
+    C: 'static + Component + Reflect + FromReflect + Clone,
+{
+    removals.retain(*tick, &*acks);
+
+    let replicate_id = replicate_id::<C>();
+    let removed = removed
+        .iter()
+        .filter(|entity| !present.contains(*entity))
+        .map(|entity| (entity, replicate_id))
+        .collect::<Vec<_>>();
+
+    queue_removals(
+        *tick,
+        entities,
+        &removed,
+        &*known,
+        &mut *queues,
+        &mut *versions,
+        &mut *removals,
+    );
+}
+
+pub fn server_detect_despawns(
+    tick: Res<NetworkTick>,
+    entities: &Entities,
+    acks: Res<ClientAcks>,
+    mut known: ResMut<ClientKnownEntities>,
+    mut despawns: ResMut<ClientEntityDespawns>,
+) {
+    despawns.retain(*tick, &*acks);
+    detect_despawns(*tick, entities, &mut *known, &mut *despawns);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn despawn_once() {
+        let mut world = World::new();
+        let mut known = ClientKnownEntities::new();
+        let mut despawns = ClientEntityDespawns::new();
+
+        let despawned = world.spawn_empty().id();
+        let alive = world.spawn_empty().id();
+        known.insert(1, despawned);
+        known.insert(1, alive);
+
+        // Slot gets reused by a new entity in the same tick.
+        world.despawn(despawned);
+        let reused = world.spawn_empty().id();
+        assert_eq!(reused.index(), despawned.index());
+        known.insert(1, reused);
+
+        detect_despawns(
+            NetworkTick::new(1),
+            world.entities(),
+            &mut known,
+            &mut despawns,
+        );
+        assert_eq!(despawns.get(&1), vec![despawned]);
+        assert!(known.knows(&1, &alive));
+        assert!(known.knows(&1, &reused));
+
+        // Already handled, shouldn't be detected again.
+        detect_despawns(
+            NetworkTick::new(2),
+            world.entities(),
+            &mut known,
+            &mut despawns,
+        );
+        assert_eq!(despawns.get(&1), vec![despawned]);
+
+        // Keeps being sent until the client acks an update it was in.
+        despawns.sent(&1, NetworkTick::new(2));
+        let mut acks = ClientAcks::new();
+        despawns.retain(NetworkTick::new(3), &acks);
+        assert_eq!(despawns.get(&1), vec![despawned]);
+
+        let mut ack = NetworkAck::new(NetworkTick::new(0));
+        ack.receive(&NetworkTick::new(2));
+        acks.apply_ack(1, &ack);
+        despawns.retain(NetworkTick::new(3), &acks);
+        assert!(despawns.get(&1).is_empty());
+    }
+
+    #[test]
+    pub fn removal_drops_queued_update() {
+        let mut world = World::new();
+        let mut known = ClientKnownEntities::new();
+        let mut queues = ClientInterestQueues::new();
+        let mut versions = ClientSentVersions::new();
+        let mut removals = ClientComponentRemovals::new();
+
+        let entity = world.spawn_empty().id();
+        let interest = (entity, ReplicateId(1));
+        known.insert(1, entity);
+        queues.entry(1).push_back(interest);
+        versions.bump(1, interest);
+
+        // Client 2 never heard of this entity.
+        queues.entry(2);
+
+        queue_removals(
+            NetworkTick::new(1),
+            world.entities(),
+            &[interest],
+            &known,
+            &mut queues,
+            &mut versions,
+            &mut removals,
+        );
+
+        assert_eq!(queues.get(&1).unwrap().iter().count(), 0);
+        assert_eq!(
+            removals.get(&1),
+            vec![(entity, ReplicateId(1), ReplicateVersion(2))]
+        );
+        assert!(removals.get(&2).is_empty());
+
+        // Never acked, given up on once the acks can't tell us anymore.
+        removals.sent(&1, NetworkTick::new(1));
+        let acks = ClientAcks::new();
+        removals.retain(NetworkTick::new(ACK_BITS as u64), &acks);
+        assert_eq!(removals.get(&1).len(), 1);
+        removals.retain(NetworkTick::new(1 + ACK_BITS as u64), &acks);
+        assert!(removals.get(&1).is_empty());
+    }
+
+    #[test]
+    pub fn readded_component_is_not_removed() {
+        let mut world = World::new();
+        world.insert_resource(NetworkTick::new(1));
+        world.insert_resource(ClientAcks::new());
+        world.insert_resource(ClientInterestQueues::new());
+        world.insert_resource(ClientSentVersions::new());
+        world.insert_resource(ClientComponentRemovals::new());
+
+        let removed = world.spawn(Transform::default()).id();
+        let readded = world.spawn(Transform::default()).id();
+        let mut known = ClientKnownEntities::new();
+        known.insert(1, removed);
+        known.insert(1, readded);
+        world.insert_resource(known);
+        world.resource_mut::<ClientInterestQueues>().entry(1);
+
+        world.entity_mut(removed).remove::<Transform>();
+        world.entity_mut(readded).remove::<Transform>();
+        world
+            .entity_mut(readded)
+            .insert(Transform::from_xyz(1.0, 0.0, 0.0));
+
+        let mut stage = SystemStage::single_threaded().with_system(component_removals::<Transform>);
+        stage.run(&mut world);
+
+        let replicate_id = replicate_id::<Transform>();
+        let removals = world.resource::<ClientComponentRemovals>().get(&1);
+        assert_eq!(removals.len(), 1);
+        assert_eq!((removals[0].0, removals[0].1), (removed, replicate_id));
+    }
+}
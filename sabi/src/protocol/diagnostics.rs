@@ -0,0 +1,81 @@
+//! Replication numbers exported to Bevy's `Diagnostics`.
+
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticId, Diagnostics},
+    prelude::*,
+};
+use iyes_loopless::prelude::IntoConditionalSystem;
+
+use super::{demands::ReplicateSizeEstimates, interest::InterestsToSend};
+use crate::stage::NetworkSimulationAppExt;
+
+/// Estimated bytes of components queued up for all clients on the last tick.
+pub const INTEREST_SIZE_ESTIMATE: DiagnosticId =
+    DiagnosticId::from_u128(0x7c1f_5a0e_31d4_4b8e_9a62_0d5c_e3f1_a4b7);
+
+/// Adds the replication diagnostics, only the server has anything to measure.
+#[derive(Default, Debug, Clone)]
+pub struct DiagnosticsPlugin;
+
+impl Plugin for DiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Diagnostics>();
+        app.add_startup_system(setup_diagnostics);
+        app.add_meta_network_system(
+            interest_diagnostics
+                .run_if_resource_exists::<InterestsToSend>()
+                .after("queue_interests"),
+        );
+    }
+}
+
+pub fn setup_diagnostics(mut diagnostics: ResMut<Diagnostics>) {
+    diagnostics.add(Diagnostic::new(
+        INTEREST_SIZE_ESTIMATE,
+        "interest_size_estimate",
+        20,
+    ));
+}
+
+pub fn interest_diagnostics(
+    mut diagnostics: ResMut<Diagnostics>,
+    to_send: Res<InterestsToSend>,
+    estimates: Res<ReplicateSizeEstimates>,
+) {
+    diagnostics.add_measurement(
+        INTEREST_SIZE_ESTIMATE,
+        to_send.total_size_estimate(&*estimates) as f64,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn interest_size_estimate() {
+        let mut world = World::new();
+        world.init_resource::<Diagnostics>();
+
+        let mut estimates = ReplicateSizeEstimates::new();
+        estimates.add(crate::replicate::ReplicateId(1), 40);
+        estimates.add(crate::replicate::ReplicateId(2), 100);
+        world.insert_resource(estimates);
+
+        let mut to_send = InterestsToSend::new();
+        let entity = Entity::from_raw(0);
+        to_send.push(1, (entity, crate::replicate::ReplicateId(1)));
+        to_send.push(1, (entity, crate::replicate::ReplicateId(2)));
+        to_send.push(2, (entity, crate::replicate::ReplicateId(1)));
+        world.insert_resource(to_send);
+
+        let mut stage = SystemStage::single_threaded()
+            .with_system(setup_diagnostics.label("setup"))
+            .with_system(interest_diagnostics.after("setup"));
+        stage.run(&mut world);
+
+        let diagnostics = world.resource::<Diagnostics>();
+        let estimate = diagnostics.get(INTEREST_SIZE_ESTIMATE).unwrap();
+        assert_eq!(estimate.value(), Some(180.0));
+    }
+}
@@ -1,6 +1,9 @@
 use std::collections::VecDeque;
 use std::fmt::Debug;
-use std::{collections::BTreeMap, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
 
 use bevy::{
     prelude::*,
@@ -12,21 +15,150 @@ use bevy_renet::renet::{RenetClient, RenetServer};
 
 use serde::{Deserialize, Serialize};
 
-use crate::prelude::*;
+use crate::{prelude::*, stage::Resimulating};
 
 use super::{
-    ack::{ClientAcks, NetworkAck},
-    ClientId, NetworkTick,
+    ack::{ClientAcks, NetworkAck, ReceivedTicks, ACK_BITS},
+    codec::{CompressionConfig, MalformedMessage, MessageCodec, NetworkCodec, PassthroughCodec},
+    delta::ClientSentBaselines,
+    interest::{ClientInterestQueues, Interest},
+    version::ReceivedVersions,
+    ClientId, ClientState, NetworkTick,
 };
 
-/// How many inputs we should retain for replaying inputs.
-pub const INPUT_RETAIN_BUFFER: i64 = 32;
-/// How many inputs we should send to the server for future ticks.
-pub const INPUT_SEND_BUFFER: i64 = 12;
+/// Sizes of the input buffers on the server and client.
+#[derive(Resource, Debug, Clone)]
+pub struct InputConfig {
+    /// How many ticks of inputs we retain for replaying inputs.
+    pub retain: i64,
+    /// Fewest ticks of inputs sent with each input message.
+    pub send: i64,
+    /// Most ticks of inputs sent with each input message, the client sends more while
+    /// inputs are being lost.
+    pub max_send: i64,
+    /// How many ticks of inputs `InputLossStats` is measured over.
+    pub loss_window: usize,
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        Self {
+            retain: 32,
+            send: 12,
+            max_send: 32,
+            loss_window: 128,
+        }
+    }
+}
+
+/// What the server applies for a client when their input for a tick hasn't arrived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFallback {
+    /// Repeat the newest input we have from them, up to `InputFallbackConfig::max_repeats`
+    /// ticks in a row before falling back to `I::default()`.
+    HoldLast,
+    /// Apply `I::default()`.
+    Default,
+    /// Leave the player's input component as it was.
+    None,
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct InputFallbackConfig {
+    pub fallback: InputFallback,
+    pub max_repeats: u32,
+}
+
+impl Default for InputFallbackConfig {
+    fn default() -> Self {
+        Self {
+            fallback: InputFallback::Default,
+            max_repeats: 4,
+        }
+    }
+}
+
+impl InputFallbackConfig {
+    pub fn hold_last(max_repeats: u32) -> Self {
+        Self {
+            fallback: InputFallback::HoldLast,
+            max_repeats,
+        }
+    }
+}
+
+/// Last input the server applied for a client.
+#[derive(Debug, Clone)]
+pub struct AppliedInput<I> {
+    pub tick: NetworkTick,
+    pub input: I,
+    /// How many ticks in a row this was a guess rather than the client's real input.
+    pub repeats: u32,
+}
+
+/// Limits on input messages received from clients so a bad client can't make us
+/// allocate huge buffers.
+#[derive(Resource, Debug, Clone)]
+pub struct MaxInputSize {
+    /// Maximum size of the compressed message as it came off the wire.
+    pub compressed: usize,
+    /// Maximum size the message can decompress to.
+    pub decompressed: usize,
+}
+
+impl Default for MaxInputSize {
+    fn default() -> Self {
+        Self {
+            compressed: 1200,
+            decompressed: 10 * 1024,
+        }
+    }
+}
+
+/// Count of input messages that were dropped for each client because they were malformed
+/// or too large.
+#[derive(Resource, Default, Debug, Clone)]
+pub struct DroppedInputs {
+    clients: BTreeMap<ClientId, u64>,
+}
+
+impl DroppedInputs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, client_id: ClientId) {
+        *self.clients.entry(client_id).or_default() += 1;
+    }
+
+    pub fn get(&self, client_id: &ClientId) -> u64 {
+        self.clients.get(client_id).cloned().unwrap_or(0)
+    }
+}
+
+impl ClientState for DroppedInputs {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+    }
+}
+
+/// How much each new sample moves the moving averages in `ReceivedHistory`.
+pub const DEVIATION_ALPHA: f32 = 0.1;
+
+/// Samples before the moving averages in `ReceivedHistory` settle down.
+pub const DEVIATION_WARMUP: u32 = 10;
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct InputDeviation {
     pub deviation: f32,
+    /// How many samples the deviation is from, it is unreliable below `DEVIATION_WARMUP`.
+    pub sample_count: u32,
+}
+
+impl InputDeviation {
+    pub fn is_warm(&self) -> bool {
+        self.sample_count >= DEVIATION_WARMUP
+    }
 }
 
 #[derive(Resource, Default, Debug, Clone)]
@@ -46,12 +178,32 @@ impl ClientReceivedHistory {
     pub fn deviation(&mut self, client_id: ClientId) -> InputDeviation {
         self.clients.entry(client_id).or_default().deviation()
     }
+
+    pub fn set_ping(&mut self, client_id: ClientId, ping_ms: u32) {
+        self.clients.entry(client_id).or_default().ping_ms = ping_ms;
+    }
+
+    /// Round trip time to the client as of their last input.
+    pub fn ping_ms(&self, client_id: &ClientId) -> Option<u32> {
+        self.clients.get(client_id).map(|history| history.ping_ms)
+    }
+}
+
+impl ClientState for ClientReceivedHistory {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+    }
 }
 
+/// Time between inputs arriving from a client, as exponential moving averages so recent
+/// jitter shows up quickly.
 #[derive(Default, Debug, Clone)]
 pub struct ReceivedHistory {
     previous: Option<Duration>,
-    times: VecDeque<f32>,
+    ema_mean: f32,
+    ema_variance: f32,
+    sample_count: u32,
+    ping_ms: u32,
 }
 
 impl ReceivedHistory {
@@ -61,40 +213,33 @@ impl ReceivedHistory {
 
     pub fn push(&mut self, sample: Duration) {
         if let Some(previous) = self.previous {
-            let new_sample = sample.saturating_sub(previous);
-            self.times.push_back(new_sample.as_secs_f32());
+            let new_sample = sample.saturating_sub(previous).as_secs_f32();
 
-            if self.times.len() > 128 {
-                self.times.pop_front();
+            if self.sample_count == 0 {
+                self.ema_mean = new_sample;
+                self.ema_variance = 0.0;
+            } else {
+                let diff = new_sample - self.ema_mean;
+                let increment = DEVIATION_ALPHA * diff;
+                self.ema_mean += increment;
+                self.ema_variance =
+                    (1.0 - DEVIATION_ALPHA) * (self.ema_variance + diff * increment);
             }
+
+            self.sample_count = self.sample_count.saturating_add(1);
         }
 
         self.previous = Some(sample);
     }
 
-    pub fn deviation(&self) -> InputDeviation {
-        if self.times.len() == 0 {
-            return InputDeviation::default();
-        }
-
-        let samples = self.times.len() as f32;
-        let sum: f32 = self.times.iter().sum();
-        let mean = sum / samples;
-
-        let deviations_sum: f32 = self
-            .times
-            .iter()
-            .map(|sample| {
-                let diff = sample - mean;
-                diff * diff
-            })
-            .sum();
-        let variance = deviations_sum / samples;
-        let standard_deviation = variance.sqrt();
+    pub fn mean(&self) -> f32 {
+        self.ema_mean
+    }
 
+    pub fn deviation(&self) -> InputDeviation {
         InputDeviation {
-            //mean: mean,
-            deviation: standard_deviation,
+            deviation: self.ema_variance.sqrt(),
+            sample_count: self.sample_count,
         }
     }
 }
@@ -104,17 +249,27 @@ pub struct ClientInputMessage<I> {
     pub tick: NetworkTick,
     pub ack: NetworkAck,
     pub inputs: QueuedInputs<I>,
+    /// Components we missed versions of and need the full state for.
+    pub resend: Vec<Interest>,
 }
 
 #[derive(Resource, Debug, Clone)]
 pub struct ClientQueuedInputs<I> {
     clients: HashMap<ClientId, QueuedInputs<I>>,
+    applied: HashMap<ClientId, AppliedInput<I>>,
+    /// Ticks we guessed a client's input for and haven't gotten the real input of yet.
+    predicted: HashMap<ClientId, BTreeSet<NetworkTick>>,
+    /// How many guessed inputs had the real input show up afterwards.
+    late: HashMap<ClientId, u64>,
 }
 
 impl<I> ClientQueuedInputs<I> {
     pub fn new() -> Self {
         Self {
             clients: HashMap::new(),
+            applied: HashMap::new(),
+            predicted: HashMap::new(),
+            late: HashMap::new(),
         }
     }
 
@@ -122,7 +277,90 @@ impl<I> ClientQueuedInputs<I> {
         self.clients.get(&client).and_then(|queue| queue.get(tick))
     }
 
+    pub fn applied(&self, client: ClientId) -> Option<&AppliedInput<I>> {
+        self.applied.get(&client)
+    }
+
+    /// Whether the input applied for this tick was a guess we haven't gotten the real
+    /// input for.
+    pub fn is_predicted(&self, client: ClientId, tick: &NetworkTick) -> bool {
+        self.predicted
+            .get(&client)
+            .map_or(false, |predicted| predicted.contains(tick))
+    }
+
+    /// How many of a client's inputs arrived after we had to guess them.
+    pub fn late_inputs(&self, client: ClientId) -> u64 {
+        self.late.get(&client).cloned().unwrap_or(0)
+    }
+
+    /// Input to apply for a client on this tick, `None` if nothing should be applied.
+    pub fn next_input(
+        &mut self,
+        client: ClientId,
+        tick: NetworkTick,
+        config: &InputFallbackConfig,
+    ) -> Option<I>
+    where
+        I: Clone + Default,
+    {
+        if let Some(input) = self.get(client, &tick).cloned() {
+            self.applied.insert(
+                client,
+                AppliedInput {
+                    tick,
+                    input: input.clone(),
+                    repeats: 0,
+                },
+            );
+            return Some(input);
+        }
+
+        let repeats = self
+            .applied
+            .get(&client)
+            .map_or(0, |applied| applied.repeats)
+            + 1;
+        let input = match config.fallback {
+            InputFallback::None => return None,
+            InputFallback::Default => I::default(),
+            InputFallback::HoldLast if repeats > config.max_repeats => I::default(),
+            InputFallback::HoldLast => {
+                // Anything that showed up late is newer than what we last guessed.
+                let latest = self
+                    .clients
+                    .get(&client)
+                    .and_then(|queue| queue.latest_before(tick))
+                    .map(|(_, input)| input.clone());
+                let applied = self
+                    .applied
+                    .get(&client)
+                    .map(|applied| applied.input.clone());
+                latest.or(applied).unwrap_or_default()
+            }
+        };
+
+        self.predicted.entry(client).or_default().insert(tick);
+        self.applied.insert(
+            client,
+            AppliedInput {
+                tick,
+                input: input.clone(),
+                repeats,
+            },
+        );
+        Some(input)
+    }
+
     pub fn upsert(&mut self, client: ClientId, input: QueuedInputs<I>) {
+        if let Some(predicted) = self.predicted.get_mut(&client) {
+            for tick in input.ticks() {
+                if predicted.remove(tick) {
+                    *self.late.entry(client).or_default() += 1;
+                }
+            }
+        }
+
         match self.clients.entry(client) {
             Entry::Occupied(mut entry) => {
                 entry.get_mut().apply_buffer(input);
@@ -143,6 +381,25 @@ impl<I> ClientQueuedInputs<I> {
         for (_, queue) in &mut self.clients {
             queue.retain(buffer);
         }
+
+        // Real inputs this old won't be sent anymore.
+        for (client, predicted) in &mut self.predicted {
+            let newest = match self.applied.get(client) {
+                Some(applied) => applied.tick,
+                None => continue,
+            };
+
+            predicted.retain(|tick| newest.signed_diff(*tick) < buffer);
+        }
+    }
+}
+
+impl<I> ClientState for ClientQueuedInputs<I> {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+        self.applied.remove(client_id);
+        self.predicted.remove(client_id);
+        self.late.remove(client_id);
     }
 }
 
@@ -151,6 +408,12 @@ pub struct QueuedInputs<I> {
     queue: BTreeMap<NetworkTick, I>,
 }
 
+impl<I> Default for QueuedInputs<I> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<I> QueuedInputs<I> {
     pub fn new() -> Self {
         Self {
@@ -162,6 +425,11 @@ impl<I> QueuedInputs<I> {
         self.queue.get(tick)
     }
 
+    /// Newest input from before `tick`.
+    pub fn latest_before(&self, tick: NetworkTick) -> Option<(&NetworkTick, &I)> {
+        self.queue.range(..tick).next_back()
+    }
+
     pub fn apply_buffer(&mut self, other: Self) {
         for (tick, input) in other.queue {
             self.upsert(tick, input);
@@ -191,7 +459,15 @@ impl<I> QueuedInputs<I> {
     /// Push an input into the queue
     pub fn push(&mut self, tick: NetworkTick, input: I) {
         self.queue.insert(tick, input);
-        self.retain(INPUT_RETAIN_BUFFER);
+    }
+
+    pub fn ticks(&self) -> impl Iterator<Item = &NetworkTick> {
+        self.queue.keys()
+    }
+
+    /// Remove any inputs the other side acked.
+    pub fn drop_acked(&mut self, ack: &NetworkAck) {
+        self.queue.retain(|tick, _| !ack.is_acked(tick));
     }
 
     /// Retain any in the queue that are within a buffer range.
@@ -199,8 +475,121 @@ impl<I> QueuedInputs<I> {
         let newest = self.queue.keys().max().cloned().unwrap_or_default();
 
         self.queue
-            .retain(|tick, _| (newest.tick() as i64) - (tick.tick() as i64) < buffer);
+            .retain(|tick, _| newest.signed_diff(*tick) < buffer);
+    }
+}
+
+/// Server side acks of the input ticks each client sent us, sent back with updates.
+#[derive(Resource, Default, Debug, Clone)]
+pub struct ClientInputAcks {
+    clients: BTreeMap<ClientId, NetworkAck>,
+}
+
+impl ClientInputAcks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn receive(&mut self, client_id: ClientId, tick: &NetworkTick) {
+        self.clients.entry(client_id).or_default().receive(tick);
+    }
+
+    pub fn get(&self, client_id: &ClientId) -> Option<&NetworkAck> {
+        self.clients.get(client_id)
+    }
+}
+
+impl ClientState for ClientInputAcks {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+    }
+}
+
+/// Client side acks from the server of the inputs it received.
+#[derive(Resource, Default, Debug, Clone, Deref, DerefMut)]
+pub struct AckedInputs(pub NetworkAck);
+
+/// How many of our inputs never made it to the server.
+///
+/// Inputs are sent a few times over, so an input only counts as lost once the server
+/// acked newer inputs without it.
+#[derive(Resource, Debug, Clone)]
+pub struct InputLossStats {
+    samples: VecDeque<bool>,
+    window: usize,
+    /// Next input tick to check the server's acks for.
+    next: Option<NetworkTick>,
+    since_loss: usize,
+    /// How many ticks of inputs are currently sent with each message.
+    pub send_buffer: i64,
+}
+
+impl Default for InputLossStats {
+    fn default() -> Self {
+        Self::new(&InputConfig::default())
+    }
+}
+
+impl InputLossStats {
+    pub fn new(config: &InputConfig) -> Self {
+        Self {
+            samples: VecDeque::new(),
+            window: config.loss_window.max(1),
+            next: None,
+            since_loss: 0,
+            send_buffer: config.send,
+        }
+    }
+
+    /// Percentage of inputs lost recently, from 0 to 100.
+    pub fn loss_percent(&self) -> f32 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+
+        let lost = self.samples.iter().filter(|lost| **lost).count();
+        lost as f32 / self.samples.len() as f32 * 100.0
+    }
+
+    /// Record whether an input was lost, sending more inputs per message while we are
+    /// losing them and backing off once things are stable again.
+    pub fn record(&mut self, lost: bool, config: &InputConfig) {
+        self.samples.push_back(lost);
+        while self.samples.len() > self.window {
+            self.samples.pop_front();
+        }
+
+        if lost {
+            self.since_loss = 0;
+            self.send_buffer = (self.send_buffer * 2).min(config.max_send);
+        } else {
+            self.since_loss += 1;
+            if self.since_loss >= self.window {
+                self.since_loss = 0;
+                self.send_buffer = (self.send_buffer - 1).max(config.send);
+            }
+        }
+    }
+}
+
+/// Decompress and deserialize an input message from a client within the size limits.
+pub fn decode_input_message<I>(
+    message: &[u8],
+    max: &MaxInputSize,
+    codec: &mut dyn MessageCodec,
+) -> Result<ClientInputMessage<I>, SabiError>
+where
+    I: for<'de> Deserialize<'de>,
+{
+    if message.len() > max.compressed {
+        return Err(SabiError::MessageTooLarge {
+            size: message.len(),
+            max: max.compressed,
+        });
     }
+
+    let decompressed = codec.decompress(message, max.decompressed)?;
+    bincode::deserialize(&decompressed).map_err(|err| SabiError::Deserialize(err.to_string()))
 }
 
 pub fn server_recv_input<I>(
@@ -208,8 +597,17 @@ pub fn server_recv_input<I>(
     mut recv_history: ResMut<ClientReceivedHistory>,
     tick: Res<NetworkTick>,
     mut server: ResMut<RenetServer>,
+    config: Res<InputConfig>,
     mut queued_inputs: ResMut<ClientQueuedInputs<I>>,
+    mut input_acks: ResMut<ClientInputAcks>,
     mut acks: ResMut<ClientAcks>,
+    mut queues: ResMut<ClientInterestQueues>,
+    mut sent_baselines: ResMut<ClientSentBaselines>,
+    max_size: Res<MaxInputSize>,
+    mut dropped: ResMut<DroppedInputs>,
+    mut malformed: EventWriter<MalformedMessage>,
+    mut codec: ResMut<NetworkCodec>,
+    compression: Res<CompressionConfig>,
 ) where
     I: 'static
         + Send
@@ -221,16 +619,48 @@ pub fn server_recv_input<I>(
         + for<'de> Deserialize<'de>
         + Debug,
 {
-    queued_inputs.retain(32);
+    queued_inputs.retain(config.retain);
+
+    let mut passthrough = PassthroughCodec;
+    let codec: &mut dyn MessageCodec = if compression.inputs {
+        &mut **codec
+    } else {
+        &mut passthrough
+    };
 
     for client_id in server.clients_id().into_iter() {
         while let Some(message) = server.receive_message(client_id, ClientChannel::Input.id()) {
-            let decompressed = zstd::bulk::decompress(&message.as_slice(), 10 * 1024).unwrap();
-            let input_message: ClientInputMessage<I> = bincode::deserialize(&decompressed).unwrap();
+            let input_message: ClientInputMessage<I> =
+                match decode_input_message(message.as_slice(), &*max_size, codec) {
+                    Ok(input_message) => input_message,
+                    Err(err) => {
+                        warn!("dropping input from client {}: {}", client_id, err);
+                        dropped.record(client_id);
+                        malformed.send(MalformedMessage {
+                            client_id: Some(client_id),
+                            channel: ClientChannel::Input.id(),
+                            error: err,
+                        });
+                        continue;
+                    }
+                };
 
             recv_history.push(client_id, time.elapsed());
+            if let Some(info) = server.network_info(client_id) {
+                recv_history.set_ping(client_id, info.rtt.round() as u32);
+            }
             acks.apply_ack(client_id, &input_message.ack);
+            for input_tick in input_message.inputs.ticks() {
+                input_acks.receive(client_id, input_tick);
+            }
             queued_inputs.upsert(client_id, input_message.inputs);
+
+            let queue = queues.entry(client_id);
+            for interest in input_message.resend {
+                // The client couldn't build on what we sent, start over from a full value.
+                sent_baselines.forget(&client_id, &interest);
+                queue.push_front(interest);
+            }
         }
     }
 }
@@ -239,21 +669,19 @@ pub fn server_apply_input<I>(
     mut commands: Commands,
     entities: &Entities,
     tick: Res<NetworkTick>,
-    queued_inputs: Res<ClientQueuedInputs<I>>,
+    fallback: Res<InputFallbackConfig>,
+    mut queued_inputs: ResMut<ClientQueuedInputs<I>>,
     lobby: Res<Lobby>,
 ) where
     I: 'static + Send + Sync + Component + Clone + Default + Serialize + for<'de> Deserialize<'de>,
 {
-    for (client, entity) in lobby.players.iter() {
-        let apply_input = if let Some(input) = queued_inputs.get(*client, &tick) {
-            input.clone()
-        } else {
-            //error!("no input for player {} on tick {}", client, tick.tick());
-            I::default()
-        };
+    for (client, entity) in lobby.iter() {
+        if !entities.contains(*entity) {
+            continue;
+        }
 
-        if entities.contains(*entity) {
-            commands.entity(*entity).insert(apply_input);
+        if let Some(input) = queued_inputs.next_input(*client, *tick, &*fallback) {
+            commands.entity(*entity).insert(input);
         }
     }
 }
@@ -261,6 +689,12 @@ pub fn server_apply_input<I>(
 pub fn client_send_input<I>(
     tick: Res<NetworkTick>,
     input_buffer: Res<QueuedInputs<I>>,
+    received: Res<ReceivedTicks>,
+    acked: Res<AckedInputs>,
+    stats: Res<InputLossStats>,
+    mut versions: ResMut<ReceivedVersions>,
+    mut codec: ResMut<NetworkCodec>,
+    compression: Res<CompressionConfig>,
     mut client: ResMut<RenetClient>,
 ) where
     I: 'static
@@ -277,24 +711,34 @@ pub fn client_send_input<I>(
         return;
     }
 
+    // Acked inputs are only dropped from what we send, they are still needed to resimulate.
     let mut send_buffer = input_buffer.clone();
-    send_buffer.retain(INPUT_SEND_BUFFER);
+    send_buffer.retain(stats.send_buffer);
+    send_buffer.drop_acked(&acked);
 
     let message = ClientInputMessage {
         tick: tick.clone(),
-        ack: NetworkAck::new(tick.clone()),
+        ack: received.0.clone(),
         inputs: send_buffer,
+        resend: versions.drain_resend_requests(),
     };
 
     let serialized = bincode::serialize(&message).unwrap();
     //crate::message_sample::try_add_sample("input", &serialized);
-    let compressed = zstd::bulk::compress(&serialized.as_slice(), 0).unwrap();
+    let compressed = if compression.inputs {
+        codec
+            .compress(&serialized.as_slice())
+            .expect("couldn't compress input")
+    } else {
+        serialized
+    };
 
     client.send_message(ClientChannel::Input.id(), compressed);
 }
 
 pub fn client_update_input_buffer<I>(
     tick: Res<NetworkTick>,
+    config: Res<InputConfig>,
     player_input: Res<I>,
     mut input_buffer: ResMut<QueuedInputs<I>>,
 ) where
@@ -312,10 +756,46 @@ pub fn client_update_input_buffer<I>(
 {
     //info!("recording {}: {:?}", tick.tick(), player_input.clone());
     input_buffer.push(*tick, player_input.clone());
+    input_buffer.retain(config.retain);
 }
 
+/// Check the server's acks for inputs we sent that it never received.
+pub fn client_track_input_loss<I>(
+    config: Res<InputConfig>,
+    acked: Res<AckedInputs>,
+    input_buffer: Res<QueuedInputs<I>>,
+    mut stats: ResMut<InputLossStats>,
+) where
+    I: 'static + Send + Sync,
+{
+    let base = acked.base();
+    // Anything older than what the ack covers was already checked or is long gone.
+    let oldest = base.saturating_sub(ACK_BITS as u64);
+    let start = stats.next.map_or(oldest, |next| next.max(oldest));
+
+    for tick in NetworkTick::range(start, base) {
+        // Not an input we ever had to send.
+        if input_buffer.get(&tick).is_none() {
+            continue;
+        }
+
+        stats.record(!acked.is_acked(&tick), &config);
+    }
+
+    if stats.next.map_or(true, |next| next < base) {
+        stats.next = Some(base);
+    }
+}
+
+/// Replay our buffered inputs while resimulating.
+///
+/// The input the game sampled is put back once we reach the last tick resimulated this
+/// frame, it is newer than what is in the buffer. A resimulation carried over to the next
+/// frame saves the input sampled on that frame instead.
 pub fn client_apply_input_buffer<I>(
     tick: Res<NetworkTick>,
+    resimulating: Option<Res<Resimulating>>,
+    mut live: Local<Option<I>>,
     mut player_input: ResMut<I>,
     input_buffer: Res<QueuedInputs<I>>,
 ) where
@@ -331,11 +811,377 @@ pub fn client_apply_input_buffer<I>(
         + Debug
         + Resource,
 {
+    if let Some(Resimulating { until, .. }) = resimulating.as_deref() {
+        if *tick >= *until {
+            if let Some(live) = live.take() {
+                *player_input = live;
+            }
+            return;
+        }
+
+        if live.is_none() {
+            *live = Some(player_input.clone());
+        }
+    }
+
     if let Some(input) = input_buffer.get(&*tick) {
         //info!("{}: {:?}", tick.tick(), input);
         *player_input = input.clone();
+    } else if let Some((_, input)) = input_buffer.latest_before(*tick) {
+        // Holding the last input is less jarring than dropping it for a tick.
+        *player_input = input.clone();
     } else {
-        //error!("no input: {}", tick.tick());
+        warn!("no input for tick {}, using the default", tick.tick());
         *player_input = I::default();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::codec::ZstdCodec;
+
+    #[derive(Resource, Component, Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+    struct TestInput {
+        buffer: Vec<u8>,
+    }
+
+    fn encode(message: &ClientInputMessage<TestInput>) -> Vec<u8> {
+        let serialized = bincode::serialize(message).unwrap();
+        ZstdCodec::default()
+            .compress(&serialized.as_slice())
+            .unwrap()
+    }
+
+    fn message(size: usize) -> ClientInputMessage<TestInput> {
+        let mut inputs = QueuedInputs::new();
+        inputs.push(
+            NetworkTick::new(1),
+            TestInput {
+                buffer: vec![0; size],
+            },
+        );
+
+        ClientInputMessage {
+            tick: NetworkTick::new(1),
+            ack: NetworkAck::new(NetworkTick::new(1)),
+            inputs: inputs,
+            resend: Vec::new(),
+        }
+    }
+
+    #[test]
+    pub fn decode_input() {
+        let max = MaxInputSize::default();
+        let decoded = decode_input_message::<TestInput>(
+            &encode(&message(16)),
+            &max,
+            &mut ZstdCodec::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            decoded
+                .inputs
+                .get(&NetworkTick::new(1))
+                .unwrap()
+                .buffer
+                .len(),
+            16
+        );
+    }
+
+    #[test]
+    pub fn deviation_moving_average() {
+        let mut history = ReceivedHistory::new();
+        assert!(!history.deviation().is_warm());
+
+        let mut time = Duration::ZERO;
+        for _ in 0..=DEVIATION_WARMUP {
+            time += Duration::from_millis(16);
+            history.push(time);
+        }
+
+        let steady = history.deviation();
+        assert!(steady.is_warm());
+        assert_eq!(steady.sample_count, DEVIATION_WARMUP);
+        assert!(steady.deviation < 1e-4);
+        assert!((history.mean() - 0.016).abs() < 1e-4);
+
+        // Jitter shows up within a few samples.
+        for step in [4, 40, 4, 40] {
+            time += Duration::from_millis(step);
+            history.push(time);
+        }
+        assert!(history.deviation().deviation > 0.005);
+    }
+
+    #[test]
+    pub fn latest_input_before() {
+        let mut inputs = QueuedInputs::new();
+        assert!(inputs.latest_before(NetworkTick::new(5)).is_none());
+
+        inputs.push(NetworkTick::new(2), TestInput { buffer: vec![2] });
+        inputs.push(NetworkTick::new(3), TestInput { buffer: vec![3] });
+        inputs.push(NetworkTick::new(6), TestInput { buffer: vec![6] });
+
+        let (tick, input) = inputs.latest_before(NetworkTick::new(5)).unwrap();
+        assert_eq!(*tick, NetworkTick::new(3));
+        assert_eq!(input.buffer, vec![3]);
+        assert!(inputs.latest_before(NetworkTick::new(2)).is_none());
+    }
+
+    #[test]
+    pub fn rewind_keeps_live_input() {
+        use crate::stage::{NetworkSimulationInfo, NetworkSimulationStage, Rewind};
+
+        let input = |value: u8| TestInput {
+            buffer: vec![value],
+        };
+        let step = crate::tick::tick_hz(32);
+
+        let mut world = World::new();
+        world.insert_resource(Time::default());
+        world.insert_resource(crate::Local);
+        world.insert_resource(NetworkSimulationInfo::new(step));
+        world.insert_resource(NetworkTick::new(10));
+
+        let mut buffer = QueuedInputs::new();
+        for tick in 5..=10 {
+            buffer.push(NetworkTick::new(tick), input(tick as u8));
+        }
+        world.insert_resource(buffer);
+
+        // Sampled for the tick we are about to simulate, not in the buffer yet.
+        world.insert_resource(input(99));
+
+        #[derive(Resource, Default)]
+        struct Replayed(Vec<TestInput>);
+        world.init_resource::<Replayed>();
+
+        let mut stage = NetworkSimulationStage::new(step);
+        stage
+            .input_history
+            .add_system(client_apply_input_buffer::<TestInput>);
+        stage.schedule.add_stage(
+            crate::stage::NetworkCoreStage::Update,
+            SystemStage::single_threaded().with_system(
+                |input: Res<TestInput>, mut replayed: ResMut<Replayed>| {
+                    replayed.0.push(input.clone());
+                },
+            ),
+        );
+
+        world.insert_resource(Rewind(NetworkTick::new(7)));
+        stage.run(&mut world);
+
+        assert_eq!(world.resource::<NetworkTick>().tick(), 10);
+        assert_eq!(world.resource::<Replayed>().0.len(), 3);
+        assert!(!world.resource::<Replayed>().0.contains(&input(99)));
+        assert_eq!(*world.resource::<TestInput>(), input(99));
+        assert!(!world.contains_resource::<Resimulating>());
+    }
+
+    #[test]
+    pub fn carried_over_resim_keeps_live_input() {
+        use crate::stage::{NetworkSimulationInfo, NetworkSimulationStage, Rewind};
+
+        let input = |value: u8| TestInput {
+            buffer: vec![value],
+        };
+        let step = crate::tick::tick_hz(32);
+
+        let mut world = World::new();
+        world.insert_resource(Time::default());
+        world.insert_resource(crate::Local);
+        world.insert_resource(NetworkTick::new(10));
+
+        // Two ticks a frame, so going back to 5 takes three frames.
+        let mut info = NetworkSimulationInfo::new(step);
+        info.max_resim_ticks = Some(2);
+        world.insert_resource(info);
+
+        let mut buffer = QueuedInputs::new();
+        for tick in 0..=10 {
+            buffer.push(NetworkTick::new(tick), input(tick as u8));
+        }
+        world.insert_resource(buffer);
+
+        #[derive(Resource, Default)]
+        struct Replayed(Vec<TestInput>);
+        world.init_resource::<Replayed>();
+
+        let mut stage = NetworkSimulationStage::new(step);
+        stage
+            .input_history
+            .add_system(client_apply_input_buffer::<TestInput>);
+        stage.schedule.add_stage(
+            crate::stage::NetworkCoreStage::Update,
+            SystemStage::single_threaded().with_system(
+                |input: Res<TestInput>, mut replayed: ResMut<Replayed>| {
+                    replayed.0.push(input.clone());
+                },
+            ),
+        );
+
+        world.insert_resource(Rewind(NetworkTick::new(5)));
+        for (frame, sampled) in [99, 98, 97].into_iter().enumerate() {
+            // The game samples new input every frame.
+            world.insert_resource(input(sampled));
+            stage.run(&mut world);
+
+            assert_eq!(*world.resource::<TestInput>(), input(sampled));
+            assert_eq!(world.resource::<Replayed>().0.len(), (frame * 2 + 2).min(5));
+        }
+
+        assert_eq!(world.resource::<NetworkTick>().tick(), 10);
+        assert!(stage.pending_resim.is_none());
+        assert_eq!(
+            world.resource::<Replayed>().0,
+            (5..=9).map(input).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    pub fn server_input_fallback() {
+        let input = |value: u8| TestInput {
+            buffer: vec![value],
+        };
+        let config = InputFallbackConfig::hold_last(2);
+
+        let mut inputs = ClientQueuedInputs::new();
+        let mut queue = QueuedInputs::new();
+        queue.push(NetworkTick::new(1), input(1));
+        inputs.upsert(1, queue);
+
+        assert_eq!(
+            inputs.next_input(1, NetworkTick::new(1), &config),
+            Some(input(1))
+        );
+        assert!(!inputs.is_predicted(1, &NetworkTick::new(1)));
+
+        // Held for as many repeats as allowed and then dropped.
+        assert_eq!(
+            inputs.next_input(1, NetworkTick::new(2), &config),
+            Some(input(1))
+        );
+        assert_eq!(
+            inputs.next_input(1, NetworkTick::new(3), &config),
+            Some(input(1))
+        );
+        assert_eq!(
+            inputs.next_input(1, NetworkTick::new(4), &config),
+            Some(TestInput::default())
+        );
+        assert!(inputs.is_predicted(1, &NetworkTick::new(2)));
+        assert_eq!(inputs.applied(1).unwrap().repeats, 3);
+
+        // Late inputs clear the guess and are held from then on.
+        let mut late = QueuedInputs::new();
+        late.push(NetworkTick::new(3), input(3));
+        inputs.upsert(1, late);
+        assert!(!inputs.is_predicted(1, &NetworkTick::new(3)));
+        assert_eq!(inputs.late_inputs(1), 1);
+        assert_eq!(
+            inputs.next_input(1, NetworkTick::new(5), &InputFallbackConfig::hold_last(10)),
+            Some(input(3))
+        );
+
+        let none = InputFallbackConfig {
+            fallback: InputFallback::None,
+            ..Default::default()
+        };
+        assert_eq!(inputs.next_input(1, NetworkTick::new(6), &none), None);
+        assert_eq!(
+            inputs.next_input(2, NetworkTick::new(6), &InputFallbackConfig::default()),
+            Some(TestInput::default())
+        );
+
+        inputs.forget_client(&1);
+        assert!(inputs.applied(1).is_none());
+    }
+
+    #[test]
+    pub fn input_loss() {
+        let config = InputConfig {
+            send: 4,
+            max_send: 10,
+            loss_window: 8,
+            ..Default::default()
+        };
+
+        let mut inputs = QueuedInputs::new();
+        for tick in 0..8 {
+            inputs.push(NetworkTick::new(tick), TestInput::default());
+        }
+
+        // Server got everything but tick 5.
+        let mut server = ClientInputAcks::new();
+        for tick in (0..8).filter(|tick| *tick != 5) {
+            server.receive(1, &NetworkTick::new(tick));
+        }
+
+        let mut world = World::new();
+        world.insert_resource(config.clone());
+        world.insert_resource(AckedInputs(server.get(&1).unwrap().clone()));
+        world.insert_resource(inputs);
+        world.insert_resource(InputLossStats::new(&config));
+
+        let mut stage =
+            SystemStage::single_threaded().with_system(client_track_input_loss::<TestInput>);
+        stage.run(&mut world);
+
+        let stats = world.resource::<InputLossStats>();
+        assert_eq!(stats.loss_percent(), 100.0 / 8.0);
+        assert_eq!(stats.send_buffer, 8);
+
+        // Checked ticks aren't counted twice.
+        stage.run(&mut world);
+        assert_eq!(
+            world.resource::<InputLossStats>().loss_percent(),
+            100.0 / 8.0
+        );
+
+        // Only what the server is missing is sent again.
+        let mut send = world.resource::<QueuedInputs<TestInput>>().clone();
+        send.drop_acked(&world.resource::<AckedInputs>());
+        assert_eq!(
+            send.ticks().cloned().collect::<Vec<_>>(),
+            vec![NetworkTick::new(5)]
+        );
+
+        // Backs off after a full window without losses.
+        let mut stats = InputLossStats::new(&config);
+        stats.record(true, &config);
+        stats.record(true, &config);
+        assert_eq!(stats.send_buffer, 10);
+        for _ in 0..8 {
+            stats.record(false, &config);
+        }
+        assert_eq!(stats.send_buffer, 9);
+        assert_eq!(stats.loss_percent(), 0.0);
+    }
+
+    #[test]
+    pub fn compression_bomb() {
+        let max = MaxInputSize::default();
+
+        // Compresses down to almost nothing but would expand to a megabyte.
+        let bomb = encode(&message(1024 * 1024));
+        assert!(bomb.len() <= max.compressed);
+        assert!(matches!(
+            decode_input_message::<TestInput>(&bomb, &max, &mut ZstdCodec::default()),
+            Err(SabiError::MessageTooLarge { .. })
+        ));
+
+        let oversized = vec![0u8; max.compressed + 1];
+        assert!(matches!(
+            decode_input_message::<TestInput>(&oversized, &max, &mut ZstdCodec::default()),
+            Err(SabiError::MessageTooLarge { .. })
+        ));
+
+        let garbage = zstd::bulk::compress(&[255u8; 8], 0).unwrap();
+        assert!(
+            decode_input_message::<TestInput>(&garbage, &max, &mut ZstdCodec::default()).is_err()
+        );
+    }
+}
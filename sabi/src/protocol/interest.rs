@@ -6,14 +6,18 @@ use std::{
 
 use bevy_renet::renet::ServerEvent;
 
-use bevy::{prelude::*, utils::HashSet};
+use bevy::{ecs::query::ReadOnlyWorldQuery, prelude::*, utils::HashSet};
 
 use super::{
+    ack::{ClientAcks, NetworkAck},
     demands::{ReplicateDemands, ReplicateMaxSize, ReplicateSizeEstimates},
-    replicate_id, ClientId, NetworkTick, ReplicateId,
+    ClientId, ClientState, NetworkBufferConfig, NetworkTick, ReplicateId,
 };
+use crate::replicate::replicate_id;
 
-pub const RESEND_INTEREST_BUFFER: i64 = 32;
+/// Default ticks to resend unacked interests for, as long as the client could still ack
+/// them, see `ack::ACK_BITS` and `NetworkBufferConfig`.
+pub const RESEND_INTEREST_BUFFER: i64 = super::ack::ACK_BITS;
 
 pub type Interest = (Entity, ReplicateId);
 
@@ -39,6 +43,110 @@ impl Baseload {
     pub fn iter_mut(&mut self) -> impl Iterator<Item = (&ClientId, &mut bool)> {
         self.clients.iter_mut()
     }
+
+    /// Is this client currently being sent the whole world?
+    pub fn is_loading(&self, client_id: &ClientId) -> bool {
+        self.clients.get(client_id).cloned().unwrap_or(false)
+    }
+}
+
+impl ClientState for Baseload {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+    }
+}
+
+/// Entities that each client has been sent at least once.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct ClientKnownEntities {
+    clients: BTreeMap<ClientId, HashSet<Entity>>,
+}
+
+impl ClientKnownEntities {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, client_id: ClientId, entity: Entity) -> bool {
+        self.clients.entry(client_id).or_default().insert(entity)
+    }
+
+    pub fn knows(&self, client_id: &ClientId, entity: &Entity) -> bool {
+        self.clients
+            .get(client_id)
+            .map(|known| known.contains(entity))
+            .unwrap_or(false)
+    }
+
+    pub fn get(&self, client_id: &ClientId) -> Option<&HashSet<Entity>> {
+        self.clients.get(client_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ClientId, &HashSet<Entity>)> {
+        self.clients.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&ClientId, &mut HashSet<Entity>)> {
+        self.clients.iter_mut()
+    }
+}
+
+impl ClientState for ClientKnownEntities {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+    }
+}
+
+/// Interests queued by a baseload that haven't been sent yet.
+///
+/// These go out on the reliable `ServerChannel::BaselineUpdate` so a dropped packet
+/// doesn't leave the client waiting on components that rarely change.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct BaselineInterests {
+    clients: BTreeMap<ClientId, HashSet<Interest>>,
+}
+
+impl BaselineInterests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, client_id: ClientId, interest: Interest) -> bool {
+        self.clients.entry(client_id).or_default().insert(interest)
+    }
+
+    /// Remove the interest, returns true if it came from a baseload.
+    pub fn take(&mut self, client_id: &ClientId, interest: &Interest) -> bool {
+        self.clients
+            .get_mut(client_id)
+            .map(|interests| interests.remove(interest))
+            .unwrap_or(false)
+    }
+}
+
+impl ClientState for BaselineInterests {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+    }
+}
+
+/// Components that always go out on the reliable `ServerChannel::BaselineUpdate`, see
+/// `ReplicateConfig::with_reliable`.
+#[derive(Resource, Default, Debug, Clone)]
+pub struct ReliableComponents(HashSet<ReplicateId>);
+
+impl ReliableComponents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, replicate_id: ReplicateId) {
+        self.0.insert(replicate_id);
+    }
+
+    pub fn contains(&self, replicate_id: &ReplicateId) -> bool {
+        self.0.contains(replicate_id)
+    }
 }
 
 pub fn setup_baseload(mut baseload: ResMut<Baseload>, mut server_events: EventReader<ServerEvent>) {
@@ -53,48 +161,211 @@ pub fn setup_baseload(mut baseload: ResMut<Baseload>, mut server_events: EventRe
     }
 }
 
-pub fn baseload_components<C>(
+/// Most interests we will queue for a single client.
+///
+/// Past this new changes are dropped instead of growing the queue, this is a last resort
+/// for an overloaded server.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct MaxQueueDepth(pub usize);
+
+impl Default for MaxQueueDepth {
+    fn default() -> Self {
+        Self(crate::plugin::DEFAULT_MAX_QUEUE_DEPTH)
+    }
+}
+
+/// Spreads the initial world state for new clients over multiple ticks.
+///
+/// Baseloaded interests wait here and are moved into the client's interest queue
+/// `chunk_size` at a time, whenever the queue has drained below `chunk_size`.
+#[derive(Resource, Debug, Clone)]
+pub struct BaseloadChunker {
+    pub chunk_size: usize,
+    clients: BTreeMap<ClientId, InterestQueue<Interest>>,
+}
+
+impl Default for BaseloadChunker {
+    fn default() -> Self {
+        Self::new(crate::plugin::DEFAULT_BASELOAD_CHUNK_SIZE)
+    }
+}
+
+impl BaseloadChunker {
+    pub fn new(chunk_size: usize) -> Self {
+        Self {
+            chunk_size: chunk_size.max(1),
+            clients: Default::default(),
+        }
+    }
+
+    pub fn push(&mut self, client_id: ClientId, interest: Interest) {
+        self.clients
+            .entry(client_id)
+            .or_default()
+            .push_back(interest);
+    }
+
+    /// Is this interest still waiting to be queued for the client?
+    pub fn is_pending(&self, client_id: &ClientId, interest: &Interest) -> bool {
+        self.clients
+            .get(client_id)
+            .map(|pending| pending.contains(interest))
+            .unwrap_or(false)
+    }
+
+    pub fn is_loading(&self, client_id: &ClientId) -> bool {
+        self.clients.contains_key(client_id)
+    }
+
+    /// How many interests are still waiting to be queued for the client.
+    pub fn pending(&self, client_id: &ClientId) -> usize {
+        self.clients
+            .get(client_id)
+            .map(|pending| pending.len())
+            .unwrap_or(0)
+    }
+
+    /// Move the next chunk of pending interests into the interest queues.
+    pub fn feed(
+        &mut self,
+        queues: &mut ClientInterestQueues,
+        known: &mut ClientKnownEntities,
+        baseline: &mut BaselineInterests,
+    ) {
+        let chunk_size = self.chunk_size;
+        self.clients.retain(|client_id, pending| {
+            let queue = queues.entry(*client_id);
+            if queue.len() >= chunk_size {
+                return true;
+            }
+
+            for _ in 0..chunk_size {
+                match pending.pop_front() {
+                    Some(interest) => {
+                        known.insert(*client_id, interest.0);
+                        baseline.insert(*client_id, interest);
+                        queue.push_back(interest);
+                    }
+                    None => break,
+                }
+            }
+
+            let loading = pending.len() > 0;
+            if !loading {
+                info!("finished queueing baseload for {}", client_id);
+            }
+
+            loading
+        });
+    }
+}
+
+impl ClientState for BaseloadChunker {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+    }
+}
+
+pub fn baseload_components<C, F>(
+    max_depth: Res<MaxQueueDepth>,
     mut baseload: ResMut<Baseload>,
-    mut queues: ResMut<ClientInterestQueues>,
-    query: Query<Entity, With<C>>,
+    mut chunker: ResMut<BaseloadChunker>,
+    query: Query<Entity, (With<C>, F)>,
 ) where
     C: 'static + Component + Reflect + FromReflect + Clone,
+    F: 'static + ReadOnlyWorldQuery,
 {
+    let replicate_id = replicate_id::<C>();
     for (client_id, should_load) in baseload.iter_mut() {
         if *should_load {
-            let queue = queues.entry(*client_id);
-            for interest in query.iter().map(|e| (e, replicate_id::<C>())) {
-                queue.push_back(interest);
+            for interest in query.iter().map(|e| (e, replicate_id)) {
+                if chunker.pending(client_id) >= max_depth.0 {
+                    warn!("baseload for {} is over the max queue depth", client_id);
+                    break;
+                }
+
+                chunker.push(*client_id, interest);
             }
         }
     }
 }
 
+pub fn feed_baseloads(
+    mut chunker: ResMut<BaseloadChunker>,
+    mut queues: ResMut<ClientInterestQueues>,
+    mut known: ResMut<ClientKnownEntities>,
+    mut baseline: ResMut<BaselineInterests>,
+) {
+    chunker.feed(&mut *queues, &mut *known, &mut *baseline);
+}
+
 pub fn clear_baseloads(mut baseload: ResMut<Baseload>) {
     for (_client_id, should_load) in baseload.iter_mut() {
         *should_load = false;
     }
 }
 
-pub fn component_changes<C>(
+pub fn component_changes<C, F>(
+    max_depth: Res<MaxQueueDepth>,
+    baseload: Res<Baseload>,
+    chunker: Res<BaseloadChunker>,
     mut queues: ResMut<ClientInterestQueues>,
-    query: Query<Entity, Changed<C>>,
+    mut known: ResMut<ClientKnownEntities>,
+    query: Query<Entity, (Changed<C>, F)>,
 ) where
     C: 'static + Component + Reflect + FromReflect + Clone,
+    F: 'static + ReadOnlyWorldQuery,
 {
-    let changes = query
-        .iter()
-        .map(|e| (e, replicate_id::<C>()))
-        .collect::<Vec<_>>();
+    let replicate_id = replicate_id::<C>();
+    let changes = query.iter().map(|e| (e, replicate_id)).collect::<Vec<_>>();
+
+    queue_changes(
+        &*baseload,
+        &*chunker,
+        *max_depth,
+        &mut *queues,
+        &mut *known,
+        &changes,
+    );
+}
+
+/// Queue changed components for clients.
+///
+/// Clients that are about to be baseloaded are skipped and so are interests still
+/// waiting in the `BaseloadChunker`, so we don't send the same initial state twice.
+pub fn queue_changes(
+    baseload: &Baseload,
+    chunker: &BaseloadChunker,
+    max_depth: MaxQueueDepth,
+    queues: &mut ClientInterestQueues,
+    known: &mut ClientKnownEntities,
+    changes: &[Interest],
+) {
+    for (client_id, queue) in queues.iter_mut() {
+        if baseload.is_loading(client_id) {
+            continue;
+        }
 
-    for (_client_id, queue) in queues.iter_mut() {
         for change in changes.iter() {
+            if chunker.is_pending(client_id, change) {
+                continue;
+            }
+
+            if queue.len() >= max_depth.0 && !queue.contains(change) {
+                warn!("interest queue for {} is full, dropping changes", client_id);
+                break;
+            }
+
+            known.insert(*client_id, change.0);
             queue.push_back(change.clone());
         }
     }
 }
 
-/// Sent clients interests for this frame.
+/// Interests sent to clients that haven't been acked yet.
+///
+/// Only recorded once `server_send_interest` actually sends them, anything requeued or
+/// dropped before then would otherwise be resent on top of going out again.
 #[derive(Resource, Default, Debug, Clone)]
 pub struct ClientUnackedInterests {
     clients: BTreeMap<ClientId, UnackedInterests>,
@@ -112,25 +383,43 @@ impl ClientUnackedInterests {
             .record(tick, interests);
     }
 
-    pub fn record_from_queue(&mut self, tick: NetworkTick, queue: &InterestsToSend) {
-        for (client_id, interests) in queue.iter() {
-            self.record(*client_id, tick, interests.clone());
+    pub fn ack(&mut self, client_id: &ClientId, tick: &NetworkTick) {
+        if let Some(sent) = self.clients.get_mut(client_id) {
+            sent.ack(tick);
         }
     }
 
-    pub fn ack(&mut self, client_id: &ClientId, tick: &NetworkTick) {
+    pub fn apply_ack(&mut self, client_id: &ClientId, ack: &NetworkAck) {
         if let Some(sent) = self.clients.get_mut(client_id) {
-            sent.ack(tick);
+            sent.apply_ack(ack);
         }
     }
 
-    pub fn resend_unacked(&mut self, tick: NetworkTick, queues: &mut ClientInterestQueues) {
+    pub fn resend_unacked(
+        &mut self,
+        tick: NetworkTick,
+        buffer: i64,
+        acks: &ClientAcks,
+        queues: &mut ClientInterestQueues,
+    ) {
+        let no_ack = NetworkAck::default();
         for (client_id, sent) in &mut self.clients {
+            let ack = acks.get(client_id).unwrap_or(&no_ack);
             let queue = queues.entry(*client_id);
-            sent.resend_unacked(tick, queue);
+            let expired = sent.resend_unacked(tick, buffer, ack, queue);
+            if expired > 0 {
+                warn!(
+                    "client {} hasn't acked {} interests in {} ticks, dropping them",
+                    client_id, expired, buffer
+                );
+            }
         }
     }
 
+    pub fn get(&self, client_id: &ClientId) -> Option<&UnackedInterests> {
+        self.clients.get(client_id)
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = (&ClientId, &UnackedInterests)> {
         self.clients.iter()
     }
@@ -140,6 +429,12 @@ impl ClientUnackedInterests {
     }
 }
 
+impl ClientState for ClientUnackedInterests {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+    }
+}
+
 #[derive(Default, Debug, Clone)]
 pub struct UnackedInterests {
     unacked: BTreeMap<NetworkTick, Vec<Interest>>,
@@ -158,45 +453,81 @@ impl UnackedInterests {
         self.unacked.remove(tick);
     }
 
+    /// Forget interests from any ticks the client acked.
+    pub fn apply_ack(&mut self, ack: &NetworkAck) {
+        self.unacked.retain(|tick, _| !ack.is_acked(tick));
+    }
+
+    /// Queue interests from ticks the client has received past without acking, those
+    /// updates were lost.
+    ///
+    /// Anything older than `buffer` ticks is dropped so a stalled client doesn't grow this
+    /// forever, returns how many interests were dropped.
     pub fn resend_unacked(
         &mut self,
         current_tick: NetworkTick,
+        buffer: i64,
+        ack: &NetworkAck,
         queue: &mut InterestQueue<Interest>,
-    ) {
+    ) -> usize {
+        let mut expired = 0;
         let mut resend = Vec::new();
-        for (tick, interests) in self.unacked.iter().filter(|(tick, _)| {
-            (current_tick.tick() as i64 - tick.tick() as i64) < RESEND_INTEREST_BUFFER
-        }) {
-            for interest in interests.iter() {
-                queue.push_front(*interest);
+        for (tick, interests) in self.unacked.iter() {
+            if current_tick.signed_diff(*tick) >= buffer {
+                expired += interests.len();
+                resend.push(*tick);
+                continue;
             }
 
-            resend.push(*tick);
+            if ack.base().signed_diff(*tick) > 0 && !ack.is_acked(tick) {
+                for interest in interests.iter() {
+                    queue.push_front(*interest);
+                }
+
+                resend.push(*tick);
+            }
         }
 
         for tick in resend {
             self.unacked.remove(&tick);
         }
+
+        expired
+    }
+
+    pub fn len(&self) -> usize {
+        self.unacked.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.unacked.is_empty()
+    }
+}
+
+/// Stop tracking interests the clients told us they received.
+pub fn ack_interests(acks: Res<ClientAcks>, mut unacked: ResMut<ClientUnackedInterests>) {
+    for (client_id, ack) in acks.iter() {
+        unacked.apply_ack(client_id, ack);
     }
 }
 
 pub fn resend_unacked(
     tick: Res<NetworkTick>,
+    buffers: Res<NetworkBufferConfig>,
+    acks: Res<ClientAcks>,
     mut unacked: ResMut<ClientUnackedInterests>,
     mut queues: ResMut<ClientInterestQueues>,
 ) {
-    unacked.resend_unacked(*tick, &mut *queues);
+    unacked.resend_unacked(*tick, buffers.resend_interests, &*acks, &mut *queues);
 }
 
 /// Queue up components that we need to send.
 pub fn queue_interests(
-    tick: Res<NetworkTick>,
     mut queues: ResMut<ClientInterestQueues>,
     demands: Res<ReplicateDemands>,
     estimates: Res<ReplicateSizeEstimates>,
     max: Res<ReplicateMaxSize>,
     mut to_send: ResMut<InterestsToSend>,
-    mut sent_unacked: ResMut<ClientUnackedInterests>,
 ) {
     to_send.clear();
 
@@ -230,8 +561,21 @@ pub fn queue_interests(
                 }
             }
 
-            for id in grouped_ids {
-                to_send.push(*client_id, (entity, *id));
+            for id in grouped_ids.iter() {
+                to_send.push(*client_id, (entity, **id));
+            }
+
+            // Anything made redundant by what we just sent doesn't need to go out.
+            for id in grouped_ids.iter() {
+                for redundant in demands.dedup.get(id).into_iter().flatten() {
+                    if grouped_ids.contains(&redundant) {
+                        continue;
+                    }
+
+                    let interest = (entity, *redundant);
+                    queue.remove(&interest);
+                    unsent.retain(|unsent| *unsent != interest);
+                }
             }
 
             used += estimate;
@@ -243,8 +587,6 @@ pub fn queue_interests(
             queue.push_front(interest);
         }
     }
-
-    sent_unacked.record_from_queue(*tick, &*to_send);
 }
 
 #[derive(Resource, Default, Clone)]
@@ -278,6 +620,12 @@ impl ClientInterestQueues {
     }
 }
 
+impl ClientState for ClientInterestQueues {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.queues.remove(client_id);
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct InterestQueue<I>
 where
@@ -344,6 +692,29 @@ where
         contains
     }
 
+    /// Remove an interest from anywhere in the queue, returns true if it was in.
+    pub fn remove(&mut self, interest: &I) -> bool {
+        let contains = self.contains.remove(interest);
+        if contains {
+            self.queue.retain(|queued| queued != interest);
+        }
+
+        contains
+    }
+
+    /// Keep only the interests `keep` returns true for, in the same order.
+    pub fn retain(&mut self, mut keep: impl FnMut(&I) -> bool) {
+        let contains = &mut self.contains;
+        self.queue.retain(|interest| {
+            let kept = keep(interest);
+            if !kept {
+                contains.remove(interest);
+            }
+
+            kept
+        });
+    }
+
     /// Pop the next entity/component pair from the front.
     pub fn pop_front(&mut self) -> Option<I> {
         if let Some(key) = self.queue.pop_front() {
@@ -358,6 +729,23 @@ where
         self.queue.iter()
     }
 
+    pub fn contains(&self, interest: &I) -> bool {
+        self.contains.contains(interest)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.contains.clear();
+        self.queue.clear();
+    }
+
     pub fn peek_first(&self) -> Option<&I> {
         self.iter().next()
     }
@@ -409,6 +797,129 @@ pub fn interest_queue() {
     );
 }
 
+#[test]
+pub fn queue_depth_limit() {
+    let baseload = Baseload::new();
+    let chunker = BaseloadChunker::default();
+    let mut queues = ClientInterestQueues::new();
+    let mut known = ClientKnownEntities::new();
+    queues.entry(1);
+
+    let changes = (0..3)
+        .map(|index| (Entity::from_raw(index), ReplicateId(1)))
+        .collect::<Vec<_>>();
+    queue_changes(
+        &baseload,
+        &chunker,
+        MaxQueueDepth(2),
+        &mut queues,
+        &mut known,
+        &changes,
+    );
+    assert_eq!(queues.get(&1).unwrap().len(), 2);
+    assert!(!known.knows(&1, &changes[2].0));
+
+    // Already queued changes are still fine.
+    queue_changes(
+        &baseload,
+        &chunker,
+        MaxQueueDepth(2),
+        &mut queues,
+        &mut known,
+        &changes[..1],
+    );
+    assert_eq!(queues.get(&1).unwrap().len(), 2);
+
+    let queue = queues.get_mut(&1).unwrap();
+    queue.clear();
+    assert!(queue.is_empty());
+    assert!(!queue.contains(&changes[0]));
+}
+
+#[test]
+pub fn no_changes_while_baseloading() {
+    let entity = Entity::from_raw(0);
+    let interest = (entity, ReplicateId(1));
+
+    let mut baseload = Baseload::new();
+    let chunker = BaseloadChunker::default();
+    let mut queues = ClientInterestQueues::new();
+    let mut known = ClientKnownEntities::new();
+
+    // Client 1 is already connected, client 2 is joining and getting the world.
+    queues.entry(1);
+    queues.entry(2).push_back(interest);
+    known.insert(2, entity);
+    baseload.mark(2);
+
+    queue_changes(
+        &baseload,
+        &chunker,
+        MaxQueueDepth::default(),
+        &mut queues,
+        &mut known,
+        &[interest],
+    );
+
+    assert_eq!(
+        queues.get(&1).unwrap().iter().cloned().collect::<Vec<_>>(),
+        vec![interest]
+    );
+    assert_eq!(
+        queues.get(&2).unwrap().iter().cloned().collect::<Vec<_>>(),
+        vec![interest]
+    );
+    assert!(known.knows(&1, &entity));
+}
+
+#[test]
+pub fn chunked_baseload() {
+    let mut chunker = BaseloadChunker::new(2);
+    let mut queues = ClientInterestQueues::new();
+    let mut known = ClientKnownEntities::new();
+    let mut baseline = BaselineInterests::new();
+
+    let interests = (0..5)
+        .map(|index| (Entity::from_raw(index), ReplicateId(1)))
+        .collect::<Vec<_>>();
+    for interest in interests.iter() {
+        chunker.push(1, *interest);
+    }
+
+    chunker.feed(&mut queues, &mut known, &mut baseline);
+    assert_eq!(queues.get(&1).unwrap().len(), 2);
+    assert!(chunker.is_pending(&1, &interests[2]));
+
+    // Queue hasn't drained yet so nothing more is added.
+    chunker.feed(&mut queues, &mut known, &mut baseline);
+    assert_eq!(queues.get(&1).unwrap().len(), 2);
+
+    // A change to something still pending is left for the chunker.
+    let baseload = Baseload::new();
+    queue_changes(
+        &baseload,
+        &chunker,
+        MaxQueueDepth::default(),
+        &mut queues,
+        &mut known,
+        &[interests[4]],
+    );
+    assert_eq!(queues.get(&1).unwrap().len(), 2);
+
+    let queue = queues.get_mut(&1).unwrap();
+    queue.pop_front();
+    queue.pop_front();
+    chunker.feed(&mut queues, &mut known, &mut baseline);
+    assert!(chunker.is_loading(&1));
+
+    queues.get_mut(&1).unwrap().pop_front();
+    queues.get_mut(&1).unwrap().pop_front();
+    chunker.feed(&mut queues, &mut known, &mut baseline);
+    assert!(!chunker.is_loading(&1));
+    assert!(known.knows(&1, &interests[4].0));
+    assert!(baseline.take(&1, &interests[4]));
+}
+
 #[derive(Resource, Default, Debug, Clone)]
 pub struct InterestsToSend {
     clients: BTreeMap<ClientId, Vec<Interest>>,
@@ -436,4 +947,157 @@ impl InterestsToSend {
             interests.clear();
         }
     }
+
+    /// Estimated serialized size of everything queued for a client.
+    pub fn client_size_estimate(
+        &self,
+        client_id: &ClientId,
+        estimates: &ReplicateSizeEstimates,
+    ) -> usize {
+        self.clients
+            .get(client_id)
+            .into_iter()
+            .flatten()
+            .map(|(_, replicate_id)| estimates.get(replicate_id))
+            .sum()
+    }
+
+    /// Estimated serialized size of everything queued for all clients.
+    pub fn total_size_estimate(&self, estimates: &ReplicateSizeEstimates) -> usize {
+        self.clients
+            .keys()
+            .map(|client_id| self.client_size_estimate(client_id, estimates))
+            .sum()
+    }
+}
+
+impl ClientState for InterestsToSend {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+    }
+}
+
+#[test]
+pub fn dedup_redundant_interests() {
+    let entity = Entity::from_raw(0);
+    let transform = ReplicateId(1);
+    let global_transform = ReplicateId(2);
+
+    let mut demands = ReplicateDemands::default();
+    demands.dedup.insert(transform, vec![global_transform]);
+
+    let mut world = World::new();
+    world.insert_resource(NetworkTick::new(0));
+    world.insert_resource(demands);
+    world.insert_resource(ReplicateSizeEstimates::new());
+    world.insert_resource(ReplicateMaxSize::default());
+    world.init_resource::<InterestsToSend>();
+
+    let mut queues = ClientInterestQueues::new();
+    queues.entry(1).push_back((entity, transform));
+    queues.entry(1).push_back((entity, global_transform));
+    queues
+        .entry(1)
+        .push_back((Entity::from_raw(1), global_transform));
+    world.insert_resource(queues);
+
+    let mut stage = SystemStage::single_threaded().with_system(queue_interests);
+    stage.run(&mut world);
+
+    let queue = world.resource::<ClientInterestQueues>().get(&1).unwrap();
+    assert_eq!(
+        queue.iter().cloned().collect::<Vec<_>>(),
+        vec![(Entity::from_raw(1), global_transform)]
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replicate::NoReplicate;
+
+    #[test]
+    pub fn filtered_changes() {
+        let mut world = World::new();
+        world.insert_resource(MaxQueueDepth::default());
+        world.insert_resource(Baseload::new());
+        world.insert_resource(BaseloadChunker::default());
+        world.insert_resource(ClientKnownEntities::new());
+
+        let mut queues = ClientInterestQueues::new();
+        queues.entry(1);
+        world.insert_resource(queues);
+
+        let replicated = world.spawn(Transform::default()).id();
+        world.spawn((Transform::default(), NoReplicate));
+
+        let mut stage = SystemStage::single_threaded()
+            .with_system(component_changes::<Transform, Without<NoReplicate>>);
+        stage.run(&mut world);
+
+        let queued = world
+            .resource::<ClientInterestQueues>()
+            .get(&1)
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(queued, vec![(replicated, replicate_id::<Transform>())]);
+    }
+
+    #[test]
+    pub fn resend_lost_interests() {
+        let interest = |index| (Entity::from_raw(index), ReplicateId(1));
+
+        let mut unacked = ClientUnackedInterests::new();
+        for tick in 1..=3 {
+            unacked.record(1, NetworkTick::new(tick), vec![interest(tick as u32)]);
+        }
+        unacked.record(1, NetworkTick::new(4), vec![interest(4)]);
+
+        // Client got ticks 1 and 3 but not 2, and hasn't seen 4 yet.
+        let mut ack = NetworkAck::new(NetworkTick::new(0));
+        ack.receive(&NetworkTick::new(1));
+        ack.receive(&NetworkTick::new(3));
+        let mut acks = ClientAcks::new();
+        acks.apply_ack(1, &ack);
+
+        let mut world = World::new();
+        world.insert_resource(NetworkTick::new(5));
+        world.insert_resource(NetworkBufferConfig::default());
+        world.insert_resource(acks);
+        world.insert_resource(unacked);
+        world.insert_resource(ClientInterestQueues::new());
+
+        let mut stage = SystemStage::single_threaded()
+            .with_system(ack_interests.label("ack_interests"))
+            .with_system(resend_unacked.after("ack_interests"));
+        stage.run(&mut world);
+
+        let queued = world
+            .resource::<ClientInterestQueues>()
+            .get(&1)
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(queued, vec![interest(2)]);
+        assert_eq!(
+            world
+                .resource::<ClientUnackedInterests>()
+                .get(&1)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Tick 4 never gets acked and is eventually given up on.
+        world.insert_resource(NetworkTick::new(4 + RESEND_INTEREST_BUFFER as u64));
+        stage.run(&mut world);
+        assert!(world
+            .resource::<ClientUnackedInterests>()
+            .get(&1)
+            .unwrap()
+            .is_empty());
+    }
 }
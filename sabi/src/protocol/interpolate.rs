@@ -0,0 +1,297 @@
+//! Interpolation of entities we don't predict.
+//!
+//! Updates for these entities are buffered by tick and the shown value is sampled from
+//! slightly in the past, `InterpolationDelay` ticks behind the latest server update, so
+//! there are usually two updates to blend between. Only the rendered value (e.g.
+//! `GlobalTransform`) is written, the simulated one is left alone.
+
+use std::collections::BTreeMap;
+
+use bevy::{ecs::entity::Entities, ecs::query::ReadOnlyWorldQuery, prelude::*};
+
+use crate::{prelude::*, stage::NetworkSimulationInfo};
+
+use super::{update::UpdateMessages, ComponentsUpdate, NetworkTick};
+
+/// How many received updates to keep per entity.
+pub const DEFAULT_INTERPOLATION_BUFFER: usize = 16;
+
+/// Components that can be blended between two received updates.
+///
+/// The default implementation snaps to the newer value once it is reached, for
+/// components where blending doesn't make sense.
+pub trait Interpolate: 'static + Component + Clone {
+    /// Component the blended value is shown on.
+    type Render: Component;
+
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        if t < 1.0 {
+            self.clone()
+        } else {
+            other.clone()
+        }
+    }
+
+    fn apply(render: &mut Self::Render, value: &Self);
+}
+
+impl Interpolate for Transform {
+    type Render = GlobalTransform;
+
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        Transform {
+            translation: self.translation.lerp(other.translation, t),
+            rotation: self.rotation.slerp(other.rotation, t),
+            scale: self.scale.lerp(other.scale, t),
+        }
+    }
+
+    fn apply(render: &mut Self::Render, value: &Self) {
+        *render = GlobalTransform::from(*value);
+    }
+}
+
+impl Interpolate for Name {
+    type Render = Name;
+
+    fn apply(render: &mut Self::Render, value: &Self) {
+        *render = value.clone();
+    }
+}
+
+/// How many ticks behind the latest server update interpolated entities are shown.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct InterpolationDelay(pub f64);
+
+impl Default for InterpolationDelay {
+    fn default() -> Self {
+        Self(2.0)
+    }
+}
+
+/// Received values of `C` for an entity by the tick they were sent on.
+#[derive(Component, Debug, Clone)]
+pub struct InterpolationBuffer<C> {
+    samples: BTreeMap<NetworkTick, C>,
+    capacity: usize,
+}
+
+impl<C> Default for InterpolationBuffer<C> {
+    fn default() -> Self {
+        Self::new(DEFAULT_INTERPOLATION_BUFFER)
+    }
+}
+
+impl<C> InterpolationBuffer<C> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: BTreeMap::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Record a value, replacing any from the same tick in case of a resimulation.
+    pub fn push(&mut self, tick: NetworkTick, value: C) {
+        self.samples.insert(tick, value);
+        while self.samples.len() > self.capacity {
+            let oldest = *self.samples.keys().next().expect("buffer is not empty");
+            self.samples.remove(&oldest);
+        }
+    }
+
+    pub fn latest(&self) -> Option<&NetworkTick> {
+        self.samples.keys().next_back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+}
+
+impl<C: Interpolate> InterpolationBuffer<C> {
+    /// Value at a fractional tick, clamped to the oldest and newest values we have.
+    pub fn sample(&self, render_tick: f64) -> Option<C> {
+        let before = self
+            .samples
+            .iter()
+            .rev()
+            .find(|(tick, _)| tick.tick() as f64 <= render_tick);
+        let after = self
+            .samples
+            .iter()
+            .find(|(tick, _)| tick.tick() as f64 > render_tick);
+
+        match (before, after) {
+            (Some((from_tick, from)), Some((to_tick, to))) => {
+                let span = to_tick.signed_diff(*from_tick) as f64;
+                let t = (render_tick - from_tick.tick() as f64) / span;
+                Some(from.interpolate(to, t as f32))
+            }
+            (Some((_, value)), None) | (None, Some((_, value))) => Some(value.clone()),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Record the values `client_update` applied this tick.
+pub fn buffer_interpolated<C, F>(
+    tick: Res<NetworkTick>,
+    mut commands: Commands,
+    entities: &Entities,
+    server_entities: Res<ServerEntities>,
+    mut update_events: EventReader<(ServerEntity, ComponentsUpdate)>,
+    mut query: Query<(&C, Option<&mut InterpolationBuffer<C>>), F>,
+) where
+    C: Interpolate,
+    F: 'static + ReadOnlyWorldQuery,
+{
+    let replicate_id = replicate_id::<C>();
+    for (server_entity, components_update) in update_events.iter() {
+        if !components_update.contains_key(&replicate_id) {
+            continue;
+        }
+
+        let entity = match server_entities.get(entities, *server_entity) {
+            Some(entity) => entity,
+            None => continue,
+        };
+
+        // Newly inserted components show up next tick.
+        if let Ok((component, buffer)) = query.get_mut(entity) {
+            match buffer {
+                Some(mut buffer) => buffer.push(*tick, component.clone()),
+                None => {
+                    let mut buffer = InterpolationBuffer::<C>::default();
+                    buffer.push(*tick, component.clone());
+                    commands.entity(entity).insert(buffer);
+                }
+            }
+        }
+    }
+}
+
+/// Show interpolated values `InterpolationDelay` ticks behind the latest server update.
+///
+/// Owned entities are predicted, so they are never interpolated even if `F` lets them
+/// through.
+pub fn interpolate<C, F>(
+    delay: Res<InterpolationDelay>,
+    sim_info: Res<NetworkSimulationInfo>,
+    server_updates: Res<UpdateMessages>,
+    mut query: Query<(&mut C::Render, &InterpolationBuffer<C>), (F, Without<Owned>)>,
+) where
+    C: Interpolate,
+    F: 'static + ReadOnlyWorldQuery,
+{
+    let latest = match server_updates.latest() {
+        Some(latest) => latest.tick() as f64,
+        None => return,
+    };
+
+    let render_tick = latest + sim_info.overstep() - delay.0;
+    for (mut render, buffer) in query.iter_mut() {
+        if let Some(value) = buffer.sample(render_tick) {
+            C::apply(&mut *render, &value);
+        }
+    }
+}
+
+/// Stop interpolating entities once we own them, they are predicted from then on.
+pub fn remove_owned_interpolation<C: Interpolate>(
+    mut commands: Commands,
+    query: Query<Entity, (With<InterpolationBuffer<C>>, With<Owned>)>,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).remove::<InterpolationBuffer<C>>();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn sample_between_ticks() {
+        let mut buffer = InterpolationBuffer::<Transform>::new(3);
+        assert!(buffer.sample(0.0).is_none());
+
+        buffer.push(NetworkTick::new(10), Transform::from_xyz(0.0, 0.0, 0.0));
+        buffer.push(NetworkTick::new(12), Transform::from_xyz(4.0, 0.0, 0.0));
+
+        let sampled = buffer.sample(11.0).unwrap();
+        assert!(sampled.translation.abs_diff_eq(Vec3::X * 2.0, 1e-5));
+
+        // Clamped to what we have.
+        assert_eq!(buffer.sample(5.0).unwrap().translation, Vec3::ZERO);
+        assert_eq!(buffer.sample(20.0).unwrap().translation, Vec3::X * 4.0);
+
+        // Oldest samples are dropped past capacity.
+        buffer.push(NetworkTick::new(13), Transform::from_xyz(5.0, 0.0, 0.0));
+        buffer.push(NetworkTick::new(14), Transform::from_xyz(6.0, 0.0, 0.0));
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.sample(5.0).unwrap().translation, Vec3::X * 4.0);
+        assert_eq!(buffer.latest(), Some(&NetworkTick::new(14)));
+    }
+
+    #[test]
+    pub fn render_only_unowned() {
+        use crate::protocol::update::{EntityUpdate, UpdateMessage};
+
+        let mut world = World::new();
+        world.init_resource::<InterpolationDelay>();
+        world.insert_resource(NetworkSimulationInfo::new(crate::tick::tick_hz(32)));
+        let mut updates = UpdateMessages::new();
+        updates.push(UpdateMessage {
+            tick: NetworkTick::new(13),
+            input_deviation: Default::default(),
+            input_ack: Default::default(),
+            entity_update: EntityUpdate::new(),
+            component_despawn: Vec::new(),
+            entity_despawn: Vec::new(),
+        });
+        world.insert_resource(updates);
+
+        let mut buffer = InterpolationBuffer::<Transform>::default();
+        buffer.push(NetworkTick::new(10), Transform::from_xyz(0.0, 0.0, 0.0));
+        buffer.push(NetworkTick::new(12), Transform::from_xyz(4.0, 0.0, 0.0));
+
+        let simulated = Transform::from_xyz(8.0, 0.0, 0.0);
+        let bundle = (simulated, GlobalTransform::from(simulated), buffer);
+        let remote = world.spawn(bundle.clone()).id();
+        let owned = world.spawn((bundle, Owned)).id();
+
+        let mut stage = SystemStage::single_threaded()
+            .with_system(interpolate::<Transform, ()>)
+            .with_system(remove_owned_interpolation::<Transform>);
+        stage.run(&mut world);
+
+        // Shown 2 ticks behind tick 13, halfway between the two updates.
+        let shown = world.get::<GlobalTransform>(remote).unwrap().translation();
+        assert!(shown.abs_diff_eq(Vec3::X * 2.0, 1e-5));
+        assert_eq!(*world.get::<Transform>(remote).unwrap(), simulated);
+
+        // Predicted entities are left alone and stop buffering.
+        assert_eq!(
+            world.get::<GlobalTransform>(owned).unwrap().translation(),
+            simulated.translation
+        );
+        assert!(world.get::<InterpolationBuffer<Transform>>(owned).is_none());
+        assert!(world
+            .get::<InterpolationBuffer<Transform>>(remote)
+            .is_some());
+    }
+
+    #[test]
+    pub fn passthrough_snaps() {
+        let mut buffer = InterpolationBuffer::<Name>::default();
+        buffer.push(NetworkTick::new(1), Name::new("before"));
+        buffer.push(NetworkTick::new(3), Name::new("after"));
+
+        assert_eq!(buffer.sample(2.5).unwrap().as_str(), "before");
+        assert_eq!(buffer.sample(3.0).unwrap().as_str(), "after");
+    }
+}
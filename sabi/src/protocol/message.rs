@@ -0,0 +1,172 @@
+//! Reliable messages from clients to the server for game events that aren't input,
+//! e.g. readying up, chat or asking to respawn.
+//!
+//! Only one message type can be used per app since they share `ClientChannel::Message`,
+//! use an enum for multiple kinds of messages.
+
+use std::{fmt::Debug, marker::PhantomData};
+
+use bevy::prelude::*;
+use bevy_renet::renet::{RenetClient, RenetServer};
+use iyes_loopless::prelude::{ConditionHelpers, IntoConditionalSystem};
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+
+use super::{client_connected, codec::MalformedMessage};
+
+/// Messages waiting to be sent to the server.
+#[derive(Resource, Debug, Clone)]
+pub struct ClientMessageQueue<M> {
+    messages: Vec<M>,
+}
+
+impl<M> Default for ClientMessageQueue<M> {
+    fn default() -> Self {
+        Self {
+            messages: Vec::new(),
+        }
+    }
+}
+
+impl<M> ClientMessageQueue<M> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, message: M) {
+        self.messages.push(message);
+    }
+
+    pub fn drain(&mut self) -> std::vec::Drain<'_, M> {
+        self.messages.drain(..)
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+}
+
+pub fn encode_client_message<M: Serialize>(message: &M) -> Vec<u8> {
+    bincode::serialize(message).expect("could not serialize client message")
+}
+
+pub fn decode_client_message<M>(data: &[u8]) -> Result<M, SabiError>
+where
+    M: for<'de> Deserialize<'de>,
+{
+    bincode::deserialize(data).map_err(|err| SabiError::Deserialize(err.to_string()))
+}
+
+pub fn client_send_messages<M>(
+    mut queue: ResMut<ClientMessageQueue<M>>,
+    mut client: ResMut<RenetClient>,
+) where
+    M: 'static + Send + Sync + Serialize,
+{
+    for message in queue.drain() {
+        client.send_message(ClientChannel::Message.id(), encode_client_message(&message));
+    }
+}
+
+/// Receive messages from clients and send them out as `(ClientId, M)` events.
+pub fn server_recv_messages<M>(
+    mut server: ResMut<RenetServer>,
+    mut messages: EventWriter<(ClientId, M)>,
+    mut malformed: EventWriter<MalformedMessage>,
+) where
+    M: 'static + Send + Sync + for<'de> Deserialize<'de>,
+{
+    for client_id in server.clients_id().into_iter() {
+        while let Some(message) = server.receive_message(client_id, ClientChannel::Message.id()) {
+            match decode_client_message::<M>(message.as_slice()) {
+                Ok(message) => messages.send((client_id, message)),
+                Err(err) => {
+                    warn!("dropping message from client {}: {}", client_id, err);
+                    malformed.send(MalformedMessage {
+                        client_id: Some(client_id),
+                        channel: ClientChannel::Message.id(),
+                        error: err,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Marker so we can tell if a `ClientMessagePlugin` was already added.
+#[derive(Resource)]
+struct ClientMessageType(&'static str);
+
+/// Send `M` from clients to the server.
+///
+/// Clients push to `ClientMessageQueue<M>` and the server reads `EventReader<(ClientId, M)>`.
+pub struct ClientMessagePlugin<M>(PhantomData<M>);
+
+impl<M> Default for ClientMessagePlugin<M> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<M> Plugin for ClientMessagePlugin<M>
+where
+    M: 'static + Send + Sync + Debug + Serialize + for<'de> Deserialize<'de>,
+{
+    fn build(&self, app: &mut App) {
+        if let Some(existing) = app.world.get_resource::<ClientMessageType>() {
+            panic!(
+                "only one client message type is supported, already using {}",
+                existing.0
+            );
+        }
+        app.insert_resource(ClientMessageType(std::any::type_name::<M>()));
+
+        if app.world.contains_resource::<crate::Server>() {
+            app.add_event::<(ClientId, M)>();
+            app.add_event::<MalformedMessage>();
+            app.add_system(
+                server_recv_messages::<M>
+                    .run_if_resource_exists::<RenetServer>()
+                    .label("server_recv_messages"),
+            );
+        }
+
+        if app.world.contains_resource::<crate::Client>() {
+            app.insert_resource(ClientMessageQueue::<M>::new());
+            app.add_system_to_stage(
+                CoreStage::PostUpdate,
+                client_send_messages::<M>
+                    .run_if_resource_exists::<RenetClient>()
+                    .run_if(client_connected)
+                    .label("client_send_messages"),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum LobbyMessage {
+        Ready,
+        Chat(String),
+    }
+
+    #[test]
+    pub fn client_message_round_trip() {
+        let message = LobbyMessage::Chat("gg".to_owned());
+        let decoded: LobbyMessage =
+            decode_client_message(&encode_client_message(&message)).unwrap();
+        assert_eq!(decoded, message);
+
+        let ready = encode_client_message(&LobbyMessage::Ready);
+        assert_eq!(
+            decode_client_message::<LobbyMessage>(&ready).unwrap(),
+            LobbyMessage::Ready
+        );
+        assert!(decode_client_message::<LobbyMessage>(&[255, 255, 255, 255, 255]).is_err());
+    }
+}
@@ -12,6 +12,7 @@ use bevy_renet::renet::{
 use std::{
     hash::{Hash, Hasher},
     net::UdpSocket,
+    time::Duration,
 };
 
 use serde::{Deserialize, Serialize};
@@ -20,12 +21,22 @@ use crate::prelude::*;
 
 pub mod ack;
 pub mod client;
+pub mod codec;
+pub mod correction;
+pub mod delta;
 pub mod demands;
+pub mod despawn;
+pub mod diagnostics;
 pub mod input;
 pub mod interest;
+pub mod interpolate;
+pub mod message;
+pub mod relevancy;
 pub mod resim;
 pub mod server;
+pub mod stats;
 pub mod update;
+pub mod version;
 
 pub use client::*;
 pub use server::*;
@@ -38,10 +49,56 @@ pub use update::{ComponentsUpdate, EntityUpdate};
 pub const PRIVATE_KEY: &[u8; NETCODE_KEY_BYTES] = b"JKS$C14tDvez8trgbdZcIuU&wz#OjG&3"; // 32-bytes
 pub const PORT: u16 = 42069;
 
+/// How many ticks of history are kept around for rewinding and resending.
+///
+/// High latency connections need more history to rewind to the server's tick, see
+/// `fit_rtt`. Input buffers are sized by `input::InputConfig`.
+#[derive(Resource, Debug, Clone, PartialEq, Eq)]
+pub struct NetworkBufferConfig {
+    /// Ticks of snapshots kept for rewinding predicted components.
+    pub snapshots: i64,
+    /// Ticks of server updates the client keeps.
+    pub updates: i64,
+    /// Ticks the server keeps resending lost interests for before giving up on them.
+    pub resend_interests: i64,
+}
+
+impl Default for NetworkBufferConfig {
+    fn default() -> Self {
+        Self {
+            snapshots: resim::SNAPSHOT_RETAIN_BUFFER,
+            updates: resim::SNAPSHOT_RETAIN_BUFFER,
+            resend_interests: interest::RESEND_INTEREST_BUFFER,
+        }
+    }
+}
+
+impl NetworkBufferConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Grow the snapshot and update buffers to cover a couple round trips of `rtt` with
+    /// ticks of `step`, never going below the defaults.
+    pub fn fit_rtt(&mut self, rtt: Duration, step: Duration) {
+        if step.is_zero() {
+            return;
+        }
+
+        let round_trip = (rtt.as_secs_f64() / step.as_secs_f64()).ceil() as i64;
+        let needed = round_trip.saturating_mul(2);
+        let default = Self::default();
+        self.snapshots = needed.max(default.snapshots);
+        self.updates = needed.max(default.updates);
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ServerChannel {
     Message,
     EntityUpdate,
+    /// Reliable entity updates for the initial state sent to a client when it connects.
+    BaselineUpdate,
 }
 
 impl ServerChannel {
@@ -49,6 +106,7 @@ impl ServerChannel {
         match *self {
             ServerChannel::Message => 0,
             ServerChannel::EntityUpdate => 1,
+            ServerChannel::BaselineUpdate => 2,
         }
     }
 
@@ -62,11 +120,19 @@ impl ServerChannel {
                 channel_id: self.id(),
                 ..Default::default()
             }),
+            ServerChannel::BaselineUpdate => ChannelConfig::Reliable(ReliableChannelConfig {
+                channel_id: self.id(),
+                ..Default::default()
+            }),
         }
     }
 
     pub fn configs() -> Vec<ChannelConfig> {
-        let channels = vec![ServerChannel::Message, ServerChannel::EntityUpdate];
+        let channels = vec![
+            ServerChannel::Message,
+            ServerChannel::EntityUpdate,
+            ServerChannel::BaselineUpdate,
+        ];
         channels.iter().map(|channel| channel.config()).collect()
     }
 }
@@ -74,12 +140,15 @@ impl ServerChannel {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ClientChannel {
     Input,
+    /// Reliable game events, see `message::ClientMessagePlugin`.
+    Message,
 }
 
 impl ClientChannel {
     pub fn id(&self) -> u8 {
         match *self {
             ClientChannel::Input => 0,
+            ClientChannel::Message => 1,
         }
     }
 
@@ -89,11 +158,15 @@ impl ClientChannel {
                 channel_id: self.id(),
                 ..Default::default()
             }),
+            ClientChannel::Message => ChannelConfig::Reliable(ReliableChannelConfig {
+                channel_id: self.id(),
+                ..Default::default()
+            }),
         }
     }
 
     pub fn configs() -> Vec<ChannelConfig> {
-        let channels = vec![ClientChannel::Input];
+        let channels = vec![ClientChannel::Input, ClientChannel::Message];
         channels.iter().map(|channel| channel.config()).collect()
     }
 }
@@ -105,19 +178,36 @@ impl ClientChannel {
 #[derive(Debug, Deserialize, Component, Reflect)]
 pub struct Owned;
 
+/// Client that has control over this entity, e.g. whose inputs move it.
+///
+/// Inserted along with `AssignOwnership`, so server systems can find an entity's owner
+/// without going through the `Lobby`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Component, Reflect)]
+pub struct OwnedBy(pub ClientId);
+
 /// Reliable protocol from the server to the clients for communicating the
 /// overall gamestate and assigning what the clients should predict.
-#[derive(Debug, Serialize, Deserialize, Component, Reflect, FromReflect)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Component, Reflect, FromReflect)]
 pub enum ServerMessage {
+    /// Settings the client needs to match the server, sent first thing on connect.
+    ServerInfo { tick_rate_ns: u64, max_clients: u8 },
     SetPlayer { id: ClientId },
-    AssignOwnership { entity: Entity },
+    AssignOwnership { entity: Entity, client_id: ClientId },
     PlayerConnected { id: ClientId, entity: Entity },
     PlayerDisconnected { id: ClientId },
 }
 
 impl ServerMessage {
     pub fn protocol_id() -> u64 {
-        1
+        4
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("could not serialize server message")
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, SabiError> {
+        bincode::deserialize(data).map_err(|err| SabiError::Deserialize(err.to_string()))
     }
 }
 
@@ -168,10 +258,34 @@ pub fn localhost_ip() -> &'static str {
 
 /// Protocol identifier so we have more obvious breakage when we change the protocol.
 pub fn protocol_id() -> u64 {
+    protocol_id_with_compression(&codec::CompressionConfig::default())
+}
+
+/// Protocol identifier when using a different `CompressionConfig` than the default.
+pub fn protocol_id_with_compression(config: &codec::CompressionConfig) -> u64 {
+    protocol_id_with_channels(&config.codec(), config.updates, config.inputs)
+}
+
+/// Protocol identifier when using a different `NetworkCodec` than the default.
+pub fn protocol_id_with_codec(codec: &dyn codec::MessageCodec) -> u64 {
+    protocol_id_with_channels(codec, true, true)
+}
+
+fn protocol_id_with_channels(
+    codec: &dyn codec::MessageCodec,
+    compress_updates: bool,
+    compress_inputs: bool,
+) -> u64 {
     let concat = format!(
-        "server:{};entity:{};",
+        "server:{};entity:{};codec:{};dictionary:{:?};compress:{}/{};channels:{}/{};",
         ServerMessage::protocol_id().to_string(),
         EntityUpdate::protocol_id().to_string(),
+        codec.id(),
+        codec.dictionary_id(),
+        compress_updates,
+        compress_inputs,
+        ServerChannel::configs().len(),
+        ClientChannel::configs().len(),
     );
     let mut s = std::collections::hash_map::DefaultHasher::new();
     concat.hash(&mut s);
@@ -0,0 +1,329 @@
+//! Distance based relevancy of interests for each client.
+//!
+//! With a radius set in the `RelevancyPolicy`, clients are only sent components of entities
+//! within that distance of their player entity in the `Lobby`. Entities that come back into
+//! range are resent in full like a baseload, since the client missed every change while
+//! they were out of range. Clients keep whatever they last saw of entities out of range.
+//!
+//! Clients without a player entity, and entities without a `GlobalTransform`, are always
+//! relevant.
+
+use std::collections::BTreeMap;
+
+use bevy::{
+    ecs::entity::Entities,
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use super::{
+    interest::{
+        BaselineInterests, Baseload, BaseloadChunker, ClientInterestQueues, ClientKnownEntities,
+        Interest,
+    },
+    ClientId, ClientState, ReplicateId,
+};
+use crate::{lobby::Lobby, replicate::replicate_id};
+
+/// How far from a client's player entity components are replicated.
+///
+/// The default replicates everything to everyone.
+#[derive(Resource, Default, Debug, Clone)]
+pub struct RelevancyPolicy {
+    /// Radius for any components without their own radius, `None` is unlimited.
+    pub radius: Option<f32>,
+    pub component_radius: HashMap<ReplicateId, f32>,
+    /// Components that are always sent regardless of distance.
+    pub always: HashSet<ReplicateId>,
+}
+
+impl RelevancyPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_radius(mut self, radius: f32) -> Self {
+        self.radius = Some(radius);
+        self
+    }
+
+    pub fn with_component_radius<C: 'static>(mut self, radius: f32) -> Self {
+        self.component_radius.insert(replicate_id::<C>(), radius);
+        self
+    }
+
+    pub fn always_relevant<C: 'static>(mut self) -> Self {
+        self.always.insert(replicate_id::<C>());
+        self
+    }
+
+    /// Radius to replicate a component in, `None` if it is always relevant.
+    pub fn radius(&self, replicate_id: &ReplicateId) -> Option<f32> {
+        if self.always.contains(replicate_id) {
+            return None;
+        }
+
+        self.component_radius
+            .get(replicate_id)
+            .cloned()
+            .or(self.radius)
+    }
+}
+
+/// Where each client is and which of the distance limited interests are relevant to them.
+#[derive(Resource, Default, Debug, Clone)]
+pub struct ClientRelevancy {
+    origins: BTreeMap<ClientId, Vec3>,
+    /// Clients that got a player entity this tick.
+    fresh: HashSet<ClientId>,
+    relevant: BTreeMap<ClientId, HashSet<Interest>>,
+}
+
+impl ClientRelevancy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn origin(&self, client_id: &ClientId) -> Option<Vec3> {
+        self.origins.get(client_id).cloned()
+    }
+
+    pub fn is_relevant(&self, client_id: &ClientId, interest: &Interest) -> bool {
+        self.relevant
+            .get(client_id)
+            .map(|relevant| relevant.contains(interest))
+            .unwrap_or(false)
+    }
+}
+
+impl ClientState for ClientRelevancy {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.origins.remove(client_id);
+        self.fresh.remove(client_id);
+        self.relevant.remove(client_id);
+    }
+}
+
+/// Move each client's origin to their player entity.
+pub fn update_relevancy(
+    lobby: Res<Lobby>,
+    entities: &Entities,
+    mut relevancy: ResMut<ClientRelevancy>,
+    transforms: Query<&GlobalTransform>,
+) {
+    relevancy.fresh.clear();
+
+    let players = lobby
+        .iter()
+        .filter_map(|(client_id, player)| {
+            let transform = transforms.get(*player).ok()?;
+            Some((*client_id, transform.translation()))
+        })
+        .collect::<Vec<_>>();
+
+    relevancy.origins.clear();
+    for (client_id, origin) in players {
+        relevancy.origins.insert(client_id, origin);
+    }
+
+    // Anything that didn't have a player last tick was sent everything, so it has
+    // nothing to catch up on.
+    let ClientRelevancy {
+        origins,
+        fresh,
+        relevant,
+    } = &mut *relevancy;
+    relevant.retain(|client_id, _| origins.contains_key(client_id));
+    for client_id in origins.keys() {
+        if !relevant.contains_key(client_id) {
+            fresh.insert(*client_id);
+            relevant.insert(*client_id, HashSet::default());
+        }
+    }
+
+    for interests in relevant.values_mut() {
+        interests.retain(|(entity, _)| entities.contains(*entity));
+    }
+}
+
+/// Track which entities with `C` are in range of each client, queueing a full resend
+/// for any that came back into range.
+pub fn component_relevancy<C>(
+    policy: Res<RelevancyPolicy>,
+    baseload: Res<Baseload>,
+    chunker: Res<BaseloadChunker>,
+    mut relevancy: ResMut<ClientRelevancy>,
+    mut queues: ResMut<ClientInterestQueues>,
+    mut known: ResMut<ClientKnownEntities>,
+    mut baseline: ResMut<BaselineInterests>,
+    query: Query<(Entity, Option<&GlobalTransform>), With<C>>,
+) where
+    C: 'static + Component,
+{
+    let replicate_id = replicate_id::<C>();
+    let radius = match policy.radius(&replicate_id) {
+        Some(radius) => radius,
+        None => return,
+    };
+
+    let ClientRelevancy {
+        origins,
+        fresh,
+        relevant,
+    } = &mut *relevancy;
+    for (client_id, origin) in origins.iter() {
+        let relevant = relevant.entry(*client_id).or_default();
+        // Whatever is relevant is already on its way to these clients.
+        let catching_up = fresh.contains(client_id)
+            || baseload.is_loading(client_id)
+            || chunker.is_loading(client_id);
+
+        for (entity, transform) in query.iter() {
+            let interest = (entity, replicate_id);
+            let in_range = transform.map_or(true, |transform| {
+                transform.translation().distance_squared(*origin) <= radius * radius
+            });
+
+            if !in_range {
+                relevant.remove(&interest);
+                continue;
+            }
+
+            if relevant.insert(interest) && !catching_up {
+                known.insert(*client_id, entity);
+                baseline.insert(*client_id, interest);
+                queues.entry(*client_id).push_back(interest);
+            }
+        }
+    }
+}
+
+/// Drop queued interests that aren't relevant to the client.
+pub fn filter_relevancy(
+    policy: Res<RelevancyPolicy>,
+    relevancy: Res<ClientRelevancy>,
+    mut queues: ResMut<ClientInterestQueues>,
+) {
+    for (client_id, queue) in queues.iter_mut() {
+        if relevancy.origin(client_id).is_none() {
+            continue;
+        }
+
+        queue.retain(|interest| {
+            policy.radius(&interest.1).is_none() || relevancy.is_relevant(client_id, interest)
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::interest::{component_changes, MaxQueueDepth};
+
+    fn queued(world: &World, client_id: ClientId) -> HashSet<Entity> {
+        world
+            .resource::<ClientInterestQueues>()
+            .get(&client_id)
+            .unwrap()
+            .iter()
+            .map(|(entity, _)| *entity)
+            .collect()
+    }
+
+    fn spawn_at(world: &mut World, x: f32) -> Entity {
+        let transform = Transform::from_xyz(x, 0.0, 0.0);
+        world
+            .spawn((transform, GlobalTransform::from(transform)))
+            .id()
+    }
+
+    #[test]
+    pub fn far_apart_clients_are_disjoint() {
+        let mut world = World::new();
+        world.insert_resource(RelevancyPolicy::new().with_radius(50.0));
+        world.insert_resource(ClientRelevancy::new());
+        world.insert_resource(MaxQueueDepth::default());
+        world.insert_resource(Baseload::new());
+        world.insert_resource(BaseloadChunker::default());
+        world.insert_resource(ClientKnownEntities::new());
+        world.insert_resource(BaselineInterests::new());
+
+        let mut queues = ClientInterestQueues::new();
+        queues.entry(1);
+        queues.entry(2);
+        world.insert_resource(queues);
+
+        let player_1 = spawn_at(&mut world, 0.0);
+        let player_2 = spawn_at(&mut world, 1000.0);
+        let near_1 = spawn_at(&mut world, 10.0);
+        let near_2 = spawn_at(&mut world, 990.0);
+
+        let mut lobby = Lobby::default();
+        lobby.insert(1, player_1);
+        lobby.insert(2, player_2);
+        world.insert_resource(lobby);
+
+        let mut stage = SystemStage::single_threaded()
+            .with_system(update_relevancy.label("update_relevancy"))
+            .with_system(
+                component_relevancy::<Transform>
+                    .label("component_relevancy")
+                    .after("update_relevancy"),
+            )
+            .with_system(
+                component_changes::<Transform, ()>
+                    .label("component_changes")
+                    .after("component_relevancy"),
+            )
+            .with_system(filter_relevancy.after("component_changes"));
+        stage.run(&mut world);
+
+        let queued_1 = queued(&world, 1);
+        let queued_2 = queued(&world, 2);
+        assert_eq!(
+            queued_1,
+            [player_1, near_1].into_iter().collect::<HashSet<_>>()
+        );
+        assert_eq!(
+            queued_2,
+            [player_2, near_2].into_iter().collect::<HashSet<_>>()
+        );
+        assert!(queued_1.is_disjoint(&queued_2));
+
+        // Moving into client 2's range is a full resend for it.
+        for client_id in [1, 2] {
+            world
+                .resource_mut::<ClientInterestQueues>()
+                .entry(client_id)
+                .clear();
+        }
+        let moved = Transform::from_xyz(1010.0, 0.0, 0.0);
+        world
+            .entity_mut(near_1)
+            .insert((moved, GlobalTransform::from(moved)));
+        stage.run(&mut world);
+
+        assert!(queued(&world, 1).is_empty());
+        assert_eq!(
+            queued(&world, 2),
+            [near_1].into_iter().collect::<HashSet<_>>()
+        );
+        let interest = (near_1, replicate_id::<Transform>());
+        assert!(world
+            .resource_mut::<BaselineInterests>()
+            .take(&2, &interest));
+    }
+
+    #[test]
+    pub fn policy_radius() {
+        let policy = RelevancyPolicy::new()
+            .with_radius(10.0)
+            .with_component_radius::<Name>(20.0)
+            .always_relevant::<GlobalTransform>();
+
+        assert_eq!(policy.radius(&replicate_id::<Transform>()), Some(10.0));
+        assert_eq!(policy.radius(&replicate_id::<Name>()), Some(20.0));
+        assert_eq!(policy.radius(&replicate_id::<GlobalTransform>()), None);
+        assert_eq!(RelevancyPolicy::new().radius(&replicate_id::<Name>()), None);
+    }
+}
@@ -1,9 +1,13 @@
 use std::collections::BTreeMap;
 
-use bevy::{ecs::entity::Entities, prelude::*};
+use bevy::{
+    ecs::{entity::Entities, query::ReadOnlyWorldQuery},
+    prelude::*,
+};
 
-use super::NetworkTick;
+use super::{NetworkBufferConfig, NetworkTick};
 
+/// Default ticks of snapshots to keep, see `NetworkBufferConfig`.
 pub const SNAPSHOT_RETAIN_BUFFER: i64 = 64;
 
 #[derive(Deref, DerefMut, Debug)]
@@ -35,25 +39,38 @@ impl<C> SnapshotBuffer<C> {
 
     pub fn push(&mut self, tick: NetworkTick, snapshot: ComponentSnapshot<C>) {
         self.snapshots.insert(tick, snapshot);
+    }
 
-        self.clean_old();
+    pub fn get(&self, tick: &NetworkTick) -> Option<&ComponentSnapshot<C>> {
+        self.snapshots.get(tick)
     }
 
-    pub fn clean_old(&mut self) {
+    /// Drop any snapshots more than `buffer` ticks older than the newest.
+    pub fn clean_old(&mut self, buffer: i64) {
         let newest = self.snapshots.keys().max().cloned().unwrap_or_default();
 
-        self.snapshots.retain(|tick, _| {
-            (newest.tick() as i64) - (tick.tick() as i64) < SNAPSHOT_RETAIN_BUFFER
-        });
+        self.snapshots
+            .retain(|tick, _| newest.signed_diff(*tick) < buffer);
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
     }
 }
 
-pub fn store_snapshot<C>(
+/// Snapshot the predicted entities, the ones matching `F`.
+pub fn store_snapshot<C, F>(
     tick: Res<NetworkTick>,
+    buffers: Res<NetworkBufferConfig>,
     mut snapshots: ResMut<SnapshotBuffer<C>>,
-    query: Query<(Entity, &C)>,
+    query: Query<(Entity, &C), F>,
 ) where
     C: 'static + Component + Clone,
+    F: 'static + ReadOnlyWorldQuery,
 {
     let mut snapshot = ComponentSnapshot::default();
     for (entity, component) in query.iter() {
@@ -61,19 +78,97 @@ pub fn store_snapshot<C>(
     }
 
     snapshots.push(*tick, snapshot);
+    snapshots.clean_old(buffers.snapshots);
+}
+
+/// Values of a predicted resource by tick, the resource version of `SnapshotBuffer`.
+#[derive(Resource, Debug)]
+pub struct ResourceSnapshotBuffer<R> {
+    snapshots: BTreeMap<NetworkTick, R>,
+}
+
+impl<R> Default for ResourceSnapshotBuffer<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R> ResourceSnapshotBuffer<R> {
+    pub fn new() -> Self {
+        Self {
+            snapshots: Default::default(),
+        }
+    }
+
+    pub fn push(&mut self, tick: NetworkTick, snapshot: R) {
+        self.snapshots.insert(tick, snapshot);
+    }
+
+    pub fn get(&self, tick: &NetworkTick) -> Option<&R> {
+        self.snapshots.get(tick)
+    }
+
+    /// Drop any snapshots more than `buffer` ticks older than the newest.
+    pub fn clean_old(&mut self, buffer: i64) {
+        let newest = self.snapshots.keys().max().cloned().unwrap_or_default();
+
+        self.snapshots
+            .retain(|tick, _| newest.signed_diff(*tick) < buffer);
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+}
+
+/// Snapshot a predicted resource.
+pub fn store_resource_snapshot<R>(
+    tick: Res<NetworkTick>,
+    buffers: Res<NetworkBufferConfig>,
+    resource: Res<R>,
+    mut snapshots: ResMut<ResourceSnapshotBuffer<R>>,
+) where
+    R: Resource + Clone,
+{
+    snapshots.push(*tick, resource.clone());
+    snapshots.clean_old(buffers.snapshots);
+}
+
+/// Put a predicted resource back to how it was at the tick we are rewinding to.
+pub fn rewind_resource<R>(
+    tick: Res<NetworkTick>,
+    snapshots: Res<ResourceSnapshotBuffer<R>>,
+    mut resource: ResMut<R>,
+) where
+    R: Resource + Clone,
+{
+    match snapshots.get(&*tick) {
+        Some(snapshot) => *resource = snapshot.clone(),
+        None => error!("no snapshot for resource: {:?}", std::any::type_name::<R>()),
+    }
 }
 
-pub fn rewind<C>(
+/// Put predicted entities back to how they were at the tick we are rewinding to.
+///
+/// Entities that don't match `F` (anymore) are left alone so they keep the latest state
+/// the server sent instead of being resimulated without their inputs.
+pub fn rewind<C, F>(
     mut commands: Commands,
     entities: &Entities,
     tick: Res<NetworkTick>,
     snapshots: Res<SnapshotBuffer<C>>,
+    predicted: Query<(), F>,
 ) where
     C: 'static + Component + Clone,
+    F: 'static + ReadOnlyWorldQuery,
 {
     if let Some(snapshot) = snapshots.snapshots.get(&*tick) {
         for (entity, component) in snapshot.0.iter() {
-            if entities.contains(*entity) {
+            if entities.contains(*entity) && predicted.contains(*entity) {
                 commands.entity(*entity).insert(component.clone());
             }
         }
@@ -84,3 +179,157 @@ pub fn rewind<C>(
         );
     }
 }
+
+/// Remove components that were added after the snapshot we are rewinding to.
+///
+/// `rewind` only puts back what was in the snapshot, so without this anything added
+/// since then would stick around through the resimulation.
+pub fn rewind_remove<C, F>(
+    mut commands: Commands,
+    tick: Res<NetworkTick>,
+    snapshots: Res<SnapshotBuffer<C>>,
+    query: Query<Entity, (With<C>, F)>,
+) where
+    C: 'static + Component + Clone,
+    F: 'static + ReadOnlyWorldQuery,
+{
+    // `rewind` already complains about missing snapshots, and removing everything
+    // because of one would be worse than leaving it.
+    if let Some(snapshot) = snapshots.get(&*tick) {
+        for entity in query.iter() {
+            if !snapshot.contains_key(&entity) {
+                commands.entity(entity).remove::<C>();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Component, Clone, Debug, PartialEq)]
+    struct Effect(u32);
+
+    #[derive(Component)]
+    struct Predicted;
+
+    #[test]
+    pub fn rewind_removes_new_components() {
+        let mut world = World::new();
+        let existing = world.spawn(Effect(1)).id();
+
+        let mut snapshot = ComponentSnapshot::default();
+        snapshot.insert(existing, Effect(0));
+        let mut snapshots = SnapshotBuffer::<Effect>::new();
+        snapshots.push(NetworkTick::new(5), snapshot);
+        world.insert_resource(snapshots);
+        world.insert_resource(NetworkTick::new(5));
+
+        // Added after the snapshot was taken.
+        let added = world.spawn(Effect(2)).id();
+
+        let mut stage = SystemStage::single_threaded()
+            .with_system(rewind::<Effect, ()>)
+            .with_system(rewind_remove::<Effect, ()>);
+        stage.run(&mut world);
+
+        assert_eq!(world.get::<Effect>(existing), Some(&Effect(0)));
+        assert_eq!(world.get::<Effect>(added), None);
+    }
+
+    #[test]
+    pub fn rewind_only_predicted() {
+        let mut world = World::new();
+        let predicted = world.spawn((Effect(0), Predicted)).id();
+        let remote = world.spawn(Effect(0)).id();
+        world.insert_resource(NetworkTick::new(5));
+        world.insert_resource(NetworkBufferConfig::default());
+        world.insert_resource(SnapshotBuffer::<Effect>::new());
+
+        let mut store =
+            SystemStage::single_threaded().with_system(store_snapshot::<Effect, With<Predicted>>);
+        store.run(&mut world);
+
+        let snapshot = world
+            .resource::<SnapshotBuffer<Effect>>()
+            .get(&NetworkTick::new(5))
+            .unwrap();
+        assert!(snapshot.contains_key(&predicted));
+        assert!(!snapshot.contains_key(&remote));
+
+        // Server moved the remote entity since, it shouldn't get snapped back or removed.
+        world.entity_mut(predicted).insert(Effect(1));
+        world.entity_mut(remote).insert(Effect(2));
+
+        let mut rewind_stage = SystemStage::single_threaded()
+            .with_system(rewind::<Effect, With<Predicted>>)
+            .with_system(rewind_remove::<Effect, With<Predicted>>);
+        rewind_stage.run(&mut world);
+
+        assert_eq!(world.get::<Effect>(predicted), Some(&Effect(0)));
+        assert_eq!(world.get::<Effect>(remote), Some(&Effect(2)));
+    }
+
+    #[test]
+    pub fn configurable_retain() {
+        let mut world = World::new();
+        world.spawn((Effect(0), Predicted));
+        world.insert_resource(NetworkBufferConfig {
+            snapshots: 4,
+            ..Default::default()
+        });
+        world.insert_resource(SnapshotBuffer::<Effect>::new());
+
+        let mut store =
+            SystemStage::single_threaded().with_system(store_snapshot::<Effect, With<Predicted>>);
+        for tick in 0..10 {
+            world.insert_resource(NetworkTick::new(tick));
+            store.run(&mut world);
+        }
+
+        let snapshots = world.resource::<SnapshotBuffer<Effect>>();
+        assert_eq!(snapshots.len(), 4);
+        assert!(snapshots.get(&NetworkTick::new(5)).is_none());
+        assert!(snapshots.get(&NetworkTick::new(6)).is_some());
+
+        // A 250ms round trip at 50hz is 13 ticks, still under the defaults.
+        let step = crate::tick::tick_hz(50);
+        let mut buffers = NetworkBufferConfig::new();
+        buffers.fit_rtt(std::time::Duration::from_millis(250), step);
+        assert_eq!(buffers, NetworkBufferConfig::default());
+
+        buffers.fit_rtt(std::time::Duration::from_millis(1000), step);
+        assert_eq!(buffers.snapshots, 100);
+        assert_eq!(buffers.updates, 100);
+    }
+
+    #[derive(Resource, Clone, Debug, PartialEq)]
+    struct Seed(u64);
+
+    #[test]
+    pub fn rewind_resources() {
+        let mut world = World::new();
+        world.insert_resource(NetworkBufferConfig::default());
+        world.insert_resource(ResourceSnapshotBuffer::<Seed>::new());
+
+        let mut store = SystemStage::single_threaded().with_system(store_resource_snapshot::<Seed>);
+        for tick in 1..=3 {
+            world.insert_resource(NetworkTick::new(tick));
+            world.insert_resource(Seed(tick * 100));
+            store.run(&mut world);
+        }
+        assert_eq!(world.resource::<ResourceSnapshotBuffer<Seed>>().len(), 3);
+
+        // Resimulating from tick 2 starts from the same seed it had then.
+        world.insert_resource(NetworkTick::new(2));
+        let mut rewind = SystemStage::single_threaded().with_system(rewind_resource::<Seed>);
+        rewind.run(&mut world);
+        assert_eq!(world.resource::<Seed>(), &Seed(200));
+
+        // Nothing to go back to, leave it be.
+        world.insert_resource(NetworkTick::new(10));
+        rewind.run(&mut world);
+        assert_eq!(world.resource::<Seed>(), &Seed(200));
+    }
+}
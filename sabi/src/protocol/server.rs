@@ -1,5 +1,5 @@
-use bevy::prelude::*;
-use bevy_renet::renet::{RenetServer, ServerAuthentication, ServerConfig};
+use bevy::{ecs::event::ManualEventReader, prelude::*};
+use bevy_renet::renet::{RenetServer, ServerAuthentication, ServerConfig, ServerEvent};
 
 use std::{
     error::Error,
@@ -11,10 +11,22 @@ use std::time::SystemTime;
 
 use crate::protocol::*;
 
+/// Maximum number of clients connected to a server at once.
+pub const MAX_CLIENTS: usize = 10;
+
 pub fn new_renet_server<S: AsRef<str>>(
+    local_ip: S,
+    public_ip: Option<String>,
+    port: u16,
+) -> Result<RenetServer, Box<dyn Error>> {
+    new_renet_server_with_protocol(local_ip, public_ip, port, crate::protocol::protocol_id())
+}
+
+pub fn new_renet_server_with_protocol<S: AsRef<str>>(
     local_ip: S,
     mut public_ip: Option<String>,
     port: u16,
+    protocol_id: u64,
 ) -> Result<RenetServer, Box<dyn Error>> {
     let local_ip = local_ip.as_ref();
 
@@ -70,7 +82,6 @@ pub fn new_renet_server<S: AsRef<str>>(
         .ok_or(SabiError::NoSocketAddr)?;
 
     println!("binding to {:?}", server_addr);
-    let protocol_id = crate::protocol::protocol_id();
     println!("protocol id: {:?}", protocol_id,);
 
     let socket = UdpSocket::bind(local_addr)?;
@@ -78,7 +89,7 @@ pub fn new_renet_server<S: AsRef<str>>(
 
     let connection_config = crate::protocol::server_renet_config();
     let server_config = ServerConfig {
-        max_clients: 10,
+        max_clients: MAX_CLIENTS,
         protocol_id: protocol_id,
         public_addr: server_addr,
         authentication: ServerAuthentication::Secure {
@@ -93,3 +104,481 @@ pub fn new_renet_server<S: AsRef<str>>(
         socket,
     )?)
 }
+
+/// Send a reliable message to a specific client.
+pub fn send_server_message(server: &mut RenetServer, client_id: ClientId, message: &ServerMessage) {
+    server.send_message(client_id, ServerChannel::Message.id(), message.encode());
+}
+
+/// Send a reliable message to every connected client.
+pub fn broadcast_server_message(server: &mut RenetServer, message: &ServerMessage) {
+    server.broadcast_message(ServerChannel::Message.id(), message.encode());
+}
+
+/// Messages for game code to send to clients, sent by `server_send_messages`.
+#[derive(Resource, Default, Debug, Clone)]
+pub struct ServerMessages {
+    /// `None` goes to every connected client.
+    messages: Vec<(Option<ClientId>, ServerMessage)>,
+}
+
+impl ServerMessages {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn send(&mut self, client_id: ClientId, message: ServerMessage) {
+        self.messages.push((Some(client_id), message));
+    }
+
+    pub fn broadcast(&mut self, message: ServerMessage) {
+        self.messages.push((None, message));
+    }
+
+    pub fn drain(&mut self) -> std::vec::Drain<'_, (Option<ClientId>, ServerMessage)> {
+        self.messages.drain(..)
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+}
+
+pub fn server_send_messages(mut messages: ResMut<ServerMessages>, mut server: ResMut<RenetServer>) {
+    for (client_id, message) in messages.drain() {
+        match client_id {
+            Some(client_id) => send_server_message(&mut *server, client_id, &message),
+            None => broadcast_server_message(&mut *server, &message),
+        }
+    }
+}
+
+/// Server resource that keeps state per client.
+pub trait ClientState {
+    /// Drop everything kept for this client.
+    fn forget_client(&mut self, client_id: &ClientId);
+}
+
+/// Every `ClientState` resource to drop a client from once it disconnects.
+#[derive(Resource, Default, Clone)]
+pub struct ClientStates {
+    forgets: Vec<fn(&mut World, &ClientId)>,
+}
+
+impl ClientStates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<R>(&mut self)
+    where
+        R: Resource + ClientState,
+    {
+        self.forgets.push(forget_client::<R>);
+    }
+
+    pub fn len(&self) -> usize {
+        self.forgets.len()
+    }
+}
+
+fn forget_client<R>(world: &mut World, client_id: &ClientId)
+where
+    R: Resource + ClientState,
+{
+    if let Some(mut state) = world.get_resource_mut::<R>() {
+        state.forget_client(client_id);
+    }
+}
+
+/// Drop the state kept for clients once they disconnect so it doesn't leak.
+///
+/// Renet's events are cleared every network tick, so this needs to run in the meta stage
+/// before the clear or a frame with multiple ticks can miss the disconnect.
+pub fn forget_disconnected(
+    world: &mut World,
+    mut server_events: Local<ManualEventReader<ServerEvent>>,
+) {
+    let disconnected = match world.get_resource::<Events<ServerEvent>>() {
+        Some(events) => server_events
+            .iter(events)
+            .filter_map(|event| match event {
+                ServerEvent::ClientDisconnected(client_id) => Some(*client_id),
+                _ => None,
+            })
+            .collect::<Vec<_>>(),
+        None => return,
+    };
+
+    if disconnected.is_empty() {
+        return;
+    }
+
+    let forgets = match world.get_resource::<ClientStates>() {
+        Some(states) => states.forgets.clone(),
+        None => return,
+    };
+    for client_id in disconnected {
+        for forget in &forgets {
+            forget(world, &client_id);
+        }
+    }
+}
+
+/// Spawns the player entity for a newly connected client.
+#[derive(Resource)]
+pub struct PlayerSpawner(pub Box<dyn Fn(&mut Commands, ClientId) -> Entity + Send + Sync>);
+
+impl PlayerSpawner {
+    pub fn new<F>(spawner: F) -> Self
+    where
+        F: 'static + Fn(&mut Commands, ClientId) -> Entity + Send + Sync,
+    {
+        Self(Box::new(spawner))
+    }
+
+    pub fn spawn(&self, commands: &mut Commands, client_id: ClientId) -> Entity {
+        (self.0)(commands, client_id)
+    }
+}
+
+/// Give newly connected clients a player entity and let everyone know about it.
+///
+/// If there is no `PlayerSpawner` then an empty entity is used for the player. Messages go
+/// through `ServerMessages` so they are sent by `server_send_messages` later in the frame.
+/// Like `forget_disconnected` this runs in the meta stage before renet's events are cleared.
+pub fn server_handle_connections(
+    mut commands: Commands,
+    spawner: Option<Res<PlayerSpawner>>,
+    sim_info: Res<crate::stage::NetworkSimulationInfo>,
+    mut lobby: ResMut<Lobby>,
+    mut messages: ResMut<ServerMessages>,
+    mut server_events: EventReader<ServerEvent>,
+    mut connected: EventWriter<PlayerConnected>,
+    mut disconnected: EventWriter<PlayerDisconnected>,
+) {
+    for event in server_events.iter() {
+        match event {
+            ServerEvent::ClientConnected(client_id, _user_data) => {
+                let entity = match spawner {
+                    Some(ref spawner) => spawner.spawn(&mut commands, *client_id),
+                    None => commands.spawn_empty().id(),
+                };
+
+                info!("player {} connected as {:?}", client_id, entity);
+                commands.entity(entity).insert(PlayerInfo::new(*client_id));
+
+                messages.send(
+                    *client_id,
+                    ServerMessage::ServerInfo {
+                        tick_rate_ns: sim_info.step.as_nanos() as u64,
+                        max_clients: MAX_CLIENTS as u8,
+                    },
+                );
+
+                for (other_id, other_entity) in lobby.iter() {
+                    messages.send(
+                        *client_id,
+                        ServerMessage::PlayerConnected {
+                            id: *other_id,
+                            entity: *other_entity,
+                        },
+                    );
+                }
+
+                lobby.insert(*client_id, entity);
+                connected.send(PlayerConnected {
+                    client_id: *client_id,
+                    entity,
+                });
+
+                messages.send(*client_id, ServerMessage::SetPlayer { id: *client_id });
+                messages.send(
+                    *client_id,
+                    ServerMessage::AssignOwnership {
+                        entity,
+                        client_id: *client_id,
+                    },
+                );
+                commands.entity(entity).insert(OwnedBy(*client_id));
+                messages.broadcast(ServerMessage::PlayerConnected {
+                    id: *client_id,
+                    entity,
+                });
+            }
+            ServerEvent::ClientDisconnected(client_id) => {
+                info!("player {} disconnected", client_id);
+
+                if let Some(entity) = lobby.remove(client_id) {
+                    commands.entity(entity).despawn_recursive();
+                }
+                disconnected.send(PlayerDisconnected {
+                    client_id: *client_id,
+                });
+
+                messages.broadcast(ServerMessage::PlayerDisconnected { id: *client_id });
+            }
+        }
+    }
+}
+
+/// Copy each client's ping onto their player's `PlayerInfo`.
+pub fn update_player_info(
+    lobby: Res<Lobby>,
+    history: Res<crate::protocol::input::ClientReceivedHistory>,
+    mut players: Query<&mut PlayerInfo>,
+) {
+    for (client_id, entity) in lobby.iter() {
+        let ping_ms = match history.ping_ms(client_id) {
+            Some(ping_ms) => ping_ms,
+            None => continue,
+        };
+
+        if let Ok(mut info) = players.get_mut(*entity) {
+            if info.ping_ms != ping_ms {
+                info.ping_ms = ping_ms;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn disconnect_forgets_client() {
+        use crate::protocol::interest::ClientKnownEntities;
+
+        let mut world = World::new();
+        world.init_resource::<Events<ServerEvent>>();
+        let mut known = ClientKnownEntities::new();
+        known.insert(1, Entity::from_raw(1));
+        known.insert(2, Entity::from_raw(2));
+        world.insert_resource(known);
+
+        let mut states = ClientStates::new();
+        states.register::<ClientKnownEntities>();
+        world.insert_resource(states);
+
+        let mut stage = SystemStage::single_threaded().with_system(forget_disconnected);
+        world
+            .resource_mut::<Events<ServerEvent>>()
+            .send(ServerEvent::ClientDisconnected(1));
+        stage.run(&mut world);
+
+        let known = world.resource::<ClientKnownEntities>();
+        assert!(!known.knows(&1, &Entity::from_raw(1)));
+        assert!(known.knows(&2, &Entity::from_raw(2)));
+    }
+
+    #[test]
+    pub fn disconnect_survives_multiple_ticks() {
+        use crate::protocol::interest::ClientKnownEntities;
+        use crate::stage::{NetworkCoreStage, NetworkSimulationInfo, NetworkSimulationStage};
+
+        let step = crate::tick::tick_hz(32);
+        let mut world = World::new();
+        world.insert_resource(Time::default());
+        world.insert_resource(crate::Server);
+        world.insert_resource(crate::tick::NetworkTick::new(0));
+        world.init_resource::<Events<ServerEvent>>();
+
+        // Several ticks on one frame, each of which clears renet's events.
+        let mut info = NetworkSimulationInfo::new(step);
+        info.accumulator = step * 3;
+        world.insert_resource(info);
+
+        let mut known = ClientKnownEntities::new();
+        known.insert(1, Entity::from_raw(1));
+        world.insert_resource(known);
+        let mut states = ClientStates::new();
+        states.register::<ClientKnownEntities>();
+        world.insert_resource(states);
+
+        let mut stage = NetworkSimulationStage::new(step);
+        stage
+            .schedule
+            .add_stage(NetworkCoreStage::Update, SystemStage::parallel());
+        stage
+            .meta
+            .add_system(forget_disconnected.before("clear_server_events"))
+            .add_system(Events::<ServerEvent>::update_system.label("clear_server_events"));
+
+        world
+            .resource_mut::<Events<ServerEvent>>()
+            .send(ServerEvent::ClientDisconnected(1));
+        stage.run(&mut world);
+
+        assert_eq!(world.resource::<crate::tick::NetworkTick>().tick(), 3);
+        let known = world.resource::<ClientKnownEntities>();
+        assert!(!known.knows(&1, &Entity::from_raw(1)));
+    }
+
+    #[test]
+    pub fn connections_assign_players() {
+        use bevy_renet::renet::NETCODE_USER_DATA_BYTES;
+
+        #[derive(Component)]
+        struct Spawned;
+
+        let mut world = World::new();
+        world.init_resource::<Events<ServerEvent>>();
+        world.init_resource::<Events<PlayerConnected>>();
+        world.init_resource::<Events<PlayerDisconnected>>();
+        world.init_resource::<Lobby>();
+        world.init_resource::<ServerMessages>();
+        world.insert_resource(crate::stage::NetworkSimulationInfo::new(
+            crate::tick::tick_hz(32),
+        ));
+
+        let mut stage = SystemStage::single_threaded().with_system(server_handle_connections);
+        let mut connect = |world: &mut World, client_id: ClientId| {
+            world
+                .resource_mut::<Events<ServerEvent>>()
+                .send(ServerEvent::ClientConnected(
+                    client_id,
+                    Box::new([0; NETCODE_USER_DATA_BYTES]),
+                ));
+            stage.run(world);
+            world
+                .resource_mut::<ServerMessages>()
+                .drain()
+                .collect::<Vec<_>>()
+        };
+
+        // Without a spawner the player is an empty entity.
+        let messages = connect(&mut world, 1);
+        let first = world.resource::<Lobby>().entity(&1).expect("in the lobby");
+        assert_eq!(world.get::<OwnedBy>(first), Some(&OwnedBy(1)));
+        assert!(world.get::<PlayerInfo>(first).is_some());
+        assert!(messages.contains(&(Some(1), ServerMessage::SetPlayer { id: 1 })));
+        assert!(messages.contains(&(
+            Some(1),
+            ServerMessage::AssignOwnership {
+                entity: first,
+                client_id: 1,
+            }
+        )));
+        assert!(messages.contains(&(
+            None,
+            ServerMessage::PlayerConnected {
+                id: 1,
+                entity: first,
+            }
+        )));
+
+        world.insert_resource(PlayerSpawner::new(|commands, _client_id| {
+            commands.spawn(Spawned).id()
+        }));
+        let messages = connect(&mut world, 2);
+        let second = world.resource::<Lobby>().entity(&2).expect("in the lobby");
+        assert!(world.get::<Spawned>(second).is_some());
+        assert_eq!(world.get::<OwnedBy>(second), Some(&OwnedBy(2)));
+
+        // The new player hears about the players already connected.
+        assert!(messages.contains(&(
+            Some(2),
+            ServerMessage::PlayerConnected {
+                id: 1,
+                entity: first,
+            }
+        )));
+
+        let events = world.resource::<Events<PlayerConnected>>();
+        let connected = events
+            .get_reader()
+            .iter(events)
+            .map(|connected| (connected.client_id, connected.entity))
+            .collect::<Vec<_>>();
+        assert_eq!(connected, vec![(1, first), (2, second)]);
+    }
+
+    #[test]
+    pub fn connection_survives_multiple_ticks() {
+        use crate::protocol::interest::{setup_baseload, Baseload};
+        use crate::stage::{NetworkCoreStage, NetworkSimulationInfo, NetworkSimulationStage};
+        use bevy_renet::renet::NETCODE_USER_DATA_BYTES;
+
+        let step = crate::tick::tick_hz(32);
+        let mut world = World::new();
+        world.insert_resource(Time::default());
+        world.insert_resource(crate::Server);
+        world.insert_resource(crate::tick::NetworkTick::new(0));
+        world.init_resource::<Events<ServerEvent>>();
+        world.init_resource::<Events<PlayerConnected>>();
+        world.init_resource::<Events<PlayerDisconnected>>();
+        world.init_resource::<Lobby>();
+        world.init_resource::<ServerMessages>();
+        world.init_resource::<Baseload>();
+
+        let mut info = NetworkSimulationInfo::new(step);
+        info.accumulator = step * 3;
+        world.insert_resource(info);
+
+        let mut stage = NetworkSimulationStage::new(step);
+        stage
+            .schedule
+            .add_stage(NetworkCoreStage::Update, SystemStage::parallel());
+        stage
+            .meta
+            .add_system(setup_baseload.before("clear_server_events"))
+            .add_system(server_handle_connections.before("clear_server_events"))
+            .add_system(Events::<ServerEvent>::update_system.label("clear_server_events"));
+
+        world
+            .resource_mut::<Events<ServerEvent>>()
+            .send(ServerEvent::ClientConnected(
+                1,
+                Box::new([0; NETCODE_USER_DATA_BYTES]),
+            ));
+        stage.run(&mut world);
+
+        assert_eq!(world.resource::<crate::tick::NetworkTick>().tick(), 3);
+        assert!(world.resource::<Lobby>().entity(&1).is_some());
+        assert!(world.resource::<Baseload>().is_loading(&1));
+        let messages = world
+            .resource_mut::<ServerMessages>()
+            .drain()
+            .collect::<Vec<_>>();
+        assert!(messages.contains(&(Some(1), ServerMessage::SetPlayer { id: 1 })));
+    }
+
+    #[test]
+    pub fn player_info_ping() {
+        use crate::protocol::input::ClientReceivedHistory;
+
+        let mut world = World::new();
+        let player = world.spawn(PlayerInfo::new(3)).id();
+        let mut lobby = Lobby::default();
+        lobby.insert(3, player);
+        world.insert_resource(lobby);
+
+        let mut history = ClientReceivedHistory::new();
+        history.set_ping(3, 48);
+        world.insert_resource(history);
+
+        let mut stage = SystemStage::single_threaded().with_system(update_player_info);
+        stage.run(&mut world);
+
+        assert_eq!(world.get::<PlayerInfo>(player).unwrap().ping_ms, 48);
+    }
+
+    #[test]
+    pub fn queued_messages_keep_order() {
+        let mut messages = ServerMessages::new();
+        messages.send(1, ServerMessage::SetPlayer { id: 1 });
+        messages.broadcast(ServerMessage::PlayerDisconnected { id: 2 });
+        assert_eq!(messages.len(), 2);
+
+        let drained = messages.drain().collect::<Vec<_>>();
+        assert_eq!(
+            drained,
+            vec![
+                (Some(1), ServerMessage::SetPlayer { id: 1 }),
+                (None, ServerMessage::PlayerDisconnected { id: 2 }),
+            ]
+        );
+        assert_eq!(messages.len(), 0);
+    }
+}
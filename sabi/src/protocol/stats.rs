@@ -0,0 +1,314 @@
+//! Bandwidth and replication numbers over the last few ticks.
+//!
+//! Everything is bucketed by tick and only the last `window` ticks are kept, so recording
+//! is a couple of map lookups and these can be left on in release builds.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
+use bevy::prelude::*;
+
+use super::{ClientId, ClientState, NetworkTick, ReplicateId};
+
+/// How many ticks of stats are kept by default.
+pub const DEFAULT_STATS_WINDOW: usize = 64;
+
+/// Bytes and updates for a single component.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComponentStats {
+    /// Serialized bytes before compression.
+    pub bytes: usize,
+    pub updates: u32,
+}
+
+/// Everything recorded on one tick.
+#[derive(Debug, Clone)]
+pub struct TickStats {
+    pub tick: NetworkTick,
+    /// Bytes of messages on the wire.
+    pub bytes: usize,
+    /// Bytes of those same messages before compression.
+    pub uncompressed: usize,
+    /// Messages dropped for being over `MAX_UPDATE_BYTES`.
+    pub dropped: u32,
+    pub components: BTreeMap<ReplicateId, ComponentStats>,
+}
+
+impl TickStats {
+    pub fn new(tick: NetworkTick) -> Self {
+        Self {
+            tick: tick,
+            bytes: 0,
+            uncompressed: 0,
+            dropped: 0,
+            components: BTreeMap::new(),
+        }
+    }
+}
+
+/// Sliding window of the last few ticks of stats.
+#[derive(Default, Debug, Clone)]
+pub struct StatsWindow {
+    ticks: VecDeque<TickStats>,
+}
+
+impl StatsWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stats for `tick`, starting a new bucket and dropping the oldest past `window`.
+    pub fn tick(&mut self, tick: NetworkTick, window: usize) -> &mut TickStats {
+        match self.ticks.iter().rposition(|stats| stats.tick == tick) {
+            Some(index) => &mut self.ticks[index],
+            None => {
+                self.ticks.push_back(TickStats::new(tick));
+                while self.ticks.len() > window.max(1) {
+                    self.ticks.pop_front();
+                }
+                self.ticks.back_mut().expect("just pushed")
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TickStats> {
+        self.ticks.iter()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.ticks.iter().map(|stats| stats.bytes).sum()
+    }
+
+    pub fn dropped(&self) -> u32 {
+        self.ticks.iter().map(|stats| stats.dropped).sum()
+    }
+
+    /// Wire bytes per second, assuming a tick every `step`.
+    pub fn bytes_per_second(&self, step: Duration) -> f32 {
+        let (first, last) = match (self.ticks.front(), self.ticks.back()) {
+            (Some(first), Some(last)) => (first.tick, last.tick),
+            _ => return 0.0,
+        };
+
+        let ticks = last.signed_diff(first).max(0) as f32 + 1.0;
+        self.bytes() as f32 / (ticks * step.as_secs_f32())
+    }
+
+    /// Uncompressed bytes over wire bytes, `None` if nothing was sent.
+    pub fn compression_ratio(&self) -> Option<f32> {
+        let bytes = self.bytes();
+        if bytes == 0 {
+            return None;
+        }
+
+        let uncompressed: usize = self.ticks.iter().map(|stats| stats.uncompressed).sum();
+        Some(uncompressed as f32 / bytes as f32)
+    }
+
+    pub fn component(&self, replicate_id: &ReplicateId) -> ComponentStats {
+        let mut total = ComponentStats::default();
+        for stats in self.ticks.iter() {
+            if let Some(component) = stats.components.get(replicate_id) {
+                total.bytes += component.bytes;
+                total.updates += component.updates;
+            }
+        }
+        total
+    }
+
+    /// Components that took up the most bytes, largest first.
+    pub fn top_components(&self, n: usize) -> Vec<(ReplicateId, ComponentStats)> {
+        let mut totals = BTreeMap::<ReplicateId, ComponentStats>::new();
+        for stats in self.ticks.iter() {
+            for (replicate_id, component) in stats.components.iter() {
+                let total = totals.entry(*replicate_id).or_default();
+                total.bytes += component.bytes;
+                total.updates += component.updates;
+            }
+        }
+
+        let mut totals = totals.into_iter().collect::<Vec<_>>();
+        totals.sort_by(|(_, a), (_, b)| b.bytes.cmp(&a.bytes));
+        totals.truncate(n);
+        totals
+    }
+}
+
+/// What the server sent to each client over the last `window` ticks.
+#[derive(Resource, Debug, Clone)]
+pub struct ReplicationStats {
+    pub window: usize,
+    /// Time between ticks for per second rates, kept up to date by `server_send_interest`.
+    pub step: Duration,
+    clients: BTreeMap<ClientId, StatsWindow>,
+}
+
+impl Default for ReplicationStats {
+    fn default() -> Self {
+        Self {
+            window: DEFAULT_STATS_WINDOW,
+            step: Duration::from_secs_f64(1.0 / 60.0),
+            clients: BTreeMap::new(),
+        }
+    }
+}
+
+impl ReplicationStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = window;
+        self
+    }
+
+    pub fn tick(&mut self, client_id: ClientId, tick: NetworkTick) -> &mut TickStats {
+        self.clients
+            .entry(client_id)
+            .or_default()
+            .tick(tick, self.window)
+    }
+
+    pub fn record_component(
+        &mut self,
+        client_id: ClientId,
+        tick: NetworkTick,
+        replicate_id: ReplicateId,
+        bytes: usize,
+    ) {
+        let component = self
+            .tick(client_id, tick)
+            .components
+            .entry(replicate_id)
+            .or_default();
+        component.bytes += bytes;
+        component.updates += 1;
+    }
+
+    pub fn client(&self, client_id: &ClientId) -> Option<&StatsWindow> {
+        self.clients.get(client_id)
+    }
+
+    pub fn bytes_per_second(&self, client_id: &ClientId) -> f32 {
+        self.client(client_id)
+            .map(|stats| stats.bytes_per_second(self.step))
+            .unwrap_or(0.0)
+    }
+
+    pub fn compression_ratio(&self, client_id: &ClientId) -> Option<f32> {
+        self.client(client_id)?.compression_ratio()
+    }
+
+    pub fn dropped(&self, client_id: &ClientId) -> u32 {
+        self.client(client_id)
+            .map(|stats| stats.dropped())
+            .unwrap_or(0)
+    }
+
+    pub fn top_components(
+        &self,
+        client_id: &ClientId,
+        n: usize,
+    ) -> Vec<(ReplicateId, ComponentStats)> {
+        self.client(client_id)
+            .map(|stats| stats.top_components(n))
+            .unwrap_or_default()
+    }
+}
+
+impl ClientState for ReplicationStats {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+    }
+}
+
+/// Wire bytes and component bytes the client received over the last `window` ticks.
+#[derive(Resource, Debug, Clone)]
+pub struct ReceivedStats {
+    pub window: usize,
+    /// Time between ticks for per second rates, kept up to date by `client_recv_interest`.
+    pub step: Duration,
+    stats: StatsWindow,
+}
+
+impl Default for ReceivedStats {
+    fn default() -> Self {
+        Self {
+            window: DEFAULT_STATS_WINDOW,
+            step: Duration::from_secs_f64(1.0 / 60.0),
+            stats: StatsWindow::new(),
+        }
+    }
+}
+
+impl ReceivedStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tick(&mut self, tick: NetworkTick) -> &mut TickStats {
+        self.stats.tick(tick, self.window)
+    }
+
+    pub fn stats(&self) -> &StatsWindow {
+        &self.stats
+    }
+
+    pub fn bytes_per_second(&self) -> f32 {
+        self.stats.bytes_per_second(self.step)
+    }
+
+    pub fn top_components(&self, n: usize) -> Vec<(ReplicateId, ComponentStats)> {
+        self.stats.top_components(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn sliding_window() {
+        let mut stats = ReplicationStats::new().with_window(4);
+        stats.step = Duration::from_millis(20);
+        for tick in 0..8 {
+            let tick = NetworkTick::new(tick);
+            stats.record_component(1, tick, ReplicateId(1), 10);
+            stats.record_component(1, tick, ReplicateId(2), 30);
+            stats.record_component(1, tick, ReplicateId(2), 30);
+
+            let sent = stats.tick(1, tick);
+            sent.bytes += 50;
+            sent.uncompressed += 100;
+        }
+        stats.tick(1, NetworkTick::new(7)).dropped += 1;
+
+        // Only the last 4 ticks are kept: 200 bytes over 80ms.
+        let client = stats.client(&1).unwrap();
+        assert_eq!(client.iter().count(), 4);
+        assert_eq!(client.bytes(), 200);
+        assert!((stats.bytes_per_second(&1) - 2500.0).abs() < 0.01);
+        assert_eq!(stats.compression_ratio(&1), Some(2.0));
+        assert_eq!(stats.dropped(&1), 1);
+
+        let top = stats.top_components(&1, 1);
+        assert_eq!(
+            top,
+            vec![(
+                ReplicateId(2),
+                ComponentStats {
+                    bytes: 240,
+                    updates: 8
+                }
+            )]
+        );
+        assert_eq!(client.component(&ReplicateId(1)).updates, 4);
+
+        assert_eq!(stats.bytes_per_second(&2), 0.0);
+        stats.forget_client(&1);
+        assert!(stats.client(&1).is_none());
+    }
+}
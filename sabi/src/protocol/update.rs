@@ -5,9 +5,9 @@ use std::{
 };
 
 use bevy::{
-    ecs::entity::Entities,
+    ecs::{entity::Entities, query::ReadOnlyWorldQuery, system::EntityCommands},
     prelude::*,
-    reflect::serde::{ReflectSerializer, UntypedReflectDeserializer},
+    reflect::TypeRegistry,
 };
 use bevy_renet::renet::{RenetClient, RenetServer};
 
@@ -15,23 +15,43 @@ use crate::{
     prelude::*,
     stage::{NetworkSimulationInfo, Rewind},
 };
-use serde::{de::DeserializeSeed, Deserialize, Serialize};
+use serde::{Deserialize, Serialize};
 
 use super::{
+    ack::{ClientAcks, NetworkAck, ReceivedTicks},
+    codec::{
+        ComponentCodec, CompressionConfig, MalformedMessage, MaxMessageBytes, MessageCodec,
+        NetworkCodec, PassthroughCodec, ReplicationCodec,
+    },
+    delta::{
+        ClientSentBaselines, DeltaBaseline, DeltaComponents, Diffable, ReceivedBaselines,
+        DELTA_HISTORY,
+    },
     demands::ReplicateSizeEstimates,
-    input::{ClientReceivedHistory, InputDeviation},
-    interest::InterestsToSend,
-    ClientId, NetworkTick,
+    despawn::{ClientComponentRemovals, ClientEntityDespawns},
+    input::{AckedInputs, ClientInputAcks, ClientReceivedHistory, InputDeviation},
+    interest::{
+        BaselineInterests, ClientInterestQueues, ClientUnackedInterests, Interest, InterestsToSend,
+        ReliableComponents,
+    },
+    stats::{ReceivedStats, ReplicationStats, TickStats},
+    version::{ClientSentVersions, ReceivedVersions, ReplicateVersion, VersionCheck},
+    ClientId, ClientState, NetworkBufferConfig, NetworkTick,
 };
 
+/// Largest compressed update we send in one message, anything larger is split up.
+pub const MAX_UPDATE_BYTES: usize = 3000;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpdateMessage {
     pub tick: NetworkTick,
     pub input_deviation: InputDeviation,
+    /// Input ticks the server received from this client.
+    pub input_ack: NetworkAck,
     pub entity_update: EntityUpdate,
 
     // Clean up stragglers.
-    pub component_despawn: Vec<(Entity, ReplicateId)>,
+    pub component_despawn: Vec<(Entity, ReplicateId, ReplicateVersion)>,
     pub entity_despawn: Vec<Entity>,
 }
 
@@ -42,12 +62,27 @@ impl UpdateMessage {
         }
 
         self.entity_update.apply(other.entity_update);
+        self.input_ack.apply_ack(&other.input_ack);
+
+        for despawn in other.component_despawn {
+            if !self.component_despawn.contains(&despawn) {
+                self.component_despawn.push(despawn);
+            }
+        }
+
+        for despawn in other.entity_despawn {
+            if !self.entity_despawn.contains(&despawn) {
+                self.entity_despawn.push(despawn);
+            }
+        }
     }
 }
 
 #[derive(Resource, Default, Debug, Clone)]
 pub struct ClientEntityUpdates {
     clients: BTreeMap<ClientId, EntityUpdate>,
+    /// Updates from a baseload, sent reliably.
+    baseline: BTreeMap<ClientId, EntityUpdate>,
 }
 
 impl ClientEntityUpdates {
@@ -74,6 +109,31 @@ impl ClientEntityUpdates {
     pub fn upsert(&mut self, client_id: ClientId) -> &mut EntityUpdate {
         self.clients.entry(client_id).or_default()
     }
+
+    pub fn baseline(&self, client_id: &ClientId) -> Option<&EntityUpdate> {
+        self.baseline.get(client_id)
+    }
+
+    pub fn upsert_baseline(&mut self, client_id: ClientId) -> &mut EntityUpdate {
+        self.baseline.entry(client_id).or_default()
+    }
+
+    pub fn clear(&mut self) {
+        for (_, update) in self.clients.iter_mut() {
+            update.clear();
+        }
+
+        for (_, update) in self.baseline.iter_mut() {
+            update.clear();
+        }
+    }
+}
+
+impl ClientState for ClientEntityUpdates {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+        self.baseline.remove(client_id);
+    }
 }
 
 #[derive(Resource, Deref, DerefMut, Default, Clone, Serialize, Deserialize)]
@@ -89,7 +149,7 @@ impl fmt::Debug for EntityUpdate {
             for (replicate_id, _) in component_update.iter() {
                 let count = counts
                     .entry(*replicate_id)
-                    .or_insert_with(|| (replicate_id.name(), 0));
+                    .or_insert_with(|| (replicate_name(replicate_id), 0));
 
                 count.1 += 1;
             }
@@ -133,8 +193,17 @@ impl EntityUpdate {
     }
 }
 
+/// Serialized component along with the version it was sent as.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ComponentData {
+    pub version: ReplicateVersion,
+    /// `data` is a delta against this version, see `protocol::delta`.
+    pub delta_from: Option<ReplicateVersion>,
+    pub data: Vec<u8>,
+}
+
 #[derive(Resource, Default, Deref, DerefMut, Clone, PartialEq, Serialize, Deserialize)]
-pub struct ComponentsUpdate(pub BTreeMap<ReplicateId, Vec<u8>>);
+pub struct ComponentsUpdate(pub BTreeMap<ReplicateId, ComponentData>);
 
 impl ComponentsUpdate {
     pub fn new() -> Self {
@@ -146,6 +215,13 @@ impl ComponentsUpdate {
     }
 }
 
+/// Name of the replicated type, or just the id if we don't know about it.
+fn replicate_name(replicate_id: &ReplicateId) -> String {
+    replicate_id
+        .try_name()
+        .unwrap_or_else(|| format!("{:?}", replicate_id))
+}
+
 impl Debug for ComponentsUpdate {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_tuple("ComponentsUpdate")
@@ -156,11 +232,11 @@ impl Debug for ComponentsUpdate {
 
 impl EntityUpdate {
     pub fn protocol_id() -> u64 {
-        1
+        7
     }
 }
 
-#[derive(Resource, Debug, Clone)]
+#[derive(Resource, Default, Debug, Clone)]
 pub struct UpdateMessages {
     messages: BTreeMap<NetworkTick, UpdateMessage>,
 }
@@ -192,13 +268,19 @@ impl UpdateMessages {
     }
 
     /// Retain any in the queue that are within a buffer range.
-    pub fn retain(&mut self) {
+    pub fn retain(&mut self, buffer: i64) {
         let newest = self.latest().cloned().unwrap_or_default();
 
-        self.messages.retain(|tick, _| {
-            (newest.tick() as i64) - (tick.tick() as i64)
-                < crate::protocol::resim::SNAPSHOT_RETAIN_BUFFER
-        });
+        self.messages
+            .retain(|tick, _| newest.signed_diff(*tick) < buffer);
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
     }
 }
 
@@ -209,8 +291,13 @@ pub fn client_frame_buffer(
 ) -> f32 {
     let info = client.network_info();
 
-    // 2nd standard deviation so its ~2.1% chance we fall outside of it.
-    let deviation = deviation.deviation * 2.0;
+    // 2nd standard deviation so its ~2.1% chance we fall outside of it, the first few
+    // samples are too noisy to go by.
+    let deviation = if deviation.is_warm() {
+        deviation.deviation * 2.0
+    } else {
+        0.0
+    };
     let extra_buffer = sim_info.step.as_secs_f32() * 3.0;
     (info.rtt / 2.0) / 1000.0 + deviation + extra_buffer
 }
@@ -219,35 +306,111 @@ pub fn client_recv_interest(
     tick: Option<Res<NetworkTick>>,
     mut commands: Commands,
     mut network_sim_info: ResMut<NetworkSimulationInfo>,
+    buffers: Res<NetworkBufferConfig>,
     mut server_updates: ResMut<UpdateMessages>,
     mut server_entities: ResMut<ServerEntities>,
+    mut versions: ResMut<ReceivedVersions>,
+    mut baselines: ResMut<ReceivedBaselines>,
+    mut received: ResMut<ReceivedTicks>,
+    mut acked_inputs: ResMut<AckedInputs>,
+    delta: Res<DeltaComponents>,
+    (mut codec, compression, max_bytes): (
+        ResMut<NetworkCodec>,
+        Res<CompressionConfig>,
+        Res<MaxMessageBytes>,
+    ),
+    mut stats: ResMut<ReceivedStats>,
+    mut malformed: EventWriter<MalformedMessage>,
     mut client: ResMut<RenetClient>,
 ) {
+    stats.step = network_sim_info.step;
     let mut rewind: Option<NetworkTick> = None;
 
-    while let Some(message) = client.receive_message(ServerChannel::EntityUpdate.id()) {
-        /*
-        let dict = crate::message_sample::DICTIONARIES
-            .get("update")
-            .expect("no update dictionary");
-        let mut decompressor =
-            zstd::bulk::Decompressor::with_dictionary(dict).expect("couldn't make decompressor");
-        */
-        let mut decompressor = zstd::bulk::Decompressor::new().expect("couldn't make decompressor");
+    let mut messages = Vec::new();
+    for channel in [ServerChannel::BaselineUpdate, ServerChannel::EntityUpdate] {
+        while let Some(message) = client.receive_message(channel.id()) {
+            messages.push((channel, message));
+        }
+    }
+
+    let mut passthrough = PassthroughCodec;
+    let codec: &mut dyn MessageCodec = if compression.updates {
+        &mut **codec
+    } else {
+        &mut passthrough
+    };
+
+    for (channel, bytes) in messages {
+        let mut message = match decode_update(codec, &bytes, *max_bytes) {
+            Ok(message) => message,
+            Err(err) => {
+                error!("dropping update from server: {}", err);
+                malformed.send(MalformedMessage {
+                    client_id: None,
+                    channel: channel.id(),
+                    error: err,
+                });
+                continue;
+            }
+        };
+        received.receive(&message.tick);
+        acked_inputs.apply_ack(&message.input_ack);
+
+        let received_stats = stats.tick(message.tick);
+        received_stats.bytes += bytes.len();
+        for (_, components) in message.entity_update.iter() {
+            for (replicate_id, component) in components.iter() {
+                let component_stats = received_stats.components.entry(*replicate_id).or_default();
+                component_stats.bytes += component.data.len();
+                component_stats.updates += 1;
+            }
+        }
+
+        // Drop anything that arrived out of order, gaps are requested again from the server.
+        for (server_entity, components) in message.entity_update.iter_mut() {
+            components.retain(|replicate_id, component| {
+                let interest = (*server_entity, *replicate_id);
+                if versions.receive(interest, component.version) == VersionCheck::Stale {
+                    return false;
+                }
 
-        let decompressed = decompressor
-            .decompress(&message.as_slice(), 10 * 1024)
-            .expect("could not decompress message");
+                if let Some(from) = component.delta_from.take() {
+                    match baselines.rebuild(&interest, from, &component.data) {
+                        Some(data) => component.data = data,
+                        None => {
+                            versions.request_resend(interest);
+                            return false;
+                        }
+                    }
+                }
 
-        let message: UpdateMessage = bincode::deserialize(&decompressed).unwrap();
+                if delta.contains(replicate_id) {
+                    baselines.insert(
+                        interest,
+                        DeltaBaseline {
+                            version: component.version,
+                            data: component.data.clone(),
+                        },
+                    );
+                }
+
+                true
+            });
+        }
+
+        message
+            .component_despawn
+            .retain(|(server_entity, replicate_id, version)| {
+                versions.receive((*server_entity, *replicate_id), *version) != VersionCheck::Stale
+            });
 
         let frame_buffer =
             client_frame_buffer(&*network_sim_info, &client, &message.input_deviation);
 
         match tick {
             Some(ref tick) => {
-                let diff = (tick.tick() as i64 - message.tick.tick() as i64) as f32
-                    * network_sim_info.step.as_secs_f32();
+                let diff =
+                    tick.signed_diff(message.tick) as f32 * network_sim_info.step.as_secs_f32();
                 if diff > frame_buffer {
                     network_sim_info.decel(0.01);
                 } else if diff < frame_buffer {
@@ -278,6 +441,7 @@ pub fn client_recv_interest(
 
         server_updates.push(message);
     }
+    server_updates.retain(buffers.updates);
 
     if let Some(rewind) = rewind {
         commands.insert_resource(Rewind(rewind));
@@ -287,10 +451,17 @@ pub fn client_recv_interest(
 pub fn client_apply_server_update(
     tick: Res<NetworkTick>,
     server_updates: Res<UpdateMessages>,
-    mut update_events: EventWriter<(Entity, ComponentsUpdate)>,
+    mut update_events: EventWriter<(ServerEntity, ComponentsUpdate)>,
 ) {
     if let Some(update) = server_updates.get(&*tick) {
-        update_events.send_batch(update.entity_update.updates.clone().into_iter());
+        update_events.send_batch(
+            update
+                .entity_update
+                .updates
+                .clone()
+                .into_iter()
+                .map(|(entity, components)| (ServerEntity::from_entity(entity), components)),
+        );
     } else {
         //info!("no server update for tick: {}", tick.tick())
     }
@@ -298,6 +469,7 @@ pub fn client_apply_server_update(
 
 pub fn client_update<C>(
     type_registry: Res<AppTypeRegistry>,
+    codec: Res<ComponentCodec>,
     mut commands: Commands,
     entities: &Entities,
     server_entities: Res<ServerEntities>,
@@ -307,11 +479,16 @@ pub fn client_update<C>(
     C: 'static + Component + Reflect + FromReflect + Clone,
 {
     let type_registry = type_registry.read();
+    let replicate_id = replicate_id::<C>();
     for (server_entity, components_update) in update_events.iter() {
-        if let Some(update_data) = components_update.get(&crate::replicate_id::<C>()) {
-            let reflect_deserializer = UntypedReflectDeserializer::new(&type_registry);
-            let mut deserializer = ron::de::Deserializer::from_bytes(&update_data).unwrap();
-            let reflect_value = reflect_deserializer.deserialize(&mut deserializer).unwrap();
+        if let Some(update_data) = components_update.get(&replicate_id) {
+            let reflect_value = match codec.decode(&update_data.data, &type_registry) {
+                Ok(reflect_value) => reflect_value,
+                Err(err) => {
+                    error!("dropping {}: {}", std::any::type_name::<C>(), err);
+                    continue;
+                }
+            };
 
             if let Some(entity) = server_entities.get(entities, *server_entity) {
                 if let Ok(mut component) = query.get_mut(entity) {
@@ -332,45 +509,234 @@ pub fn client_update<C>(
     }
 }
 
-pub fn server_clear_queue(mut updates: ResMut<ClientEntityUpdates>) {
-    for (_client_id, update) in updates.iter_mut() {
-        update.clear();
+/// Serialize a component the same way it is sent over the network.
+pub fn serialize_component<C>(
+    component: &C,
+    codec: &dyn ReplicationCodec,
+    type_registry: &TypeRegistry,
+) -> Vec<u8>
+where
+    C: 'static + Component + Reflect + FromReflect + Clone,
+{
+    codec
+        .encode(component, type_registry)
+        .unwrap_or_else(|err| panic!("{}: {}", std::any::type_name::<C>(), err))
+}
+
+type SerializeFn = fn(&World, Entity, &dyn ReplicationCodec, &TypeRegistry) -> Option<Vec<u8>>;
+
+/// Type erased serialization of replicated components so we can inspect an entity's
+/// replicated state without knowing its component types.
+#[derive(Resource, Default)]
+pub struct ComponentSerializers {
+    serializers: BTreeMap<ReplicateId, SerializeFn>,
+}
+
+impl ComponentSerializers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<C>(&mut self)
+    where
+        C: 'static + Component + Reflect + FromReflect + Clone,
+    {
+        self.serializers.insert(
+            replicate_id::<C>(),
+            |world, entity, codec, type_registry| {
+                world
+                    .get::<C>(entity)
+                    .map(|component| serialize_component(component, codec, type_registry))
+            },
+        );
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ReplicateId, &SerializeFn)> {
+        self.serializers.iter()
+    }
+}
+
+/// Replicated components of an entity serialized exactly as they would be sent.
+///
+/// Mostly useful for tests and debugging tools.
+pub fn replicated_state(world: &World, entity: Entity) -> BTreeMap<ReplicateId, Vec<u8>> {
+    let type_registry = world.resource::<AppTypeRegistry>().read();
+    let serializers = world.resource::<ComponentSerializers>();
+    let codec = world.resource::<ComponentCodec>();
+
+    serializers
+        .iter()
+        .filter_map(|(replicate_id, serialize)| {
+            serialize(world, entity, &**codec, &type_registry).map(|data| (*replicate_id, data))
+        })
+        .collect()
+}
+
+/// Type erased removal of replicated components so we can act on a `ReplicateId`.
+#[derive(Resource, Default)]
+pub struct ComponentRemovers {
+    removers: BTreeMap<ReplicateId, fn(&mut EntityCommands)>,
+}
+
+impl ComponentRemovers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<C>(&mut self)
+    where
+        C: 'static + Component + Reflect + FromReflect + Clone,
+    {
+        self.removers.insert(replicate_id::<C>(), |entity| {
+            entity.remove::<C>();
+        });
+    }
+
+    pub fn remove(&self, entity: &mut EntityCommands, replicate_id: &ReplicateId) -> bool {
+        match self.removers.get(replicate_id) {
+            Some(remover) => {
+                remover(entity);
+                true
+            }
+            None => false,
+        }
     }
 }
 
-pub fn server_queue_interest<C>(
+/// Despawn entities and remove components the server told us about.
+pub fn client_apply_despawns(
+    tick: Res<NetworkTick>,
+    mut commands: Commands,
+    entities: &Entities,
+    removers: Res<ComponentRemovers>,
+    mut server_entities: ResMut<ServerEntities>,
+    mut versions: ResMut<ReceivedVersions>,
+    mut baselines: ResMut<ReceivedBaselines>,
+    server_updates: Res<UpdateMessages>,
+) {
+    let update = match server_updates.get(&*tick) {
+        Some(update) => update,
+        None => return,
+    };
+
+    for (server_entity, replicate_id, _) in update.component_despawn.iter() {
+        let server_entity = ServerEntity::from_entity(*server_entity);
+        if let Some(entity) = server_entities.get(entities, server_entity) {
+            if !removers.remove(&mut commands.entity(entity), replicate_id) {
+                error!("no remover registered for {:?}", replicate_id);
+            }
+        }
+    }
+
+    for server_entity in update.entity_despawn.iter() {
+        if let Some(entity) = server_entities.remove(ServerEntity::from_entity(*server_entity)) {
+            if entities.contains(entity) {
+                commands.entity(entity).despawn_recursive();
+            }
+        }
+
+        versions.forget_entity(*server_entity);
+        baselines.forget_entity(*server_entity);
+    }
+}
+
+pub fn server_clear_queue(mut updates: ResMut<ClientEntityUpdates>) {
+    updates.clear();
+}
+
+pub fn server_queue_interest<C, F>(
+    tick: Res<NetworkTick>,
     type_registry: Res<AppTypeRegistry>,
+    codec: Res<ComponentCodec>,
     mut estimate: ResMut<ReplicateSizeEstimates>,
     mut updates: ResMut<ClientEntityUpdates>,
+    mut versions: ResMut<ClientSentVersions>,
+    mut baseline: ResMut<BaselineInterests>,
+    delta: Res<DeltaComponents>,
+    acks: Res<ClientAcks>,
+    mut sent_baselines: ResMut<ClientSentBaselines>,
+    mut stats: ResMut<ReplicationStats>,
     to_send: Res<InterestsToSend>,
-    query: Query<&C>,
+    reliable: Res<ReliableComponents>,
+    query: Query<&C, F>,
 ) where
     C: 'static + Component + Reflect + FromReflect + Clone,
+    F: 'static + ReadOnlyWorldQuery,
 {
     let type_registry = type_registry.read();
+    let component_id = replicate_id::<C>();
+    let reliable = reliable.contains(&component_id);
 
     for (client_id, interests) in to_send.iter() {
-        let entity_update = updates.upsert(*client_id);
+        updates.upsert(*client_id);
         for (entity, replicate_id) in interests.iter() {
-            if *replicate_id == crate::replicate_id::<C>() {
+            if *replicate_id == component_id {
                 if let Ok(component) = query.get(*entity) {
-                    let serializer = ReflectSerializer::new(component, &type_registry);
-                    let component_data = ron::ser::to_string(&serializer).unwrap().into_bytes();
+                    let component_data = serialize_component(component, &**codec, &type_registry);
 
                     if component_data.len() > 1000 {
                         warn!(
                             "component serialized to a large object: {:?}, {:?}",
                             entity,
-                            replicate_id.name()
+                            replicate_name(replicate_id)
                         );
                     }
 
-                    estimate.add(crate::replicate_id::<C>(), component_data.len());
+                    let interest = (*entity, *replicate_id);
+                    let version = versions.bump(*client_id, interest);
+                    let is_baseline = baseline.take(client_id, &interest);
+
+                    // Baseloads are sent in full so the client has something to build on,
+                    // otherwise diff against the newest version the client acked if it is
+                    // still in the client's history.
+                    let mut delta_from = None;
+                    let mut data = component_data.clone();
+                    if !is_baseline && delta.worth_it(&component_id, &*estimate) {
+                        let acked = acks
+                            .get(client_id)
+                            .and_then(|ack| sent_baselines.acked(client_id, &interest, ack));
+                        if let Some(base) = acked {
+                            if version.0.wrapping_sub(base.version.0) < DELTA_HISTORY {
+                                if let Some(encoded) = component_data.diff(&base.data) {
+                                    delta_from = Some(base.version);
+                                    data = encoded;
+                                }
+                            }
+                        }
+                    }
+
+                    // Only becomes a baseline once `server_send_interest` actually sends it.
+                    if delta.contains(&component_id) {
+                        sent_baselines.queue(
+                            *client_id,
+                            interest,
+                            *tick,
+                            DeltaBaseline {
+                                version,
+                                data: component_data.clone(),
+                            },
+                        );
+                    }
+
+                    estimate.add(component_id, component_data.len());
+                    stats.record_component(*client_id, *tick, component_id, data.len());
 
+                    let entity_update = if is_baseline || reliable {
+                        updates.upsert_baseline(*client_id)
+                    } else {
+                        updates.upsert(*client_id)
+                    };
                     let update = entity_update
                         .entry(*entity)
                         .or_insert(ComponentsUpdate::new());
-                    update.insert(crate::replicate_id::<C>(), component_data);
+                    update.insert(
+                        component_id,
+                        ComponentData {
+                            version,
+                            delta_from,
+                            data,
+                        },
+                    );
                 }
             }
         }
@@ -380,24 +746,89 @@ pub fn server_queue_interest<C>(
 pub fn server_send_interest(
     tick: Res<NetworkTick>,
     mut history: ResMut<ClientReceivedHistory>,
+    input_acks: Res<ClientInputAcks>,
     updates: Res<ClientEntityUpdates>,
+    mut despawns: ResMut<ClientEntityDespawns>,
+    mut removals: ResMut<ClientComponentRemovals>,
+    to_send: Res<InterestsToSend>,
+    estimates: Res<ReplicateSizeEstimates>,
+    mut codec: ResMut<NetworkCodec>,
+    compression: Res<CompressionConfig>,
+    sim_info: Res<NetworkSimulationInfo>,
+    mut stats: ResMut<ReplicationStats>,
+    mut queues: ResMut<ClientInterestQueues>,
+    mut baseline: ResMut<BaselineInterests>,
+    (mut versions, mut sent_baselines, mut unacked): (
+        ResMut<ClientSentVersions>,
+        ResMut<ClientSentBaselines>,
+        ResMut<ClientUnackedInterests>,
+    ),
     mut server: ResMut<RenetServer>,
 ) {
-    /*
-       let dict = crate::message_sample::DICTIONARIES
-           .get("update")
-           .expect("no update dictionary");
-       let mut compressor =
-           zstd::bulk::Compressor::with_dictionary(0, dict).expect("couldn't make compressor");
-    */
-    let mut compressor = zstd::bulk::Compressor::new(0).expect("couldn't make compressor");
+    stats.step = sim_info.step;
+
+    let mut passthrough = PassthroughCodec;
+    let codec: &mut dyn MessageCodec = if compression.updates {
+        &mut **codec
+    } else {
+        &mut passthrough
+    };
 
     for (client_id, update) in updates.iter() {
-        if !server.can_send_message(*client_id, ServerChannel::EntityUpdate.id()) {
-            continue;
+        let input_ack = input_acks.get(client_id).cloned().unwrap_or_default();
+
+        let estimate = to_send.client_size_estimate(client_id, &*estimates);
+        if estimate > MAX_UPDATE_BYTES * 9 / 10 {
+            warn!(
+                "update for client {} is estimated at {} bytes, it will likely be split",
+                client_id, estimate
+            );
         }
 
-        if update.iter().count() == 0 {
+        let sent_stats = stats.tick(*client_id, *tick);
+        let mut sent = Vec::new();
+
+        if let Some(baseline_update) = updates.baseline(client_id) {
+            if !baseline_update.is_empty() {
+                let message = UpdateMessage {
+                    tick: *tick,
+                    input_deviation: history.deviation(*client_id),
+                    input_ack: input_ack.clone(),
+                    entity_update: baseline_update.clone(),
+
+                    component_despawn: Vec::new(),
+                    entity_despawn: Vec::new(),
+                };
+
+                let channel = ServerChannel::BaselineUpdate.id();
+                let encoded = encode_update(codec, message);
+                let result = send_encoded(encoded, sent_stats, |message| {
+                    if !server.can_send_message(*client_id, channel) {
+                        return false;
+                    }
+                    server.send_message(*client_id, channel, message);
+                    true
+                });
+
+                unbump_unsent(&mut *versions, *client_id, baseline_update, &result);
+                for interest in result.dropped.iter() {
+                    sent_baselines.forget(client_id, interest);
+                }
+
+                // Still needs to go out in full when there is room.
+                let queue = queues.entry(*client_id);
+                for interest in result.unsent.into_iter().rev() {
+                    baseline.insert(*client_id, interest);
+                    queue.push_front(interest);
+                }
+                sent.extend(result.sent);
+            }
+        }
+
+        let entity_despawn = despawns.get(client_id);
+        let component_despawn = removals.get(client_id);
+        if update.is_empty() && entity_despawn.is_empty() && component_despawn.is_empty() {
+            sent_baselines.confirm_sent(*client_id, &sent);
             continue;
         }
 
@@ -408,26 +839,608 @@ pub fn server_send_interest(
         // check the size of each individual component to find outliers.
         let message = UpdateMessage {
             tick: *tick,
-            input_deviation: input_deviation,
+            input_deviation,
+            input_ack,
             entity_update: update.clone(),
 
+            component_despawn,
+            entity_despawn,
+        };
+
+        let channel = ServerChannel::EntityUpdate.id();
+        let encoded = encode_update(codec, message);
+        let mut any_sent = false;
+        let result = send_encoded(encoded, sent_stats, |message| {
+            if !server.can_send_message(*client_id, channel) {
+                return false;
+            }
+            server.send_message(*client_id, channel, message);
+            any_sent = true;
+            true
+        });
+
+        // Despawns are in the first message, resent until the client acks this tick.
+        if any_sent {
+            despawns.sent(client_id, *tick);
+            removals.sent(client_id, *tick);
+        }
+
+        unbump_unsent(&mut *versions, *client_id, update, &result);
+        for interest in result.dropped.iter() {
+            sent_baselines.forget(client_id, interest);
+        }
+
+        // Whatever didn't fit is sent again as soon as possible instead of being cleared
+        // with the rest of this tick's updates.
+        let queue = queues.entry(*client_id);
+        for interest in result.unsent.into_iter().rev() {
+            queue.push_front(interest);
+        }
+
+        // `ServerChannel::BaselineUpdate` is reliable, only these can be lost and resent.
+        if !result.sent.is_empty() {
+            unacked.record(*client_id, *tick, result.sent.clone());
+        }
+        sent.extend(result.sent);
+
+        // Only what the client can actually receive is diffed against later.
+        sent_baselines.confirm_sent(*client_id, &sent);
+    }
+}
+
+/// Interests that didn't go out never reached the client, so their versions are rolled
+/// back instead of leaving a gap the client would ask to have resent.
+fn unbump_unsent(
+    versions: &mut ClientSentVersions,
+    client_id: ClientId,
+    update: &EntityUpdate,
+    result: &SentInterests,
+) {
+    for interest in result.unsent.iter().chain(result.dropped.iter()) {
+        let (entity, replicate_id) = interest;
+        let sent_version = update
+            .get(entity)
+            .and_then(|components| components.get(replicate_id))
+            .map(|component| component.version);
+        if let Some(version) = sent_version {
+            versions.unbump(&client_id, interest, version);
+        }
+    }
+}
+
+/// What happened to the interests of an `EncodedUpdate`, see `send_encoded`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct SentInterests {
+    pub sent: Vec<Interest>,
+    /// In messages that there wasn't room for, these should be queued again.
+    pub unsent: Vec<Interest>,
+    /// Too large to send at all.
+    pub dropped: Vec<Interest>,
+}
+
+/// Hand each message to `send` until it refuses one, the interests of messages after
+/// that are returned as unsent.
+pub fn send_encoded(
+    encoded: EncodedUpdate,
+    stats: &mut TickStats,
+    mut send: impl FnMut(Vec<u8>) -> bool,
+) -> SentInterests {
+    stats.dropped += encoded.dropped.len() as u32;
+
+    let mut result = SentInterests {
+        dropped: encoded.dropped,
+        ..Default::default()
+    };
+    let mut full = false;
+    for message in encoded.messages {
+        let bytes = message.data.len();
+        if !full && send(message.data) {
+            stats.bytes += bytes;
+            stats.uncompressed += message.uncompressed;
+            result.sent.extend(message.interests);
+        } else {
+            full = true;
+            result.unsent.extend(message.interests);
+        }
+    }
+
+    result
+}
+
+/// A compressed update message ready to send.
+#[derive(Debug, Clone)]
+pub struct EncodedMessage {
+    pub data: Vec<u8>,
+    /// Serialized bytes before compression.
+    pub uncompressed: usize,
+    /// Components in this message, to queue them again if it can't be sent.
+    pub interests: Vec<Interest>,
+}
+
+/// Update message split into chunks that fit in `MAX_UPDATE_BYTES`.
+#[derive(Default, Debug, Clone)]
+pub struct EncodedUpdate {
+    pub messages: Vec<EncodedMessage>,
+    /// Components that were dropped for being too large even on their own.
+    pub dropped: Vec<Interest>,
+}
+
+impl EncodedUpdate {
+    pub fn bytes(&self) -> usize {
+        self.messages.iter().map(|message| message.data.len()).sum()
+    }
+}
+
+/// Serialize and compress an update, splitting it into more messages for the same tick
+/// if it is too large. The client merges them back together in `UpdateMessages`.
+///
+/// Large entities are split up by component, components that are still too large on
+/// their own are dropped.
+pub fn encode_update(codec: &mut dyn MessageCodec, message: UpdateMessage) -> EncodedUpdate {
+    let mut encoded = EncodedUpdate::default();
+    encode_update_chunks(codec, message, &mut encoded);
+    encoded
+}
+
+fn encode_update_chunks(
+    codec: &mut dyn MessageCodec,
+    mut message: UpdateMessage,
+    encoded: &mut EncodedUpdate,
+) {
+    let serialized = bincode::serialize(&message).unwrap();
+
+    //crate::message_sample::try_add_sample("update", &serialized);
+    let compressed = codec
+        .compress(&serialized.as_slice())
+        .expect("couldn't compress message");
+
+    if compressed.len() < MAX_UPDATE_BYTES {
+        encoded.messages.push(EncodedMessage {
+            data: compressed,
+            uncompressed: serialized.len(),
+            interests: update_interests(&message.entity_update),
+        });
+        return;
+    }
+
+    let entities = message.entity_update.len();
+    let components = message
+        .entity_update
+        .iter()
+        .map(|(_, components)| components.len())
+        .sum::<usize>();
+    if components <= 1 {
+        warn!(
+            "dropping update for tick {}, {} bytes is too large to send",
+            message.tick.tick(),
+            compressed.len()
+        );
+        encoded
+            .dropped
+            .extend(update_interests(&message.entity_update));
+        return;
+    }
+
+    let second_half = if entities > 1 {
+        let middle = *message
+            .entity_update
+            .keys()
+            .nth(entities / 2)
+            .expect("more than one entity");
+        message.entity_update.split_off(&middle)
+    } else {
+        let (entity, components) = message.entity_update.iter_mut().next().expect("one entity");
+        let middle = *components
+            .keys()
+            .nth(components.len() / 2)
+            .expect("more than one component");
+        let mut second_half = BTreeMap::new();
+        second_half.insert(*entity, ComponentsUpdate(components.split_off(&middle)));
+        second_half
+    };
+
+    // Despawns only need to go out once, so they stay with the first half.
+    let second = UpdateMessage {
+        tick: message.tick,
+        input_deviation: message.input_deviation.clone(),
+        input_ack: message.input_ack.clone(),
+        entity_update: EntityUpdate {
+            updates: second_half,
+        },
+        component_despawn: Vec::new(),
+        entity_despawn: Vec::new(),
+    };
+
+    encode_update_chunks(codec, message, encoded);
+    encode_update_chunks(codec, second, encoded);
+}
+
+fn update_interests(update: &EntityUpdate) -> Vec<Interest> {
+    update
+        .iter()
+        .flat_map(|(entity, components)| {
+            components
+                .keys()
+                .map(move |replicate_id| (*entity, *replicate_id))
+        })
+        .collect()
+}
+
+/// Decompress and deserialize an update from the server within the size limit.
+pub fn decode_update(
+    codec: &mut dyn MessageCodec,
+    message: &[u8],
+    max_bytes: MaxMessageBytes,
+) -> Result<UpdateMessage, SabiError> {
+    let decompressed = codec.decompress(message, max_bytes.0)?;
+    bincode::deserialize(&decompressed).map_err(|err| SabiError::Deserialize(err.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Component, Reflect, FromReflect, Default, Clone)]
+    #[reflect(Component)]
+    struct InspectHealth(u32);
+
+    #[test]
+    pub fn replicated_state_matches_update() {
+        let replicate_id = replicate_id::<InspectHealth>();
+
+        let mut world = World::new();
+        let type_registry = AppTypeRegistry::default();
+        type_registry.write().register::<InspectHealth>();
+        world.insert_resource(type_registry);
+
+        let mut serializers = ComponentSerializers::new();
+        serializers.register::<InspectHealth>();
+        world.insert_resource(serializers);
+        world.insert_resource(ComponentCodec::default());
+
+        world.insert_resource(ReplicateSizeEstimates::new());
+        world.insert_resource(ClientEntityUpdates::new());
+        world.insert_resource(ClientSentVersions::new());
+        world.insert_resource(BaselineInterests::new());
+        world.insert_resource(DeltaComponents::new());
+        world.insert_resource(ClientSentBaselines::new());
+        world.insert_resource(ReplicationStats::new());
+        world.insert_resource(ClientAcks::new());
+        world.insert_resource(ReliableComponents::new());
+        world.insert_resource(NetworkTick::new(0));
+
+        let entity = world.spawn(InspectHealth(42)).id();
+        let mut to_send = InterestsToSend::new();
+        to_send.push(1, (entity, replicate_id));
+        world.insert_resource(to_send);
+
+        let mut stage =
+            SystemStage::single_threaded().with_system(server_queue_interest::<InspectHealth, ()>);
+        stage.run(&mut world);
+
+        let sent = world
+            .resource::<ClientEntityUpdates>()
+            .get(&1)
+            .and_then(|update| update.updates.get(&entity))
+            .and_then(|components| components.get(&replicate_id))
+            .map(|component| component.data.clone())
+            .expect("component was queued");
+
+        let state = replicated_state(&world, entity);
+        assert_eq!(state.len(), 1);
+        assert_eq!(state.get(&replicate_id), Some(&sent));
+
+        let stats = world.resource::<ReplicationStats>();
+        let top = stats.top_components(&1, 1);
+        assert_eq!(top[0].0, replicate_id);
+        assert_eq!(top[0].1.bytes, sent.len());
+        assert_eq!(top[0].1.updates, 1);
+    }
+
+    #[test]
+    pub fn reliable_component_channel() {
+        let replicate_id = replicate_id::<InspectHealth>();
+
+        let mut world = World::new();
+        let type_registry = AppTypeRegistry::default();
+        type_registry.write().register::<InspectHealth>();
+        world.insert_resource(type_registry);
+        world.insert_resource(ComponentCodec::default());
+
+        world.insert_resource(ReplicateSizeEstimates::new());
+        world.insert_resource(ClientEntityUpdates::new());
+        world.insert_resource(ClientSentVersions::new());
+        world.insert_resource(BaselineInterests::new());
+        world.insert_resource(DeltaComponents::new());
+        world.insert_resource(ClientSentBaselines::new());
+        world.insert_resource(ReplicationStats::new());
+        world.insert_resource(ClientAcks::new());
+        world.insert_resource(NetworkTick::new(0));
+
+        let mut reliable = ReliableComponents::new();
+        reliable.insert(replicate_id);
+        world.insert_resource(reliable);
+
+        let entity = world.spawn(InspectHealth(42)).id();
+        let mut to_send = InterestsToSend::new();
+        to_send.push(1, (entity, replicate_id));
+        world.insert_resource(to_send);
+
+        let mut stage =
+            SystemStage::single_threaded().with_system(server_queue_interest::<InspectHealth, ()>);
+        stage.run(&mut world);
+
+        // Goes out on `ServerChannel::BaselineUpdate` even though it isn't from a baseload.
+        let updates = world.resource::<ClientEntityUpdates>();
+        assert!(updates
+            .baseline(&1)
+            .and_then(|update| update.updates.get(&entity))
+            .map(|components| components.contains_key(&replicate_id))
+            .unwrap_or(false));
+        assert!(updates.get(&1).unwrap().updates.get(&entity).is_none());
+    }
+
+    #[test]
+    pub fn decode_within_limit() {
+        let mut codec = crate::protocol::codec::ZstdCodec::default();
+        let mut despawns = Vec::new();
+        despawns.resize(4096, Entity::from_raw(1));
+        let message = UpdateMessage {
+            tick: NetworkTick::new(3),
+            input_deviation: InputDeviation::default(),
+            input_ack: NetworkAck::default(),
+            entity_update: EntityUpdate::new(),
             component_despawn: Vec::new(),
-            entity_despawn: Vec::new(),
+            entity_despawn: despawns,
         };
+
         let serialized = bincode::serialize(&message).unwrap();
+        let compressed = codec.compress(&serialized).unwrap();
+
+        let decoded = decode_update(&mut codec, &compressed, MaxMessageBytes::default()).unwrap();
+        assert_eq!(decoded.tick, message.tick);
+        assert_eq!(decoded.entity_despawn.len(), 4096);
+
+        assert!(matches!(
+            decode_update(&mut codec, &compressed, MaxMessageBytes(1024)),
+            Err(SabiError::MessageTooLarge { .. })
+        ));
+        assert!(decode_update(&mut codec, &[1, 2, 3], MaxMessageBytes::default()).is_err());
+    }
+
+    #[test]
+    pub fn split_large_update() {
+        let mut codec = crate::protocol::codec::ZstdCodec::default();
+        let mut entity_update = EntityUpdate::new();
+        for index in 0..64 {
+            let mut components = ComponentsUpdate::new();
+            components.insert(
+                replicate_id::<InspectHealth>(),
+                ComponentData {
+                    version: ReplicateVersion(1),
+                    delta_from: None,
+                    // Random so it doesn't compress below the limit.
+                    data: (0..256).map(|_| rand::random::<u8>()).collect(),
+                },
+            );
+            entity_update.insert(Entity::from_raw(index), components);
+        }
+
+        let message = UpdateMessage {
+            tick: NetworkTick::new(3),
+            input_deviation: InputDeviation::default(),
+            input_ack: NetworkAck::default(),
+            entity_update,
+            component_despawn: Vec::new(),
+            entity_despawn: vec![Entity::from_raw(100)],
+        };
 
-        //info!("len: {:?}", serialized.len());
-        //crate::message_sample::try_add_sample("update", &serialized);
-        let compressed = compressor
-            .compress(&serialized.as_slice())
-            .expect("couldn't compress message");
+        let encoded = encode_update(&mut codec, message.clone());
+        assert!(encoded.messages.len() > 1);
+        assert!(encoded.dropped.is_empty());
 
-        if compressed.len() >= 3000 {
-            info!("Message is too long");
-            return;
+        let mut updates = UpdateMessages::new();
+        for chunk in encoded.messages {
+            assert!(chunk.data.len() < MAX_UPDATE_BYTES);
+            updates
+                .push(decode_update(&mut codec, &chunk.data, MaxMessageBytes::default()).unwrap());
         }
-        //info!("compressed len: {:?}", compressed.len());
 
-        server.send_message(*client_id, ServerChannel::EntityUpdate.id(), compressed)
+        let merged = updates.get(&NetworkTick::new(3)).unwrap();
+        assert_eq!(merged.entity_update.len(), 64);
+        assert_eq!(merged.entity_update.updates, message.entity_update.updates);
+        assert_eq!(merged.entity_despawn, vec![Entity::from_raw(100)]);
+
+        // A single component that can't fit is dropped rather than sent.
+        let mut huge = ComponentsUpdate::new();
+        huge.insert(
+            replicate_id::<InspectHealth>(),
+            ComponentData {
+                version: ReplicateVersion(1),
+                delta_from: None,
+                data: (0..8192).map(|_| rand::random::<u8>()).collect(),
+            },
+        );
+        let mut entity_update = EntityUpdate::new();
+        entity_update.insert(Entity::from_raw(0), huge);
+        let message = UpdateMessage {
+            entity_update,
+            entity_despawn: Vec::new(),
+            ..message
+        };
+        let encoded = encode_update(&mut codec, message);
+        assert!(encoded.messages.is_empty());
+        assert_eq!(encoded.dropped.len(), 1);
+    }
+
+    #[test]
+    pub fn oversized_updates_requeued() {
+        use crate::protocol::interest::InterestQueue;
+
+        let mut codec = crate::protocol::codec::ZstdCodec::default();
+        let replicate_id = replicate_id::<InspectHealth>();
+        let large_component = || ComponentData {
+            version: ReplicateVersion(1),
+            delta_from: None,
+            // Random so it doesn't compress, about 2KB each.
+            data: (0..2000).map(|_| rand::random::<u8>()).collect(),
+        };
+
+        // Components of one large entity are split across messages.
+        let mut components = ComponentsUpdate::new();
+        components.insert(replicate_id, large_component());
+        components.insert(ReplicateId(u16::MAX), large_component());
+        let mut entity_update = EntityUpdate::new();
+        entity_update.insert(Entity::from_raw(0), components);
+        let message = UpdateMessage {
+            tick: NetworkTick::new(1),
+            input_deviation: InputDeviation::default(),
+            input_ack: NetworkAck::default(),
+            entity_update,
+            component_despawn: Vec::new(),
+            entity_despawn: Vec::new(),
+        };
+        let encoded = encode_update(&mut codec, message);
+        assert_eq!(encoded.messages.len(), 2);
+        assert!(encoded.dropped.is_empty());
+
+        // Only room for a few messages each tick, the rest should be queued again.
+        let mut queue = InterestQueue::new();
+        for index in 0..40 {
+            queue.push_back((Entity::from_raw(index), replicate_id));
+        }
+
+        let mut received = UpdateMessages::new();
+        let mut stats = TickStats::new(NetworkTick::new(0));
+        let mut ticks = 0;
+        while !queue.is_empty() {
+            ticks += 1;
+            assert!(ticks < 100, "updates never made it through");
+
+            let mut entity_update = EntityUpdate::new();
+            while let Some((entity, replicate_id)) = queue.pop_front() {
+                let mut components = ComponentsUpdate::new();
+                components.insert(replicate_id, large_component());
+                entity_update.insert(entity, components);
+            }
+
+            let message = UpdateMessage {
+                tick: NetworkTick::new(ticks),
+                input_deviation: InputDeviation::default(),
+                input_ack: NetworkAck::default(),
+                entity_update,
+                component_despawn: Vec::new(),
+                entity_despawn: Vec::new(),
+            };
+
+            let encoded = encode_update(&mut codec, message);
+            let mut room = 3;
+            let result = send_encoded(encoded, &mut stats, |data| {
+                if room == 0 {
+                    return false;
+                }
+                room -= 1;
+                received
+                    .push(decode_update(&mut codec, &data, MaxMessageBytes::default()).unwrap());
+                true
+            });
+
+            assert!(result.dropped.is_empty());
+            for interest in result.unsent.into_iter().rev() {
+                queue.push_front(interest);
+            }
+        }
+
+        let arrived = (1..=ticks)
+            .filter_map(|tick| received.get(&NetworkTick::new(tick)))
+            .flat_map(|message| message.entity_update.updates.keys().cloned())
+            .collect::<Vec<_>>();
+        assert_eq!(arrived.len(), 40);
+        for index in 0..40 {
+            assert!(arrived.contains(&Entity::from_raw(index)));
+        }
+        assert_eq!(stats.dropped, 0);
+        assert!(stats.bytes > 40 * 2000);
+    }
+
+    #[test]
+    pub fn despawn_reaches_client() {
+        use crate::protocol::{despawn::detect_despawns, interest::ClientKnownEntities};
+
+        // Server replicated an entity to client 1 and then despawned it.
+        let mut server = World::new();
+        let mut known = ClientKnownEntities::new();
+        let mut despawns = ClientEntityDespawns::new();
+        let server_entity = server.spawn(InspectHealth(1)).id();
+        known.insert(1, server_entity);
+        server.despawn(server_entity);
+        detect_despawns(
+            NetworkTick::new(5),
+            server.entities(),
+            &mut known,
+            &mut despawns,
+        );
+
+        let mut client = World::new();
+        client.insert_resource(NetworkTick::new(5));
+        client.insert_resource(ComponentRemovers::new());
+        client.insert_resource(ServerEntities::new());
+        client.insert_resource(ReceivedVersions::new());
+        client.insert_resource(ReceivedBaselines::new());
+
+        let mut updates = UpdateMessages::new();
+        updates.push(UpdateMessage {
+            tick: NetworkTick::new(5),
+            input_deviation: InputDeviation::default(),
+            input_ack: NetworkAck::default(),
+            entity_update: EntityUpdate::new(),
+            component_despawn: Vec::new(),
+            entity_despawn: despawns.get(&1),
+        });
+        client.insert_resource(updates);
+
+        let mut spawn = SystemStage::single_threaded().with_system(
+            move |mut commands: Commands, mut server_entities: ResMut<ServerEntities>| {
+                server_entities.spawn_or_get(&mut commands, server_entity);
+            },
+        );
+        spawn.run(&mut client);
+
+        let local = client
+            .resource::<ServerEntities>()
+            .get(client.entities(), ServerEntity::from_entity(server_entity))
+            .expect("server entity was mapped");
+
+        let mut stage = SystemStage::single_threaded().with_system(client_apply_despawns);
+        stage.run(&mut client);
+
+        assert!(client.get_entity(local).is_none());
+        let server_entities = client.resource::<ServerEntities>();
+        assert_eq!(server_entities.local_to_server(local), None);
+        assert_eq!(
+            server_entities.get(client.entities(), ServerEntity::from_entity(server_entity)),
+            None
+        );
+    }
+
+    #[test]
+    pub fn debug_unknown_id() {
+        let unknown = ReplicateId(u16::MAX);
+        assert_eq!(unknown.try_name(), None);
+
+        let mut components = ComponentsUpdate::new();
+        components.insert(
+            unknown,
+            ComponentData {
+                version: ReplicateVersion(1),
+                delta_from: None,
+                data: Vec::new(),
+            },
+        );
+        let mut update = EntityUpdate::new();
+        update.updates.insert(Entity::from_raw(0), components);
+
+        let debug = format!("{:?}", update);
+        assert!(debug.contains("ReplicateId(65535)"));
     }
 }
@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+
+use bevy::{prelude::*, utils::HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use super::{interest::Interest, ClientId, ClientState};
+
+/// Monotonically increasing counter for each time a component is sent for an entity.
+///
+/// This lets the client figure out whether it has a contiguous history of a component
+/// or whether it missed an update in between.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct ReplicateVersion(pub u32);
+
+impl ReplicateVersion {
+    pub fn next(&self) -> Self {
+        Self(self.0.wrapping_add(1))
+    }
+}
+
+/// Server side versions sent to each client.
+#[derive(Resource, Default, Debug, Clone)]
+pub struct ClientSentVersions {
+    clients: BTreeMap<ClientId, HashMap<Interest, ReplicateVersion>>,
+}
+
+impl ClientSentVersions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bump the version of an interest for a client and return the new version.
+    pub fn bump(&mut self, client_id: ClientId, interest: Interest) -> ReplicateVersion {
+        let version = self
+            .clients
+            .entry(client_id)
+            .or_default()
+            .entry(interest)
+            .or_default();
+        *version = version.next();
+        *version
+    }
+
+    /// Undo a `bump` for an update that never went out, so the client doesn't see a gap.
+    pub fn unbump(&mut self, client_id: &ClientId, interest: &Interest, bumped: ReplicateVersion) {
+        if let Some(version) = self
+            .clients
+            .get_mut(client_id)
+            .and_then(|versions| versions.get_mut(interest))
+        {
+            if *version == bumped {
+                *version = ReplicateVersion(bumped.0.wrapping_sub(1));
+            }
+        }
+    }
+}
+
+impl ClientState for ClientSentVersions {
+    fn forget_client(&mut self, client_id: &ClientId) {
+        self.clients.remove(client_id);
+    }
+}
+
+/// What we should do with a component update we received from the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionCheck {
+    /// Next version in the sequence, or the first one we have seen.
+    Apply,
+    /// Newer than what we have but we missed some versions in between.
+    Gap,
+    /// Older or the same as what we have already seen, this arrived out of order.
+    Stale,
+}
+
+/// Client side record of the latest version received for each server entity's components.
+#[derive(Resource, Default, Debug, Clone)]
+pub struct ReceivedVersions {
+    latest: HashMap<Interest, ReplicateVersion>,
+    resend: Vec<Interest>,
+}
+
+impl ReceivedVersions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a received version, queueing a full resend if we detect a gap.
+    pub fn receive(&mut self, interest: Interest, version: ReplicateVersion) -> VersionCheck {
+        let check = match self.latest.get(&interest) {
+            None => VersionCheck::Apply,
+            Some(latest) if version <= *latest => VersionCheck::Stale,
+            Some(latest) if version == latest.next() => VersionCheck::Apply,
+            Some(_) => VersionCheck::Gap,
+        };
+
+        match check {
+            VersionCheck::Apply => {
+                self.latest.insert(interest, version);
+            }
+            VersionCheck::Gap => {
+                self.latest.insert(interest, version);
+                if !self.resend.contains(&interest) {
+                    self.resend.push(interest);
+                }
+            }
+            VersionCheck::Stale => {}
+        }
+
+        check
+    }
+
+    /// Interests we need the server to send the full state for.
+    pub fn resend_requests(&self) -> &[Interest] {
+        &self.resend
+    }
+
+    /// Ask the server for the full state of an interest.
+    pub fn request_resend(&mut self, interest: Interest) {
+        if !self.resend.contains(&interest) {
+            self.resend.push(interest);
+        }
+    }
+
+    pub fn drain_resend_requests(&mut self) -> Vec<Interest> {
+        std::mem::take(&mut self.resend)
+    }
+
+    /// Stop tracking an entity the server despawned.
+    pub fn forget_entity(&mut self, entity: Entity) {
+        self.latest.retain(|interest, _| interest.0 != entity);
+        self.resend.retain(|interest| interest.0 != entity);
+    }
+
+    pub fn clear(&mut self) {
+        self.latest.clear();
+        self.resend.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ReplicateId;
+
+    #[test]
+    pub fn detect_gap() {
+        let interest = (Entity::from_raw(1), ReplicateId(1));
+        let mut received = ReceivedVersions::new();
+
+        assert_eq!(
+            received.receive(interest, ReplicateVersion(1)),
+            VersionCheck::Apply
+        );
+        assert_eq!(
+            received.receive(interest, ReplicateVersion(2)),
+            VersionCheck::Apply
+        );
+
+        // Missed version 3 and 4.
+        assert_eq!(
+            received.receive(interest, ReplicateVersion(5)),
+            VersionCheck::Gap
+        );
+        assert_eq!(received.resend_requests(), &[interest]);
+
+        // Version 4 finally arrives after 5.
+        assert_eq!(
+            received.receive(interest, ReplicateVersion(4)),
+            VersionCheck::Stale
+        );
+        assert_eq!(
+            received.receive(interest, ReplicateVersion(6)),
+            VersionCheck::Apply
+        );
+
+        assert_eq!(received.drain_resend_requests(), vec![interest]);
+        assert!(received.resend_requests().is_empty());
+    }
+
+    #[test]
+    pub fn unbump_unsent() {
+        let interest = (Entity::from_raw(1), ReplicateId(1));
+        let mut sent = ClientSentVersions::new();
+        assert_eq!(sent.bump(1, interest), ReplicateVersion(1));
+        let unsent = sent.bump(1, interest);
+
+        // The version that didn't go out is reused, so the client sees no gap.
+        sent.unbump(&1, &interest, unsent);
+        assert_eq!(sent.bump(1, interest), ReplicateVersion(2));
+
+        // Only undoes the version it was given.
+        sent.unbump(&1, &interest, ReplicateVersion(1));
+        assert_eq!(sent.bump(1, interest), ReplicateVersion(3));
+    }
+}
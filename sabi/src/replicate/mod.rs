@@ -1,16 +1,49 @@
-use std::collections::HashMap;
-use std::hash::Hash;
-use std::sync::{Arc, RwLock};
+//! Replication type registry.
+//!
+//! `ReplicateId`s are a FNV-1a hash of the type name truncated to 16 bits, so the server and
+//! client agree on them no matter what order types are registered in or what files are
+//! around. Two registered types hashing to the same id is caught on startup by
+//! `check_replicate_ids`, and at build time for the types in an embedded `types.toml`.
+//!
+//! `types.toml` is only a human readable list of the names behind the ids. With the
+//! `dev-registry` feature every registered type is added to it on exit. By default it is
+//! `types.toml` in the current working directory, this can be changed with the
+//! `SABI_TYPES_PATH` environment variable or `SabiPlugin::with_types_path`. Setting
+//! `SABI_TYPES_PATH` while building embeds the names so `ReplicateId::try_name` works.
+//!
+//! `freeze_registry` stops new types from being registered and `compact_registry` drops
+//! names of types that are no longer registered from the file.
+//!
+//! With the `toml-ids` feature ids are handed out from a counter in `types.toml` like
+//! they were before hashing, for talking to older builds. The file is then the source of
+//! truth for ids, so it should be committed and embedded with `SABI_TYPES_PATH`, and ids are
+//! shared process wide by `replicate_id`.
+
+use std::collections::{BTreeMap, HashSet};
+#[cfg(feature = "dev-registry")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "toml-ids")]
+use std::sync::RwLock;
+
+#[cfg(feature = "dev-registry")]
+use bevy::app::AppExit;
 
 use bevy::prelude::*;
 use bevy::reflect::FromReflect;
-use std::marker::PhantomData;
 
 use serde::{Deserialize, Serialize};
 
+use crate::error::SabiError;
+
 //pub mod general;
 //pub mod physics2d;
 pub mod physics3d;
+pub mod prefab;
+pub mod quantize;
+pub mod team;
+pub mod visibility;
 
 pub fn deserialize_number_from_string<'de, T, D>(deserializer: D) -> Result<T, D::Error>
 where
@@ -31,21 +64,61 @@ where
     }
 }
 
+/// Version of the registry format, bumped whenever ids from an older registry can't be
+/// trusted anymore.
+#[cfg(not(feature = "toml-ids"))]
+pub const TYPES_VERSION: u32 = 2;
+
+/// Files with ids from the counter are the format from before ids were hashed.
+#[cfg(feature = "toml-ids")]
+pub const TYPES_VERSION: u32 = 1;
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Types {
+    /// Files from before the registry was versioned count as version 0.
+    #[serde(default)]
+    pub version: u32,
     #[serde(default)]
     pub replicate: ReplicateTypes,
 }
 
+impl Default for Types {
+    fn default() -> Self {
+        Self::new(ReplicateTypes::default())
+    }
+}
+
 impl Types {
+    pub fn new(replicate: ReplicateTypes) -> Self {
+        Self {
+            version: TYPES_VERSION,
+            replicate: replicate,
+        }
+    }
+
     pub fn to_toml(&self) -> String {
         let replicate_toml = self.replicate.to_toml();
-        format!("{}\n", replicate_toml)
+        format!("version = {}\n\n{}\n", self.version, replicate_toml)
+    }
+
+    pub fn check_version(&self) -> Result<(), SabiError> {
+        if self.version != TYPES_VERSION {
+            return Err(SabiError::RegistryVersionMismatch {
+                expected: TYPES_VERSION,
+                found: self.version,
+            });
+        }
+
+        Ok(())
     }
 }
 
+/// Type names and their `ReplicateId`s.
+///
+/// The ids are always derived from the names, they are only written out so the file is
+/// easier to read when debugging.
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct ReplicateTypes(HashMap<String, u16>);
+pub struct ReplicateTypes(BTreeMap<String, u16>);
 
 impl ReplicateTypes {
     // Do the serialization ourselves because of bugs with the `toml` crate.
@@ -59,88 +132,767 @@ impl ReplicateTypes {
         format!("[replicate]\n{}", types)
     }
 
-    pub fn next_id(&self) -> u16 {
-        self.0.iter().map(|(_name, ty)| ty).max().unwrap_or(&0) + 1
-    }
-
     pub fn from_id(&self, id: u16) -> Option<String> {
         self.0
             .iter()
             .find(|(_, replicate_id)| **replicate_id == id)
             .map(|(name, _)| name.clone())
     }
+
+    pub fn get(&self, name: &str) -> Option<ReplicateId> {
+        self.0.get(name).map(|id| ReplicateId(*id))
+    }
+
+    /// Add a type by name, returning its id.
+    #[cfg(not(feature = "toml-ids"))]
+    pub fn insert(&mut self, name: &str) -> ReplicateId {
+        let id = ReplicateId::from_name(name);
+        self.0.insert(name.to_owned(), id.0);
+        id
+    }
+
+    /// Add a type by name, giving it the next free id if it is new.
+    #[cfg(feature = "toml-ids")]
+    pub fn insert(&mut self, name: &str) -> ReplicateId {
+        if let Some(id) = self.get(name) {
+            return id;
+        }
+
+        let id = self.next_id();
+        self.0.insert(name.to_owned(), id);
+        ReplicateId(id)
+    }
+
+    #[cfg(feature = "toml-ids")]
+    pub fn next_id(&self) -> u16 {
+        self.0.values().max().map_or(1, |id| id + 1)
+    }
+
+    /// Add the types from `other`, keeping their ids.
+    pub fn extend(&mut self, other: &ReplicateTypes) {
+        for (name, id) in other.0.iter() {
+            self.0.insert(name.clone(), *id);
+        }
+    }
+
+    /// Remove types that aren't in `live`, returning the removed names.
+    pub fn prune(&mut self, live: &HashSet<String>) -> Vec<String> {
+        let mut removed = Vec::new();
+        self.0.retain(|name, _| {
+            let keep = live.contains(name);
+            if !keep {
+                removed.push(name.clone());
+            }
+            keep
+        });
+
+        removed
+    }
+
+    /// Ids used to be handed out from a counter and could be renumbered to reclaim the
+    /// ones left by removed types. They are hashes of the names now, so there is nothing
+    /// to reclaim and renumbering would break every id.
+    #[cfg(not(feature = "toml-ids"))]
+    #[deprecated(note = "ids are hashes of the type names, use `prune` to drop stale names")]
+    pub fn compact(&mut self) {}
+
+    /// Renumber ids from 1 keeping their order.
+    ///
+    /// This changes ids, so anything built against the old registry can't talk to
+    /// anything built against the new one.
+    #[cfg(feature = "toml-ids")]
+    pub fn compact(&mut self) {
+        let mut types = std::mem::take(&mut self.0).into_iter().collect::<Vec<_>>();
+        types.sort_by_key(|(_, id)| *id);
+        for (index, (name, _)) in types.into_iter().enumerate() {
+            self.0.insert(name, index as u16 + 1);
+        }
+    }
+
+    /// Recompute the ids from the names, in case the file was edited by hand.
+    #[cfg(not(feature = "toml-ids"))]
+    pub fn rehash(&mut self) {
+        for (name, id) in self.0.iter_mut() {
+            *id = ReplicateId::from_name(name).0;
+        }
+    }
+
+    /// Make sure no two types share an id.
+    ///
+    /// Ids are truncated hashes so this can happen, and would otherwise deserialize one
+    /// component's data as another. Renaming one of the types fixes it.
+    pub fn validate(&self) -> Result<(), SabiError> {
+        let mut ids: BTreeMap<u16, Vec<String>> = BTreeMap::new();
+        for (name, id) in self.0.iter() {
+            ids.entry(*id).or_default().push(name.clone());
+        }
+
+        match ids.into_iter().find(|(_, names)| names.len() > 1) {
+            Some((id, mut names)) => {
+                names.sort();
+                Err(SabiError::DuplicateReplicateId {
+                    id: id,
+                    names: names,
+                })
+            }
+            None => Ok(()),
+        }
+    }
 }
 
-lazy_static::lazy_static! {
-    pub static ref TYPES: Arc<RwLock<Types>> = Arc::new(RwLock::new(read_types_file()));
+mod embedded {
+    include!(concat!(env!("OUT_DIR"), "/types.rs"));
+}
+
+pub use embedded::EMBEDDED_TYPES;
+
+/// Set by `freeze_registry`.
+static FROZEN: AtomicBool = AtomicBool::new(false);
+
+/// Stop registering new types, registering a type that isn't already registered panics.
+///
+/// Ids don't need the registry, but release builds can freeze it after startup to catch
+/// types that only show up mid-game and were never checked for id collisions.
+pub fn freeze_registry() {
+    FROZEN.store(true, Ordering::Release);
+}
+
+pub fn is_registry_frozen() -> bool {
+    FROZEN.load(Ordering::Acquire)
+}
+
+/// Set by `compact_registry`.
+static COMPACT: AtomicBool = AtomicBool::new(false);
+
+/// Remove types this app doesn't register from the registry file on startup, see
+/// `compact_registry_on_startup`.
+///
+/// Ids are hashes of the names so this doesn't change any of them, it only drops the names
+/// of renamed and removed types. Builds that still use those types lose their names in
+/// debug output, so this is never done unless asked for.
+pub fn compact_registry() {
+    COMPACT.store(true, Ordering::Release);
+}
+
+pub fn is_compact_requested() -> bool {
+    COMPACT.load(Ordering::Acquire)
 }
 
 pub const TYPES_PATH: &'static str = "types.toml";
+pub const TYPES_PATH_ENV: &'static str = "SABI_TYPES_PATH";
 
-pub fn read_types_file() -> Types {
-    use std::io::Read;
+/// Default path of the registry file.
+///
+/// `SABI_TYPES_PATH` if it is set, otherwise `types.toml` relative to the working directory.
+pub fn default_types_path() -> PathBuf {
+    match std::env::var_os(TYPES_PATH_ENV) {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => PathBuf::from(TYPES_PATH),
+    }
+}
 
-    let mut file = match std::fs::File::open(TYPES_PATH) {
-        Ok(file) => file,
-        Err(err) => std::fs::File::create(TYPES_PATH).expect("could not create types.toml"),
+#[cfg(feature = "dev-registry")]
+pub fn read_types_from<P: AsRef<Path>>(path: P) -> Result<Types, SabiError> {
+    let path = path.as_ref();
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => panic!("could not read {}: {}", path.display(), err),
     };
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).expect("read types.toml");
 
-    let types: Types = toml::from_str(&contents).expect("parse types.toml");
-    types
+    // Newly created file.
+    if contents.trim().is_empty() {
+        return Ok(Types::default());
+    }
+
+    let mut types: Types = toml::from_str(&contents).unwrap_or_else(|err| {
+        panic!(
+            "could not parse {}, fix or delete it to regenerate: {}",
+            path.display(),
+            err
+        )
+    });
+    types.check_version()?;
+    #[cfg(not(feature = "toml-ids"))]
+    types.replicate.rehash();
+    #[cfg(feature = "toml-ids")]
+    types.replicate.validate()?;
+    Ok(types)
 }
 
-pub fn write_types_file() {
+/// Write the registry to a temporary file next to `path` and then rename it over
+/// the target, so crashing mid-write can never leave a truncated `types.toml`.
+///
+/// `std::fs::rename` already replaces the target atomically on Windows as well, through
+/// `MoveFileExW` with `MOVEFILE_REPLACE_EXISTING`. The file is only for persistence, the
+/// `ReplicateRegistry` resource is what is used in process.
+#[cfg(feature = "dev-registry")]
+pub fn write_types_to<P: AsRef<Path>>(path: P, types: &Types) -> Result<(), std::io::Error> {
     use std::io::Write;
 
-    let types = TYPES.read().expect("read TYPES so we can write");
-    let mut file = std::fs::File::create(TYPES_PATH).expect("open types.toml");
-    let new_toml = types.to_toml();
-    file.write_all(new_toml.as_bytes())
-        .expect("write to types.toml");
-    file.flush().expect("could not flush to types.toml");
+    let path = path.as_ref();
+    let temp_path = path.with_extension("toml.tmp");
+
+    let mut file = std::fs::File::create(&temp_path)?;
+    file.write_all(types.to_toml().as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(&temp_path, path)
+}
+
+/// Add the names of every registered type to the registry file.
+///
+/// Names already in the file are kept, so a server and client with different types
+/// can share one file.
+#[cfg(feature = "dev-registry")]
+pub fn flush_types(registry: &ReplicateRegistry) {
+    let path = registry.path();
+    let mut types = read_types_from(&path).unwrap_or_else(|err| {
+        warn!("replacing {}: {}", path.display(), err);
+        Types::default()
+    });
+    types.replicate.extend(&registry.types);
+    // Ids can be handed out without registering the type, those need to be kept too.
+    #[cfg(feature = "toml-ids")]
+    types
+        .replicate
+        .extend(&TOML_IDS.read().expect("read toml ids"));
+
+    write_types_to(&path, &types)
+        .unwrap_or_else(|err| panic!("could not write {}: {}", path.display(), err));
+}
+
+/// Flush the registry when the app is exiting.
+#[cfg(feature = "dev-registry")]
+pub fn flush_types_on_exit(registry: Res<ReplicateRegistry>, mut exit: EventReader<AppExit>) {
+    if exit.iter().last().is_some() {
+        flush_types(&*registry);
+    }
+}
+
+/// Drop the names of types that aren't registered from the registry file, returning them.
+#[cfg(feature = "dev-registry")]
+pub fn compact_types_file(registry: &ReplicateRegistry) -> Vec<String> {
+    let path = registry.path();
+    let mut types = read_types_from(&path).unwrap_or_else(|err| panic!("{}", err));
+
+    let live = registry.types.0.keys().cloned().collect::<HashSet<_>>();
+    let removed = types.replicate.prune(&live);
+    types.replicate.extend(&registry.types);
+    #[cfg(feature = "toml-ids")]
+    {
+        types.replicate.compact();
+        warn!(
+            "compacted {}, replicate ids changed and will be used after a restart",
+            path.display()
+        );
+    }
+
+    write_types_to(&path, &types)
+        .unwrap_or_else(|err| panic!("could not write {}: {}", path.display(), err));
+    removed
+}
+
+/// Compact the registry file if `compact_registry` was called.
+///
+/// This runs after startup so every `ReplicatePlugin` has registered its type.
+#[cfg(feature = "dev-registry")]
+pub fn compact_registry_on_startup(registry: Res<ReplicateRegistry>) {
+    if !is_compact_requested() {
+        return;
+    }
+
+    let path = registry.path();
+    for name in compact_types_file(&*registry) {
+        info!("removing unused type from {}: {}", path.display(), name);
+    }
 }
 
+/// Panic if two registered types ended up with the same `ReplicateId`.
+pub fn check_replicate_ids(registry: Res<ReplicateRegistry>) {
+    if let Err(err) = registry.check() {
+        panic!("{}", err);
+    }
+}
+
+/// Types registered for replication in an `App`.
+///
+/// Ids don't depend on this, it is kept for looking up names and for catching two types
+/// hashing to the same id.
+#[derive(Resource, Debug, Default, Clone)]
+pub struct ReplicateRegistry {
+    types: ReplicateTypes,
+    path: Option<PathBuf>,
+    frozen: bool,
+}
+
+impl ReplicateRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write names to `path` instead of `default_types_path`.
+    pub fn set_path<P: Into<PathBuf>>(&mut self, path: P) {
+        self.path = Some(path.into());
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.path.clone().unwrap_or_else(default_types_path)
+    }
+
+    /// Stop registering new types in this registry, see `freeze_registry`.
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen || is_registry_frozen()
+    }
+
+    /// `ReplicateId` of `T`, this doesn't need `T` to be registered.
+    pub fn id<T>(&self) -> ReplicateId
+    where
+        T: 'static + Reflect + FromReflect,
+    {
+        replicate_id::<T>()
+    }
+
+    pub fn register<T>(&mut self) -> ReplicateId
+    where
+        T: 'static + Reflect + FromReflect,
+    {
+        self.register_name(std::any::type_name::<T>())
+    }
+
+    /// Panics if a different type was already registered with the same id, or if the
+    /// type is new and the registry is frozen.
+    pub fn register_name(&mut self, name: &str) -> ReplicateId {
+        if let Some(id) = self.types.get(name) {
+            return id;
+        }
+
+        if self.is_frozen() {
+            panic!("{} is not in the types registry and it is frozen", name);
+        }
+
+        let id = replicate_id_by_name(name);
+        if let Some(other) = self.types.from_id(id.0) {
+            if other != name {
+                let mut names = vec![other, name.to_owned()];
+                names.sort();
+                panic!(
+                    "{}",
+                    SabiError::DuplicateReplicateId {
+                        id: id.0,
+                        names: names
+                    }
+                );
+            }
+        }
+
+        self.types.0.insert(name.to_owned(), id.0);
+        id
+    }
+
+    pub fn types(&self) -> &ReplicateTypes {
+        &self.types
+    }
+
+    /// Name of a registered or embedded type.
+    pub fn name(&self, id: ReplicateId) -> Option<String> {
+        self.types.from_id(id.0).or_else(|| id.try_name())
+    }
+
+    pub fn check(&self) -> Result<(), SabiError> {
+        self.types.validate()
+    }
+}
+
+/// Register types with the world's `ReplicateRegistry`.
+pub trait WorldReplicateExt {
+    fn replicate_id<T>(&mut self) -> ReplicateId
+    where
+        T: 'static + Reflect + FromReflect;
+}
+
+impl WorldReplicateExt for World {
+    fn replicate_id<T>(&mut self) -> ReplicateId
+    where
+        T: 'static + Reflect + FromReflect,
+    {
+        self.get_resource_or_insert_with(ReplicateRegistry::default)
+            .register::<T>()
+    }
+}
+
+/// Keeps an entity from being replicated by any `ReplicatePlugin` using the default filter.
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct NoReplicate;
+
 /// Smaller unique id per type for serialization so it is easier to compress for network packets.
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 #[serde(transparent)]
 pub struct ReplicateId(pub u16);
 
 impl ReplicateId {
+    /// FNV-1a hash of the type name truncated to 16 bits.
+    pub fn from_name(name: &str) -> Self {
+        let mut hash: u32 = 0x811c9dc5;
+        for byte in name.as_bytes() {
+            hash ^= *byte as u32;
+            hash = hash.wrapping_mul(0x01000193);
+        }
+
+        Self(hash as u16)
+    }
+
+    /// Type name for this id.
+    ///
+    /// # Panics
+    ///
+    /// If the id isn't in the embedded registry, ids received over the network can be
+    /// anything so prefer `try_name` for those.
     pub fn name(&self) -> String {
-        let types = TYPES.read().expect("read TYPES so we can write");
-        types.replicate.from_id(self.0).unwrap()
+        self.try_name()
+            .unwrap_or_else(|| panic!("{:?} is not in the types registry", self))
+    }
+
+    /// Name from the `types.toml` embedded at build time, see `ReplicateRegistry::name` for
+    /// types registered at runtime.
+    #[cfg(not(feature = "toml-ids"))]
+    pub fn try_name(&self) -> Option<String> {
+        EMBEDDED_TYPES
+            .iter()
+            .find(|(_, id)| *id == self.0)
+            .map(|(name, _)| (*name).to_owned())
     }
+
+    /// Name of a type that was given this id.
+    #[cfg(feature = "toml-ids")]
+    pub fn try_name(&self) -> Option<String> {
+        TOML_IDS.read().expect("read toml ids").from_id(self.0)
+    }
+}
+
+#[cfg(feature = "toml-ids")]
+lazy_static::lazy_static! {
+    /// Ids handed out so far, starting from the embedded registry and the registry file
+    /// with `dev-registry`.
+    static ref TOML_IDS: RwLock<ReplicateTypes> = RwLock::new(load_toml_ids());
 }
 
-/// An id that should be the same over time/builds/etc. so that the server and client can
+#[cfg(feature = "toml-ids")]
+fn load_toml_ids() -> ReplicateTypes {
+    let mut types = ReplicateTypes::default();
+    for (name, id) in EMBEDDED_TYPES {
+        types.0.insert((*name).to_owned(), *id);
+    }
+
+    #[cfg(feature = "dev-registry")]
+    {
+        let file = read_types_from(default_types_path()).unwrap_or_else(|err| panic!("{}", err));
+        types.extend(&file.replicate);
+    }
+
+    types
+}
+
+/// `ReplicateId` of a type name, see `replicate_id`.
+#[cfg(not(feature = "toml-ids"))]
+pub fn replicate_id_by_name(name: &str) -> ReplicateId {
+    ReplicateId::from_name(name)
+}
+
+/// `ReplicateId` of a type name from `types.toml`, new types are given the next free id.
+#[cfg(feature = "toml-ids")]
+pub fn replicate_id_by_name(name: &str) -> ReplicateId {
+    if let Some(id) = TOML_IDS.read().expect("read toml ids").get(name) {
+        return id;
+    }
+
+    if is_registry_frozen() {
+        panic!("{} is not in the types registry and it is frozen", name);
+    }
+
+    TOML_IDS.write().expect("write toml ids").insert(name)
+}
+
+/// An id that is the same over time/builds/etc. so that the server and client can
 /// accurately communicate with eachother.
 ///
-/// Currently this is persistent based on the `types.toml` file in the project folder.
-/// If this file is cleared then it may not be the same in the next build.
+/// This is a hash of the type name, so renaming or moving a type changes its id. With the
+/// `toml-ids` feature it is the id in `types.toml` instead.
 pub fn replicate_id<T>() -> ReplicateId
 where
     T: 'static + Reflect + FromReflect,
 {
-    let long_id = std::any::type_name::<T>().to_owned();
-
-    let read_lock = TYPES.read().expect("read TYPES");
-    let short_id = match read_lock.replicate.0.get(&long_id) {
-        Some(short_id) => *short_id,
-        None => {
-            drop(read_lock);
-
-            info!("adding new type to types.toml: {}", long_id);
-            let mut write_lock = TYPES.write().expect("could not write short id");
-            let next_id = write_lock.replicate.next_id();
-            write_lock.replicate.0.insert(long_id, next_id);
-            drop(write_lock);
-
-            write_types_file();
-            next_id
+    replicate_id_by_name(std::any::type_name::<T>())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "dev-registry")]
+    pub fn crash_mid_write() {
+        let dir = std::env::temp_dir().join(format!("sabi-types-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("types.toml");
+
+        let mut types = Types::default();
+        let id = types.replicate.insert("a::Component");
+        write_types_to(&path, &types).unwrap();
+
+        // Crash after the temporary file was truncated but before it was written/renamed.
+        std::fs::File::create(path.with_extension("toml.tmp")).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(!contents.is_empty());
+
+        let read = read_types_from(&path).unwrap();
+        assert_eq!(read.replicate.get("a::Component"), Some(id));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "dev-registry")]
+    #[should_panic(expected = "could not parse")]
+    pub fn corrupt_types_file() {
+        let dir = std::env::temp_dir().join(format!("sabi-corrupt-types-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("types.toml");
+        std::fs::write(&path, "[replicate\n\"a::Component\" = ").unwrap();
+
+        let _ = read_types_from(&path);
+    }
+
+    #[test]
+    #[cfg(feature = "dev-registry")]
+    pub fn registry_version() {
+        let dir = std::env::temp_dir().join(format!("sabi-types-version-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("types.toml");
+
+        let mut types = Types::default();
+        types.replicate.insert("a::Component");
+        write_types_to(&path, &types).unwrap();
+        assert!(std::fs::read_to_string(&path)
+            .unwrap()
+            .starts_with(&format!("version = {}", TYPES_VERSION)));
+        assert_eq!(read_types_from(&path).unwrap().version, TYPES_VERSION);
+
+        // Written before the registry was versioned.
+        std::fs::write(&path, "[replicate]\n\"a::Component\" = 1\n").unwrap();
+        match read_types_from(&path) {
+            Err(SabiError::RegistryVersionMismatch { expected, found }) => {
+                assert_eq!(expected, TYPES_VERSION);
+                assert_eq!(found, 0);
+            }
+            result => panic!("expected version mismatch, got {:?}", result),
         }
-    };
 
-    ReplicateId(short_id)
+        // Ids edited by hand are ignored.
+        #[cfg(not(feature = "toml-ids"))]
+        {
+            std::fs::write(
+                &path,
+                format!(
+                    "version = {}\n[replicate]\n\"a::Component\" = 1\n",
+                    TYPES_VERSION
+                ),
+            )
+            .unwrap();
+            assert_eq!(
+                read_types_from(&path)
+                    .unwrap()
+                    .replicate
+                    .get("a::Component"),
+                Some(ReplicateId::from_name("a::Component"))
+            );
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(not(feature = "toml-ids"))]
+    pub fn duplicate_ids() {
+        use std::collections::HashMap;
+
+        // Find two names that hash to the same id.
+        let mut seen = HashMap::new();
+        let (first, second) = (0..)
+            .map(|index| format!("a::Component{}", index))
+            .find_map(|name| {
+                let id = ReplicateId::from_name(&name);
+                match seen.insert(id, name.clone()) {
+                    Some(other) => Some((other, name)),
+                    None => None,
+                }
+            })
+            .unwrap();
+
+        let mut types = ReplicateTypes::default();
+        types.insert(&first);
+        assert!(types.validate().is_ok());
+
+        types.insert(&second);
+        match types.validate() {
+            Err(SabiError::DuplicateReplicateId { id, names }) => {
+                assert_eq!(ReplicateId(id), ReplicateId::from_name(&first));
+                assert_eq!(names.len(), 2);
+            }
+            result => panic!("expected duplicate id, got {:?}", result),
+        }
+
+        let mut registry = ReplicateRegistry::new();
+        registry.register_name(&first);
+        registry.register_name(&first);
+        let collision = std::panic::catch_unwind(move || registry.register_name(&second));
+        assert!(collision.is_err());
+    }
+
+    #[test]
+    pub fn prune_stale_types() {
+        let mut types = ReplicateTypes::default();
+        let position = types.insert("a::Position");
+        types.insert("a::Renamed");
+        let velocity = types.insert("a::Velocity");
+        types.insert("a::Removed");
+
+        let live = ["a::Position", "a::Velocity"]
+            .iter()
+            .map(|name| (*name).to_owned())
+            .collect::<HashSet<_>>();
+        let mut removed = types.prune(&live);
+        removed.sort();
+        assert_eq!(removed, vec!["a::Removed", "a::Renamed"]);
+
+        // Live ids are untouched.
+        assert_eq!(types.0.len(), 2);
+        assert_eq!(types.get("a::Position"), Some(position));
+        assert_eq!(types.get("a::Velocity"), Some(velocity));
+    }
+
+    #[test]
+    #[cfg(all(feature = "dev-registry", not(feature = "toml-ids")))]
+    pub fn compact_file() {
+        let dir = std::env::temp_dir().join(format!("sabi-compact-types-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("types.toml");
+
+        let mut types = Types::default();
+        types.replicate.insert("a::Stale");
+        types.replicate.insert("a::Position");
+        write_types_to(&path, &types).unwrap();
+
+        let mut registry = ReplicateRegistry::new();
+        registry.set_path(&path);
+        registry.register_name("a::Position");
+        registry.register_name("a::Velocity");
+        assert_eq!(compact_types_file(&registry), vec!["a::Stale"]);
+
+        let compacted = read_types_from(&path).unwrap().replicate;
+        assert_eq!(compacted.0.len(), 2);
+        assert!(compacted.get("a::Stale").is_none());
+        assert_eq!(
+            compacted.get("a::Velocity"),
+            Some(ReplicateId::from_name("a::Velocity"))
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    pub fn frozen_registry() {
+        let mut registry = ReplicateRegistry::new();
+        let position = registry.register_name("a::Position");
+        registry.freeze();
+
+        // Already registered types are fine.
+        assert_eq!(registry.register_name("a::Position"), position);
+
+        let new_type = std::panic::catch_unwind(move || registry.register_name("a::Velocity"));
+        let message = new_type.unwrap_err();
+        assert!(message.downcast_ref::<String>().unwrap().contains("frozen"));
+    }
+
+    #[derive(Reflect, FromReflect)]
+    struct Position;
+
+    #[derive(Reflect, FromReflect)]
+    struct Velocity;
+
+    #[test]
+    #[cfg(not(feature = "toml-ids"))]
+    pub fn ids_from_names() {
+        // Known FNV-1a values, the ids can never change without breaking compatibility.
+        assert_eq!(ReplicateId::from_name(""), ReplicateId(0x9dc5));
+        assert_eq!(ReplicateId::from_name("a"), ReplicateId(0x292c));
+
+        // Registration order doesn't matter.
+        let mut server = ReplicateRegistry::new();
+        let mut client = ReplicateRegistry::new();
+        let position = server.register::<Position>();
+        server.register::<Velocity>();
+        client.register::<Velocity>();
+        assert_eq!(client.register::<Position>(), position);
+
+        assert_eq!(position, replicate_id::<Position>());
+        assert_eq!(
+            server.name(position).as_deref(),
+            Some(std::any::type_name::<Position>())
+        );
+        assert!(server.check().is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "toml-ids")]
+    pub fn toml_ids() {
+        // Ids come from a counter instead of the names.
+        let mut types = ReplicateTypes::default();
+        assert_eq!(types.insert("a::Position"), ReplicateId(1));
+        assert_eq!(types.insert("a::Removed"), ReplicateId(2));
+        assert_eq!(types.insert("a::Health"), ReplicateId(3));
+        assert_eq!(types.insert("a::Position"), ReplicateId(1));
+
+        let live = ["a::Position", "a::Health"]
+            .iter()
+            .map(|name| (*name).to_owned())
+            .collect::<HashSet<_>>();
+        assert_eq!(types.prune(&live), vec!["a::Removed"]);
+        types.compact();
+        assert_eq!(types.get("a::Position"), Some(ReplicateId(1)));
+        assert_eq!(types.get("a::Health"), Some(ReplicateId(2)));
+        assert_eq!(types.next_id(), 3);
+
+        // Handed out once per process, registries agree with `replicate_id`.
+        let position = replicate_id::<Position>();
+        assert_eq!(replicate_id::<Position>(), position);
+        assert_ne!(replicate_id::<Velocity>(), position);
+        assert_eq!(ReplicateRegistry::new().register::<Position>(), position);
+        assert_eq!(
+            position.try_name().as_deref(),
+            Some(std::any::type_name::<Position>())
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "toml-ids", feature = "dev-registry"))]
+    pub fn toml_ids_file() {
+        let dir = std::env::temp_dir().join(format!("sabi-toml-ids-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("types.toml");
+
+        // Files from before ids were hashed keep their ids.
+        std::fs::write(&path, "version = 1\n[replicate]\n\"a::Position\" = 7\n").unwrap();
+        let mut types = read_types_from(&path).unwrap();
+        assert_eq!(types.replicate.get("a::Position"), Some(ReplicateId(7)));
+        assert_eq!(types.replicate.insert("a::Velocity"), ReplicateId(8));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
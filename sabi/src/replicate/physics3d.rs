@@ -3,13 +3,17 @@ use bevy_rapier3d::{prelude::*, rapier::prelude::SharedShape};
 
 use serde::{Deserialize, Serialize};
 
-use crate::{plugin::ReplicatePlugin, protocol::demands::RequireDependency};
+use crate::{
+    plugin::{ReplicateConfig, ReplicatePlugin},
+    protocol::demands::RequireDependency,
+};
 
 pub struct ReplicatePhysics3dPlugin;
 impl Plugin for ReplicatePhysics3dPlugin {
     fn build(&self, app: &mut App) {
         app.register_type::<CoefficientCombineRule>()
             .register_type::<ColliderMassProperties>()
+            .register_type::<MassProperties>()
             .register_type::<Group>();
 
         app.add_plugin(ReplicatePlugin::<RigidBody>::default());
@@ -32,6 +36,12 @@ impl Plugin for ReplicatePhysics3dPlugin {
 
         app.add_plugin(ReplicatePlugin::<AdditionalMassProperties>::default());
         app.add_plugin(ReplicatePlugin::<ColliderMassProperties>::default());
+        // Computed by rapier from the mass properties above, so the server's value is
+        // always used as is.
+        app.add_plugin(ReplicatePlugin::<ReadMassProperties>::with_config(
+            ReplicateConfig::default().without_prediction(),
+        ));
+        app.add_plugin(RequireDependency::<ReadMassProperties, RigidBody>::default());
 
         //app.add_plugin(RequireDependency::<Collider, RigidBody>::default());
     }
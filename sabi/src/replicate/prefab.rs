@@ -0,0 +1,160 @@
+//! Spawn the local-only parts of replicated entities as soon as they show up.
+//!
+//! The server marks entities with a `ReplicatePrefab` id and the client runs the spawn
+//! function it registered for that id in its `PrefabRegistry`, so meshes, materials, etc.
+//! exist the moment the entity does instead of after all of its components trickle in.
+//! `ReplicatePrefab` is moved to the front of each client's interest queue so it goes out
+//! before the rest of the entity's components.
+
+use std::collections::BTreeMap;
+
+use bevy::{ecs::system::EntityCommands, prelude::*};
+
+use crate::{
+    plugin::ReplicatePlugin, protocol::interest::ClientInterestQueues, replicate::replicate_id,
+    stage::NetworkSimulationAppExt,
+};
+
+/// Adds the local-only components of a prefab to a newly replicated entity.
+pub type PrefabFn = Box<dyn Fn(&mut EntityCommands) + Send + Sync>;
+
+/// Id of the prefab in the `PrefabRegistry` this entity was spawned from.
+#[derive(Component, Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect, FromReflect)]
+pub struct ReplicatePrefab(pub u32);
+
+/// Spawn functions for each prefab id, both the server and client should register the
+/// same ids at startup.
+#[derive(Resource, Default)]
+pub struct PrefabRegistry {
+    prefabs: BTreeMap<u32, PrefabFn>,
+}
+
+impl PrefabRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<F>(&mut self, id: u32, spawn: F)
+    where
+        F: 'static + Fn(&mut EntityCommands) + Send + Sync,
+    {
+        self.prefabs.insert(id, Box::new(spawn));
+    }
+
+    pub fn get(&self, id: u32) -> Option<&PrefabFn> {
+        self.prefabs.get(&id)
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = &u32> {
+        self.prefabs.keys()
+    }
+}
+
+/// Run the spawn function for entities we just learned the prefab of.
+pub fn apply_prefabs(
+    mut commands: Commands,
+    registry: Res<PrefabRegistry>,
+    query: Query<(Entity, &ReplicatePrefab), Added<ReplicatePrefab>>,
+) {
+    for (entity, prefab) in query.iter() {
+        match registry.get(prefab.0) {
+            Some(spawn) => spawn(&mut commands.entity(entity)),
+            None => error!(
+                "unknown prefab {} on {:?}, registered prefabs are {:?}",
+                prefab.0,
+                entity,
+                registry.ids().collect::<Vec<_>>()
+            ),
+        }
+    }
+}
+
+/// Send prefab ids ahead of anything else queued.
+pub fn prioritize_prefabs(
+    mut queues: ResMut<ClientInterestQueues>,
+    prefabs: Query<(), With<ReplicatePrefab>>,
+) {
+    if prefabs.is_empty() {
+        return;
+    }
+
+    let prefab_id = replicate_id::<ReplicatePrefab>();
+    for (_, queue) in queues.iter_mut() {
+        let queued = queue
+            .iter()
+            .filter(|(_, replicate_id)| *replicate_id == prefab_id)
+            .cloned()
+            .collect::<Vec<_>>();
+        for interest in queued.into_iter().rev() {
+            queue.push_front(interest);
+        }
+    }
+}
+
+pub struct ReplicatePrefabPlugin;
+impl Plugin for ReplicatePrefabPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(ReplicatePlugin::<ReplicatePrefab>::default());
+        app.init_resource::<PrefabRegistry>();
+
+        if app.world.contains_resource::<crate::Server>() {
+            app.add_meta_network_system(
+                prioritize_prefabs
+                    .after("feed_baseloads")
+                    .after("resend_unacked")
+                    .before("queue_interests"),
+            );
+        }
+
+        if app.world.contains_resource::<crate::Client>() {
+            app.add_system(apply_prefabs);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Component)]
+    struct Model;
+
+    #[test]
+    pub fn spawn_prefab() {
+        let mut world = World::new();
+        let mut registry = PrefabRegistry::new();
+        registry.register(1, |commands| {
+            commands.insert(Model);
+        });
+        world.insert_resource(registry);
+
+        let known = world.spawn(ReplicatePrefab(1)).id();
+        let unknown = world.spawn(ReplicatePrefab(7)).id();
+
+        let mut stage = SystemStage::single_threaded().with_system(apply_prefabs);
+        stage.run(&mut world);
+
+        assert!(world.get::<Model>(known).is_some());
+        assert!(world.get::<Model>(unknown).is_none());
+    }
+
+    #[test]
+    pub fn prefab_sent_first() {
+        let mut world = World::new();
+        let entity = world.spawn(ReplicatePrefab(1)).id();
+
+        let transform = (entity, replicate_id::<Transform>());
+        let prefab = (entity, replicate_id::<ReplicatePrefab>());
+        let mut queues = ClientInterestQueues::new();
+        queues.entry(1).push_back(transform);
+        queues.entry(1).push_back(prefab);
+        world.insert_resource(queues);
+
+        let mut stage = SystemStage::single_threaded().with_system(prioritize_prefabs);
+        stage.run(&mut world);
+
+        let queue = world.resource::<ClientInterestQueues>().get(&1).unwrap();
+        assert_eq!(queue.peek_first(), Some(&prefab));
+        assert_eq!(queue.len(), 2);
+    }
+}
@@ -0,0 +1,258 @@
+//! Quantized replication of `Transform`.
+//!
+//! Entities with a `QuantizedTransform` have it replicated instead of sending the full
+//! `f32`s of their `Transform`. Translation and scale are fixed-point in multiples of
+//! `TransformQuantization::unit`, rotations are packed as a smallest-three quaternion.
+//!
+//! Error bounds after a round trip:
+//! - translation and scale are within `unit / 2` on each axis, as long as they fit in
+//!   `i32::MAX * unit` (~2000 km at the default 1/1024 m).
+//! - rotations are within `QUAT_TOLERANCE` radians.
+//!
+//! Use `SabiPlugin::without_transform` so the unquantized `Transform` isn't sent as well.
+
+use std::f32::consts::FRAC_1_SQRT_2;
+
+use bevy::prelude::*;
+
+use crate::{
+    plugin::ReplicatePlugin,
+    stage::{NetworkCoreStage, NetworkSimulationAppExt},
+};
+
+/// Default fixed-point unit for translations and scales, 1/1024 m.
+pub const DEFAULT_QUANTIZATION_UNIT: f32 = 1.0 / 1024.0;
+
+/// Largest angle in radians between a rotation and its quantized value.
+pub const QUAT_TOLERANCE: f32 = 0.005;
+
+/// Bits for each of the smallest three components of a quaternion.
+const QUAT_COMPONENT_BITS: u32 = 10;
+const QUAT_COMPONENT_MAX: u32 = (1 << QUAT_COMPONENT_BITS) - 1;
+
+/// Size of a fixed-point step, must be the same on the server and client.
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct TransformQuantization {
+    pub unit: f32,
+}
+
+impl Default for TransformQuantization {
+    fn default() -> Self {
+        Self {
+            unit: DEFAULT_QUANTIZATION_UNIT,
+        }
+    }
+}
+
+impl TransformQuantization {
+    pub fn new(unit: f32) -> Self {
+        Self { unit }
+    }
+
+    pub fn quantize_vec3(&self, value: Vec3) -> IVec3 {
+        // `as` saturates anything out of range.
+        (value / self.unit).round().as_ivec3()
+    }
+
+    pub fn dequantize_vec3(&self, value: IVec3) -> Vec3 {
+        value.as_vec3() * self.unit
+    }
+
+    pub fn quantize(&self, transform: &Transform) -> QuantizedTransform {
+        QuantizedTransform {
+            translation: self.quantize_vec3(transform.translation),
+            rotation: quantize_quat(transform.rotation),
+            scale: self.quantize_vec3(transform.scale),
+        }
+    }
+
+    pub fn dequantize(&self, quantized: &QuantizedTransform) -> Transform {
+        Transform {
+            translation: self.dequantize_vec3(quantized.translation),
+            rotation: dequantize_quat(quantized.rotation),
+            scale: self.dequantize_vec3(quantized.scale),
+        }
+    }
+}
+
+/// Replicated stand-in for an entity's `Transform`, see `TransformQuantization`.
+///
+/// Insert this on the server for any entities that should be quantized.
+#[derive(Component, Debug, Default, Copy, Clone, PartialEq, Eq, Reflect, FromReflect)]
+#[reflect(Component)]
+pub struct QuantizedTransform {
+    pub translation: IVec3,
+    pub rotation: u32,
+    pub scale: IVec3,
+}
+
+/// Pack a rotation into 32 bits.
+///
+/// The largest component is dropped and rebuilt from the other three since the quaternion
+/// is normalized. That leaves 2 bits for which component was dropped and 10 bits for each
+/// of the rest, which are always within +-1/sqrt(2).
+pub fn quantize_quat(rotation: Quat) -> u32 {
+    let mut components = rotation.normalize().to_array();
+
+    let largest = (0..4)
+        .max_by(|a, b| components[*a].abs().total_cmp(&components[*b].abs()))
+        .expect("quaternion has components");
+
+    // `q` and `-q` are the same rotation, so the dropped component is always positive.
+    if components[largest] < 0.0 {
+        for component in components.iter_mut() {
+            *component = -*component;
+        }
+    }
+
+    let mut packed = (largest as u32) << (QUAT_COMPONENT_BITS * 3);
+    let mut shift = QUAT_COMPONENT_BITS * 3;
+    for (index, component) in components.iter().enumerate() {
+        if index == largest {
+            continue;
+        }
+
+        shift -= QUAT_COMPONENT_BITS;
+        let normalized = (component / FRAC_1_SQRT_2 + 1.0) / 2.0;
+        let bits = (normalized * QUAT_COMPONENT_MAX as f32)
+            .round()
+            .clamp(0.0, QUAT_COMPONENT_MAX as f32) as u32;
+        packed |= bits << shift;
+    }
+
+    packed
+}
+
+/// Unpack a rotation from `quantize_quat`.
+pub fn dequantize_quat(packed: u32) -> Quat {
+    let largest = (packed >> (QUAT_COMPONENT_BITS * 3)) as usize & 0b11;
+
+    let mut components = [0.0; 4];
+    let mut shift = QUAT_COMPONENT_BITS * 3;
+    let mut sum = 0.0;
+    for (index, component) in components.iter_mut().enumerate() {
+        if index == largest {
+            continue;
+        }
+
+        shift -= QUAT_COMPONENT_BITS;
+        let bits = (packed >> shift) & QUAT_COMPONENT_MAX;
+        let normalized = bits as f32 / QUAT_COMPONENT_MAX as f32;
+        *component = (normalized * 2.0 - 1.0) * FRAC_1_SQRT_2;
+        sum += *component * *component;
+    }
+
+    components[largest] = (1.0 - sum).max(0.0).sqrt();
+    Quat::from_array(components).normalize()
+}
+
+/// Server side, keep `QuantizedTransform`s in line with their `Transform`.
+///
+/// Only written when the quantized value actually changed so tiny movements don't get
+/// replicated.
+pub fn quantize_transforms(
+    quantization: Res<TransformQuantization>,
+    mut query: Query<(&Transform, &mut QuantizedTransform), Changed<Transform>>,
+) {
+    for (transform, mut quantized) in query.iter_mut() {
+        let new = quantization.quantize(transform);
+        if *quantized != new {
+            *quantized = new;
+        }
+    }
+}
+
+/// Client side, apply replicated `QuantizedTransform`s to the `Transform`.
+pub fn dequantize_transforms(
+    mut commands: Commands,
+    quantization: Res<TransformQuantization>,
+    mut query: Query<
+        (Entity, &QuantizedTransform, Option<&mut Transform>),
+        Changed<QuantizedTransform>,
+    >,
+) {
+    for (entity, quantized, transform) in query.iter_mut() {
+        let new = quantization.dequantize(quantized);
+        match transform {
+            Some(mut transform) => *transform = new,
+            None => {
+                commands
+                    .entity(entity)
+                    .insert(TransformBundle::from_transform(new));
+            }
+        }
+    }
+}
+
+/// Replicate `QuantizedTransform`s and keep them in sync with `Transform`.
+#[derive(Default, Debug, Clone)]
+pub struct ReplicateQuantizedTransformPlugin {
+    pub quantization: TransformQuantization,
+}
+
+impl ReplicateQuantizedTransformPlugin {
+    /// Quantize translations and scales in steps of `unit`.
+    pub fn with_unit(unit: f32) -> Self {
+        Self {
+            quantization: TransformQuantization::new(unit),
+        }
+    }
+}
+
+impl Plugin for ReplicateQuantizedTransformPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(self.quantization);
+        app.add_plugin(ReplicatePlugin::<QuantizedTransform>::default());
+
+        if app.world.contains_resource::<crate::Server>() {
+            // Before the meta stage picks up changes to replicate.
+            app.add_system_to_network_stage(NetworkCoreStage::Last, quantize_transforms);
+        }
+
+        if app.world.contains_resource::<crate::Client>() {
+            app.add_update_history_network_system(dequantize_transforms.after("client_update"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn quat_within_tolerance() {
+        let steps = 24;
+        for x in 0..steps {
+            for y in 0..steps {
+                for z in 0..steps {
+                    let angle = |step: i32| step as f32 / steps as f32 * std::f32::consts::TAU;
+                    let rotation = Quat::from_euler(EulerRot::XYZ, angle(x), angle(y), angle(z));
+
+                    let round_trip = dequantize_quat(quantize_quat(rotation));
+                    let error = 2.0 * rotation.dot(round_trip).abs().min(1.0).acos();
+                    assert!(
+                        error < QUAT_TOLERANCE,
+                        "{:?} came back as {:?}, off by {} radians",
+                        rotation,
+                        round_trip,
+                        error
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn translation_within_unit() {
+        let quantization = TransformQuantization::default();
+        let transform = Transform::from_xyz(12.3456, -0.0001, 4096.5)
+            .with_rotation(Quat::from_rotation_y(1.0))
+            .with_scale(Vec3::splat(1.5));
+
+        let round_trip = quantization.dequantize(&quantization.quantize(&transform));
+        assert!(round_trip
+            .translation
+            .abs_diff_eq(transform.translation, quantization.unit / 2.0));
+        assert_eq!(round_trip.scale, transform.scale);
+    }
+}
@@ -0,0 +1,102 @@
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::plugin::ReplicatePlugin;
+
+/// Team/faction an entity belongs to.
+///
+/// Much cheaper to replicate than a full `Color`, the client maps it to a color
+/// locally with `TeamColors`.
+#[derive(Component, Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect, FromReflect)]
+pub struct Team(pub u8);
+
+/// Local color for an entity's `Team`, games should use this to update their materials/sprites.
+#[derive(Component, Debug, Copy, Clone, PartialEq)]
+pub struct TeamColor(pub Color);
+
+/// Client side mapping of team ids to colors.
+#[derive(Resource, Debug, Clone)]
+pub struct TeamColors {
+    pub colors: HashMap<u8, Color>,
+    /// Color used for any teams we don't know about.
+    pub unknown: Color,
+}
+
+impl Default for TeamColors {
+    fn default() -> Self {
+        Self {
+            colors: HashMap::default(),
+            unknown: Color::WHITE,
+        }
+    }
+}
+
+impl TeamColors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, team: Team, color: Color) -> Self {
+        self.insert(team, color);
+        self
+    }
+
+    pub fn insert(&mut self, team: Team, color: Color) {
+        self.colors.insert(team.0, color);
+    }
+
+    pub fn get(&self, team: &Team) -> Color {
+        self.colors.get(&team.0).cloned().unwrap_or(self.unknown)
+    }
+}
+
+pub fn apply_team_colors(
+    mut commands: Commands,
+    team_colors: Res<TeamColors>,
+    query: Query<(Entity, &Team), Changed<Team>>,
+) {
+    for (entity, team) in query.iter() {
+        commands
+            .entity(entity)
+            .insert(TeamColor(team_colors.get(team)));
+    }
+}
+
+pub struct ReplicateTeamPlugin;
+impl Plugin for ReplicateTeamPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(ReplicatePlugin::<Team>::default());
+
+        if app.world.contains_resource::<crate::Client>() {
+            app.init_resource::<TeamColors>();
+            app.add_system(apply_team_colors);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn team_color() {
+        let mut world = World::new();
+        world.insert_resource(TeamColors::new().with(Team(1), Color::RED));
+
+        let red = world.spawn(Team(1)).id();
+        let unknown = world.spawn(Team(7)).id();
+
+        let mut stage = SystemStage::single_threaded().with_system(apply_team_colors);
+        stage.run(&mut world);
+
+        assert_eq!(world.get::<TeamColor>(red), Some(&TeamColor(Color::RED)));
+        assert_eq!(
+            world.get::<TeamColor>(unknown),
+            Some(&TeamColor(Color::WHITE))
+        );
+
+        // Replicated team change.
+        world.get_mut::<Team>(red).unwrap().0 = 7;
+        stage.run(&mut world);
+        assert_eq!(world.get::<TeamColor>(red), Some(&TeamColor(Color::WHITE)));
+    }
+}
@@ -0,0 +1,156 @@
+//! Replication of `Visibility` and `ComputedVisibility`.
+//!
+//! `Visibility` is replicated as is, it is just the `is_visible` bool. `ComputedVisibility`
+//! can't be, its fields are private and bevy recomputes them every frame, so only
+//! `is_visible_in_hierarchy` is sent through the `HierarchyVisibility` stand-in.
+//!
+//! `ComputedVisibility` should probably be server-authoritative, e.g. for entities the
+//! server hides because they are out of bounds. Clients still compute it themselves unless
+//! the entity is marked with `ServerComputedVisibility` to opt in.
+
+use bevy::{prelude::*, render::view::VisibilitySystems};
+
+use crate::plugin::{ReplicateConfig, ReplicatePlugin};
+
+/// Replicated stand-in for `ComputedVisibility::is_visible_in_hierarchy`.
+#[derive(Component, Debug, Default, Copy, Clone, PartialEq, Eq, Reflect, FromReflect)]
+#[reflect(Component)]
+pub struct HierarchyVisibility(pub bool);
+
+/// Use the server's `ComputedVisibility::is_visible_in_hierarchy` on the client instead of
+/// the one computed locally.
+#[derive(Component, Debug, Default, Copy, Clone)]
+pub struct ServerComputedVisibility;
+
+/// Server side, keep `HierarchyVisibility` in sync with what bevy computed.
+///
+/// Only written when it actually changed, bevy touches `ComputedVisibility` every frame.
+pub fn server_hierarchy_visibility(
+    mut commands: Commands,
+    mut query: Query<(
+        Entity,
+        &ComputedVisibility,
+        Option<&mut HierarchyVisibility>,
+    )>,
+) {
+    for (entity, computed, hierarchy) in query.iter_mut() {
+        let visible = computed.is_visible_in_hierarchy();
+        match hierarchy {
+            Some(mut hierarchy) => {
+                if hierarchy.0 != visible {
+                    hierarchy.0 = visible;
+                }
+            }
+            None => {
+                commands.entity(entity).insert(HierarchyVisibility(visible));
+            }
+        }
+    }
+}
+
+/// Client side, overwrite the locally computed `is_visible_in_hierarchy` with the server's
+/// on entities marked with `ServerComputedVisibility`.
+pub fn client_hierarchy_visibility(
+    mut query: Query<
+        (&HierarchyVisibility, &mut ComputedVisibility),
+        With<ServerComputedVisibility>,
+    >,
+) {
+    for (hierarchy, mut computed) in query.iter_mut() {
+        // Private, but reflected.
+        if let Some(visible) = computed.get_field_mut::<bool>("is_visible_in_hierarchy") {
+            *visible = hierarchy.0;
+        }
+    }
+}
+
+/// Replicate `Visibility` and the hierarchy visibility of `ComputedVisibility`.
+#[derive(Default, Debug, Clone)]
+pub struct ReplicateVisibilityPlugin;
+
+impl Plugin for ReplicateVisibilityPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(ReplicatePlugin::<Visibility>::default());
+        // Computed by bevy, so there is nothing to predict.
+        app.add_plugin(ReplicatePlugin::<HierarchyVisibility>::with_config(
+            ReplicateConfig::default().without_prediction(),
+        ));
+
+        if app.world.contains_resource::<crate::Server>() {
+            app.add_system_to_stage(
+                CoreStage::PostUpdate,
+                server_hierarchy_visibility.after(VisibilitySystems::VisibilityPropagate),
+            );
+        }
+
+        if app.world.contains_resource::<crate::Client>() {
+            app.add_system_to_stage(
+                CoreStage::PostUpdate,
+                client_hierarchy_visibility
+                    .after(VisibilitySystems::VisibilityPropagate)
+                    .before(VisibilitySystems::CheckVisibility),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn set_hierarchy_visible(computed: &mut ComputedVisibility, visible: bool) {
+        *computed
+            .get_field_mut::<bool>("is_visible_in_hierarchy")
+            .unwrap() = visible;
+    }
+
+    #[test]
+    pub fn server_hierarchy() {
+        let mut world = World::new();
+        let entity = world.spawn(ComputedVisibility::default()).id();
+
+        let mut stage = SystemStage::single_threaded().with_system(server_hierarchy_visibility);
+        stage.run(&mut world);
+        assert_eq!(
+            world.get::<HierarchyVisibility>(entity),
+            Some(&HierarchyVisibility(false))
+        );
+
+        set_hierarchy_visible(
+            &mut world.get_mut::<ComputedVisibility>(entity).unwrap(),
+            true,
+        );
+        stage.run(&mut world);
+        assert_eq!(
+            world.get::<HierarchyVisibility>(entity),
+            Some(&HierarchyVisibility(true))
+        );
+    }
+
+    #[test]
+    pub fn client_opts_in() {
+        let mut world = World::new();
+        let server_computed = world
+            .spawn((
+                HierarchyVisibility(true),
+                ComputedVisibility::default(),
+                ServerComputedVisibility,
+            ))
+            .id();
+        let local = world
+            .spawn((HierarchyVisibility(true), ComputedVisibility::default()))
+            .id();
+
+        let mut stage = SystemStage::single_threaded().with_system(client_hierarchy_visibility);
+        stage.run(&mut world);
+
+        let visible = |entity| {
+            world
+                .get::<ComputedVisibility>(entity)
+                .unwrap()
+                .is_visible_in_hierarchy()
+        };
+        assert!(visible(server_computed));
+        assert!(!visible(local));
+    }
+}
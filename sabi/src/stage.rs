@@ -6,6 +6,9 @@ use bevy::prelude::*;
 
 use crate::tick::NetworkTick;
 
+/// Default for `NetworkSimulationInfo::max_ticks_per_frame`.
+pub const DEFAULT_MAX_TICKS_PER_FRAME: u32 = 5;
+
 /// This type will be available as a resource, while a fixed timestep stage
 /// runs, to provide info about the current status of the fixed timestep.
 ///
@@ -15,26 +18,63 @@ use crate::tick::NetworkTick;
 #[derive(Resource, Debug, Clone)]
 pub struct NetworkSimulationInfo {
     pub step: Duration,
+    /// Step we were configured with locally, `step` can be changed by the server's tick rate.
+    pub configured_step: Duration,
     pub accumulator: Duration,
 
     pub accel: bool,
     pub accel_step: Duration,
 
     pub slowdown: f64,
+
+    /// Maximum number of ticks to resimulate per frame after a rewind.
+    ///
+    /// Anything past this is carried over to the following frames so a deep correction
+    /// doesn't cause a frame spike. This needs to be larger than the number of ticks we
+    /// simulate per frame or we will never catch back up. `None` resimulates everything
+    /// in the same frame.
+    pub max_resim_ticks: Option<u32>,
+
+    /// Furthest back a rewind is allowed to go.
+    ///
+    /// Rewinds further back than this aren't resimulated, the server updates since the
+    /// rewind are applied in order without running the game so we snap to the server's
+    /// latest state. `None` always resimulates.
+    pub max_rollback_ticks: Option<u32>,
+
+    /// Maximum number of ticks to simulate in a single frame, at least 1.
+    ///
+    /// After a long pause (debugger, minimized window) the accumulator can be far behind.
+    /// Catching all of it up at once makes each frame take longer, which only puts us
+    /// further behind. Anything past this is dropped instead, so the game stutters once
+    /// rather than spiraling. Set it higher to keep more of the simulation time.
+    pub max_ticks_per_frame: u32,
 }
 
 impl NetworkSimulationInfo {
     pub fn new(timestep: Duration) -> Self {
         Self {
             step: timestep,
+            configured_step: timestep,
             accumulator: Duration::default(),
 
             accel: true,
             accel_step: Duration::ZERO,
 
             slowdown: 1.0,
+
+            max_resim_ticks: None,
+            max_rollback_ticks: None,
+            max_ticks_per_frame: DEFAULT_MAX_TICKS_PER_FRAME,
         }
     }
+    pub fn set_max_ticks_per_frame(&mut self, max_ticks: u32) {
+        self.max_ticks_per_frame = max_ticks;
+    }
+    /// Go back to the locally configured step, e.g. after disconnecting from a server.
+    pub fn reset_step(&mut self) {
+        self.step = self.configured_step;
+    }
     /// The time duration of each timestep
     pub fn static_timestep(&self) -> Duration {
         self.step
@@ -52,6 +92,20 @@ impl NetworkSimulationInfo {
     pub fn overstep(&self) -> f64 {
         self.accumulator.as_secs_f64() / self.step.as_secs_f64()
     }
+    /// How far we are between the last tick and the next one, in `[0, 1]`.
+    ///
+    /// This is the fraction of a timestep left in the accumulator, so rendering can lerp
+    /// from the state before the last tick to the state after it. `NetworkSimulationInfo`
+    /// is kept up to date after the network stage runs, so read it from `CoreStage::Update`
+    /// or later.
+    pub fn interpolation_alpha(&self) -> f32 {
+        let timestep = self.timestep().as_secs_f64();
+        if timestep <= 0.0 {
+            return 1.0;
+        }
+
+        (self.accumulator.as_secs_f64() / timestep).clamp(0.0, 1.0) as f32
+    }
 
     pub fn accel(&mut self, percentage: f64) {
         self.accel = true;
@@ -114,6 +168,17 @@ pub struct NetworkSimulationStage {
     pub schedule: Schedule,
     /// How many times to apply buffers.
     pub apply_buffers: u8,
+    /// Rewind that didn't finish resimulating in a single frame.
+    pub pending_resim: Option<PendingResim>,
+}
+
+/// Resimulation carried over to the next frame, see `NetworkSimulationInfo::max_resim_ticks`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingResim {
+    /// Tick the simulation was originally rewound to.
+    pub from: NetworkTick,
+    /// Tick we are resimulating towards, moves forward with every live tick we defer.
+    pub to: NetworkTick,
 }
 
 impl NetworkSimulationStage {
@@ -127,6 +192,7 @@ impl NetworkSimulationStage {
             meta: SystemStage::single_threaded(),
             schedule: Schedule::default(),
             apply_buffers: u8::MAX,
+            pending_resim: None,
         }
     }
 }
@@ -134,6 +200,72 @@ impl NetworkSimulationStage {
 #[derive(Resource, Debug, Clone)]
 pub struct Rewind(pub NetworkTick);
 
+/// Only present while ticks are being replayed after a rewind, for systems that shouldn't
+/// repeat themselves like sound effects, e.g. `run_unless_resource_exists::<Resimulating>()`.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resimulating {
+    /// Tick the simulation was rewound to.
+    pub from: NetworkTick,
+    /// Tick we are resimulating up to.
+    pub to: NetworkTick,
+    /// Last tick resimulated this frame, before `to` if the rest is carried over to the
+    /// next frame by `NetworkSimulationInfo::max_resim_ticks`.
+    pub until: NetworkTick,
+}
+
+impl Resimulating {
+    /// Resimulated ticks left after `tick` before we are caught up.
+    pub fn steps_remaining(&self, tick: NetworkTick) -> u32 {
+        self.to.tick().saturating_sub(tick.tick()) as u32
+    }
+}
+
+/// Pause or speed up the network simulation without touching networking.
+///
+/// While paused nothing is accumulated or simulated, so the tick stays put and clients stop
+/// sending inputs. `speed` scales the frame delta added to the accumulator.
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct SimulationControl {
+    pub paused: bool,
+    pub speed: f32,
+}
+
+impl Default for SimulationControl {
+    fn default() -> Self {
+        Self {
+            paused: false,
+            speed: 1.0,
+        }
+    }
+}
+
+impl SimulationControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+}
+
+/// Run criteria for systems that shouldn't repeat during resimulation, like sound effects,
+/// e.g. `app.add_network_system(play_footsteps.run_if(not_resimulating))`.
+pub fn not_resimulating(resimulating: Option<Res<Resimulating>>) -> bool {
+    resimulating.is_none()
+}
+
+/// Sent when the simulation is rewound to `from` to resimulate up to `to`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RewindEvent {
+    pub from: NetworkTick,
+    pub to: NetworkTick,
+}
+
 impl Stage for NetworkSimulationStage {
     fn run(&mut self, world: &mut World) {
         if let Some(info) = world.get_resource::<NetworkSimulationInfo>() {
@@ -149,17 +281,31 @@ impl Stage for NetworkSimulationStage {
             }
         }
 
+        let control = world
+            .get_resource::<SimulationControl>()
+            .cloned()
+            .unwrap_or_default();
+        if control.paused {
+            world.insert_resource(self.info.clone());
+            return;
+        }
+
         self.info.accumulator += {
             if let Some(time) = world.get_resource::<Time>() {
-                time.delta()
+                time.delta().mul_f64(control.speed.max(0.0) as f64)
             } else {
                 warn!("World does not have a `Time`");
+                world.insert_resource(self.info.clone());
                 return;
             }
         };
 
         world.insert_resource(self.info.clone());
 
+        if !world.contains_resource::<NetworkTick>() {
+            self.pending_resim = None;
+        }
+
         let increment_network_tick = |world: &mut World| {
             world
                 .get_resource_mut::<NetworkTick>()
@@ -167,23 +313,49 @@ impl Stage for NetworkSimulationStage {
                 .increment_tick();
         };
 
-        // A maximum of 1 timestep stage per frame.
-        //
-        // This avoids some input weirdness and not amazing since we are
-        // now bound by the renderer.
+        // At most `max_ticks_per_frame` timesteps per frame, see
+        // `NetworkSimulationInfo::max_ticks_per_frame`.
+        let mut ticks = 0;
         while self.info.accumulator >= self.info.timestep() {
+            if ticks >= self.info.max_ticks_per_frame.max(1) {
+                // Drop whole ticks we are behind on, keep the partial one for `overstep`.
+                let timestep = self.info.timestep().as_secs_f64();
+                warn!(
+                    "simulation fell {} ticks behind, dropping them to catch up",
+                    (self.info.accumulator.as_secs_f64() / timestep).floor()
+                );
+                self.info.accumulator =
+                    Duration::from_secs_f64(self.info.accumulator.as_secs_f64() % timestep);
+                break;
+            }
+
+            ticks += 1;
             self.info.accumulator -= self.info.timestep();
             world.insert_resource(self.info.clone());
 
-            if world.contains_resource::<NetworkTick>() {
-                increment_network_tick(world);
+            if let Some(pending) = &mut self.pending_resim {
+                // Still catching up on a rewind, so defer this tick to the resimulation
+                // but make sure the meta systems see the tick we are actually on.
+                pending.to.increment_tick();
 
-                world.insert_resource(bevy::ecs::schedule::ReportExecutionOrderAmbiguities);
-                self.schedule.run(world);
-                world.remove_resource::<bevy::ecs::schedule::ReportExecutionOrderAmbiguities>();
-            }
+                let resimmed_tick = world
+                    .get_resource::<NetworkTick>()
+                    .expect("expected network tick")
+                    .clone();
+                world.insert_resource(pending.to);
+                self.meta.run(world);
+                world.insert_resource(resimmed_tick);
+            } else {
+                if world.contains_resource::<NetworkTick>() {
+                    increment_network_tick(world);
 
-            self.meta.run(world);
+                    world.insert_resource(bevy::ecs::schedule::ReportExecutionOrderAmbiguities);
+                    self.schedule.run(world);
+                    world.remove_resource::<bevy::ecs::schedule::ReportExecutionOrderAmbiguities>();
+                }
+
+                self.meta.run(world);
+            }
 
             if let Some(info) = world.get_resource::<NetworkSimulationInfo>() {
                 self.info = info.clone();
@@ -191,13 +363,80 @@ impl Stage for NetworkSimulationStage {
         }
 
         if let Some(current_tick) = world.get_resource::<NetworkTick>().cloned() {
-            if let Some(rewind) = world.get_resource::<Rewind>() {
+            let target_tick = self
+                .pending_resim
+                .map_or(current_tick, |pending| pending.to);
+            let mut resimulate = self.pending_resim.is_some();
+            let mut resim_from = self
+                .pending_resim
+                .map_or(current_tick, |pending| pending.from);
+            let mut rewound = false;
+
+            let max_resim_ticks = self.info.max_resim_ticks.unwrap_or(u32::MAX);
+            let resim_until = |cursor: NetworkTick| {
+                NetworkTick::new(
+                    target_tick
+                        .tick()
+                        .min(cursor.tick().saturating_add(max_resim_ticks as u64)),
+                )
+            };
+
+            world.insert_resource(bevy::ecs::schedule::ReportExecutionOrderAmbiguities);
+            if resimulate {
+                world.insert_resource(Resimulating {
+                    from: resim_from,
+                    to: target_tick,
+                    until: resim_until(current_tick),
+                });
+            }
+
+            if let Some(rewind) = world.remove_resource::<Rewind>() {
                 let rewind_tick = rewind.0.clone();
 
-                if rewind_tick.tick() <= current_tick.tick() {
-                    world.insert_resource(bevy::ecs::schedule::ReportExecutionOrderAmbiguities);
+                // Measured from the newest tick we simulated, not where a carried over
+                // resimulation is at.
+                let distance = target_tick.tick().saturating_sub(rewind_tick.tick());
+                let too_far = self
+                    .info
+                    .max_rollback_ticks
+                    .map_or(false, |max_rollback| distance > max_rollback as u64);
 
+                // If we are still resimulating and the rewind is past where we are at then
+                // we will get to it anyways.
+                if rewind_tick.tick() <= current_tick.tick() && too_far {
+                    warn!(
+                        "rewind of {} ticks is past the rollback limit, snapping to the server",
+                        distance
+                    );
+
+                    // Only the server's updates are applied, nothing is resimulated.
+                    world.remove_resource::<Resimulating>();
+                    let mut snapped_tick = rewind_tick;
+                    loop {
+                        world.insert_resource(snapped_tick);
+                        self.update_history.run(world);
+                        if snapped_tick.tick() >= target_tick.tick() {
+                            break;
+                        }
+                        snapped_tick.increment_tick();
+                    }
+
+                    self.pending_resim = None;
+                    resimulate = false;
+                } else if rewind_tick.tick() <= current_tick.tick() {
+                    resim_from = rewind_tick;
                     world.insert_resource(rewind_tick);
+                    world.insert_resource(Resimulating {
+                        from: rewind_tick,
+                        to: target_tick,
+                        until: resim_until(rewind_tick),
+                    });
+                    if let Some(mut events) = world.get_resource_mut::<Events<RewindEvent>>() {
+                        events.send(RewindEvent {
+                            from: rewind_tick,
+                            to: target_tick,
+                        });
+                    }
                     /*
                                        info!("");
                                        info!(
@@ -210,33 +449,58 @@ impl Stage for NetworkSimulationStage {
                     self.input_history.run(world);
                     self.update_history.run(world);
 
-                    for tick in (rewind_tick.tick() + 1)..=current_tick.tick() {
-                        increment_network_tick(world);
+                    resimulate = true;
+                    rewound = true;
+                }
+            }
 
-                        let replayed_tick = world
-                            .get_resource::<NetworkTick>()
-                            .expect("expected network tick")
-                            .clone();
-                        assert_eq!(replayed_tick.tick(), tick);
+            if resimulate {
+                if !rewound {
+                    // Carried over from an earlier frame, the game has sampled new input
+                    // since then so put back the input for the tick we stopped on.
+                    self.input_history.run(world);
+                }
 
-                        //info!("replaying {}", tick);
+                let mut resimmed = 0;
 
-                        self.schedule.run(world);
-                        self.input_history.run(world);
-                        self.update_history.run(world);
+                loop {
+                    let replayed_tick = world
+                        .get_resource::<NetworkTick>()
+                        .expect("expected network tick")
+                        .clone();
+
+                    if replayed_tick.tick() >= target_tick.tick() || resimmed >= max_resim_ticks {
+                        break;
                     }
 
-                    world.remove_resource::<bevy::ecs::schedule::ReportExecutionOrderAmbiguities>();
+                    increment_network_tick(world);
+                    resimmed += 1;
+
+                    //info!("replaying {}", tick);
+
+                    self.schedule.run(world);
+                    self.input_history.run(world);
+                    self.update_history.run(world);
                 }
 
                 let resimmed_current_tick = world
                     .get_resource::<NetworkTick>()
                     .expect("expected network tick")
                     .clone();
-                assert_eq!(current_tick.tick(), resimmed_current_tick.tick());
 
-                world.remove_resource::<Rewind>();
+                if resimmed_current_tick.tick() < target_tick.tick() {
+                    self.pending_resim = Some(PendingResim {
+                        from: resim_from,
+                        to: target_tick,
+                    });
+                } else {
+                    assert_eq!(target_tick.tick(), resimmed_current_tick.tick());
+                    self.pending_resim = None;
+                }
             }
+
+            world.remove_resource::<Resimulating>();
+            world.remove_resource::<bevy::ecs::schedule::ReportExecutionOrderAmbiguities>();
         }
 
         world.insert_resource(self.info.clone());
@@ -303,6 +567,8 @@ pub trait NetworkSimulationAppExt {
         &mut self,
         system: impl IntoSystemDescriptor<Params>,
     ) -> &mut Self;
+
+    fn add_meta_network_system_set(&mut self, system_set: SystemSet) -> &mut Self;
 }
 
 impl NetworkSimulationAppExt for App {
@@ -414,4 +680,395 @@ impl NetworkSimulationAppExt for App {
         self.get_network_stage().meta.add_system(system);
         self
     }
+
+    fn add_meta_network_system_set(&mut self, system_set: SystemSet) -> &mut Self {
+        self.get_network_stage().meta.add_system_set(system_set);
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Resource, Default)]
+    struct Simulated(u32);
+
+    fn simulate(mut simulated: ResMut<Simulated>) {
+        simulated.0 += 1;
+    }
+
+    #[test]
+    pub fn bounded_catch_up() {
+        let step = crate::tick::tick_hz(32);
+
+        let mut world = World::new();
+        world.insert_resource(Time::default());
+        world.insert_resource(crate::Local);
+        world.insert_resource(NetworkTick::new(0));
+        world.init_resource::<Simulated>();
+
+        // Came back from a long pause.
+        let mut info = NetworkSimulationInfo::new(step);
+        info.accumulator = step * 100 + step / 2;
+        world.insert_resource(info);
+
+        let mut stage = NetworkSimulationStage::new(step);
+        stage.schedule.add_stage(
+            NetworkCoreStage::Update,
+            SystemStage::single_threaded().with_system(simulate),
+        );
+
+        stage.run(&mut world);
+        assert_eq!(world.resource::<Simulated>().0, DEFAULT_MAX_TICKS_PER_FRAME);
+        assert_eq!(
+            world.resource::<NetworkTick>().tick(),
+            DEFAULT_MAX_TICKS_PER_FRAME as u64
+        );
+
+        let info = world.resource::<NetworkSimulationInfo>();
+        assert!(info.accumulator < step);
+        assert!((info.overstep() - 0.5).abs() < 1e-3);
+        assert!((info.interpolation_alpha() - 0.5).abs() < 1e-3);
+
+        let mut behind = info.clone();
+        behind.accumulator = step * 3;
+        assert_eq!(behind.interpolation_alpha(), 1.0);
+
+        // Nothing left to catch up on.
+        stage.run(&mut world);
+        assert_eq!(world.resource::<Simulated>().0, DEFAULT_MAX_TICKS_PER_FRAME);
+    }
+
+    #[test]
+    pub fn paused() {
+        let step = crate::tick::tick_hz(32);
+
+        let mut world = World::new();
+        let mut time = Time::default();
+        let start = time.startup();
+        time.update_with_instant(start);
+        world.insert_resource(time);
+        world.insert_resource(crate::Local);
+        world.insert_resource(NetworkTick::new(0));
+        world.insert_resource(NetworkSimulationInfo::new(step));
+        world.init_resource::<Simulated>();
+        world.insert_resource(SimulationControl {
+            paused: true,
+            speed: 1.0,
+        });
+
+        let mut stage = NetworkSimulationStage::new(step);
+        stage.schedule.add_stage(
+            NetworkCoreStage::Update,
+            SystemStage::single_threaded().with_system(simulate),
+        );
+
+        let mut frame = |world: &mut World, n: u32| {
+            world
+                .resource_mut::<Time>()
+                .update_with_instant(start + step * n);
+            stage.run(world);
+        };
+
+        for n in 1..=4 {
+            frame(&mut world, n);
+            assert_eq!(world.resource::<NetworkTick>().tick(), 0);
+            assert_eq!(world.resource::<Simulated>().0, 0);
+        }
+
+        // Double speed, two ticks per frame.
+        world.insert_resource(SimulationControl {
+            paused: false,
+            speed: 2.0,
+        });
+        frame(&mut world, 5);
+        assert_eq!(world.resource::<NetworkTick>().tick(), 2);
+
+        world.resource_mut::<SimulationControl>().pause();
+        frame(&mut world, 6);
+        assert_eq!(world.resource::<NetworkTick>().tick(), 2);
+    }
+
+    #[test]
+    pub fn bounded_resim() {
+        #[derive(Resource, Default)]
+        struct Ticks {
+            simulated: Vec<u64>,
+            meta: Vec<u64>,
+        }
+
+        let step = crate::tick::tick_hz(32);
+        let mut world = World::new();
+        let mut time = Time::default();
+        let start = time.startup();
+        time.update_with_instant(start);
+        world.insert_resource(time);
+        world.insert_resource(crate::Local);
+        world.insert_resource(NetworkTick::new(100));
+        world.init_resource::<Ticks>();
+
+        let mut info = NetworkSimulationInfo::new(step);
+        info.max_resim_ticks = Some(10);
+        world.insert_resource(info);
+
+        let mut stage = NetworkSimulationStage::new(step);
+        stage.schedule.add_stage(
+            NetworkCoreStage::Update,
+            SystemStage::single_threaded().with_system(
+                |tick: Res<NetworkTick>, mut ticks: ResMut<Ticks>| {
+                    ticks.simulated.push(tick.tick());
+                },
+            ),
+        );
+        stage
+            .meta
+            .add_system(|tick: Res<NetworkTick>, mut ticks: ResMut<Ticks>| {
+                ticks.meta.push(tick.tick());
+            });
+
+        // One new tick every frame while the rewind is still being resimulated.
+        let frame = |stage: &mut NetworkSimulationStage, world: &mut World, n: u32| {
+            world
+                .resource_mut::<Time>()
+                .update_with_instant(start + step * n);
+            stage.run(world);
+        };
+
+        world.insert_resource(Rewind(NetworkTick::new(50)));
+        frame(&mut stage, &mut world, 1);
+        {
+            let ticks = world.resource::<Ticks>();
+            let mut expected = vec![101];
+            expected.extend(51..=60);
+            assert_eq!(ticks.simulated, expected);
+            assert_eq!(ticks.meta, vec![101]);
+        }
+        assert_eq!(world.resource::<NetworkTick>().tick(), 60);
+
+        // New ticks are deferred to the resimulation, the meta systems still see them.
+        for n in 2..=5 {
+            frame(&mut stage, &mut world, n);
+            assert_eq!(world.resource::<NetworkTick>().tick(), 50 + n as u64 * 10);
+        }
+        assert!(stage.pending_resim.is_some());
+
+        // Catches up to the live tick, which moved forward while we were resimulating.
+        frame(&mut stage, &mut world, 6);
+        assert!(stage.pending_resim.is_none());
+        assert_eq!(world.resource::<NetworkTick>().tick(), 106);
+
+        let ticks = world.resource::<Ticks>();
+        let mut expected = vec![101];
+        expected.extend(51..=106);
+        assert_eq!(ticks.simulated, expected);
+        assert_eq!(ticks.meta, (101..=106).collect::<Vec<_>>());
+
+        // Caught up, only the live tick from now on.
+        frame(&mut stage, &mut world, 7);
+        let ticks = world.resource::<Ticks>();
+        assert_eq!(ticks.simulated.last(), Some(&107));
+        assert_eq!(ticks.simulated.len(), 58);
+    }
+
+    #[test]
+    pub fn resimulating_marker() {
+        #[derive(Resource, Default)]
+        struct Seen(Vec<Option<Resimulating>>);
+
+        let step = crate::tick::tick_hz(32);
+        let mut world = World::new();
+        world.insert_resource(Time::default());
+        world.insert_resource(crate::Local);
+        world.insert_resource(NetworkTick::new(10));
+        world.insert_resource(NetworkSimulationInfo::new(step));
+        world.init_resource::<Events<RewindEvent>>();
+        world.init_resource::<Seen>();
+
+        let mut stage = NetworkSimulationStage::new(step);
+        stage.schedule.add_stage(
+            NetworkCoreStage::Update,
+            SystemStage::single_threaded().with_system(
+                |resimulating: Option<Res<Resimulating>>, mut seen: ResMut<Seen>| {
+                    seen.0.push(resimulating.as_deref().cloned());
+                },
+            ),
+        );
+
+        // Nothing to replay, so not resimulating.
+        stage.run(&mut world);
+        assert!(world.resource::<Seen>().0.is_empty());
+        assert!(!world.contains_resource::<Resimulating>());
+
+        world.insert_resource(Rewind(NetworkTick::new(7)));
+        stage.run(&mut world);
+
+        let marker = Resimulating {
+            from: NetworkTick::new(7),
+            to: NetworkTick::new(10),
+            until: NetworkTick::new(10),
+        };
+        assert_eq!(world.resource::<Seen>().0, vec![Some(marker); 3]);
+        assert!(!world.contains_resource::<Resimulating>());
+
+        let events = world.resource::<Events<RewindEvent>>();
+        let sent = events
+            .get_reader()
+            .iter(events)
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sent,
+            vec![RewindEvent {
+                from: NetworkTick::new(7),
+                to: NetworkTick::new(10),
+            }]
+        );
+    }
+
+    #[test]
+    pub fn carried_over_resim() {
+        #[derive(Resource, Default)]
+        struct Seen(Vec<Resimulating>);
+
+        let step = crate::tick::tick_hz(32);
+        let mut world = World::new();
+        world.insert_resource(Time::default());
+        world.insert_resource(crate::Local);
+        world.insert_resource(NetworkTick::new(10));
+        world.init_resource::<Seen>();
+
+        let mut info = NetworkSimulationInfo::new(step);
+        info.max_resim_ticks = Some(2);
+        info.max_rollback_ticks = Some(8);
+        world.insert_resource(info);
+
+        let mut stage = NetworkSimulationStage::new(step);
+        stage.schedule.add_stage(
+            NetworkCoreStage::Update,
+            SystemStage::single_threaded().with_system(
+                |resimulating: Option<Res<Resimulating>>, mut seen: ResMut<Seen>| {
+                    if let Some(resimulating) = resimulating {
+                        seen.0.push(*resimulating);
+                    }
+                },
+            ),
+        );
+
+        world.insert_resource(Rewind(NetworkTick::new(5)));
+        stage.run(&mut world);
+        stage.run(&mut world);
+
+        // Still rewound to 5 on the following frame, not where we left off.
+        let marker = |until| Resimulating {
+            from: NetworkTick::new(5),
+            to: NetworkTick::new(10),
+            until: NetworkTick::new(until),
+        };
+        assert_eq!(
+            world.resource::<Seen>().0,
+            vec![marker(7), marker(7), marker(9), marker(9)]
+        );
+        assert_eq!(world.resource::<NetworkTick>().tick(), 9);
+
+        // Only 8 ticks behind where the resimulation is at, but 9 behind the live tick.
+        world.insert_resource(Rewind(NetworkTick::new(1)));
+        stage.run(&mut world);
+        assert_eq!(world.resource::<Seen>().0.len(), 4);
+        assert_eq!(world.resource::<NetworkTick>().tick(), 10);
+        assert!(stage.pending_resim.is_none());
+    }
+
+    #[test]
+    pub fn skip_while_resimulating() {
+        use iyes_loopless::prelude::IntoConditionalSystem;
+
+        #[derive(Resource, Default)]
+        struct Seen(Vec<Option<(NetworkTick, u32)>>);
+
+        let step = crate::tick::tick_hz(32);
+        let mut world = World::new();
+        world.insert_resource(Time::default());
+        world.insert_resource(crate::Local);
+        world.insert_resource(NetworkTick::new(10));
+        world.init_resource::<Seen>();
+        world.init_resource::<Simulated>();
+
+        // One live tick and then a rewind on the same frame.
+        let mut info = NetworkSimulationInfo::new(step);
+        info.accumulator = step;
+        world.insert_resource(info);
+        world.insert_resource(Rewind(NetworkTick::new(7)));
+
+        let mut stage = NetworkSimulationStage::new(step);
+        stage.schedule.add_stage(
+            NetworkCoreStage::Update,
+            SystemStage::single_threaded()
+                .with_system(
+                    |tick: Res<NetworkTick>,
+                     resimulating: Option<Res<Resimulating>>,
+                     mut seen: ResMut<Seen>| {
+                        seen.0.push(
+                            resimulating.map(|resim| (resim.from, resim.steps_remaining(*tick))),
+                        );
+                    },
+                )
+                .with_system(simulate.run_if(not_resimulating)),
+        );
+        stage.run(&mut world);
+
+        let resim = |steps_remaining| Some((NetworkTick::new(7), steps_remaining));
+        assert_eq!(
+            world.resource::<Seen>().0,
+            vec![None, resim(3), resim(2), resim(1), resim(0)]
+        );
+        assert_eq!(world.resource::<Simulated>().0, 1);
+        assert!(!world.contains_resource::<Resimulating>());
+    }
+
+    #[test]
+    pub fn rollback_limit_snaps() {
+        #[derive(Resource, Default)]
+        struct Applied(Vec<u64>);
+
+        let step = crate::tick::tick_hz(32);
+        let mut world = World::new();
+        world.insert_resource(Time::default());
+        world.insert_resource(crate::Local);
+        world.insert_resource(NetworkTick::new(100));
+        world.init_resource::<Simulated>();
+        world.init_resource::<Applied>();
+
+        let mut info = NetworkSimulationInfo::new(step);
+        info.max_rollback_ticks = Some(10);
+        world.insert_resource(info);
+
+        let mut stage = NetworkSimulationStage::new(step);
+        stage.schedule.add_stage(
+            NetworkCoreStage::Update,
+            SystemStage::single_threaded().with_system(simulate),
+        );
+        stage
+            .update_history
+            .add_system(|tick: Res<NetworkTick>, mut applied: ResMut<Applied>| {
+                applied.0.push(tick.tick());
+            });
+
+        // Within the limit is resimulated like normal.
+        world.insert_resource(Rewind(NetworkTick::new(95)));
+        stage.run(&mut world);
+        assert_eq!(world.resource::<Simulated>().0, 5);
+        world.resource_mut::<Applied>().0.clear();
+
+        // Past it only applies the server updates up to where we were.
+        world.insert_resource(Rewind(NetworkTick::new(50)));
+        stage.run(&mut world);
+        assert_eq!(world.resource::<Simulated>().0, 5);
+        assert_eq!(world.resource::<NetworkTick>().tick(), 100);
+        assert_eq!(
+            world.resource::<Applied>().0,
+            (50..=100).collect::<Vec<_>>()
+        );
+        assert!(stage.pending_resim.is_none());
+    }
 }
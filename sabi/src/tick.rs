@@ -1,6 +1,6 @@
 use bevy::{prelude::*, reflect::FromReflect};
 
-use std::time::Duration;
+use std::{cmp::Ordering, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
@@ -33,8 +33,12 @@ impl NetworkTick {
         Self(tick)
     }
 
+    /// Move to the next tick, wrapping back to 0 after `u64::MAX`.
+    ///
+    /// At any realistic tick rate this never actually wraps, `signed_diff` and
+    /// `wrapping_cmp` still handle it if it does.
     pub fn increment_tick(&mut self) {
-        self.0 += 1;
+        self.0 = self.0.wrapping_add(1);
     }
 
     pub fn set_tick(&mut self, tick: u64) {
@@ -44,9 +48,252 @@ impl NetworkTick {
     pub fn tick(&self) -> u64 {
         self.0
     }
+
+    /// How many ticks `self` is ahead of `other`, negative if it is behind.
+    ///
+    /// Ticks more than `i64::MAX` apart are treated as having wrapped around.
+    pub fn signed_diff(self, other: NetworkTick) -> i64 {
+        self.0.wrapping_sub(other.0) as i64
+    }
+
+    /// Compare ticks by which one is ahead, unlike `Ord` this accounts for wrapping.
+    pub fn wrapping_cmp(&self, other: &NetworkTick) -> Ordering {
+        self.signed_diff(*other).cmp(&0)
+    }
+
+    /// Ticks from `from` up to but not including `to`, empty if `to` isn't ahead of `from`.
+    pub fn range(from: NetworkTick, to: NetworkTick) -> impl Iterator<Item = NetworkTick> {
+        let len = to.signed_diff(from).max(0) as u64;
+        (0..len).map(move |offset| NetworkTick(from.0.wrapping_add(offset)))
+    }
+
+    /// Tick `ticks` before this one, stopping at 0.
+    pub fn saturating_sub(&self, ticks: u64) -> NetworkTick {
+        Self(self.0.saturating_sub(ticks))
+    }
+
+    /// Tick `ticks` before this one, `None` if that would be before 0.
+    pub fn checked_sub(&self, ticks: u64) -> Option<NetworkTick> {
+        self.0.checked_sub(ticks).map(Self)
+    }
+
+    /// Tick `ticks` after this one, `None` if that overflows.
+    pub fn checked_add(&self, ticks: u64) -> Option<NetworkTick> {
+        self.0.checked_add(ticks).map(Self)
+    }
+}
+
+/// Timer that is measured in network ticks rather than wall-clock time.
+///
+/// Durations don't survive the trip between server and client since both sides
+/// have their own clocks, so instead we store the tick that the timer expires on
+/// which both sides agree on regardless of clock offset. This also makes the timer
+/// rewind correctly when we resimulate.
+///
+/// Replicated like any other component, see `SabiPlugin::with_network_timer`.
+#[derive(Component, Debug, Default, Copy, Clone, PartialEq, Eq, Reflect, FromReflect)]
+pub struct NetworkTimer {
+    expires: NetworkTick,
+}
+
+impl NetworkTimer {
+    /// Timer that expires `ticks` after the `current` tick.
+    pub fn new(current: NetworkTick, ticks: u64) -> Self {
+        Self {
+            // Far enough out that it might as well never expire.
+            expires: current
+                .checked_add(ticks)
+                .unwrap_or(NetworkTick::new(u64::MAX)),
+        }
+    }
+
+    /// Timer that expires after `duration`, rounded up to the next tick.
+    pub fn from_duration(current: NetworkTick, duration: Duration, step: Duration) -> Self {
+        let ticks = (duration.as_secs_f64() / step.as_secs_f64()).ceil() as u64;
+        Self::new(current, ticks)
+    }
+
+    /// Tick this timer expires on.
+    pub fn expires(&self) -> NetworkTick {
+        self.expires
+    }
+
+    /// How many ticks are left until this timer finishes relative to the `current` tick.
+    pub fn remaining_ticks(&self, current: NetworkTick) -> u64 {
+        self.expires.tick().saturating_sub(current.tick())
+    }
+
+    /// Remaining time until this timer finishes relative to the `current` tick.
+    pub fn remaining(&self, current: NetworkTick, step: Duration) -> Duration {
+        step * self.remaining_ticks(current) as u32
+    }
+
+    pub fn finished(&self, current: NetworkTick) -> bool {
+        self.remaining_ticks(current) == 0
+    }
+
+    /// Restart the timer to expire `ticks` after the `current` tick.
+    pub fn reset(&mut self, current: NetworkTick, ticks: u64) {
+        *self = Self::new(current, ticks);
+    }
 }
 
 /// Quick function for getting a duration for tick rates.
 pub const fn tick_hz(rate: u64) -> Duration {
     Duration::from_nanos(1_000_000_000 / rate)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn tick_arithmetic_near_zero() {
+        let zero = NetworkTick::new(0);
+        let three = NetworkTick::new(3);
+
+        // Rewinding from 3 to 0.
+        assert_eq!(zero.signed_diff(three), -3);
+        assert_eq!(three.signed_diff(zero), 3);
+        assert_eq!(zero.wrapping_cmp(&three), Ordering::Less);
+        assert_eq!(three.wrapping_cmp(&three), Ordering::Equal);
+
+        assert_eq!(three.saturating_sub(5), zero);
+        assert_eq!(three.checked_sub(5), None);
+        assert_eq!(three.checked_sub(3), Some(zero));
+        assert_eq!(zero.checked_add(3), Some(three));
+    }
+
+    #[test]
+    pub fn tick_arithmetic_large_values() {
+        let max = NetworkTick::new(u64::MAX);
+        let before_max = NetworkTick::new(u64::MAX - 2);
+
+        assert_eq!(max.signed_diff(before_max), 2);
+        assert_eq!(before_max.signed_diff(max), -2);
+        assert_eq!(max.checked_add(1), None);
+        assert_eq!(before_max.checked_add(2), Some(max));
+
+        // Wraps around instead of overflowing, and still compares as the newer tick.
+        let mut wrapped = max;
+        wrapped.increment_tick();
+        assert_eq!(wrapped, NetworkTick::new(0));
+        assert_eq!(wrapped.signed_diff(max), 1);
+        assert_eq!(wrapped.wrapping_cmp(&max), Ordering::Greater);
+        assert_eq!(max.wrapping_cmp(&wrapped), Ordering::Less);
+
+        let timer = NetworkTimer::new(before_max, 10);
+        assert_eq!(timer.expires(), max);
+
+        // Further apart than `i64` can hold in either direction.
+        assert_eq!(max.signed_diff(NetworkTick::new(0)), -1);
+        assert_eq!(
+            NetworkTick::new(u64::MAX / 2).signed_diff(NetworkTick::new(0)),
+            i64::MAX
+        );
+        assert_eq!(
+            NetworkTick::new(0).signed_diff(NetworkTick::new(u64::MAX / 2 + 1)),
+            i64::MIN
+        );
+    }
+
+    #[test]
+    pub fn tick_range() {
+        let range = |from: u64, to: u64| {
+            NetworkTick::range(NetworkTick::new(from), NetworkTick::new(to))
+                .map(|tick| tick.tick())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(range(3, 6), vec![3, 4, 5]);
+        assert!(range(6, 6).is_empty());
+        assert!(range(6, 3).is_empty());
+        assert_eq!(range(u64::MAX - 1, 1), vec![u64::MAX - 1, u64::MAX, 0]);
+    }
+
+    #[cfg(feature = "public")]
+    #[test]
+    pub fn timer_expiry_agrees() {
+        use crate::protocol::{
+            codec::ComponentCodec,
+            update::{client_update, serialize_component, ComponentData, ComponentsUpdate},
+            version::ReplicateVersion,
+            ServerEntities, ServerEntity,
+        };
+        use crate::replicate::replicate_id;
+
+        let step = tick_hz(32);
+        let type_registry = AppTypeRegistry::default();
+        type_registry.write().register::<NetworkTick>();
+        type_registry.write().register::<NetworkTimer>();
+
+        // Each side has its own clock, far apart.
+        let time = |elapsed: Duration| {
+            let mut time = Time::default();
+            let startup = time.startup();
+            time.update_with_instant(startup);
+            time.update_with_instant(startup + elapsed);
+            time
+        };
+
+        // Server starts a 1 second cooldown on tick 100.
+        let mut server = World::new();
+        server.insert_resource(time(Duration::from_secs(1000)));
+        let server_tick = NetworkTick::new(100);
+        let timer = NetworkTimer::from_duration(server_tick, Duration::from_secs(1), step);
+        assert_eq!(timer.expires(), NetworkTick::new(132));
+        let server_entity = server.spawn(timer).id();
+        let codec = ComponentCodec::default();
+        let data = serialize_component(
+            server.get::<NetworkTimer>(server_entity).unwrap(),
+            &*codec,
+            &type_registry.read(),
+        );
+
+        // Applied on a client that is running a few ticks ahead.
+        let client_tick = NetworkTick::new(105);
+        let mut client = World::new();
+        client.insert_resource(time(Duration::from_secs(3)));
+        client.insert_resource(client_tick);
+        client.insert_resource(type_registry.clone());
+        client.insert_resource(ComponentCodec::default());
+        client.init_resource::<Events<(ServerEntity, ComponentsUpdate)>>();
+
+        let mut server_entities = ServerEntities::new();
+        let mut queue = bevy::ecs::system::CommandQueue::default();
+        let entity =
+            server_entities.spawn_or_get(&mut Commands::new(&mut queue, &client), server_entity);
+        queue.apply(&mut client);
+        client.insert_resource(server_entities);
+
+        let mut components = ComponentsUpdate::new();
+        components.insert(
+            replicate_id::<NetworkTimer>(),
+            ComponentData {
+                version: ReplicateVersion(1),
+                delta_from: None,
+                data,
+            },
+        );
+        client
+            .resource_mut::<Events<(ServerEntity, ComponentsUpdate)>>()
+            .send((ServerEntity::from_entity(server_entity), components));
+        let mut stage = SystemStage::single_threaded().with_system(client_update::<NetworkTimer>);
+        stage.run(&mut client);
+
+        let replicated = *client
+            .get::<NetworkTimer>(entity)
+            .expect("timer was replicated");
+        assert_eq!(replicated.expires(), timer.expires());
+        assert_eq!(timer.remaining_ticks(server_tick), 32);
+        assert_eq!(replicated.remaining_ticks(client_tick), 27);
+
+        assert!(!replicated.finished(NetworkTick::new(131)));
+        assert!(replicated.finished(NetworkTick::new(132)));
+        assert!(replicated.finished(NetworkTick::new(500)));
+        assert_eq!(
+            replicated.remaining(NetworkTick::new(500), step),
+            Duration::ZERO
+        );
+    }
+}